@@ -0,0 +1,151 @@
+#ifndef RAW_TIFF_EDIT_H
+#define RAW_TIFF_EDIT_H
+
+/* Declarations of src/ffi.rs; regenerate with cbindgen instead of editing them. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Outcome of a call
+ */
+typedef enum RawEditStatus {
+  RAW_EDIT_STATUS_OK = 0,
+  /**
+   * A pointer was null, or a string wasn't UTF-8
+   */
+  RAW_EDIT_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The handle has no decoded image, or no encoded file, yet
+   */
+  RAW_EDIT_STATUS_NOT_READY = 2,
+  /**
+   * Reading or writing a file failed
+   */
+  RAW_EDIT_STATUS_IO = 3,
+  /**
+   * The file has no raw data the codec understands
+   */
+  RAW_EDIT_STATUS_UNSUPPORTED = 4,
+  /**
+   * The raw data ends before all of the image has been read
+   */
+  RAW_EDIT_STATUS_TRUNCATED = 5,
+  /**
+   * The plane doesn't fit the raw data of the file
+   */
+  RAW_EDIT_STATUS_MISMATCH = 6,
+  RAW_EDIT_STATUS_PANIC = 7,
+  /**
+   * Saving would write over the file the handle was opened from
+   */
+  RAW_EDIT_STATUS_WOULD_OVERWRITE_ORIGINAL = 8,
+} RawEditStatus;
+
+/**
+ * A raw file being edited; opaque to C
+ */
+typedef struct RawEditHandle RawEditHandle;
+
+/**
+ * The decoded pixel plane of a handle, along with what the host needs to edit it
+ */
+typedef struct RawEditPlane {
+  /**
+   * Linear sensor values, row by row, owned by the handle
+   */
+  uint16_t *pixels;
+  size_t width;
+  size_t height;
+  uint16_t black_level;
+  uint16_t white_level;
+  /**
+   * The visible area, in plane coordinates
+   */
+  size_t crop_x;
+  size_t crop_y;
+  size_t crop_width;
+  size_t crop_height;
+} RawEditPlane;
+
+/**
+ * Reads the file at `path` into a new handle. Returns null if the path is null or not
+ * UTF-8, or the file can't be read.
+ *
+ * # Safety
+ *
+ * `path` is null or a NUL-terminated string.
+ */
+RawEditHandle *raw_edit_open(const char *path);
+
+/**
+ * Decodes the raw data of the file into the pixel plane of the handle, replacing the plane
+ * decoded before, and describes the plane in `plane`
+ *
+ * # Safety
+ *
+ * `handle` is null or was returned by `raw_edit_open` and not closed; `plane` is null or
+ * points to a `RawEditPlane`.
+ */
+RawEditStatus raw_edit_decode(RawEditHandle *handle, RawEditPlane *plane);
+
+/**
+ * Stamps `text` onto the plane with the built-in font, the top left of its line at `(x, y)`
+ * of the plane, `scale` photosites high, in the raw value `value`
+ *
+ * # Safety
+ *
+ * `handle` is null or was returned by `raw_edit_open` and not closed; `text` is null or a
+ * NUL-terminated string. The host doesn't touch the plane during the call.
+ */
+RawEditStatus raw_edit_draw_text(RawEditHandle *handle,
+                                 const char *text,
+                                 uint32_t x,
+                                 uint32_t y,
+                                 float scale,
+                                 uint16_t value);
+
+/**
+ * Replaces the raw data of a copy of the file with the plane, in the format of the file.
+ * If `data` and `len` aren't null, they are set to the encoded file, owned by the handle
+ * until the next `raw_edit_encode`, `raw_edit_decode` or `raw_edit_close`.
+ *
+ * # Safety
+ *
+ * `handle` is null or was returned by `raw_edit_open` and not closed; `data` and `len` are
+ * null or point to a pointer and a size. The host doesn't touch the plane during the call.
+ */
+RawEditStatus raw_edit_encode(RawEditHandle *handle, const uint8_t **data, size_t *len);
+
+/**
+ * Writes the file encoded by the last `raw_edit_encode` to `path`, atomically. Changes made
+ * to the plane since then are not saved. The file the handle was opened from is never
+ * written over.
+ *
+ * # Safety
+ *
+ * `handle` is null or was returned by `raw_edit_open` and not closed; `path` is null or a
+ * NUL-terminated string.
+ */
+RawEditStatus raw_edit_save(RawEditHandle *handle, const char *path);
+
+/**
+ * The message of the last failure of a call on the handle, empty if none failed; owned by
+ * the handle until the next failure or `raw_edit_close`. Null if `handle` is null.
+ *
+ * # Safety
+ *
+ * `handle` is null or was returned by `raw_edit_open` and not closed.
+ */
+const char *raw_edit_last_error(const RawEditHandle *handle);
+
+/**
+ * Frees the handle, with its plane and encoded file
+ *
+ * # Safety
+ *
+ * `handle` is null or was returned by `raw_edit_open` and not closed.
+ */
+void raw_edit_close(RawEditHandle *handle);
+
+#endif /* RAW_TIFF_EDIT_H */
@@ -0,0 +1,290 @@
+//! Runs over several files: finding the files a directory or a pattern stands for, naming
+//! their outputs, and the summary of the run, written with `--report` so that large jobs can
+//! be audited afterwards. The report only ever goes to the file given on the command line.
+//!
+//! A name ending in `.csv` gives one line per file; anything else gives a JSON document:
+//!
+//! ```text
+//! {"total":2,"failed":1,"files":[
+//! {"input":"a.ARW","status":"ok","error":null,"seconds":1.204,"raw_bytes_changed":18312,"verification":"passed","warnings":[]},
+//! {"input":"b.ARW","status":"failed","error":{"kind":"unsupported_format","code":4,"message":"..."},...}
+//! ]}
+//! ```
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+use std::{fs, io, io::Write, path::Path, thread, time::Duration};
+
+use crate::failure::{json_string, Failed, Failure};
+
+/// Extensions of the files picked up from a directory, compared ignoring case
+const RAW_EXTENSIONS: [&str; 3] = ["arw", "sr2", "srf"];
+
+/// Whether `name` matches `pattern`, in which `*` stands for any run of characters and `?`
+/// for any one character
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', rest)), _) => {
+            wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some(('?', rest)), Some((_, name_rest))) => wildcard_match(rest, name_rest),
+        (Some((c, rest)), Some((n, name_rest))) => c == n && wildcard_match(rest, name_rest),
+        (Some(_), None) => false,
+    }
+}
+
+/// The files in `dir` whose names pass `keep`, sorted by name
+fn files_in(dir: &Path, keep: impl Fn(&str) -> bool) -> Result<Vec<String>, Failed> {
+    let entries = fs::read_dir(dir).map_err(|err| {
+        Failed::new(
+            Failure::Io,
+            format!("cannot read the directory {}: {}", dir.display(), err),
+        )
+    })?;
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter(|entry| keep(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// The files an input given on the command line stands for: the raw files (by extension)
+/// directly in a directory, the files matching a pattern with `*` or `?` in its last
+/// component, or else just the input itself. A directory or a pattern that finds no files
+/// is an error.
+pub fn expand_input(input: &str) -> Result<Vec<String>, Failed> {
+    let path = Path::new(input);
+    if path.is_dir() {
+        let files = files_in(path, |name| {
+            let extension = Path::new(name).extension().unwrap_or_default();
+            let extension = extension.to_string_lossy().to_ascii_lowercase();
+            RAW_EXTENSIONS.contains(&&extension[..])
+        })?;
+        if files.is_empty() {
+            return Err(Failed::new(
+                Failure::Usage,
+                format!("no raw files in the directory {}", input),
+            ));
+        }
+        return Ok(files);
+    }
+    let pattern = match path.file_name() {
+        Some(name) if name.to_string_lossy().contains(['*', '?']) => name.to_string_lossy(),
+        _ => return Ok(vec![input.to_owned()]),
+    };
+    let pattern: Vec<_> = pattern.chars().collect();
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let files = files_in(dir, |name| {
+        wildcard_match(&pattern, &name.chars().collect::<Vec<_>>())
+    })?;
+    if files.is_empty() {
+        return Err(Failed::new(
+            Failure::Usage,
+            format!("no files match {}", input),
+        ));
+    }
+    Ok(files)
+}
+
+/// Renders the name of the output of `input`, the `index`th (from 0) of `total` files, from a
+/// template: `{name}` is the name of the input without its extension, `{ext}` its extension
+/// and `{index}` its number from 1, padded with zeros to the width of the last number
+pub fn output_name(template: &str, input: &str, index: usize, total: usize) -> String {
+    let path = Path::new(input);
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let width = total.to_string().len();
+    template
+        .replace("{name}", &name)
+        .replace("{ext}", &extension)
+        .replace("{index}", &format!("{:0width$}", index + 1, width = width))
+}
+
+/// Runs `job` for every index below `count` on up to `jobs` threads, returning the results in
+/// the order of the indices
+pub fn run<T, F>(jobs: usize, count: usize, job: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<_> = (0..count).map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= count {
+                    break;
+                }
+                let result = job(index);
+                *results[index].lock().unwrap() = Some(result);
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Skipped,
+    Passed,
+    Failed,
+}
+
+impl Verification {
+    fn name(self) -> &'static str {
+        match self {
+            Verification::Skipped => "skipped",
+            Verification::Passed => "passed",
+            Verification::Failed => "failed",
+        }
+    }
+}
+
+/// What came out of processing a file successfully
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// Number of bytes of the raw data that the edits changed
+    pub raw_bytes_changed: usize,
+    pub verification: Verification,
+    /// Problems that didn't stop the file from being processed
+    pub warnings: Vec<String>,
+}
+
+impl Default for Outcome {
+    fn default() -> Outcome {
+        Outcome {
+            raw_bytes_changed: 0,
+            verification: Verification::Skipped,
+            warnings: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub input: String,
+    pub result: Result<Outcome, Failed>,
+    pub elapsed: Duration,
+}
+
+impl Entry {
+    fn status(&self) -> &'static str {
+        if self.result.is_ok() {
+            "ok"
+        } else {
+            "failed"
+        }
+    }
+
+    fn verification(&self) -> Verification {
+        match &self.result {
+            Ok(outcome) => outcome.verification,
+            Err(failed) if failed.failure == Failure::Verification => Verification::Failed,
+            Err(_) => Verification::Skipped,
+        }
+    }
+
+    fn raw_bytes_changed(&self) -> usize {
+        self.result
+            .as_ref()
+            .map_or(0, |outcome| outcome.raw_bytes_changed)
+    }
+
+    fn warnings(&self) -> &[String] {
+        self.result
+            .as_ref()
+            .map_or(&[], |outcome| &outcome.warnings[..])
+    }
+}
+
+/// Whether the report should be written as CSV rather than JSON
+pub fn is_csv_name(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".csv")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+pub fn write_csv<W: Write>(out: &mut W, entries: &[Entry]) -> io::Result<()> {
+    writeln!(
+        out,
+        "input,status,error,code,message,seconds,raw_bytes_changed,verification,warnings"
+    )?;
+    for entry in entries {
+        let (error, code, message) = match &entry.result {
+            Ok(_) => ("", String::new(), ""),
+            Err(failed) => (
+                failed.failure.name(),
+                failed.failure.code().to_string(),
+                &failed.message[..],
+            ),
+        };
+        writeln!(
+            out,
+            "{},{},{},{},{},{:.3},{},{},{}",
+            csv_field(&entry.input),
+            entry.status(),
+            error,
+            code,
+            csv_field(message),
+            entry.elapsed.as_secs_f64(),
+            entry.raw_bytes_changed(),
+            entry.verification().name(),
+            csv_field(&entry.warnings().join("; ")),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_json<W: Write>(out: &mut W, entries: &[Entry]) -> io::Result<()> {
+    let failed = entries.iter().filter(|entry| entry.result.is_err()).count();
+    writeln!(
+        out,
+        "{{\"total\":{},\"failed\":{},\"files\":[",
+        entries.len(),
+        failed
+    )?;
+    for (i, entry) in entries.iter().enumerate() {
+        let error = match &entry.result {
+            Ok(_) => "null".to_owned(),
+            Err(failed) => format!(
+                "{{\"kind\":\"{}\",\"code\":{},\"message\":{}}}",
+                failed.failure.name(),
+                failed.failure.code(),
+                json_string(&failed.message)
+            ),
+        };
+        let warnings: Vec<_> = entry.warnings().iter().map(|w| json_string(w)).collect();
+        write!(
+            out,
+            "{{\"input\":{},\"status\":\"{}\",\"error\":{},\"seconds\":{:.3},\"raw_bytes_changed\":{},\"verification\":\"{}\",\"warnings\":[{}]}}",
+            json_string(&entry.input),
+            entry.status(),
+            error,
+            entry.elapsed.as_secs_f64(),
+            entry.raw_bytes_changed(),
+            entry.verification().name(),
+            warnings.join(","),
+        )?;
+        writeln!(out, "{}", if i + 1 < entries.len() { "," } else { "" })?;
+    }
+    writeln!(out, "]}}")
+}
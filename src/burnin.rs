@@ -0,0 +1,103 @@
+//! Burn-in of the capture time and a frame number, for archival and evidentiary use: plain
+//! text on a solid black box in a corner of the visible area, drawn to develop neutral white
+//! whatever the white balance. Unlike the stamp, nothing about it is meant to look good; it
+//! has to stay legible on any background and survive any crop of the rest of the image.
+
+use std::path::Path;
+
+use crate::edit::{FontChain, Neutral, PreparedOverlay, RawBuffer};
+use crate::overlay::Anchor;
+use crate::template::{DateTime, Locale, Metadata};
+use crate::tiled::Rect;
+
+/// How the capture time is written
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Height of the text as a fraction of the height of the visible area
+const RELATIVE_SCALE: f32 = 1.0 / 40.0;
+
+/// Smallest text height, in photosites
+const MIN_SCALE: f32 = 16.0;
+
+/// Level of the text between the black and the white level
+const TEXT_LEVEL: f32 = 0.9;
+
+#[derive(Debug, Clone)]
+pub struct BurnIn {
+    pub text: String,
+    /// Corner of the visible area the box sits in; `Anchor::Center` is taken as bottom-right
+    pub corner: Anchor,
+    pub fonts: FontChain,
+}
+
+impl BurnIn {
+    /// The capture time (DateTimeOriginal, or else DateTime) and the frame number, as in
+    /// `2024-05-01 14:03:59  #01234`. Without a capture time in the file the time reads
+    /// `no capture time`, which is better evidence than a guess.
+    pub fn text(metadata: &Metadata, frame: &str) -> String {
+        let time = metadata
+            .get("DateTimeOriginal")
+            .or_else(|| metadata.get("DateTime"))
+            .and_then(DateTime::parse_exif)
+            .map_or("no capture time".to_owned(), |time| {
+                time.format(TIME_FORMAT, Locale::En)
+            });
+        format!("{}  #{}", time, frame)
+    }
+
+    /// The frame number of a file: the digits at the end of its name (the camera's counter in
+    /// names like `DSC01234.ARW`), or else `position`, counted from 1
+    pub fn frame_number(path: &str, position: usize) -> String {
+        let stem = Path::new(path).file_stem().unwrap_or_default();
+        let stem = stem.to_string_lossy();
+        let digits = stem.len() - stem.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 {
+            stem[stem.len() - digits..].to_owned()
+        } else {
+            (position + 1).to_string()
+        }
+    }
+
+    /// Draws the box and the text into the visible area `crop` of the image. The box starts
+    /// and ends on whole CFA patterns, so every colour is blacked out alike.
+    pub fn draw(&self, img: &mut RawBuffer, crop: Rect, neutral: &Neutral) {
+        let scale = (crop.height as f32 * RELATIVE_SCALE).max(MIN_SCALE);
+        let overlay = PreparedOverlay::text(&self.fonts, &self.text, scale);
+        let (left, top, width, height) = overlay.bounds();
+        let padding = (scale / 4.0).ceil() as usize;
+        let box_width = (width + 2 * padding)
+            .next_multiple_of(2)
+            .min(crop.width & !1);
+        let box_height = (height + 2 * padding)
+            .next_multiple_of(2)
+            .min(crop.height & !1);
+        let (right, bottom) = (crop.x + crop.width, crop.y + crop.height);
+        let x = match self.corner {
+            Anchor::TopLeft | Anchor::BottomLeft => crop.x.next_multiple_of(2),
+            _ => (right - box_width) & !1,
+        };
+        let y = match self.corner {
+            Anchor::TopLeft | Anchor::TopRight => crop.y.next_multiple_of(2),
+            _ => (bottom - box_height) & !1,
+        };
+
+        for row in y..(y + box_height).min(bottom) {
+            for column in x..(x + box_width).min(right) {
+                img.get_pixel_mut(column as u32, row as u32).0[0] = neutral.black_level;
+            }
+        }
+        let range = neutral.white_level.saturating_sub(neutral.black_level) as f32;
+        let value = (neutral.black_level as f32 + range * TEXT_LEVEL) as u16;
+        // the stamp position is the origin of the text, which may lie left of or below the
+        // top-left corner of its mask
+        let text_x = (x + padding) as i64 - left as i64;
+        let text_y = (y + padding) as i64 - top as i64;
+        overlay.draw_neutral(
+            img,
+            text_x.max(0) as u32,
+            text_y.max(0) as u32,
+            value,
+            neutral,
+        );
+    }
+}
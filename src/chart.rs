@@ -0,0 +1,299 @@
+//! Measuring the tone curve of a camera on a raw of a test chart. If the curve a file is
+//! decoded with is right, the linear values of the patches of a grey scale are proportional
+//! to their luminance in every CFA channel; a wrong curve bends them. This is how support for
+//! a new model is checked before its files are edited.
+//!
+//! The chart is described in a file, one patch per line:
+//!
+//! ```text
+//! # x,y,width,height relative to the visible area, then the luminance of the patch
+//! # relative to the others, or its optical density
+//! patch 420,610,80,80 1.0
+//! patch 520,610,80,80 0.5
+//! patch 620,610,80,80 D0.60
+//! ```
+//!
+//! Only the insides of the patches should be covered, away from their edges.
+
+use std::fmt;
+
+use crate::raw::{CfaColor, RawImage};
+use crate::rawloader::{calculate_curve, LookupTable};
+use crate::recipe::parse_rect;
+use crate::tiled::Rect;
+
+/// Largest deviation of the fitted gamma from 1 that still counts as linear
+pub const GAMMA_TOLERANCE: f64 = 0.02;
+/// Largest deviation of a patch from the linear fit that still counts as linear
+pub const ERROR_TOLERANCE: f64 = 0.03;
+/// Patches brighter than this fraction of the white level are taken as clipped
+const CLIPPING: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Patch {
+    /// Position relative to the visible area
+    pub rect: Rect,
+    pub luminance: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Chart {
+    pub patches: Vec<Patch>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChartError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ChartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "chart line {}: {}", self.line, self.message)
+    }
+}
+
+impl Chart {
+    pub fn parse(source: &str) -> Result<Chart, ChartError> {
+        let mut chart = Chart::default();
+        for (i, line) in source.lines().enumerate() {
+            let error = |message: &str| ChartError {
+                line: i + 1,
+                message: message.to_owned(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            if parts.next() != Some("patch") {
+                return Err(error("expected `patch x,y,w,h <luminance>`"));
+            }
+            let (rect, value) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(rect), Some(value), None) => (rect, value),
+                _ => return Err(error("expected `patch x,y,w,h <luminance>`")),
+            };
+            let rect = parse_rect(rect)
+                .filter(|rect| rect.width > 0 && rect.height > 0)
+                .ok_or_else(|| error("invalid rectangle"))?;
+            let luminance = match value.strip_prefix('D') {
+                Some(density) => density.parse::<f64>().map(|d| 10f64.powf(-d)),
+                None => value.parse::<f64>(),
+            }
+            .ok()
+            .filter(|luminance| luminance.is_finite() && *luminance > 0.0)
+            .ok_or_else(|| error("invalid luminance"))?;
+            chart.patches.push(Patch { rect, luminance });
+        }
+        Ok(chart)
+    }
+}
+
+/// A patch as it came out in the raw data
+#[derive(Debug, Clone, Copy)]
+pub struct PatchMeasurement {
+    pub luminance: f64,
+    /// Mean value above black at each position of the 2x2 CFA pattern, row-major
+    pub levels: [f64; 4],
+    /// Mean curve code at each position of the CFA pattern
+    pub codes: [f64; 4],
+    pub clipped: bool,
+}
+
+/// How well one CFA channel follows the luminance of the patches
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelFit {
+    pub color: CfaColor,
+    /// Value above black of a patch of luminance 1, fitted assuming a linear response
+    pub gain: f64,
+    /// Exponent of the power law through the patches; 1 for a linear response
+    pub gamma: f64,
+    /// Largest relative deviation of a patch from the linear fit
+    pub max_error: f64,
+}
+
+impl ChannelFit {
+    pub fn is_linear(&self) -> bool {
+        (self.gamma - 1.0).abs() <= GAMMA_TOLERANCE && self.max_error <= ERROR_TOLERANCE
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CurveReport {
+    pub black_level: u16,
+    pub patches: Vec<PatchMeasurement>,
+    pub channels: [ChannelFit; 4],
+    curve: LookupTable,
+}
+
+impl CurveReport {
+    /// Whether the curve the file was decoded with matches the camera
+    pub fn fits(&self) -> bool {
+        self.channels.iter().all(ChannelFit::is_linear)
+    }
+
+    /// The code the curve gives the linear fit of a patch in a channel, which is where the
+    /// patch would have ended up if the curve were right
+    fn expected_code(&self, luminance: f64, channel: usize) -> u16 {
+        let value = self.channels[channel].gain * luminance + self.black_level as f64;
+        self.curve
+            .reverse_lookup(value.round().clamp(0.0, u16::MAX as f64) as u16)
+    }
+}
+
+impl fmt::Display for CurveReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "tone curve ({} patches):", self.patches.len())?;
+        for (i, fit) in self.channels.iter().enumerate() {
+            writeln!(
+                f,
+                "  {:?} ({}, {}): gamma {:.3}, gain {:.1}, max deviation {:.1}%{}",
+                fit.color,
+                i % 2,
+                i / 2,
+                fit.gamma,
+                fit.gain,
+                fit.max_error * 100.0,
+                if fit.is_linear() { "" } else { "  <- off" }
+            )?;
+        }
+        writeln!(f, "  luminance  code/expected per channel")?;
+        for patch in &self.patches {
+            write!(f, "  {:9.4}", patch.luminance)?;
+            for (channel, code) in patch.codes.iter().enumerate() {
+                write!(
+                    f,
+                    "  {:7.1}/{:4}",
+                    code,
+                    self.expected_code(patch.luminance, channel)
+                )?;
+            }
+            writeln!(f, "{}", if patch.clipped { "  (clipped)" } else { "" })?;
+        }
+        if self.fits() {
+            write!(f, "  the curve fits")
+        } else {
+            write!(f, "  the curve does NOT fit this camera")
+        }
+    }
+}
+
+/// Least-squares slope of a line through the points
+fn fit_slope(points: &[(f64, f64)]) -> f64 {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points
+        .iter()
+        .map(|(x, _)| (x - mean_x) * (x - mean_x))
+        .sum();
+    if variance > 0.0 {
+        covariance / variance
+    } else {
+        0.0
+    }
+}
+
+/// Measures the patches of the chart on an image in display orientation and fits every CFA
+/// channel. At least three patches of different luminance have to be unclipped.
+pub fn analyze(image: &RawImage, chart: &Chart) -> Result<CurveReport, String> {
+    let curve = image.curve.clone().unwrap_or_else(calculate_curve);
+    let black = image.black_level as f64;
+    let clipping = (image.white_level as f64 - black) * CLIPPING;
+    let crop = image.crop;
+
+    let mut patches = vec![];
+    for (i, patch) in chart.patches.iter().enumerate() {
+        let rect = patch.rect;
+        if rect.x + rect.width > crop.width || rect.y + rect.height > crop.height {
+            return Err(format!("patch {} lies outside of the visible area", i + 1));
+        }
+        let mut sums = [0.0; 4];
+        let mut code_sums = [0.0; 4];
+        let mut counts = [0usize; 4];
+        for y in crop.y + rect.y..crop.y + rect.y + rect.height {
+            for x in crop.x + rect.x..crop.x + rect.x + rect.width {
+                let value = image.pixels[y * image.width + x];
+                let index = (y % 2) * 2 + x % 2;
+                sums[index] += value as f64;
+                code_sums[index] += curve.reverse_lookup(value) as f64;
+                counts[index] += 1;
+            }
+        }
+        if counts.contains(&0) {
+            return Err(format!("patch {} does not cover every CFA position", i + 1));
+        }
+        let mut levels = [0.0; 4];
+        let mut codes = [0.0; 4];
+        for index in 0..4 {
+            levels[index] = sums[index] / counts[index] as f64 - black;
+            codes[index] = code_sums[index] / counts[index] as f64;
+        }
+        patches.push(PatchMeasurement {
+            luminance: patch.luminance,
+            levels,
+            codes,
+            clipped: levels.iter().any(|level| *level >= clipping),
+        });
+    }
+    patches.sort_by(|a, b| b.luminance.total_cmp(&a.luminance));
+
+    let usable: Vec<_> = patches.iter().filter(|patch| !patch.clipped).collect();
+    let mut luminances: Vec<_> = usable.iter().map(|patch| patch.luminance).collect();
+    luminances.dedup();
+    if luminances.len() < 3 {
+        return Err(format!(
+            "{} unclipped patches of different luminance, at least 3 are needed",
+            luminances.len()
+        ));
+    }
+
+    let mut channels = [ChannelFit {
+        color: CfaColor::Green,
+        gain: 0.0,
+        gamma: 0.0,
+        max_error: 0.0,
+    }; 4];
+    for (index, fit) in channels.iter_mut().enumerate() {
+        fit.color = image.cfa.colors[index];
+        // through the origin, as black is already subtracted
+        let weighted: f64 = usable
+            .iter()
+            .map(|patch| patch.levels[index] * patch.luminance)
+            .sum();
+        let squares: f64 = usable.iter().map(|patch| patch.luminance.powi(2)).sum();
+        fit.gain = weighted / squares;
+        let logs: Vec<_> = usable
+            .iter()
+            .filter(|patch| patch.levels[index] > 0.0)
+            .map(|patch| (patch.luminance.ln(), patch.levels[index].ln()))
+            .collect();
+        fit.gamma = if logs.len() >= 2 {
+            fit_slope(&logs)
+        } else {
+            0.0
+        };
+        fit.max_error = usable
+            .iter()
+            .map(|patch| {
+                let expected = fit.gain * patch.luminance;
+                if expected > 0.0 {
+                    (patch.levels[index] / expected - 1.0).abs()
+                } else {
+                    f64::INFINITY
+                }
+            })
+            .fold(0.0, f64::max);
+    }
+
+    Ok(CurveReport {
+        black_level: image.black_level,
+        patches,
+        channels,
+        curve,
+    })
+}
@@ -0,0 +1,1425 @@
+//! The command line. Every option is described once, in `OPTIONS`: its help, how its value
+//! is read into `Args`, and what it cannot be combined with, as the options it conflicts
+//! with or needs and the groups it belongs to, which other options rule out as a whole.
+//!
+//! An option that takes a value takes it as `--option=value` or as the next argument; one
+//! whose value can be left out only takes the first form.
+
+use raw_tiff_edit::edit::TextEdit;
+use raw_tiff_edit::redact::Redaction;
+use raw_tiff_edit::{
+    container, defects, dither, dng, export, lens, ops, overlay, recipe, tags, template, tiled,
+    validate, watermark,
+};
+
+use crate::failure::{Failed, Failure};
+use crate::{NO_FRAME, NO_HEIF};
+use Group::*;
+
+/// Which frames of a file with several raw images `--frames` edits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frames {
+    All,
+    /// The N-th, counted from 1
+    Only(usize),
+}
+
+/// Everything read from the command line, before any of the files it names is
+#[derive(Default)]
+pub struct Args {
+    pub validate_tolerance: Option<u16>,
+    pub dng_path: Option<String>,
+    pub dng_layout: dng::Layout,
+    pub embed_original: bool,
+    pub all_renditions: bool,
+    pub previews_only: bool,
+    pub upright_previews: bool,
+    pub regenerate_previews: bool,
+    pub heif: bool,
+    pub text: Option<String>,
+    pub locale: Option<template::Locale>,
+    pub bit_report: bool,
+    pub column_order: bool,
+    pub full_sensor: bool,
+    pub print_stats: bool,
+    pub stats_json: bool,
+    pub calibrate_black: bool,
+    pub stretch: Option<(f64, f64)>,
+    pub recipe_path: Option<String>,
+    pub chart_path: Option<String>,
+    pub probe_point: Option<(usize, usize)>,
+    pub probe_radius: Option<usize>,
+    pub verify: bool,
+    pub strict: bool,
+    pub export_path: Option<String>,
+    pub export_preview_path: Option<String>,
+    pub export_frame_path: Option<String>,
+    pub import_frame_path: Option<String>,
+    pub export_only: bool,
+    pub import_path: Option<String>,
+    pub lens_corrections: Option<Vec<lens::CorrectionKind>>,
+    pub remove_columns: bool,
+    pub fix_hot_pixels: Option<f64>,
+    pub defect_map_path: Option<String>,
+    pub save_defect_map: Option<String>,
+    pub blur: Option<f64>,
+    pub sharpen: Option<(f64, f64)>,
+    pub redactions: Vec<Redaction>,
+    pub flip: (bool, bool),
+    pub crop_rect: Option<tiled::Rect>,
+    pub bias_path: Option<String>,
+    pub block_index_path: Option<String>,
+    pub ifd_index: Option<usize>,
+    pub frames: Option<Frames>,
+    pub print_info: bool,
+    pub print_tags: Vec<&'static tags::TagInfo>,
+    pub survey: bool,
+    pub output_path: Option<String>,
+    pub in_place: bool,
+    pub confirmed: bool,
+    /// `Native` unless given
+    pub export_scaling: Option<export::Scaling>,
+    /// `Original` unless given
+    pub container: Option<container::Container>,
+    pub strip_size: Option<usize>,
+    pub metadata_edit: raw_tiff_edit::metadata::MetadataEdit,
+    pub format_name: Option<String>,
+    /// `dither::camera` unless given
+    pub dither: Option<dither::Factory>,
+    pub raw_geometry: Option<(usize, usize, usize)>,
+    pub inputs: Vec<String>,
+    pub output_dir: Option<String>,
+    pub output_name: Option<String>,
+    pub jobs: Option<usize>,
+    pub threads: Option<usize>,
+    pub report_path: Option<String>,
+    /// Without its fonts, which are loaded from `font_paths`
+    pub stamp: TextEdit,
+    pub font_paths: Vec<String>,
+    pub overlay_path: Option<String>,
+    pub opacity: Option<f32>,
+    pub anchor: Option<overlay::Anchor>,
+    pub tile: bool,
+    pub burn_in: Option<overlay::Anchor>,
+    pub watermark: Option<String>,
+    pub extract_watermark: bool,
+    pub neutral: bool,
+    pub unstamp: bool,
+    pub dry_run: bool,
+    pub nice: bool,
+    given: Vec<Given>,
+}
+
+/// The options other options rule out together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    /// Name a single file, so they can't be used with several inputs
+    PerFile,
+    /// Are about a single raw image, so they can't be used with `--frames all`
+    SingleImage,
+    /// Need the raw data, so they can't be used with `--previews-only`
+    NeedsRaw,
+    /// Only apply to the edited file, so they can't be used when a DNG is written instead
+    EditedFile,
+    /// Write nothing next to the inputs (they print something and stop, or write the inputs
+    /// themselves), so several inputs don't need `--output-dir` with them
+    NoOutput,
+}
+
+/// What an option takes, and how it is read into the arguments
+#[derive(Clone, Copy)]
+enum Takes {
+    Nothing(fn(&mut Args) -> Result<(), String>),
+    /// A value, named as in the help
+    Value(&'static str, fn(&mut Args, &str) -> Result<(), String>),
+    /// A value that can be left out
+    Optional(
+        &'static str,
+        fn(&mut Args, Option<&str>) -> Result<(), String>,
+    ),
+}
+
+/// An option of the command line
+pub struct Spec {
+    name: &'static str,
+    takes: Takes,
+    /// Lines of the help after the first are wrapped by hand, to fit next to the others
+    help: &'static str,
+    groups: &'static [Group],
+    /// The value the option has to have to belong to its groups, where it doesn't always
+    when: Option<&'static str>,
+    conflicts: &'static [&'static str],
+    /// Options of which one has to be given as well
+    needs: &'static [&'static str],
+}
+
+impl Spec {
+    const fn new(name: &'static str, takes: Takes, help: &'static str) -> Spec {
+        Spec {
+            name,
+            takes,
+            help,
+            groups: &[],
+            when: None,
+            conflicts: &[],
+            needs: &[],
+        }
+    }
+
+    const fn switch(
+        name: &'static str,
+        help: &'static str,
+        read: fn(&mut Args) -> Result<(), String>,
+    ) -> Spec {
+        Spec::new(name, Takes::Nothing(read), help)
+    }
+
+    const fn value(
+        name: &'static str,
+        value: &'static str,
+        help: &'static str,
+        read: fn(&mut Args, &str) -> Result<(), String>,
+    ) -> Spec {
+        Spec::new(name, Takes::Value(value, read), help)
+    }
+
+    const fn optional(
+        name: &'static str,
+        value: &'static str,
+        help: &'static str,
+        read: fn(&mut Args, Option<&str>) -> Result<(), String>,
+    ) -> Spec {
+        Spec::new(name, Takes::Optional(value, read), help)
+    }
+
+    const fn groups(mut self, groups: &'static [Group]) -> Spec {
+        self.groups = groups;
+        self
+    }
+
+    const fn when(mut self, value: &'static str) -> Spec {
+        self.when = Some(value);
+        self
+    }
+
+    const fn conflicts(mut self, names: &'static [&'static str]) -> Spec {
+        self.conflicts = names;
+        self
+    }
+
+    const fn needs(mut self, names: &'static [&'static str]) -> Spec {
+        self.needs = names;
+        self
+    }
+
+    /// The option as it is shown in the help
+    fn synopsis(&self) -> String {
+        match self.takes {
+            Takes::Nothing(_) => self.name.to_owned(),
+            Takes::Value(value, _) => format!("{} {}", self.name, value),
+            Takes::Optional(value, _) => format!("{}[={}]", self.name, value),
+        }
+    }
+}
+
+/// An option as given, to check what it is combined with
+struct Given {
+    spec: &'static Spec,
+    value: Option<String>,
+}
+
+impl Given {
+    fn is_in(&self, group: Group) -> bool {
+        self.spec.groups.contains(&group)
+            && self
+                .spec
+                .when
+                .is_none_or(|when| self.value.as_deref() == Some(when))
+    }
+
+    /// The option as named in errors, with the value that put it in its groups
+    fn label(&self) -> String {
+        match self.spec.when {
+            Some(when) => format!("{} {}", self.spec.name, when),
+            None => self.spec.name.to_owned(),
+        }
+    }
+}
+
+/// Every option, in the order of the help
+const OPTIONS: &[Spec] = &[
+    Spec::value(
+        "--input",
+        "FILE",
+        "a raw file to edit, a directory of them, or a pattern with * and ?\n\
+         in the file name; can be given several times",
+        |args, path| {
+            args.inputs.push(path.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--output",
+        "FILE",
+        "where to write the edited file (default: edited.arw); a name\n\
+         ending in .dng writes a DNG of the edited raw data instead",
+        |args, path| {
+            args.output_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile])
+    .conflicts(&["--output-dir"]),
+    Spec::value(
+        "--output-dir",
+        "DIR",
+        "where to write the edited files, under their own names; needed\n\
+         with several inputs unless they are edited in place",
+        |args, path| {
+            args.output_dir = Some(path.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--output-name",
+        "TEMPLATE",
+        "name of the files in the output directory, with {name} and {ext}\n\
+         for those of the input and {index} for its number (default: the\n\
+         name of the input)",
+        |args, template| {
+            args.output_name = Some(template.to_owned());
+            Ok(())
+        },
+    )
+    .needs(&["--output-dir"]),
+    Spec::value(
+        "--jobs",
+        "N",
+        "number of files edited at once (default: one per CPU, at most 4)",
+        |args, value| match value.parse() {
+            Ok(value) if value > 0 => {
+                args.jobs = Some(value);
+                Ok(())
+            }
+            _ => Err(format!("invalid --jobs: {}", value)),
+        },
+    ),
+    Spec::value(
+        "--report",
+        "FILE",
+        "write a summary of every file processed, as CSV (for a .csv\n\
+         name) or JSON",
+        |args, path| {
+            args.report_path = Some(path.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::switch(
+        "--in-place",
+        "overwrite the input instead; needs --yes-i-know",
+        |args| {
+            args.in_place = true;
+            Ok(())
+        },
+    )
+    .groups(&[NoOutput])
+    .conflicts(&["--output", "--output-dir"]),
+    Spec::switch("--yes-i-know", "confirm --in-place", |args| {
+        args.confirmed = true;
+        Ok(())
+    }),
+    Spec::switch(
+        "--dry-run",
+        "decode, edit, encode and validate, but write nothing",
+        |args| {
+            args.dry_run = true;
+            Ok(())
+        },
+    )
+    .groups(&[NoOutput]),
+    Spec::value(
+        "--text",
+        "TEXT",
+        "text of the stamp, with {Field} placeholders",
+        |args, text| {
+            args.text = Some(text.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--font",
+        "FILE",
+        "a TrueType or OpenType font for the text; can be given several\n\
+         times, to fall back on the next font for missing characters",
+        |args, path| {
+            args.font_paths.push(path.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--x",
+        "X",
+        "horizontal position of the stamp in the visible area (default: 1000)",
+        |args, value| {
+            args.stamp.x = value
+                .parse()
+                .map_err(|_| format!("invalid --x: {}", value))?;
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--y",
+        "Y",
+        "vertical position of the stamp in the visible area (default: 1800)",
+        |args, value| {
+            args.stamp.y = value
+                .parse()
+                .map_err(|_| format!("invalid --y: {}", value))?;
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--scale",
+        "SIZE",
+        "height of the stamp in photosites (default: 400)",
+        |args, value| match value.parse::<f32>() {
+            Ok(scale) if scale > 0.0 => {
+                args.stamp.scale = scale;
+                Ok(())
+            }
+            _ => Err(format!("invalid --scale: {}", value)),
+        },
+    ),
+    Spec::value(
+        "--overlay",
+        "FILE",
+        "composite an image (a PNG with alpha) onto the visible area",
+        |args, path| {
+            args.overlay_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--opacity",
+        "F",
+        "opacity of the overlay, from 0 to 1 (default: 1)",
+        |args, value| match value.parse::<f32>() {
+            Ok(value) if (0.0..=1.0).contains(&value) => {
+                args.opacity = Some(value);
+                Ok(())
+            }
+            _ => Err(format!("invalid --opacity: {}", value)),
+        },
+    )
+    .needs(&["--overlay"]),
+    Spec::value(
+        "--anchor",
+        "top-left|top-right|bottom-left|bottom-right|center",
+        "where the overlay goes (default: bottom-right)",
+        |args, name| {
+            let anchor = overlay::Anchor::parse(name);
+            args.anchor = Some(anchor.ok_or_else(|| format!("unknown anchor: {}", name))?);
+            Ok(())
+        },
+    )
+    .conflicts(&["--tile"])
+    .needs(&["--overlay"]),
+    Spec::switch(
+        "--tile",
+        "repeat the overlay over all of the visible area instead",
+        |args| {
+            args.tile = true;
+            Ok(())
+        },
+    )
+    .needs(&["--overlay"]),
+    Spec::switch(
+        "--neutral",
+        "draw the stamp and the overlay with per-photosite values that\n\
+         develop neutral grey, rather than tinted by the white balance",
+        |args| {
+            args.neutral = true;
+            Ok(())
+        },
+    ),
+    Spec::optional(
+        "--burn-in",
+        "CORNER",
+        "burn the capture time and the frame number (from the file name,\n\
+         or else the position in the batch) into a corner of the visible\n\
+         area, white on a black box (default: bottom-left)",
+        |args, name| {
+            let corner = match name {
+                None => Some(overlay::Anchor::BottomLeft),
+                Some(name) => {
+                    overlay::Anchor::parse(name).filter(|anchor| *anchor != overlay::Anchor::Center)
+                }
+            };
+            let corner = corner.ok_or_else(|| format!("unknown corner: {}", name.unwrap()))?;
+            args.burn_in = Some(corner);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--watermark",
+        "TEXT",
+        "hide TEXT (up to 32 bytes) in the noise of ARW2 data, invisibly\n\
+         and so that it survives re-encoding and edits over parts of the\n\
+         image",
+        |args, text| {
+            if text.is_empty() || text.len() > watermark::MAX_PAYLOAD {
+                return Err(format!(
+                    "--watermark needs 1 to {} bytes of text",
+                    watermark::MAX_PAYLOAD
+                ));
+            }
+            args.watermark = Some(text.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::switch(
+        "--extract-watermark",
+        "print the watermark hidden by --watermark, and stop",
+        |args| {
+            args.extract_watermark = true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, NoOutput]),
+    Spec::value(
+        "--recipe",
+        "FILE",
+        "run the steps of a recipe instead of stamping text",
+        |args, path| {
+            args.recipe_path = Some(path.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::switch(
+        "--unstamp",
+        "remove the stamp that --text (or the text steps of --recipe)\n\
+         describes instead of drawing it, from a file whose original is\n\
+         lost; experimental, and only an approximation of the original",
+        |args| {
+            args.unstamp = true;
+            Ok(())
+        },
+    )
+    .conflicts(&["--previews-only", "--all-renditions", "--heif", "--overlay"]),
+    Spec::value(
+        "--locale",
+        "NAME",
+        "locale of the values put into templates",
+        |args, name| {
+            let locale = template::Locale::parse(name);
+            args.locale = Some(locale.ok_or_else(|| format!("unsupported locale: {}", name))?);
+            Ok(())
+        },
+    ),
+    Spec::optional(
+        "--validate",
+        "TOLERANCE",
+        "decode the written file again and compare",
+        |args, tolerance| {
+            args.validate_tolerance = Some(match tolerance {
+                None => validate::DEFAULT_TOLERANCE,
+                Some(tolerance) => tolerance
+                    .parse()
+                    .map_err(|_| format!("invalid --validate tolerance: {}", tolerance))?,
+            });
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, EditedFile]),
+    Spec::optional(
+        "--stretch",
+        "LOW,HIGH",
+        "contrast-stretch to the given percentiles",
+        |args, value| {
+            args.stretch = Some(match value {
+                None => ops::DEFAULT_STRETCH,
+                Some(value) => ops::parse_percentiles(value)
+                    .ok_or_else(|| format!("invalid --stretch percentiles: {}", value))?,
+            });
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value("--blur", "SIGMA", "blur the visible area", |args, value| {
+        let sigma = ops::parse_sigma(value);
+        args.blur = Some(sigma.ok_or_else(|| format!("invalid --blur radius: {}", value))?);
+        Ok(())
+    })
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--sharpen",
+        "SIGMA[,AMOUNT]",
+        "unsharp-mask the visible area",
+        |args, value| {
+            let settings = ops::parse_sharpen(value).ok_or_else(|| {
+                format!(
+                    "invalid --sharpen settings, expected SIGMA[,AMOUNT]: {}",
+                    value
+                )
+            })?;
+            args.sharpen = Some(settings);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--redact-rect",
+        "X,Y,W,H",
+        "fill a rectangle of the visible area with black",
+        |args, value| {
+            let redaction = Redaction::parse_fill(value)
+                .ok_or_else(|| format!("invalid --redact-rect, expected X,Y,W,H: {}", value))?;
+            args.redactions.push(redaction);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--blur-rect",
+        "X,Y,W,H,SIGMA",
+        "blur a rectangle of the visible area",
+        |args, value| {
+            let redaction = Redaction::parse_blur(value)
+                .ok_or_else(|| format!("invalid --blur-rect, expected X,Y,W,H,SIGMA: {}", value))?;
+            args.redactions.push(redaction);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--pixelate",
+        "X,Y,W,H,BLOCK",
+        "pixelate a rectangle of the visible area in blocks of BLOCK\n\
+         photosites; these three can be given several times, and are\n\
+         applied in order before the stamp",
+        |args, value| {
+            let redaction = Redaction::parse_pixelate(value)
+                .ok_or_else(|| format!("invalid --pixelate, expected X,Y,W,H,BLOCK: {}", value))?;
+            args.redactions.push(redaction);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::switch(
+        "--flip-h",
+        "mirror the visible area left to right",
+        |args| {
+            args.flip.0 ^= true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::switch(
+        "--flip-v",
+        "mirror the visible area top to bottom",
+        |args| {
+            args.flip.1 ^= true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::switch(
+        "--rotate180",
+        "turn the visible area by 180 degrees; these keep the colours of\n\
+         the mosaic by shifting the mirror by a photosite where needed",
+        |args| {
+            args.flip = (!args.flip.0, !args.flip.1);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--crop",
+        "X,Y,W,H",
+        "crop the image to a rectangle of the visible area by setting\n\
+         DefaultCropOrigin and DefaultCropSize, keeping all of the data",
+        |args, value| {
+            let rect = recipe::parse_rect(value).filter(|rect| rect.width > 0 && rect.height > 0);
+            let rect =
+                rect.ok_or_else(|| format!("invalid --crop, expected X,Y,W,H: {}", value))?;
+            args.crop_rect = Some(rect);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::switch(
+        "--remove-column-pattern",
+        "remove fixed-pattern column offsets",
+        |args| {
+            args.remove_columns = true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--bias-frame",
+        "FILE",
+        "measure the column pattern on a bias frame",
+        |args, path| {
+            args.bias_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::optional(
+        "--fix-hot-pixels",
+        "THRESHOLD",
+        "repair photosites that lie further than THRESHOLD (a fraction of\n\
+         the range from black to white) outside of the range of their\n\
+         neighbours of the same colour (default: 0.1)",
+        |args, value| {
+            args.fix_hot_pixels = Some(match value {
+                None => defects::DEFAULT_THRESHOLD,
+                Some(value) => defects::parse_threshold(value)
+                    .ok_or_else(|| format!("invalid --fix-hot-pixels threshold: {}", value))?,
+            });
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--defect-map",
+        "FILE",
+        "repair the photosites listed in a defect map as well",
+        |args, path| {
+            args.defect_map_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::value(
+        "--save-defect-map",
+        "FILE",
+        "write the defects repaired (found, and from --defect-map) to a\n\
+         defect map, to repair them in other files from the same camera",
+        |args, path| {
+            args.save_defect_map = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw])
+    .needs(&["--fix-hot-pixels", "--defect-map"]),
+    Spec::switch(
+        "--calibrate-black",
+        "measure the black level on the optical black area",
+        |args| {
+            args.calibrate_black = true;
+            Ok(())
+        },
+    ),
+    Spec::switch(
+        "--full-sensor",
+        "edit the whole readout, masked borders included",
+        |args| {
+            args.full_sensor = true;
+            Ok(())
+        },
+    ),
+    Spec::switch(
+        "--column-order",
+        "treat the data as stored column by column",
+        |args| {
+            args.column_order = true;
+            Ok(())
+        },
+    ),
+    Spec::optional(
+        "--stats",
+        "text|json",
+        "print statistics of the raw data of the visible area (levels and\n\
+         clipping of every CFA channel, and histograms in JSON) and of the\n\
+         optical black area, and stop",
+        |args, format| {
+            args.print_stats = true;
+            args.stats_json = match format {
+                None | Some("text") => false,
+                Some("json") => true,
+                Some(format) => {
+                    return Err(format!(
+                        "invalid --stats format: {}, expected text or json",
+                        format
+                    ))
+                }
+            };
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, NoOutput]),
+    Spec::value(
+        "--chart",
+        "FILE",
+        "measure the tone curve on a raw of the test chart described in\n\
+         FILE, and stop",
+        |args, path| {
+            args.chart_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, NoOutput]),
+    Spec::value(
+        "--probe",
+        "X,Y",
+        "print the raw values around a point of the visible area, and stop",
+        |args, value| {
+            let point = parse_point(value);
+            args.probe_point =
+                Some(point.ok_or_else(|| format!("invalid --probe point: {}", value))?);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, NoOutput]),
+    Spec::value(
+        "--radius",
+        "N",
+        "size of the window --probe reads around the point (default: 0)",
+        |args, value| {
+            let radius = value.parse();
+            args.probe_radius = Some(radius.map_err(|_| format!("invalid --radius: {}", value))?);
+            Ok(())
+        },
+    )
+    .needs(&["--probe"]),
+    Spec::switch(
+        "--verify",
+        "decode the raw data, encode it again without edits and report\n\
+         whether it survives unchanged, and stop",
+        |args| {
+            args.verify = true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, NoOutput]),
+    Spec::switch(
+        "--strict",
+        "refuse to edit files whose raw data looks misdecoded",
+        |args| {
+            args.strict = true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw]),
+    Spec::switch(
+        "--info",
+        "list the image IFDs of the file and stop",
+        |args| {
+            args.print_info = true;
+            Ok(())
+        },
+    )
+    .groups(&[NoOutput]),
+    Spec::switch(
+        "--survey",
+        "report the cameras, resolutions and kinds of raw data of the\n\
+         inputs and which of them cannot be edited, and stop",
+        |args| {
+            args.survey = true;
+            Ok(())
+        },
+    )
+    .groups(&[NoOutput]),
+    Spec::value(
+        "--ifd",
+        "N",
+        "take the raw data from IFD N",
+        |args, index| {
+            let parsed = index.parse();
+            args.ifd_index = Some(parsed.map_err(|_| format!("invalid --ifd index: {}", index))?);
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--frames",
+        "all|N",
+        "edit every frame of a file that holds several raw images (the\n\
+         shots of a pixel-shift capture) the same way, or only the N-th,\n\
+         counted from 1",
+        |args, value| {
+            args.frames = Some(match value {
+                "all" => Frames::All,
+                _ => match value.parse() {
+                    Ok(frame) if frame > 0 => Frames::Only(frame),
+                    _ => {
+                        return Err(format!(
+                            "invalid --frames: {}, expected all or a frame number",
+                            value
+                        ))
+                    }
+                },
+            });
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw])
+    .conflicts(&["--ifd", "--raw-geometry"]),
+    Spec::value(
+        "--raw-geometry",
+        "WxH@OFFSET",
+        "raw data layout for files without one",
+        |args, value| {
+            let geometry = parse_geometry(value).ok_or_else(|| {
+                format!(
+                    "invalid --raw-geometry, expected WIDTHxHEIGHT@OFFSET: {}",
+                    value
+                )
+            })?;
+            args.raw_geometry = Some(geometry);
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--format",
+        "arw2|sr2|srf|uncompressed|packed12|lossless|cr2|nef",
+        "raw data format (default: what the file says, or arw2)",
+        |args, name| {
+            let names = [
+                "arw2",
+                "sr2",
+                "srf",
+                "uncompressed",
+                "packed12",
+                "lossless",
+                "cr2",
+                "nef",
+            ];
+            if !names.contains(&name) {
+                return Err(format!("unknown format: {}", name));
+            }
+            args.format_name = Some(name.to_owned());
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--dither",
+        "NAME",
+        "dither generator for decoding ARW2 and lossy NEF\n\
+         (default: camera)",
+        |args, name| {
+            let factory = dither::by_name(name);
+            args.dither =
+                Some(factory.ok_or_else(|| format!("unknown dither generator: {}", name))?);
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--container",
+        "original|minimal",
+        "keep the original file around the raw data, or not",
+        |args, name| {
+            let container = container::Container::parse(name);
+            args.container = Some(container.ok_or_else(|| format!("unknown container: {}", name))?);
+            Ok(())
+        },
+    )
+    .groups(&[SingleImage, NeedsRaw, EditedFile])
+    .when("minimal"),
+    Spec::value(
+        "--strip-size",
+        "SIZE",
+        "split the raw data into strips of about SIZE bytes (K and M\n\
+         suffixes accepted), for software that handles small strips\n\
+         better; for ARW2, uncompressed and packed 12-bit data",
+        |args, value| {
+            let size = container::parse_strip_size(value);
+            args.strip_size = Some(size.ok_or_else(|| format!("invalid --strip-size: {}", value))?);
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, EditedFile]),
+    Spec::value(
+        "--artist",
+        "TEXT",
+        "set the Artist tag of the written file",
+        |args, text| {
+            args.metadata_edit.artist = Some(text.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::value(
+        "--copyright",
+        "TEXT",
+        "set the Copyright tag of the written file",
+        |args, text| {
+            args.metadata_edit.copyright = Some(text.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::value(
+        "--description",
+        "TEXT",
+        "set the ImageDescription tag of the written file",
+        |args, text| {
+            args.metadata_edit.description = Some(text.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::switch(
+        "--strip-gps",
+        "remove the GPS data from the written file",
+        |args| {
+            args.metadata_edit.strip_gps = true;
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::value(
+        "--tag",
+        "NAME",
+        "print a tag of the input, named as ExifTool does (EXIF:Artist,\n\
+         Sony:SonyModelID, ...), instead of editing it; may be repeated",
+        |args, name| {
+            args.print_tags
+                .push(tags::lookup(name).map_err(|err| err.to_string())?);
+            Ok(())
+        },
+    )
+    .groups(&[NoOutput]),
+    Spec::value(
+        "--set-tag",
+        "NAME=VALUE",
+        "set a tag of the written file by name; may be repeated",
+        |args, assignment| {
+            let (name, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| format!("--set-tag needs NAME=VALUE, not {}", assignment))?;
+            let info = tags::lookup_writable(name).map_err(|err| err.to_string())?;
+            args.metadata_edit.tags.push((info, value.to_owned()));
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::value(
+        "--block-index",
+        "FILE",
+        "cache of ARW2 group positions for partial re-encoding",
+        |args, path| {
+            args.block_index_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw, EditedFile]),
+    Spec::switch(
+        "--all-renditions",
+        "redraw the stamp into the embedded previews as well",
+        |args| {
+            args.all_renditions = true;
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::switch(
+        "--previews-only",
+        "stamp only the embedded previews, leaving the raw data alone",
+        |args| {
+            args.previews_only = true;
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::switch(
+        "--regenerate-previews",
+        "replace the embedded previews with pictures of the edited raw data\n\
+         (demosaiced simply), so they don't show what was edited away",
+        |args| {
+            args.regenerate_previews = true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, EditedFile])
+    .conflicts(&["--all-renditions"]),
+    Spec::switch(
+        "--upright-previews",
+        "store the redrawn previews turned the way they are displayed and\n\
+         set their Orientation to normal (in most files, the Orientation\n\
+         of the previews orients the raw data as well)",
+        |args| {
+            args.upright_previews = true;
+            Ok(())
+        },
+    )
+    .needs(&[
+        "--all-renditions",
+        "--previews-only",
+        "--regenerate-previews",
+    ]),
+    Spec::switch(
+        "--heif",
+        "redraw the stamp into the HEIF file shot with the input as well,\n\
+         written next to the output (needs the heif feature and libheif)",
+        |args| {
+            if !cfg!(feature = "heif") {
+                return Err(NO_HEIF.to_owned());
+            }
+            args.heif = true;
+            Ok(())
+        },
+    )
+    .groups(&[EditedFile]),
+    Spec::value("--dng", "FILE", "also write a DNG", |args, path| {
+        args.dng_path = Some(path.to_owned());
+        Ok(())
+    })
+    .groups(&[PerFile, SingleImage, NeedsRaw, EditedFile]),
+    Spec::switch("--embed-original", "embed the input in the DNG", |args| {
+        args.embed_original = true;
+        Ok(())
+    }),
+    Spec::value(
+        "--dng-layout",
+        "LAYOUT",
+        "what the DNG holds: mosaic (the raw data as it is, default) or\n\
+         linear (demosaiced, for converters that don't know the sensor)",
+        |args, name| {
+            let layout = dng::Layout::parse(name);
+            args.dng_layout = layout.ok_or_else(|| format!("unknown DNG layout: {}", name))?;
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--lens-corrections",
+        "LIST",
+        "keep only the listed lens corrections of the camera (distortion,\n\
+         ca and vignetting, comma separated, or none) in the output and\n\
+         the DNG (default: all of them)",
+        |args, list| {
+            let kinds = parse_lens_corrections(list)
+                .ok_or_else(|| format!("invalid --lens-corrections list: {}", list))?;
+            args.lens_corrections = Some(kinds);
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--export",
+        "FILE",
+        "also write a 16-bit PNG or TIFF of the mosaic",
+        |args, path| {
+            args.export_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw]),
+    Spec::value(
+        "--export-scale",
+        "native|full|white",
+        "how --export and --import map raw values to 16 bits: as they\n\
+         are, shifted to fill 16 bits, or from black and white (default:\n\
+         native)",
+        |args, name| {
+            let scaling = export::Scaling::parse(name);
+            args.export_scaling =
+                Some(scaling.ok_or_else(|| format!("unknown export scaling: {}", name))?);
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--export-preview",
+        "FILE",
+        "also write a half-size colour PNG of the raw data, made without\n\
+         a real demosaic",
+        |args, path| {
+            args.export_preview_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw]),
+    Spec::value(
+        "--import",
+        "FILE",
+        "replace the visible area with a 16-bit grayscale TIFF of it, as\n\
+         written by --export (scaled as given by --export-scale) and\n\
+         edited elsewhere, before the other edits",
+        |args, path| {
+            args.import_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw]),
+    Spec::value(
+        "--export-frame",
+        "FILE",
+        "also write the raw data as a raw frame, CBOR (or MessagePack if\n\
+         FILE ends in .msgpack), for other tools (needs the frame feature)",
+        |args, path| {
+            if !cfg!(feature = "frame") {
+                return Err(NO_FRAME.to_owned());
+            }
+            args.export_frame_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw]),
+    Spec::value(
+        "--import-frame",
+        "FILE",
+        "replace the raw data with a raw frame of it, as written by\n\
+         --export-frame and edited elsewhere, before the other edits",
+        |args, path| {
+            if !cfg!(feature = "frame") {
+                return Err(NO_FRAME.to_owned());
+            }
+            args.import_frame_path = Some(path.to_owned());
+            Ok(())
+        },
+    )
+    .groups(&[PerFile, SingleImage, NeedsRaw]),
+    Spec::switch(
+        "--export-only",
+        "write the exports of the data as decoded, without editing, and\n\
+         stop",
+        |args| {
+            args.export_only = true;
+            Ok(())
+        },
+    )
+    .groups(&[SingleImage, NeedsRaw])
+    .needs(&["--export", "--export-preview", "--export-frame"]),
+    Spec::switch(
+        "--bit-report",
+        "print how the bits of the ARW2 data are spent",
+        |args| {
+            args.bit_report = true;
+            Ok(())
+        },
+    )
+    .groups(&[NeedsRaw, EditedFile]),
+    Spec::value(
+        "--threads",
+        "N",
+        "number of threads to decode and encode with (default: one per CPU)",
+        |args, value| match value.parse() {
+            Ok(value) if value > 0 => {
+                args.threads = Some(value);
+                Ok(())
+            }
+            _ => Err(format!("invalid --threads: {}", value)),
+        },
+    ),
+    Spec::switch(
+        "--nice",
+        "run at a lower priority, to leave the machine usable",
+        |args| {
+            args.nice = true;
+            Ok(())
+        },
+    ),
+    Spec::value(
+        "--error-format",
+        "text|json",
+        "how errors are written to stderr (default: text)",
+        |_, format| match format {
+            // read before everything else, by `json_errors`
+            "text" | "json" => Ok(()),
+            _ => Err(format!("unknown error format: {}", format)),
+        },
+    ),
+];
+
+/// Width of the column of the options in the help
+const SYNOPSIS_WIDTH: usize = 24;
+
+/// The help, made of the options and their help in `OPTIONS`
+pub fn usage() -> String {
+    let mut usage =
+        "usage: raw-tiff-edit [options] --input <file>... (or just <file>...)\n".to_owned();
+    let indent = " ".repeat(2 + SYNOPSIS_WIDTH);
+    for spec in OPTIONS {
+        let synopsis = spec.synopsis();
+        usage.push('\n');
+        if synopsis.len() < SYNOPSIS_WIDTH {
+            usage += &format!("  {:width$}", synopsis, width = SYNOPSIS_WIDTH);
+        } else {
+            usage += &format!("  {}\n{}", synopsis, indent);
+        }
+        usage += &spec.help.replace('\n', &format!("\n{}", indent));
+    }
+    usage
+}
+
+/// Joins options and their values given as separate arguments into `--option=value`. An
+/// option missing its value is left alone.
+pub fn normalize<I: Iterator<Item = String>>(args: I) -> Vec<String> {
+    let mut args = args.peekable();
+    let mut result = vec![];
+    while let Some(arg) = args.next() {
+        let takes_value = OPTIONS
+            .iter()
+            .any(|spec| spec.name == arg && matches!(spec.takes, Takes::Value(..)));
+        match args.next_if(|_| takes_value) {
+            Some(value) => result.push(format!("{}={}", arg, value)),
+            None => result.push(arg),
+        }
+    }
+    result
+}
+
+/// Whether errors are to be written as JSON, known before anything else is read so that every
+/// error comes in the requested format
+pub fn json_errors(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--error-format=json")
+}
+
+/// Reads the normalized arguments, checking what the options are combined with; `None` for
+/// `--help`
+pub fn parse(args: Vec<String>) -> Result<Option<Args>, Failed> {
+    let usage_error = |message| Failed::new(Failure::Usage, message);
+    let mut parsed = Args::default();
+    for arg in args {
+        if arg == "--help" || arg == "-h" {
+            return Ok(None);
+        }
+        if !arg.starts_with("--") {
+            parsed.inputs.push(arg);
+            continue;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (&arg[..], None),
+        };
+        let spec = OPTIONS
+            .iter()
+            .find(|spec| spec.name == name)
+            .ok_or_else(|| usage_error(format!("unknown argument: {}", arg)))?;
+        match (spec.takes, value) {
+            (Takes::Nothing(read), None) => read(&mut parsed),
+            (Takes::Nothing(_), Some(_)) => Err(format!("unknown argument: {}", arg)),
+            (Takes::Value(_, read), Some(value)) => read(&mut parsed, value),
+            (Takes::Value(..), None) => Err(format!("{} needs a value", arg)),
+            (Takes::Optional(_, read), value) => read(&mut parsed, value),
+        }
+        .map_err(usage_error)?;
+        parsed.given.push(Given {
+            spec,
+            value: value.map(str::to_owned),
+        });
+    }
+    parsed.check().map_err(usage_error)?;
+    Ok(Some(parsed))
+}
+
+/// "a", "a or b", "a, b or c"
+fn one_of(names: &[&str]) -> String {
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+impl Args {
+    fn has(&self, name: &str) -> bool {
+        self.given.iter().any(|given| given.spec.name == name)
+    }
+
+    /// The first option given of `group`, as named in errors
+    pub fn first_in(&self, group: Group) -> Option<String> {
+        let given = self.given.iter().find(|given| given.is_in(group))?;
+        Some(given.label())
+    }
+
+    /// What the options given can't be combined with, save for the inputs, which are only
+    /// known once expanded
+    fn check(&self) -> Result<(), String> {
+        if self.inputs.is_empty() {
+            return Err(usage());
+        }
+        if self.in_place && !self.confirmed {
+            return Err(
+                "--in-place overwrites the original file; add --yes-i-know to confirm".to_owned(),
+            );
+        }
+        for given in &self.given {
+            let spec = given.spec;
+            if let Some(other) = spec.conflicts.iter().find(|name| self.has(name)) {
+                return Err(format!("{} and {} cannot be combined", spec.name, other));
+            }
+            if !spec.needs.is_empty() && !spec.needs.iter().any(|name| self.has(name)) {
+                return Err(format!("{} needs {}", spec.name, one_of(spec.needs)));
+            }
+        }
+        if self.frames == Some(Frames::All) {
+            if let Some(option) = self.first_in(SingleImage) {
+                return Err(format!(
+                    "{} is about a single raw image and cannot be used with --frames all",
+                    option
+                ));
+            }
+        }
+        if self.previews_only {
+            if let Some(option) = self.first_in(NeedsRaw) {
+                return Err(format!(
+                    "{} needs the raw data and cannot be used with --previews-only",
+                    option
+                ));
+            }
+        }
+        if self.container == Some(container::Container::Minimal)
+            && self
+                .format_name
+                .as_deref()
+                .is_some_and(|name| name != "arw2")
+        {
+            return Err("the minimal container is only supported for ARW2 data".to_owned());
+        }
+        Ok(())
+    }
+
+    /// What the options given can't be combined with when there are several inputs, or a
+    /// directory or pattern of them
+    pub fn check_batch(&self) -> Result<(), Failed> {
+        if let Some(option) = self.first_in(PerFile) {
+            return Err(Failed::new(
+                Failure::Usage,
+                format!(
+                    "{} names a single file and cannot be used with several inputs",
+                    option
+                ),
+            ));
+        }
+        if self.output_dir.is_none() && self.first_in(NoOutput).is_none() {
+            return Err(Failed::new(
+                Failure::Usage,
+                "several inputs need an --output-dir (or --in-place --yes-i-know)",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parses a raw data layout given as `WIDTHxHEIGHT@OFFSET`
+fn parse_geometry(value: &str) -> Option<(usize, usize, usize)> {
+    let (size, offset) = value.split_once('@')?;
+    let (width, height) = size.split_once('x')?;
+    Some((
+        width.parse().ok()?,
+        height.parse().ok()?,
+        offset.parse().ok()?,
+    ))
+}
+
+/// Parses a point given as `X,Y`
+fn parse_point(value: &str) -> Option<(usize, usize)> {
+    let (x, y) = value.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+/// Parses a list of lens corrections: names separated by commas, `all` or `none`
+fn parse_lens_corrections(list: &str) -> Option<Vec<lens::CorrectionKind>> {
+    match list {
+        "all" => Some(lens::CorrectionKind::ALL.to_vec()),
+        "none" => Some(vec![]),
+        _ => list
+            .split(',')
+            .map(|name| lens::CorrectionKind::parse(name.trim()))
+            .collect(),
+    }
+}
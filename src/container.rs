@@ -0,0 +1,306 @@
+//! Output containers. The edited raw data is either spliced into the byte layout of the
+//! original file, which keeps every IFD, maker note and preview the camera wrote, or written
+//! into a freshly serialized minimal TIFF holding only the raw IFD and the few tags this crate
+//! understands.
+
+use std::io::{self, Write};
+
+use crate::raw::RawImage;
+use crate::tiff::{self, Tiff, Value};
+use crate::tiled::Rect;
+use crate::variant::COMPRESSION_SONY_ARW;
+
+/// SonyRawFileType of cRAW data
+const RAW_FILE_TYPE_COMPRESSED: u16 = 2;
+
+/// Descriptive IFD0 tags carried over from the original file
+const COPIED_TAGS: [u16; 7] = [
+    tiff::IMAGE_DESCRIPTION,
+    tiff::MAKE,
+    tiff::MODEL,
+    tiff::ORIENTATION,
+    tiff::DATE_TIME,
+    tiff::ARTIST,
+    tiff::COPYRIGHT,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// Splice the new raw data into the original file
+    Original,
+    /// Write a new file containing only the raw IFD
+    Minimal,
+}
+
+impl Container {
+    pub fn parse(name: &str) -> Option<Container> {
+        match name {
+            "original" => Some(Container::Original),
+            "minimal" => Some(Container::Minimal),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a strip size in bytes, with an optional `K` or `M` suffix for KiB or MiB
+pub fn parse_strip_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&value[..i], 1 << 10),
+        (i, 'M') | (i, 'm') => (&value[..i], 1 << 20),
+        _ => (value, 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|size| *size > 0)
+}
+
+/// Number of rows of strips of about `strip_size` bytes, for data taking `row_len` bytes a
+/// row; at least one row and at most the whole image
+pub fn rows_per_strip(strip_size: usize, row_len: usize, height: usize) -> usize {
+    (strip_size / row_len.max(1)).clamp(1, height.max(1))
+}
+
+/// Offsets relative to the start of the raw data and byte counts of strips of
+/// `rows_per_strip` rows of a `height` rows tall image, for data in which `data_len(rows)`
+/// bytes hold the first `rows` rows
+pub fn strips(
+    height: usize,
+    rows_per_strip: usize,
+    data_len: impl Fn(usize) -> usize,
+) -> Vec<(u32, u32)> {
+    (0..height)
+        .step_by(rows_per_strip.max(1))
+        .map(|row| {
+            let end = (row + rows_per_strip).min(height);
+            let offset = data_len(row);
+            (offset as u32, (data_len(end) - offset) as u32)
+        })
+        .collect()
+}
+
+/// RowsPerStrip of the IFD at `ifd_offset`, if it has a usable one
+pub fn stored_rows_per_strip(file: &[u8], ifd_offset: usize) -> Option<usize> {
+    let tiff = Tiff::new(file)?;
+    let (ifd, _) = tiff.read_ifd(ifd_offset)?;
+    tiff.value(&ifd, tiff::ROWS_PER_STRIP)
+        .map(|rows| rows as usize)
+        .filter(|rows| *rows > 0)
+}
+
+/// Crops the image described by the IFD at `ifd_offset` to `crop` (in the coordinates of the
+/// stored raw data) without touching the data: DefaultCropOrigin and DefaultCropSize are set,
+/// relative to the ActiveArea if the IFD has one, and Sony's own crop tags are overwritten
+/// where they are in the IFD as well. The IFD is rewritten at the end of the file.
+pub fn write_default_crop(file: &mut Vec<u8>, ifd_offset: usize, crop: Rect) -> Result<(), String> {
+    let tiff = Tiff::new(file).ok_or("not a TIFF file")?;
+    let little_endian = tiff.is_little_endian();
+    let (ifd, _) = tiff
+        .read_ifd(ifd_offset)
+        .ok_or("cannot read the IFD of the raw data")?;
+    let pointer_pos = tiff
+        .pointer_to(ifd_offset)
+        .ok_or("cannot find what points to the IFD of the raw data")?;
+    let (top, left) = match ifd.entry(tiff::ACTIVE_AREA).map(|entry| tiff.values(entry)) {
+        Some(area) if area.len() == 4 => (area[0] as usize, area[1] as usize),
+        _ => (0, 0),
+    };
+    if crop.x < left || crop.y < top {
+        return Err("the crop starts outside of the active area".to_owned());
+    }
+    // Sony's tags hold (top, left) and (width, height), as SHORT or LONG
+    let sony = [
+        (tiff::SONY_CROP_TOP_LEFT, [crop.y, crop.x]),
+        (tiff::SONY_CROP_SIZE, [crop.width, crop.height]),
+    ];
+    let mut in_place = vec![];
+    for (tag, values) in sony.iter() {
+        if let Some(entry) = ifd.entry(*tag).filter(|entry| entry.count == 2) {
+            for (i, value) in values.iter().enumerate() {
+                match entry.field_type {
+                    3 => in_place.push((entry.value_pos + 2 * i, *value as u32, 2)),
+                    4 => in_place.push((entry.value_pos + 4 * i, *value as u32, 4)),
+                    _ => {}
+                }
+            }
+        }
+    }
+    for (pos, value, size) in in_place {
+        if size == 2 {
+            tiff::write_u16(file, pos, value as u16, little_endian);
+        } else {
+            tiff::write_u32(file, pos, value, little_endian);
+        }
+    }
+
+    let changes = [
+        (
+            tiff::DEFAULT_CROP_ORIGIN,
+            Some(Value::Long(vec![
+                (crop.x - left) as u32,
+                (crop.y - top) as u32,
+            ])),
+        ),
+        (
+            tiff::DEFAULT_CROP_SIZE,
+            Some(Value::Long(vec![crop.width as u32, crop.height as u32])),
+        ),
+    ];
+    tiff::rewrite_ifd(file, pointer_pos, &changes)
+        .ok_or("cannot rewrite the IFD of the raw data")?;
+    Ok(())
+}
+
+/// Splits the raw data described by the IFD at `ifd_offset` into the given strips (with
+/// offsets relative to `start`, where the data begins) by rewriting its StripOffsets,
+/// StripByteCounts and RowsPerStrip in place. The data stays where it is; the new arrays
+/// are appended to the file.
+pub fn split_strips(
+    file: &mut Vec<u8>,
+    ifd_offset: usize,
+    start: usize,
+    strips: &[(u32, u32)],
+    rows_per_strip: usize,
+) -> Result<(), String> {
+    let tiff = Tiff::new(file).ok_or("not a TIFF file")?;
+    let little_endian = tiff.is_little_endian();
+    let (ifd, _) = tiff
+        .read_ifd(ifd_offset)
+        .ok_or("cannot read the IFD of the raw data")?;
+    // where the entries themselves are, to change their type and count
+    let position = |tag| {
+        ifd.entries
+            .iter()
+            .position(|entry| entry.tag == tag)
+            .map(|index| ifd_offset + 2 + 12 * index)
+            .ok_or(format!("the IFD of the raw data has no tag {:#06x}", tag))
+    };
+    let offsets_pos = position(tiff::STRIP_OFFSETS)?;
+    let counts_pos = position(tiff::STRIP_BYTE_COUNTS)?;
+    let rows_entry = ifd
+        .entry(tiff::ROWS_PER_STRIP)
+        .copied()
+        .ok_or("the IFD of the raw data has no RowsPerStrip")?;
+
+    let write_longs = |file: &mut Vec<u8>, entry_pos: usize, values: Vec<u32>| {
+        tiff::write_u16(file, entry_pos + 2, 4, little_endian);
+        tiff::write_u32(file, entry_pos + 4, values.len() as u32, little_endian);
+        let value_pos = if values.len() == 1 {
+            entry_pos + 8
+        } else {
+            // word aligned, at the end of the file
+            file.resize(file.len().next_multiple_of(2), 0);
+            let pos = file.len();
+            file.resize(pos + 4 * values.len(), 0);
+            tiff::write_u32(file, entry_pos + 8, pos as u32, little_endian);
+            pos
+        };
+        for (i, value) in values.into_iter().enumerate() {
+            tiff::write_u32(file, value_pos + 4 * i, value, little_endian);
+        }
+    };
+    let offsets = strips
+        .iter()
+        .map(|(offset, _)| start as u32 + offset)
+        .collect();
+    let counts = strips.iter().map(|(_, count)| *count).collect();
+    write_longs(file, offsets_pos, offsets);
+    write_longs(file, counts_pos, counts);
+    match rows_entry.field_type {
+        3 => tiff::write_u16(
+            file,
+            rows_entry.value_pos,
+            rows_per_strip as u16,
+            little_endian,
+        ),
+        _ => tiff::write_u32(
+            file,
+            rows_entry.value_pos,
+            rows_per_strip as u32,
+            little_endian,
+        ),
+    }
+    Ok(())
+}
+
+/// Writes ARW2 data (in stored order, as described by `image`) as a minimal single-IFD ARW,
+/// copying the descriptive tags of `source` that can be read back unambiguously. The data is
+/// split into strips of `rows_per_strip` rows.
+pub fn write_minimal_arw2<W: Write>(
+    out: &mut W,
+    source: &[u8],
+    image: &RawImage,
+    data: &[u8],
+    rows_per_strip: usize,
+) -> io::Result<()> {
+    let strips = strips(image.height, rows_per_strip, |rows| rows * image.width);
+    let mut entries = vec![
+        (0x00fe, Value::Long(vec![0])),
+        (tiff::IMAGE_WIDTH, Value::Long(vec![image.width as u32])),
+        (tiff::IMAGE_LENGTH, Value::Long(vec![image.height as u32])),
+        (0x0102, Value::Short(vec![8])),
+        (
+            tiff::COMPRESSION,
+            Value::Short(vec![COMPRESSION_SONY_ARW as u16]),
+        ),
+        (0x0106, Value::Short(vec![32803])),
+        (
+            tiff::STRIP_OFFSETS,
+            Value::Long(
+                strips
+                    .iter()
+                    .map(|(offset, _)| tiff::DATA_OFFSET + offset)
+                    .collect(),
+            ),
+        ),
+        (0x0115, Value::Short(vec![1])),
+        (
+            tiff::ROWS_PER_STRIP,
+            Value::Long(vec![rows_per_strip.min(image.height) as u32]),
+        ),
+        (
+            tiff::STRIP_BYTE_COUNTS,
+            Value::Long(strips.iter().map(|(_, count)| *count).collect()),
+        ),
+        (0x011c, Value::Short(vec![1])),
+        (
+            tiff::SONY_RAW_FILE_TYPE,
+            Value::Short(vec![RAW_FILE_TYPE_COMPRESSED]),
+        ),
+        (
+            tiff::SONY_BLACK_LEVEL,
+            Value::Short(vec![image.black_level; 4]),
+        ),
+        (
+            tiff::SONY_WHITE_LEVEL,
+            Value::Short(vec![image.white_level; 3]),
+        ),
+        (tiff::CFA_REPEAT_PATTERN_DIM, Value::Short(vec![2, 2])),
+        (tiff::CFA_PATTERN, Value::Byte(image.cfa.tiff_values())),
+    ];
+
+    if let Some(tiff) = Tiff::new(source) {
+        if let Some(ifd0) = tiff.ifd0() {
+            for tag in COPIED_TAGS.iter() {
+                let entry = match ifd0.entry(*tag) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let value = match entry.field_type {
+                    2 => tiff.string(entry).map(Value::Ascii),
+                    3 => Some(Value::Short(
+                        tiff.values(entry).into_iter().map(|v| v as u16).collect(),
+                    )),
+                    _ => None,
+                };
+                if let Some(value) = value {
+                    entries.push((*tag, value));
+                }
+            }
+        }
+    }
+
+    tiff::write_tiff(out, entries, data.len() as u32, |out| out.write_all(data))
+}
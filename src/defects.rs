@@ -0,0 +1,315 @@
+//! Finding and repairing defective photosites: hot ones, stuck high, and dead ones, stuck at
+//! or near black. A photosite is taken as defective when it lies further outside the range
+//! of its neighbours of the same colour than a threshold; real detail that small is rare, and
+//! hardly ever reaches that far. Defective photosites are replaced with the median of their
+//! good neighbours of the same colour.
+//!
+//! Defects stay where they are from one shot to the next, so the ones found (best on a dark
+//! frame) can be kept in a map and repaired in other files from the same camera, whether or
+//! not they stand out there. A map is a text file:
+//!
+//! ```text
+//! # positions in the whole readout, turned the way edits see it
+//! size 6048x4024
+//! hot 1021,877
+//! dead 4410,12
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::edit::RawBuffer;
+use crate::raw::RawImage;
+use crate::tiled::Rect;
+
+/// Threshold used by default, as a fraction of the range from black to white
+pub const DEFAULT_THRESHOLD: f64 = 0.1;
+
+/// Neighbours of the same colour in every CFA pattern, two photosites away
+const SAME_COLOR: [(isize, isize); 8] = [
+    (-2, -2),
+    (0, -2),
+    (2, -2),
+    (-2, 0),
+    (2, 0),
+    (-2, 2),
+    (0, 2),
+    (2, 2),
+];
+/// Diagonal neighbours, of the same colour where the pattern has them (as the greens of a
+/// Bayer pattern do)
+const DIAGONALS: [(isize, isize); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+/// Parses a threshold, a fraction of the range from black to white
+pub fn parse_threshold(value: &str) -> Option<f64> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|threshold| *threshold > 0.0 && *threshold <= 1.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefectKind {
+    /// Stuck high, brighter than all of its neighbours
+    Hot,
+    /// Stuck low, darker than all of its neighbours
+    Dead,
+}
+
+impl DefectKind {
+    fn name(self) -> &'static str {
+        match self {
+            DefectKind::Hot => "hot",
+            DefectKind::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Defect {
+    pub x: usize,
+    pub y: usize,
+    pub kind: DefectKind,
+}
+
+/// Defective photosites of a sensor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefectMap {
+    width: usize,
+    height: usize,
+    /// Kinds by `(y, x)`, so that the map is listed row by row
+    defects: BTreeMap<(usize, usize), DefectKind>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DefectMapError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DefectMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "defect map line {}: {}", self.line, self.message)
+    }
+}
+
+impl DefectMap {
+    /// An empty map of a `width` x `height` readout
+    pub fn new(width: usize, height: usize) -> DefectMap {
+        DefectMap {
+            width,
+            height,
+            defects: BTreeMap::new(),
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<DefectMap, DefectMapError> {
+        let mut map: Option<DefectMap> = None;
+        for (i, line) in source.lines().enumerate() {
+            let error = |message: &str| DefectMapError {
+                line: i + 1,
+                message: message.to_owned(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| error("expected `size WxH`, `hot x,y` or `dead x,y`"))?;
+            let value = value.trim();
+            if keyword == "size" {
+                if map.is_some() {
+                    return Err(error("the size is given twice"));
+                }
+                let (width, height) = value
+                    .split_once('x')
+                    .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                    .ok_or_else(|| error("invalid size, expected WxH"))?;
+                map = Some(DefectMap::new(width, height));
+                continue;
+            }
+            let kind = match keyword {
+                "hot" => DefectKind::Hot,
+                "dead" => DefectKind::Dead,
+                _ => return Err(error("expected `size WxH`, `hot x,y` or `dead x,y`")),
+            };
+            let map = map
+                .as_mut()
+                .ok_or_else(|| error("defects have to come after the size"))?;
+            let (x, y) = value
+                .split_once(',')
+                .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+                .ok_or_else(|| error("invalid position, expected x,y"))?;
+            if x >= map.width || y >= map.height {
+                return Err(error("position outside of the readout"));
+            }
+            map.insert(Defect { x, y, kind });
+        }
+        map.ok_or(DefectMapError {
+            line: source.lines().count(),
+            message: "no size given".to_owned(),
+        })
+    }
+
+    /// Whether the map is of a readout of this size
+    pub fn matches(&self, width: usize, height: usize) -> bool {
+        (self.width, self.height) == (width, height)
+    }
+
+    pub fn insert(&mut self, defect: Defect) {
+        self.defects.insert((defect.y, defect.x), defect.kind);
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        self.defects.contains_key(&(y, x))
+    }
+
+    pub fn len(&self) -> usize {
+        self.defects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.defects.is_empty()
+    }
+
+    /// Every defect, row by row
+    pub fn defects(&self) -> impl Iterator<Item = Defect> + '_ {
+        self.defects.iter().map(|((y, x), kind)| Defect {
+            x: *x,
+            y: *y,
+            kind: *kind,
+        })
+    }
+}
+
+/// The map in the format `DefectMap::parse` reads
+impl fmt::Display for DefectMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "size {}x{}", self.width, self.height)?;
+        for defect in self.defects() {
+            writeln!(f, "{} {},{}", defect.kind.name(), defect.x, defect.y)?;
+        }
+        Ok(())
+    }
+}
+
+/// Positions of the neighbours of the same colour as `(x, y)` inside `rect`
+fn neighbours(image: &RawImage, rect: Rect, x: usize, y: usize) -> Vec<(usize, usize)> {
+    let color = |x: usize, y: usize| image.cfa.colors[(y % 2) * 2 + x % 2];
+    let diagonals = DIAGONALS.iter().filter(|(dx, dy)| {
+        // the pattern repeats every two photosites, so two more keeps the colour
+        color(
+            (x as isize + dx + 2) as usize,
+            (y as isize + dy + 2) as usize,
+        ) == color(x, y)
+    });
+    SAME_COLOR
+        .iter()
+        .chain(diagonals)
+        .filter_map(|(dx, dy)| {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            let inside = nx >= rect.x as isize
+                && ny >= rect.y as isize
+                && nx < (rect.x + rect.width) as isize
+                && ny < (rect.y + rect.height) as isize;
+            if inside {
+                Some((nx as usize, ny as usize))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Finds the photosites of the visible area that lie further than `threshold` (a fraction of
+/// the range from black to white) above or below all of their neighbours of the same colour.
+/// Only the visible area is compared, so that the masked borders don't make its edges look
+/// hot.
+///
+/// ```
+/// use raw_tiff_edit::defects::{self, DefectKind, DefectMap};
+/// use raw_tiff_edit::edit::RawBuffer;
+/// use raw_tiff_edit::raw::RawImage;
+///
+/// let mut image = RawImage::new(vec![4000; 16 * 16], 16, 16, 16383);
+/// image.pixels[5 * 16 + 6] = 9000;
+/// image.pixels[10 * 16 + 9] = 512;
+/// let mut img = RawBuffer::from_raw(16, 16, image.pixels.clone()).unwrap();
+///
+/// let found = defects::detect(&img, &image, defects::DEFAULT_THRESHOLD);
+/// assert_eq!(found.len(), 2);
+/// assert_eq!((found[0].x, found[0].y, found[0].kind), (6, 5, DefectKind::Hot));
+/// assert_eq!((found[1].x, found[1].y, found[1].kind), (9, 10, DefectKind::Dead));
+///
+/// let mut map = DefectMap::new(16, 16);
+/// found.into_iter().for_each(|defect| map.insert(defect));
+/// defects::repair(&mut img, &image, &map);
+/// assert!(img.iter().all(|value| *value == 4000));
+/// assert_eq!(DefectMap::parse(&map.to_string()).unwrap(), map);
+/// ```
+pub fn detect(img: &RawBuffer, image: &RawImage, threshold: f64) -> Vec<Defect> {
+    let crop = image.crop;
+    let margin = threshold * image.white_level.saturating_sub(image.black_level) as f64;
+    let value = |(x, y): (usize, usize)| img.get_pixel(x as u32, y as u32).0[0];
+    let right = (crop.x + crop.width).min(img.width() as usize);
+    let bottom = (crop.y + crop.height).min(img.height() as usize);
+    let mut found = vec![];
+    for y in crop.y..bottom {
+        for x in crop.x..right {
+            let around = neighbours(image, crop, x, y);
+            if around.len() < 2 {
+                continue;
+            }
+            let low = around.iter().map(|at| value(*at)).min().unwrap_or(0) as f64;
+            let high = around.iter().map(|at| value(*at)).max().unwrap_or(0) as f64;
+            let this = value((x, y)) as f64;
+            let kind = if this > high + margin {
+                DefectKind::Hot
+            } else if this < low - margin {
+                DefectKind::Dead
+            } else {
+                continue;
+            };
+            found.push(Defect { x, y, kind });
+        }
+    }
+    found
+}
+
+/// Replaces every photosite of the map with the median of its neighbours of the same colour
+/// that aren't in the map themselves. Photosites without any such neighbour are left alone.
+pub fn repair(img: &mut RawBuffer, image: &RawImage, map: &DefectMap) {
+    let full = Rect {
+        x: 0,
+        y: 0,
+        width: img.width() as usize,
+        height: img.height() as usize,
+    };
+    let repaired: Vec<_> = map
+        .defects()
+        .filter(|defect| defect.x < full.width && defect.y < full.height)
+        .filter_map(|defect| {
+            let mut values: Vec<u16> = neighbours(image, full, defect.x, defect.y)
+                .into_iter()
+                .filter(|(x, y)| !map.contains(*x, *y))
+                .map(|(x, y)| img.get_pixel(x as u32, y as u32).0[0])
+                .collect();
+            if values.is_empty() {
+                return None;
+            }
+            values.sort_unstable();
+            let middle = values.len() / 2;
+            let median = if values.len().is_multiple_of(2) {
+                (values[middle - 1] as u32 + values[middle] as u32).div_ceil(2) as u16
+            } else {
+                values[middle]
+            };
+            Some((defect, median))
+        })
+        .collect();
+    for (defect, value) in repaired {
+        img.get_pixel_mut(defect.x as u32, defect.y as u32).0[0] = value;
+    }
+}
@@ -0,0 +1,129 @@
+//! Pseudo-random generators for the dither applied when ARW2 codes are expanded through the
+//! tone curve.
+//!
+//! Every code of the curve stands for a range of linear values, and the decoder picks one of
+//! them at random. Cameras (and other decoders) use a particular multiply-with-carry generator
+//! seeded from the first bits of every row, which is what `CameraDither` reproduces; the other
+//! generators exist to study how the statistics of the dither affect round trips.
+//!
+//! A custom generator implements `DitherSource`, and a `Factory` creating it can be passed to
+//! `decode_arw2_from` directly. The built-in ones are listed by name in `GENERATORS`, where
+//! `by_name` looks them up; adding a name and a constructor there makes a generator
+//! selectable by name.
+
+/// A generator of 11-bit dither values, restarted at every row of the raw data
+pub trait DitherSource: Send + Sync {
+    /// Restarts the generator from the seed stored in the bitstream (the first 16 bits of the
+    /// row)
+    fn reseed(&mut self, seed: u32);
+    /// The next dither value, in `0..2048`
+    fn next_dither(&mut self) -> u32;
+}
+
+/// Creates a generator, ready to be reseeded
+pub type Factory = fn() -> Box<dyn DitherSource>;
+
+/// The generator Sony's own software and the usual decoders use. The state is public so
+/// that it can be stored and picked up again in the middle of a row.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CameraDither(pub u32);
+
+impl DitherSource for CameraDither {
+    fn reseed(&mut self, seed: u32) {
+        self.0 = seed;
+    }
+
+    #[inline(always)]
+    fn next_dither(&mut self) -> u32 {
+        let value = self.0 & 2047;
+        self.0 = 15700 * (self.0 & 65535) + (self.0 >> 16);
+        value
+    }
+}
+
+/// Marsaglia's xorshift32
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Xorshift32(u32);
+
+impl DitherSource for Xorshift32 {
+    fn reseed(&mut self, seed: u32) {
+        // the all-zero state is a fixed point
+        self.0 = seed.wrapping_mul(0x9e37_79b9) | 1;
+    }
+
+    fn next_dither(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 >> 21
+    }
+}
+
+/// PCG32 (XSH RR), with the reference increment
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pcg32(u64);
+
+impl Pcg32 {
+    const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+    const INCREMENT: u64 = 1_442_695_040_888_963_407;
+
+    fn step(&mut self) -> u32 {
+        let old = self.0;
+        self.0 = old
+            .wrapping_mul(Self::MULTIPLIER)
+            .wrapping_add(Self::INCREMENT);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+impl DitherSource for Pcg32 {
+    fn reseed(&mut self, seed: u32) {
+        self.0 = 0;
+        self.step();
+        self.0 = self.0.wrapping_add(seed as u64);
+        self.step();
+    }
+
+    fn next_dither(&mut self) -> u32 {
+        self.step() >> 21
+    }
+}
+
+/// Always the middle of the range: every code decodes to the same value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoDither;
+
+impl DitherSource for NoDither {
+    fn reseed(&mut self, _seed: u32) {}
+
+    fn next_dither(&mut self) -> u32 {
+        1024
+    }
+}
+
+/// The generators that can be selected by name, the default one first
+pub const GENERATORS: &[(&str, Factory)] = &[
+    ("camera", || Box::new(CameraDither::default())),
+    ("xorshift", || Box::new(Xorshift32::default())),
+    ("pcg", || Box::new(Pcg32::default())),
+    ("none", || Box::new(NoDither)),
+];
+
+/// Looks up a generator in `GENERATORS`
+pub fn by_name(name: &str) -> Option<Factory> {
+    GENERATORS
+        .iter()
+        .find(|(generator, _)| *generator == name)
+        .map(|(_, factory)| *factory)
+}
+
+/// The camera-compatible generator
+pub fn camera() -> Box<dyn DitherSource> {
+    Box::new(CameraDither::default())
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+    assert_send_sync::<dyn DitherSource>();
+};
@@ -0,0 +1,272 @@
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use deflate::deflate_bytes_zlib;
+
+use crate::export;
+use crate::lens::{self, CorrectionKind, LensCorrections};
+use crate::raw::RawImage;
+use crate::tiff::{self, Value};
+
+/// Size of the blocks the original raw file is split into before being compressed
+const ORIGINAL_BLOCK_SIZE: usize = 65536;
+
+/// The original file to be stored in the OriginalRawFileData tag
+#[derive(Debug, Clone, Copy)]
+pub struct OriginalRaw<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+/// How the DNG stores the image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// The mosaic as it is, with its colour filter array
+    #[default]
+    Mosaic,
+    /// Red, green and blue at every pixel, demosaiced but still linear; for converters that
+    /// don't know the colour filter array
+    LinearRaw,
+}
+
+impl Layout {
+    pub fn parse(name: &str) -> Option<Layout> {
+        match name {
+            "mosaic" => Some(Layout::Mosaic),
+            "linear" => Some(Layout::LinearRaw),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DngOptions<'a> {
+    pub layout: Layout,
+    pub original: Option<OriginalRaw<'a>>,
+    /// Lens corrections to translate into opcodes; those turned off are left out
+    pub lens: Option<&'a LensCorrections>,
+    /// The model of the camera, to tell converters which profile to use
+    pub model: Option<&'a str>,
+}
+
+/// Whether a file name asks for a DNG
+pub fn is_dng_name(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".dng")
+}
+
+/// Version of the DNG specification the opcodes are defined by, 1.3.0.0
+const OPCODE_VERSION: u32 = 0x0103_0000;
+/// Readers that don't know an opcode may skip it
+const OPCODE_OPTIONAL: u32 = 1;
+const WARP_RECTILINEAR: u32 = 1;
+const FIX_VIGNETTE_RADIAL: u32 = 3;
+
+/// An opcode list: the number of opcodes, then each with its header. Opcode lists are big
+/// endian regardless of the byte order of the DNG itself.
+fn opcode_list(opcodes: Vec<(u32, Vec<u8>)>) -> Vec<u8> {
+    let mut out = vec![];
+    out.write_u32::<BigEndian>(opcodes.len() as u32).unwrap();
+    for (id, params) in opcodes {
+        out.write_u32::<BigEndian>(id).unwrap();
+        out.write_u32::<BigEndian>(OPCODE_VERSION).unwrap();
+        out.write_u32::<BigEndian>(OPCODE_OPTIONAL).unwrap();
+        out.write_u32::<BigEndian>(params.len() as u32).unwrap();
+        out.extend(params);
+    }
+    out
+}
+
+/// WarpRectilinear for distortion and chromatic aberration, with a radial polynomial fitted
+/// to the curve of every plane, centred on the image
+fn warp_rectilinear(lens: &LensCorrections) -> Option<Vec<u8>> {
+    let active = |kind| lens.get(kind).filter(|correction| correction.is_active());
+    let distortion = active(CorrectionKind::Distortion).map(|correction| correction.curves());
+    let ca = active(CorrectionKind::ChromaticAberration).map(|correction| correction.curves());
+    if distortion.is_none() && ca.is_none() {
+        return None;
+    }
+    let planes: Vec<Vec<f64>> = match (&distortion, &ca) {
+        (Some(distortion), None) => vec![lens::fit_even_polynomial(&distortion[0], 4, true)],
+        (distortion, Some(ca)) => ca
+            .iter()
+            .map(|plane| {
+                // both factors apply to the radius, so they multiply
+                let curve: Vec<_> = plane
+                    .iter()
+                    .map(|(r, factor)| {
+                        let base = distortion
+                            .as_ref()
+                            .map_or(1.0, |distortion| lens::interpolate(&distortion[0], *r));
+                        (*r, factor * base)
+                    })
+                    .collect();
+                lens::fit_even_polynomial(&curve, 4, true)
+            })
+            .collect(),
+        (None, None) => unreachable!(),
+    };
+    let mut params = vec![];
+    params.write_u32::<BigEndian>(planes.len() as u32).unwrap();
+    for coefficients in planes {
+        for coefficient in coefficients {
+            params.write_f64::<BigEndian>(coefficient).unwrap();
+        }
+        // no tangential distortion
+        params.write_f64::<BigEndian>(0.0).unwrap();
+        params.write_f64::<BigEndian>(0.0).unwrap();
+    }
+    params.write_f64::<BigEndian>(0.5).unwrap();
+    params.write_f64::<BigEndian>(0.5).unwrap();
+    Some(params)
+}
+
+/// FixVignetteRadial, with the gain as an even polynomial in the radius
+fn fix_vignette_radial(lens: &LensCorrections) -> Option<Vec<u8>> {
+    let vignetting = lens
+        .get(CorrectionKind::Vignetting)
+        .filter(|correction| correction.is_active())?;
+    let mut params = vec![];
+    for coefficient in lens::fit_even_polynomial(&vignetting.curves()[0], 5, false) {
+        params.write_f64::<BigEndian>(coefficient).unwrap();
+    }
+    params.write_f64::<BigEndian>(0.5).unwrap();
+    params.write_f64::<BigEndian>(0.5).unwrap();
+    Some(params)
+}
+
+/// Encodes a file the way DNG's OriginalRawFileData expects it: a data fork split into 64 KiB
+/// zlib-compressed blocks preceded by an offset table, followed by an empty resource fork.
+/// All values are big endian regardless of the byte order of the DNG itself.
+fn original_raw_file_data(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    out.write_u32::<BigEndian>(data.len() as u32).unwrap();
+
+    let blocks: Vec<_> = data
+        .chunks(ORIGINAL_BLOCK_SIZE)
+        .map(deflate_bytes_zlib)
+        .collect();
+    // the offsets are relative to the start of the offset table
+    let mut offset = 4 * (blocks.len() as u32 + 1);
+    out.write_u32::<BigEndian>(offset).unwrap();
+    for block in &blocks {
+        offset += block.len() as u32;
+        out.write_u32::<BigEndian>(offset).unwrap();
+    }
+    for block in blocks {
+        out.extend(block);
+    }
+
+    // empty resource fork
+    out.write_u32::<BigEndian>(0).unwrap();
+    out
+}
+
+/// Writes the image as an uncompressed 16-bit DNG, the mosaic or its demosaiced colours as
+/// `options.layout` asks
+pub fn write_dng<W: Write>(out: &mut W, image: &RawImage, options: &DngOptions) -> io::Result<()> {
+    let (width, height) = (image.width, image.height);
+    let samples = match options.layout {
+        Layout::Mosaic => image.pixels.clone(),
+        Layout::LinearRaw => export::demosaic(image),
+    };
+    let channels = samples.len() / image.pixels.len().max(1);
+    let data_len = (samples.len() * 2) as u32;
+    // AsShotNeutral is the reciprocal of the white balance multipliers
+    let neutral = image
+        .white_balance
+        .iter()
+        .map(|multiplier| ((1_000_000.0 / multiplier) as u32, 1_000_000))
+        .collect();
+    let model = match options.model {
+        Some(model) => format!("Sony {}", model),
+        None => "Sony".to_owned(),
+    };
+
+    let mut entries = vec![
+        (0x00fe, Value::Long(vec![0])),
+        (0x0100, Value::Long(vec![width as u32])),
+        (0x0101, Value::Long(vec![height as u32])),
+        (0x0102, Value::Short(vec![16; channels])),
+        (0x0103, Value::Short(vec![1])),
+        (0x010f, Value::Ascii("Sony".to_owned())),
+        (
+            0x0110,
+            Value::Ascii(options.model.unwrap_or("Sony").to_owned()),
+        ),
+        (0x0111, Value::Long(vec![tiff::DATA_OFFSET])),
+        (0x0115, Value::Short(vec![channels as u16])),
+        (0x0116, Value::Long(vec![height as u32])),
+        (0x0117, Value::Long(vec![data_len])),
+        (0x011c, Value::Short(vec![1])),
+        (0xc612, Value::Byte(vec![1, 4, 0, 0])),
+        (0xc613, Value::Byte(vec![1, 1, 0, 0])),
+        (0xc614, Value::Ascii(model)),
+        (
+            0xc61a,
+            Value::Long(vec![image.black_level as u32; channels]),
+        ),
+        (
+            0xc61d,
+            Value::Long(vec![image.white_level as u32; channels]),
+        ),
+        (
+            0xc621,
+            Value::SRational(vec![
+                (1, 1),
+                (0, 1),
+                (0, 1),
+                (0, 1),
+                (1, 1),
+                (0, 1),
+                (0, 1),
+                (0, 1),
+                (1, 1),
+            ]),
+        ),
+        (0xc628, Value::Rational(neutral)),
+        (
+            tiff::DEFAULT_CROP_ORIGIN,
+            Value::Long(vec![image.crop.x as u32, image.crop.y as u32]),
+        ),
+        (
+            tiff::DEFAULT_CROP_SIZE,
+            Value::Long(vec![image.crop.width as u32, image.crop.height as u32]),
+        ),
+    ];
+    match options.layout {
+        Layout::Mosaic => {
+            entries.push((0x0106, Value::Short(vec![32803])));
+            entries.push((0x828d, Value::Short(vec![2, 2])));
+            entries.push((0x828e, Value::Byte(image.cfa.tiff_values())));
+        }
+        Layout::LinearRaw => entries.push((0x0106, Value::Short(vec![34892]))),
+    }
+    if let Some(original) = options.original {
+        entries.push((0xc68a, Value::Ascii(original.name.to_owned())));
+        entries.push((
+            0xc68b,
+            Value::Undefined(original_raw_file_data(original.data)),
+        ));
+    }
+    if let Some(lens) = options.lens {
+        // vignetting is fixed on the linear data, the warp comes after demosaicing
+        if let Some(params) = fix_vignette_radial(lens) {
+            entries.push((
+                0xc741,
+                Value::Undefined(opcode_list(vec![(FIX_VIGNETTE_RADIAL, params)])),
+            ));
+        }
+        if let Some(params) = warp_rectilinear(lens) {
+            entries.push((
+                0xc74e,
+                Value::Undefined(opcode_list(vec![(WARP_RECTILINEAR, params)])),
+            ));
+        }
+    }
+    tiff::write_tiff(out, entries, data_len, |out| {
+        for pixel in &samples {
+            out.write_u16::<LittleEndian>(*pixel)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,376 @@
+use std::{fmt, fs, io, sync::OnceLock};
+
+use image::{ImageBuffer, Luma, Pixel, RgbImage};
+use rusttype::{point, Font, FontCollection, GlyphId, PositionedGlyph, Scale};
+
+use crate::raw::{CfaColor, RawImage};
+
+static FONT: &[u8] = include_bytes!("DejaVuSans.ttf");
+
+pub type RawBuffer = ImageBuffer<Luma<u16>, Vec<<Luma<u16> as Pixel>::Subpixel>>;
+
+fn font() -> &'static Font<'static> {
+    static PARSED: OnceLock<Font<'static>> = OnceLock::new();
+    PARSED.get_or_init(|| {
+        FontCollection::from_bytes(FONT)
+            .unwrap()
+            .into_font()
+            .unwrap()
+    })
+}
+
+#[derive(Debug)]
+pub enum FontError {
+    Io(String, io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Io(path, err) => write!(f, "cannot read font {}: {}", path, err),
+            FontError::Invalid(path) => write!(f, "{} is not a TrueType or OpenType font", path),
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+/// The fonts a stamp is drawn with. Every character is taken from the first font that has a
+/// glyph for it, so fonts covering other scripts (CJK, say) can fill in for the built-in
+/// DejaVu Sans, which comes last. Characters no font has are left out.
+#[derive(Clone)]
+pub struct FontChain {
+    fonts: Vec<(String, Font<'static>)>,
+}
+
+impl fmt::Debug for FontChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.fonts.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+impl Default for FontChain {
+    fn default() -> FontChain {
+        FontChain::builtin()
+    }
+}
+
+impl FontChain {
+    /// Just the built-in font
+    pub fn builtin() -> FontChain {
+        FontChain {
+            fonts: vec![("built-in".to_owned(), font().clone())],
+        }
+    }
+
+    /// The fonts in the given files, in order, followed by the built-in one. A file holding a
+    /// collection contributes its first font.
+    pub fn load<S: AsRef<str>>(paths: &[S]) -> Result<FontChain, FontError> {
+        let mut fonts = vec![];
+        for path in paths {
+            let path = path.as_ref();
+            let data = fs::read(path).map_err(|err| FontError::Io(path.to_owned(), err))?;
+            let font = FontCollection::from_bytes(data)
+                .and_then(|collection| collection.font_at(0))
+                .map_err(|_| FontError::Invalid(path.to_owned()))?;
+            fonts.push((path.to_owned(), font));
+        }
+        fonts.extend(FontChain::builtin().fonts);
+        Ok(FontChain { fonts })
+    }
+
+    /// The first font with a glyph for the character, with its position in the chain
+    fn font_for(&self, c: char) -> Option<(usize, &Font<'static>)> {
+        self.fonts
+            .iter()
+            .map(|(_, font)| font)
+            .enumerate()
+            .find(|(_, font)| font.glyph(c).id() != GlyphId(0))
+    }
+
+    /// Characters of the text that none of the fonts can draw, each listed once
+    pub fn missing(&self, text: &str) -> Vec<char> {
+        let mut missing = vec![];
+        for c in text.chars() {
+            if !c.is_control() && !missing.contains(&c) && self.font_for(c).is_none() {
+                missing.push(c);
+            }
+        }
+        missing
+    }
+
+    /// Lays out a line of text like `Font::layout`, with the baseline of the first font and
+    /// kerning between neighbours taken from the same font
+    fn layout(&self, text: &str, scale: Scale) -> Vec<PositionedGlyph<'static>> {
+        let ascent = self.fonts[0].1.v_metrics(scale).ascent;
+        let mut glyphs = vec![];
+        let mut caret = 0.0;
+        let mut previous: Option<(usize, GlyphId)> = None;
+        for c in text.chars().filter(|c| !c.is_control()) {
+            let (index, font) = match self.font_for(c) {
+                Some(found) => found,
+                None => {
+                    previous = None;
+                    continue;
+                }
+            };
+            let glyph = font.glyph(c).scaled(scale);
+            if let Some((previous_index, previous_id)) = previous {
+                if previous_index == index {
+                    caret += font.pair_kerning(scale, previous_id, glyph.id());
+                }
+            }
+            previous = Some((index, glyph.id()));
+            let advance = glyph.h_metrics().advance_width;
+            glyphs.push(glyph.positioned(point(caret, ascent)));
+            caret += advance;
+        }
+        glyphs
+    }
+}
+
+/// Per-photosite values for drawing onto the mosaic. The same value on every photosite of a
+/// Bayer sensor develops into a tinted stamp, as white balance then scales red and blue;
+/// here red and blue photosites get the values that white balance brings back to the level
+/// of green, so that the stamp develops neutral grey.
+#[derive(Debug, Clone, Copy)]
+pub struct Neutral {
+    pub black_level: u16,
+    pub white_level: u16,
+    /// Factor of the value above black at each position of the 2x2 CFA pattern, row-major
+    pub factors: [f32; 4],
+}
+
+impl Neutral {
+    /// Uses the CFA pattern, the levels and the white balance of the image; its pixels are
+    /// not needed
+    pub fn of(image: &RawImage) -> Neutral {
+        let mut factors = [1.0; 4];
+        for (factor, color) in factors.iter_mut().zip(&image.cfa.colors) {
+            let multiplier = match color {
+                CfaColor::Red => image.white_balance[0],
+                CfaColor::Green => image.white_balance[1],
+                CfaColor::Blue => image.white_balance[2],
+            };
+            if multiplier > 0.0 {
+                *factor = 1.0 / multiplier;
+            }
+        }
+        Neutral {
+            black_level: image.black_level,
+            white_level: image.white_level,
+            factors,
+        }
+    }
+
+    /// The value to draw on the photosite at (`x`, `y`) of the image for a green value of
+    /// `value`
+    pub fn value(&self, value: f32, x: u32, y: u32) -> f32 {
+        let black = self.black_level as f32;
+        let factor = self.factors[(y as usize % 2) * 2 + x as usize % 2];
+        (black + (value - black) * factor).min(self.white_level as f32)
+    }
+}
+
+/// Rasterized coverage of a stamp, independent of where it is drawn and with which value.
+/// Shaping and rasterizing text is by far the most expensive part of stamping, so batch runs
+/// prepare the overlay once and draw it into every file. Overlays are `Send + Sync`, so one
+/// can be shared by worker threads.
+#[derive(Debug, Clone)]
+pub struct PreparedOverlay {
+    /// Position of the top-left corner of the mask relative to the stamp position
+    left: i32,
+    top: i32,
+    width: usize,
+    height: usize,
+    /// Coverage in 0..=1, row by row
+    coverage: Vec<f32>,
+}
+
+impl PreparedOverlay {
+    /// Lays out and rasterizes text the same way `imageproc::drawing::draw_text_mut` does
+    pub fn text(fonts: &FontChain, text: &str, scale: f32) -> PreparedOverlay {
+        PreparedOverlay::with_scale(fonts, text, Scale { x: scale, y: scale })
+    }
+
+    fn with_scale(fonts: &FontChain, text: &str, scale: Scale) -> PreparedOverlay {
+        let glyphs: Vec<_> = fonts
+            .layout(text, scale)
+            .into_iter()
+            .filter_map(|glyph| glyph.pixel_bounding_box().map(|bb| (glyph, bb)))
+            .collect();
+
+        let left = glyphs.iter().map(|(_, bb)| bb.min.x).min().unwrap_or(0);
+        let top = glyphs.iter().map(|(_, bb)| bb.min.y).min().unwrap_or(0);
+        let right = glyphs.iter().map(|(_, bb)| bb.max.x).max().unwrap_or(0);
+        let bottom = glyphs.iter().map(|(_, bb)| bb.max.y).max().unwrap_or(0);
+        let width = (right - left).max(0) as usize;
+        let height = (bottom - top).max(0) as usize;
+
+        let mut coverage = vec![0.0f32; width * height];
+        for (glyph, bb) in &glyphs {
+            glyph.draw(|gx, gy, v| {
+                let x = (gx as i32 + bb.min.x - left) as usize;
+                let y = (gy as i32 + bb.min.y - top) as usize;
+                // overlapping glyphs combine like successive blends with the same value
+                let c = &mut coverage[y * width + x];
+                *c = 1.0 - (1.0 - *c) * (1.0 - v);
+            });
+        }
+
+        PreparedOverlay {
+            left,
+            top,
+            width,
+            height,
+            coverage,
+        }
+    }
+
+    /// Left and top edge of the mask relative to the stamp position, and its width and height
+    pub fn bounds(&self) -> (i32, i32, usize, usize) {
+        (self.left, self.top, self.width, self.height)
+    }
+
+    /// Calls `blend` with the coverage of every pixel of an image of the given size that the
+    /// overlay touches, with the stamp position at (`x`, `y`). Parts falling outside of the
+    /// image are clipped.
+    pub(crate) fn for_each_covered<F: FnMut(u32, u32, f32)>(
+        &self,
+        (img_width, img_height): (u32, u32),
+        x: u32,
+        y: u32,
+        mut blend: F,
+    ) {
+        let (img_width, img_height) = (img_width as i64, img_height as i64);
+        for (row, coverage) in self.coverage.chunks(self.width.max(1)).enumerate() {
+            let img_y = y as i64 + self.top as i64 + row as i64;
+            if img_y < 0 || img_y >= img_height {
+                continue;
+            }
+            for (col, v) in coverage.iter().enumerate() {
+                let img_x = x as i64 + self.left as i64 + col as i64;
+                if *v == 0.0 || img_x < 0 || img_x >= img_width {
+                    continue;
+                }
+                blend(img_x as u32, img_y as u32, *v);
+            }
+        }
+        debug_assert_eq!(self.coverage.len(), self.width * self.height);
+    }
+
+    /// Blends `value` into the image according to the coverage, with the stamp position at
+    /// (`x`, `y`). Parts falling outside of the image are clipped.
+    pub fn draw(&self, img: &mut RawBuffer, x: u32, y: u32, value: u16) {
+        self.blend(img, x, y, value, None);
+    }
+
+    /// Like `draw`, with `value` being the value of green photosites and the values of the
+    /// others set for a neutral stamp
+    pub fn draw_neutral(&self, img: &mut RawBuffer, x: u32, y: u32, value: u16, neutral: &Neutral) {
+        self.blend(img, x, y, value, Some(neutral));
+    }
+
+    fn blend(&self, img: &mut RawBuffer, x: u32, y: u32, value: u16, neutral: Option<&Neutral>) {
+        self.for_each_covered(img.dimensions(), x, y, |img_x, img_y, v| {
+            let value = match neutral {
+                Some(neutral) => neutral.value(value as f32, img_x, img_y),
+                None => value as f32,
+            };
+            let pixel = img.get_pixel_mut(img_x, img_y);
+            let blended = pixel.0[0] as f32 * (1.0 - v) + value * v;
+            pixel.0[0] = blended.clamp(0.0, u16::MAX as f32) as u16;
+        });
+    }
+
+    /// Blends a gray level into an 8-bit rendition, like `draw` does for raw data
+    fn draw_rgb(&self, img: &mut RgbImage, x: u32, y: u32, level: u8) {
+        self.for_each_covered(img.dimensions(), x, y, |img_x, img_y, v| {
+            for channel in &mut img.get_pixel_mut(img_x, img_y).0 {
+                let blended = *channel as f32 * (1.0 - v) + level as f32 * v;
+                *channel = blended.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    }
+}
+
+/// A text stamp, described in raw (sensor) coordinates and values
+#[derive(Debug, Clone)]
+pub struct TextEdit {
+    pub text: String,
+    pub x: u32,
+    pub y: u32,
+    pub scale: f32,
+    pub value: u16,
+    pub fonts: FontChain,
+}
+
+impl Default for TextEdit {
+    fn default() -> TextEdit {
+        TextEdit {
+            text: "EDITED BY SIO".to_owned(),
+            x: 1000,
+            y: 1800,
+            scale: 400.0,
+            value: 17216,
+            fonts: FontChain::builtin(),
+        }
+    }
+}
+
+impl TextEdit {
+    /// Rasterizes the text of the stamp for drawing it with `draw_prepared`
+    pub fn prepare(&self) -> PreparedOverlay {
+        PreparedOverlay::text(&self.fonts, &self.text, self.scale)
+    }
+
+    pub fn draw_raw(&self, img: &mut RawBuffer) {
+        self.draw_prepared(&self.prepare(), img);
+    }
+
+    /// Draws the stamp using an overlay prepared earlier from the same text and scale
+    pub fn draw_prepared(&self, overlay: &PreparedOverlay, img: &mut RawBuffer) {
+        overlay.draw(img, self.x, self.y, self.value);
+    }
+
+    /// Draws the stamp so that it develops neutral grey
+    pub fn draw_neutral(&self, img: &mut RawBuffer, neutral: &Neutral) {
+        self.prepare()
+            .draw_neutral(img, self.x, self.y, self.value, neutral);
+    }
+
+    /// Draws the same stamp on a gamma-encoded rendition of the raw image, scaling position
+    /// and size by the ratio of the rendition size to the raw size
+    pub fn draw_rendition(
+        &self,
+        img: &mut RgbImage,
+        raw_width: usize,
+        raw_height: usize,
+        white_level: u16,
+    ) {
+        let factor_x = img.width() as f32 / raw_width as f32;
+        let factor_y = img.height() as f32 / raw_height as f32;
+        let scale = Scale {
+            x: self.scale * factor_x,
+            y: self.scale * factor_y,
+        };
+        let linear = (self.value as f32 / white_level as f32).min(1.0);
+        let level = (linear.powf(1.0 / 2.2) * 255.0).round() as u8;
+        PreparedOverlay::with_scale(&self.fonts, &self.text, scale).draw_rgb(
+            img,
+            (self.x as f32 * factor_x) as u32,
+            (self.y as f32 * factor_y) as u32,
+            level,
+        );
+    }
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FontChain>();
+    assert_send_sync::<PreparedOverlay>();
+    assert_send_sync::<TextEdit>();
+};
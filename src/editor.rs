@@ -0,0 +1,210 @@
+//! Edits applied to a decoded raw image, with undo and redo. The image is kept in tiles, and
+//! every step only remembers the tiles it changed, so a long history of small stamps costs
+//! little memory compared to a full frame copy per step.
+//!
+//! An editor owns its image; editors for different images can live on different threads.
+
+use image::{ImageBuffer, Luma};
+
+use crate::burnin::BurnIn;
+use crate::edit::{Neutral, RawBuffer, TextEdit};
+use crate::overlay::ImageOverlay;
+use crate::raw::RawImage;
+use crate::redact::Redaction;
+use crate::tiled::{Rect, TiledBuffer};
+use crate::unstamp::{unstamp, Unstamped};
+
+/// The contents of one tile before and after a step
+#[derive(Debug, Clone)]
+struct TilePatch {
+    tile: usize,
+    before: Vec<u16>,
+    after: Vec<u16>,
+}
+
+#[derive(Debug, Clone)]
+struct Step {
+    name: String,
+    patches: Vec<TilePatch>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RawEditor {
+    /// Everything but the pixels, which live in `tiles`
+    image: RawImage,
+    tiles: TiledBuffer,
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    /// Whether text and overlays are drawn to develop neutral grey
+    neutral: bool,
+}
+
+impl RawEditor {
+    pub fn new(mut image: RawImage) -> RawEditor {
+        let tiles = TiledBuffer::from_pixels(&image.pixels, image.width, image.height);
+        image.pixels = vec![];
+        RawEditor {
+            image,
+            tiles,
+            undo: vec![],
+            redo: vec![],
+            neutral: false,
+        }
+    }
+
+    /// Draws text and overlays with per-photosite values that develop neutral grey, using
+    /// the CFA pattern and white balance of the image, instead of the same value everywhere
+    pub fn set_neutral(&mut self, neutral: bool) {
+        self.neutral = neutral;
+    }
+
+    /// The image's metadata; its pixels are only available through `into_image`
+    pub fn image(&self) -> &RawImage {
+        &self.image
+    }
+
+    pub fn into_image(self) -> RawImage {
+        RawImage {
+            pixels: self.tiles.to_pixels(),
+            ..self.image
+        }
+    }
+
+    /// Tiles changed by any step so far (including undone ones)
+    pub fn dirty_rects(&self) -> Vec<Rect> {
+        self.tiles.dirty_rects()
+    }
+
+    /// Runs an operation on the image as a single undoable step. Steps that change nothing
+    /// are not recorded.
+    pub fn apply<F: FnOnce(&mut RawBuffer)>(&mut self, name: &str, operation: F) {
+        let tiles = &self.tiles;
+        let mut img: RawBuffer =
+            ImageBuffer::from_fn(tiles.width() as u32, tiles.height() as u32, |x, y| {
+                Luma([tiles.get(x as usize, y as usize)])
+            });
+        operation(&mut img);
+        let new: &[u16] = &img;
+
+        let width = self.tiles.width();
+        let mut patches = vec![];
+        for tile in 0..self.tiles.num_tiles() {
+            let rect = self.tiles.tile_rect(tile);
+            let mut after = Vec::with_capacity(rect.width * rect.height);
+            for row in rect.y..rect.y + rect.height {
+                let start = row * width + rect.x;
+                after.extend_from_slice(&new[start..start + rect.width]);
+            }
+            if after[..] != *self.tiles.tile(tile) {
+                patches.push(TilePatch {
+                    tile,
+                    before: self.tiles.tile(tile).to_vec(),
+                    after,
+                });
+            }
+        }
+        if patches.is_empty() {
+            return;
+        }
+
+        let step = Step {
+            name: name.to_owned(),
+            patches,
+        };
+        self.restore(&step, false);
+        self.undo.push(step);
+        self.redo.clear();
+    }
+
+    /// Stamps text, positioned relative to the visible area of the image
+    pub fn draw_text(&mut self, edit: &TextEdit) {
+        let crop = self.image.crop;
+        let edit = TextEdit {
+            x: edit.x + crop.x as u32,
+            y: edit.y + crop.y as u32,
+            ..edit.clone()
+        };
+        if self.neutral {
+            let neutral = Neutral::of(&self.image);
+            self.apply("text", |img| edit.draw_neutral(img, &neutral));
+        } else {
+            self.apply("text", |img| edit.draw_raw(img));
+        }
+    }
+
+    /// Removes a text stamp drawn by `draw_text` with the same settings, as far as it can be
+    /// (see `unstamp`)
+    pub fn remove_text(&mut self, edit: &TextEdit) -> Unstamped {
+        let crop = self.image.crop;
+        let edit = TextEdit {
+            x: edit.x + crop.x as u32,
+            y: edit.y + crop.y as u32,
+            ..edit.clone()
+        };
+        let white_level = self.image.white_level;
+        let neutral = Some(Neutral::of(&self.image)).filter(|_| self.neutral);
+        let mut unstamped = Unstamped::default();
+        self.apply("unstamp", |img| {
+            unstamped = unstamp(img, &edit, white_level, neutral.as_ref())
+        });
+        unstamped
+    }
+
+    /// Composites an image onto the visible area, between the black and the white level
+    pub fn draw_overlay(&mut self, overlay: &ImageOverlay) {
+        let image = &self.image;
+        let (crop, black_level, white_level) = (image.crop, image.black_level, image.white_level);
+        if self.neutral {
+            let neutral = Neutral::of(image);
+            self.apply("overlay", |img| overlay.draw_neutral(img, crop, &neutral));
+        } else {
+            self.apply("overlay", |img| {
+                overlay.draw(img, crop, black_level, white_level)
+            });
+        }
+    }
+
+    /// Burns the capture time and frame number into a corner of the visible area. It always
+    /// develops neutral, whether or not the editor draws neutral.
+    pub fn burn_in(&mut self, burn_in: &BurnIn) {
+        let crop = self.image.crop;
+        let neutral = Neutral::of(&self.image);
+        self.apply("burn-in", |img| burn_in.draw(img, crop, &neutral));
+    }
+
+    /// Fills, blurs or pixelates a region of the visible area
+    pub fn redact(&mut self, redaction: &Redaction) {
+        let (crop, black_level) = (self.image.crop, self.image.black_level);
+        self.apply(redaction.name(), |img| {
+            redaction.apply(img, crop, black_level)
+        });
+    }
+
+    /// Reverts the last step, returning its name
+    pub fn undo(&mut self) -> Option<&str> {
+        let step = self.undo.pop()?;
+        self.restore(&step, true);
+        self.redo.push(step);
+        self.redo.last().map(|step| &step.name[..])
+    }
+
+    /// Re-applies the last undone step, returning its name
+    pub fn redo(&mut self) -> Option<&str> {
+        let step = self.redo.pop()?;
+        self.restore(&step, false);
+        self.undo.push(step);
+        self.undo.last().map(|step| &step.name[..])
+    }
+
+    fn restore(&mut self, step: &Step, before: bool) {
+        for patch in &step.patches {
+            let data = if before { &patch.before } else { &patch.after };
+            self.tiles.set_tile(patch.tile, data);
+        }
+    }
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RawEditor>();
+};
@@ -0,0 +1,251 @@
+//! Export of the (edited) raw mosaic as a 16-bit grayscale PNG or TIFF, for inspecting it in
+//! tools that know nothing about camera raw formats, and of a half-size colour preview made
+//! from it without a real demosaic.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use image::{png::PNGEncoder, ColorType, Rgb, RgbImage};
+
+use crate::output::OutputFile;
+use crate::raw::{CfaColor, RawImage};
+use crate::tiff::{self, Value};
+use crate::tiled::Rect;
+use crate::RawEditError;
+
+/// How raw values are mapped to the 16-bit output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaling {
+    /// Keep the raw values as they are (e.g. 0..=16383 for 14-bit data)
+    Native,
+    /// Shift the values up so that the bit depth of the white level fills 16 bits
+    Full,
+    /// Map the black level to 0 and the white level to 65535
+    WhiteLevel,
+}
+
+impl Scaling {
+    pub fn parse(name: &str) -> Option<Scaling> {
+        match name {
+            "native" => Some(Scaling::Native),
+            "full" => Some(Scaling::Full),
+            "white" => Some(Scaling::WhiteLevel),
+            _ => None,
+        }
+    }
+
+    fn apply(self, value: u16, image: &RawImage) -> u16 {
+        match self {
+            Scaling::Native => value,
+            Scaling::Full => {
+                let bits = 16 - image.white_level.leading_zeros();
+                value << (16 - bits.max(1))
+            }
+            Scaling::WhiteLevel => {
+                let black = image.black_level as f32;
+                let range = (image.white_level as f32 - black).max(1.0);
+                let scaled = (value as f32 - black) / range * 65535.0;
+                scaled.round().clamp(0.0, 65535.0) as u16
+            }
+        }
+    }
+
+    /// The raw value an exported value came from, as near as the scaling allows
+    pub fn invert(self, value: u16, image: &RawImage) -> u16 {
+        match self {
+            Scaling::Native => value,
+            Scaling::Full => {
+                let bits = 16 - image.white_level.leading_zeros();
+                // rounded to the nearest step of the shifted values
+                let shift = 16 - bits.max(1);
+                let half = (1u32 << shift) >> 1;
+                ((value as u32 + half) >> shift).min(u16::MAX as u32) as u16
+            }
+            Scaling::WhiteLevel => {
+                let black = image.black_level as f32;
+                let range = (image.white_level as f32 - black).max(1.0);
+                let raw = black + value as f32 / 65535.0 * range;
+                raw.round().clamp(0.0, u16::MAX as f32) as u16
+            }
+        }
+    }
+}
+
+/// The visible area of the image, scaled
+fn visible_values(image: &RawImage, scaling: Scaling) -> Vec<u16> {
+    let crop = image.crop;
+    image
+        .pixels
+        .chunks(image.width)
+        .skip(crop.y)
+        .take(crop.height)
+        .flat_map(|row| &row[crop.x..crop.x + crop.width])
+        .map(|value| scaling.apply(*value, image))
+        .collect()
+}
+
+/// Whether a file name asks for TIFF (`.tif`, `.tiff`) rather than PNG
+pub fn is_tiff_name(path: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    lower.ends_with(".tif") || lower.ends_with(".tiff")
+}
+
+/// Writes the visible area as TIFF or PNG
+pub fn export<W: Write>(
+    out: &mut W,
+    as_tiff: bool,
+    image: &RawImage,
+    scaling: Scaling,
+) -> io::Result<()> {
+    let values = visible_values(image, scaling);
+    let (width, height) = (image.crop.width as u32, image.crop.height as u32);
+
+    if as_tiff {
+        let data_len = values.len() as u32 * 2;
+        let entries = vec![
+            (tiff::IMAGE_WIDTH, Value::Long(vec![width])),
+            (tiff::IMAGE_LENGTH, Value::Long(vec![height])),
+            (0x0102, Value::Short(vec![16])),
+            (tiff::COMPRESSION, Value::Short(vec![1])),
+            (0x0106, Value::Short(vec![1])),
+            (tiff::STRIP_OFFSETS, Value::Long(vec![tiff::DATA_OFFSET])),
+            (0x0115, Value::Short(vec![1])),
+            (0x0116, Value::Long(vec![height])),
+            (tiff::STRIP_BYTE_COUNTS, Value::Long(vec![data_len])),
+        ];
+        tiff::write_tiff(out, entries, data_len, |out| {
+            for value in &values {
+                out.write_u16::<LittleEndian>(*value)?;
+            }
+            Ok(())
+        })
+    } else {
+        let mut bytes = vec![0; values.len() * 2];
+        BigEndian::write_u16_into(&values, &mut bytes);
+        PNGEncoder::new(out).encode(&bytes, width, height, ColorType::Gray(16))
+    }
+}
+
+/// A colour image of half the size of the visible area, with every 2x2 block of the mosaic
+/// taken as one pixel (the two greens averaged). The values are white balanced, put between
+/// the black and the white level and gamma-encoded, but stay in the colour space of the
+/// camera, so the colours are only roughly right.
+pub fn half_size(image: &RawImage) -> RgbImage {
+    let crop = image.crop;
+    let black = image.black_level as f32;
+    let range = (image.white_level as f32 - black).max(1.0);
+    RgbImage::from_fn(crop.width as u32 / 2, crop.height as u32 / 2, |x, y| {
+        let mut sums = [0.0f32; 3];
+        let mut counts = [0usize; 3];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let column = crop.x + x as usize * 2 + dx;
+            let row = crop.y + y as usize * 2 + dy;
+            let channel = match image.cfa.colors[(row % 2) * 2 + column % 2] {
+                CfaColor::Red => 0,
+                CfaColor::Green => 1,
+                CfaColor::Blue => 2,
+            };
+            sums[channel] += image.pixels[row * image.width + column] as f32;
+            counts[channel] += 1;
+        }
+        let mut pixel = [0u8; 3];
+        for channel in 0..3 {
+            let value = sums[channel] / counts[channel].max(1) as f32;
+            let linear = (value - black) / range * image.white_balance[channel];
+            pixel[channel] = (linear.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8;
+        }
+        Rgb(pixel)
+    })
+}
+
+/// Red, green and blue at a photosite, demosaiced bilinearly: its own value for its colour,
+/// and the means of its neighbours within `area` for the others
+fn bilinear(image: &RawImage, area: Rect, column: usize, row: usize) -> [f32; 3] {
+    let channel = |column: usize, row: usize| match image.cfa.colors[(row % 2) * 2 + column % 2] {
+        CfaColor::Red => 0,
+        CfaColor::Green => 1,
+        CfaColor::Blue => 2,
+    };
+    let own = channel(column, row);
+    let mut sums = [0.0f32; 3];
+    let mut counts = [0usize; 3];
+    for neighbour_row in row.saturating_sub(1).max(area.y)..(row + 2).min(area.y + area.height) {
+        let columns = column.saturating_sub(1).max(area.x)..(column + 2).min(area.x + area.width);
+        for neighbour in columns {
+            let neighbour_channel = channel(neighbour, neighbour_row);
+            if neighbour_channel != own {
+                sums[neighbour_channel] +=
+                    image.pixels[neighbour_row * image.width + neighbour] as f32;
+                counts[neighbour_channel] += 1;
+            }
+        }
+    }
+    sums[own] = image.pixels[row * image.width + column] as f32;
+    counts[own] = 1;
+    let mut rgb = [0.0; 3];
+    for ((value, sum), count) in rgb.iter_mut().zip(sums).zip(counts) {
+        *value = sum / count.max(1) as f32;
+    }
+    rgb
+}
+
+/// A colour image of the visible area, demosaiced bilinearly. Like `half_size`, the values
+/// are white balanced and gamma-encoded in the colour space of the camera; it is good enough
+/// for the embedded previews, not for viewing the image.
+pub fn develop(image: &RawImage) -> RgbImage {
+    let crop = image.crop;
+    let black = image.black_level as f32;
+    let range = (image.white_level as f32 - black).max(1.0);
+    RgbImage::from_fn(crop.width as u32, crop.height as u32, |x, y| {
+        let rgb = bilinear(image, crop, crop.x + x as usize, crop.y + y as usize);
+        let mut pixel = [0u8; 3];
+        for channel in 0..3 {
+            let linear = (rgb[channel] - black) / range * image.white_balance[channel];
+            pixel[channel] = (linear.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8;
+        }
+        Rgb(pixel)
+    })
+}
+
+/// The whole image demosaiced bilinearly, as interleaved red, green and blue in the scale of
+/// the raw values: neither white balanced nor gamma-encoded, as a linear DNG holds it
+pub fn demosaic(image: &RawImage) -> Vec<u16> {
+    let area = image.full_rect();
+    let mut out = Vec::with_capacity(image.pixels.len() * 3);
+    for row in 0..image.height {
+        for column in 0..image.width {
+            let rgb = bilinear(image, area, column, row);
+            out.extend(rgb.iter().map(|value| value.round() as u16));
+        }
+    }
+    out
+}
+
+/// Writes the `half_size` preview as an 8-bit PNG
+pub fn export_preview<W: Write>(out: &mut W, image: &RawImage) -> io::Result<()> {
+    let preview = half_size(image);
+    PNGEncoder::new(out).encode(
+        &preview,
+        preview.width(),
+        preview.height(),
+        ColorType::RGB(8),
+    )
+}
+
+impl RawImage {
+    /// Writes the visible area of the mosaic, in the layout the image is in, as a 16-bit TIFF
+    /// (for a `.tif` or `.tiff` name) or PNG. The file is written atomically, and never over
+    /// an existing file (see `OutputFile`).
+    pub fn export<P: AsRef<Path>>(&self, path: P, scaling: Scaling) -> Result<(), RawEditError> {
+        let as_tiff = is_tiff_name(&path.as_ref().to_string_lossy());
+        OutputFile::new(path).write_with(|out| export(out, as_tiff, self, scaling))?;
+        Ok(())
+    }
+
+    /// Writes the `half_size` preview as a PNG, atomically and never over an existing file
+    pub fn export_preview<P: AsRef<Path>>(&self, path: P) -> Result<(), RawEditError> {
+        OutputFile::new(path).write_with(|out| export_preview(out, self))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! How the command line tool fails. Every kind of failure has its own exit code, and the codes
+//! don't change between versions, so that scripts can tell them apart. With
+//! `--error-format=json`, the message is written to stderr as a JSON object instead:
+//!
+//! ```text
+//! {"error":"unsupported_format","code":4,"message":"unsupported variant: Uncompressed14 raw data"}
+//! ```
+
+use std::{
+    fmt, process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use raw_tiff_edit::output::OutputError;
+use raw_tiff_edit::RawEditError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Invalid command line arguments
+    Usage,
+    /// A file couldn't be read or written
+    Io,
+    /// The input isn't a raw format (or a variant of one) the tool handles
+    UnsupportedFormat,
+    /// A file given to the tool is malformed: a recipe, the raw data, an embedded rendition
+    Parse,
+    /// The written file doesn't decode to the edited image
+    Verification,
+    /// Writing would overwrite an original
+    Refused,
+    /// Some of the files of a batch failed
+    PartialBatch,
+}
+
+impl Failure {
+    /// The kind of failure an error of the codec amounts to
+    pub fn of(err: &RawEditError) -> Failure {
+        match err {
+            RawEditError::Variant(_) => Failure::UnsupportedFormat,
+            RawEditError::Truncated(_) | RawEditError::Mismatch(_) => Failure::Parse,
+            RawEditError::Output(OutputError::WouldOverwriteOriginal(_)) => Failure::Refused,
+            RawEditError::Output(OutputError::Io(_)) | RawEditError::Io(_) => Failure::Io,
+            // the tool never cancels
+            RawEditError::Cancelled => Failure::Io,
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Usage => 2,
+            Failure::Io => 3,
+            Failure::UnsupportedFormat => 4,
+            Failure::Parse => 5,
+            Failure::Verification => 6,
+            Failure::Refused => 7,
+            Failure::PartialBatch => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::Usage => "usage",
+            Failure::Io => "io",
+            Failure::UnsupportedFormat => "unsupported_format",
+            Failure::Parse => "parse",
+            Failure::Verification => "verification",
+            Failure::Refused => "refused",
+            Failure::PartialBatch => "partial_batch",
+        }
+    }
+}
+
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Switches the error output to JSON objects
+pub fn use_json(enabled: bool) {
+    JSON_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+/// A failure of a single file, which ends the run or is recorded in a batch
+#[derive(Debug, Clone)]
+pub struct Failed {
+    pub failure: Failure,
+    pub message: String,
+}
+
+impl Failed {
+    pub fn new<M: fmt::Display>(failure: Failure, message: M) -> Failed {
+        Failed {
+            failure,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<RawEditError> for Failed {
+    fn from(err: RawEditError) -> Failed {
+        Failed::new(Failure::of(&err), err)
+    }
+}
+
+/// Quotes a string for JSON output
+pub fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Reports the error on stderr and exits with the code of the failure
+pub fn exit<M: fmt::Display>(failure: Failure, message: M) -> ! {
+    print(failure, message);
+    process::exit(failure.code())
+}
+
+/// Reports the error on stderr, carrying on afterwards
+pub fn print<M: fmt::Display>(failure: Failure, message: M) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!(
+            "{{\"error\":\"{}\",\"code\":{},\"message\":{}}}",
+            failure.name(),
+            failure.code(),
+            json_string(&message.to_string())
+        );
+    } else {
+        eprintln!("{}", message);
+    }
+}
@@ -0,0 +1,347 @@
+//! A C interface to decoding, stamping and re-encoding raw files, for tools not written in
+//! Rust. `include/raw_tiff_edit.h` declares it; the library is built for C with
+//!
+//! ```text
+//! cargo rustc --lib --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! (or `--crate-type staticlib`). This is the only module with `unsafe` code, all of it
+//! reading the arguments handed in from C.
+//!
+//! A file is edited through a `RawEditHandle`:
+//!
+//! * `raw_edit_open` reads a file into a new handle,
+//! * `raw_edit_decode` decodes its raw data into the pixel plane of the handle,
+//! * `raw_edit_draw_text` stamps text onto the plane,
+//! * `raw_edit_encode` replaces the raw data of a copy of the file with the plane,
+//! * `raw_edit_save` writes that copy, atomically, anywhere but over the file it came from,
+//! * `raw_edit_close` frees the handle with everything it owns.
+//!
+//! The ownership rules:
+//!
+//! * The handle owns the pixel plane. The host reads and writes it through the pointer in
+//!   the `RawEditPlane` filled in by `raw_edit_decode`, `width * height` values row by row,
+//!   and never frees it. The pointer stays valid until the next `raw_edit_decode` or
+//!   `raw_edit_close` of the handle; drawing and encoding don't move the plane.
+//! * The encoded file and the error message are owned by the handle too, and valid until the
+//!   next call that replaces them, or `raw_edit_close`.
+//! * Strings handed in are NUL-terminated UTF-8, and only borrowed for the call.
+//! * A handle is used by one thread at a time; different handles are independent.
+//!
+//! Every function but `raw_edit_open` and `raw_edit_close` returns a `RawEditStatus`, with the
+//! message of a failure available from `raw_edit_last_error`. Panics are caught and reported
+//! as `RAW_EDIT_STATUS_PANIC`; the handle must not be used after one, other than to close it.
+
+#![allow(unsafe_code)]
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::{fs, io::Write, path::PathBuf, ptr};
+
+use crate::edit::{FontChain, RawBuffer, TextEdit};
+use crate::output::{OutputError, OutputFile};
+use crate::raw::RawImage;
+use crate::RawEditError;
+
+/// Outcome of a call
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawEditStatus {
+    Ok = 0,
+    /// A pointer was null, or a string wasn't UTF-8
+    InvalidArgument = 1,
+    /// The handle has no decoded image, or no encoded file, yet
+    NotReady = 2,
+    /// Reading or writing a file failed
+    Io = 3,
+    /// The file has no raw data the codec understands
+    Unsupported = 4,
+    /// The raw data ends before all of the image has been read
+    Truncated = 5,
+    /// The plane doesn't fit the raw data of the file
+    Mismatch = 6,
+    Panic = 7,
+    /// Saving would write over the file the handle was opened from
+    WouldOverwriteOriginal = 8,
+}
+
+/// The decoded pixel plane of a handle, along with what the host needs to edit it
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RawEditPlane {
+    /// Linear sensor values, row by row, owned by the handle
+    pub pixels: *mut u16,
+    pub width: usize,
+    pub height: usize,
+    pub black_level: u16,
+    pub white_level: u16,
+    /// The visible area, in plane coordinates
+    pub crop_x: usize,
+    pub crop_y: usize,
+    pub crop_width: usize,
+    pub crop_height: usize,
+}
+
+/// A raw file being edited; opaque to C
+pub struct RawEditHandle {
+    /// Where the original was read from, protected from being saved over
+    path: PathBuf,
+    original: Vec<u8>,
+    image: Option<RawImage>,
+    encoded: Option<Vec<u8>>,
+    error: CString,
+}
+
+impl RawEditHandle {
+    fn fail(&mut self, status: RawEditStatus, message: String) -> RawEditStatus {
+        self.error = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        status
+    }
+
+    fn fail_with(&mut self, err: RawEditError) -> RawEditStatus {
+        let status = match err {
+            RawEditError::Variant(_) => RawEditStatus::Unsupported,
+            RawEditError::Truncated(_) => RawEditStatus::Truncated,
+            RawEditError::Mismatch(_) => RawEditStatus::Mismatch,
+            RawEditError::Output(OutputError::WouldOverwriteOriginal(_)) => {
+                RawEditStatus::WouldOverwriteOriginal
+            }
+            RawEditError::Output(OutputError::Io(_)) | RawEditError::Io(_) => RawEditStatus::Io,
+            // nothing is cancellable through this interface
+            RawEditError::Cancelled => unreachable!("a call without a cancel token was cancelled"),
+        };
+        self.fail(status, err.to_string())
+    }
+
+    fn not_decoded(&mut self) -> RawEditStatus {
+        self.fail(
+            RawEditStatus::NotReady,
+            "the image has not been decoded".to_owned(),
+        )
+    }
+}
+
+/// Borrows a string handed in from C
+unsafe fn string<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(ptr).to_str().ok()
+}
+
+/// Runs `call` on the handle behind `handle`, catching panics
+unsafe fn with_handle<F>(handle: *mut RawEditHandle, call: F) -> RawEditStatus
+where
+    F: FnOnce(&mut RawEditHandle) -> RawEditStatus,
+{
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return RawEditStatus::InvalidArgument,
+    };
+    panic::catch_unwind(AssertUnwindSafe(|| call(&mut *handle))).unwrap_or(RawEditStatus::Panic)
+}
+
+/// Reads the file at `path` into a new handle. Returns null if the path is null or not
+/// UTF-8, or the file can't be read.
+///
+/// # Safety
+///
+/// `path` is null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_open(path: *const c_char) -> *mut RawEditHandle {
+    let path = match string(path) {
+        Some(path) => path,
+        None => return ptr::null_mut(),
+    };
+    match fs::read(path) {
+        Ok(original) => Box::into_raw(Box::new(RawEditHandle {
+            path: PathBuf::from(path),
+            original,
+            image: None,
+            encoded: None,
+            error: CString::default(),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Decodes the raw data of the file into the pixel plane of the handle, replacing the plane
+/// decoded before, and describes the plane in `plane`
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `raw_edit_open` and not closed; `plane` is null or
+/// points to a `RawEditPlane`.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_decode(
+    handle: *mut RawEditHandle,
+    plane: *mut RawEditPlane,
+) -> RawEditStatus {
+    let plane = match plane.as_mut() {
+        Some(plane) => plane,
+        None => return RawEditStatus::InvalidArgument,
+    };
+    with_handle(handle, |handle| {
+        handle.image = None;
+        handle.encoded = None;
+        let image = match RawImage::decode(&handle.original) {
+            Ok(image) => image,
+            Err(err) => return handle.fail_with(err),
+        };
+        let image = handle.image.insert(image);
+        *plane = RawEditPlane {
+            pixels: image.pixels.as_mut_ptr(),
+            width: image.width,
+            height: image.height,
+            black_level: image.black_level,
+            white_level: image.white_level,
+            crop_x: image.crop.x,
+            crop_y: image.crop.y,
+            crop_width: image.crop.width,
+            crop_height: image.crop.height,
+        };
+        RawEditStatus::Ok
+    })
+}
+
+/// Stamps `text` onto the plane with the built-in font, the top left of its line at `(x, y)`
+/// of the plane, `scale` photosites high, in the raw value `value`
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `raw_edit_open` and not closed; `text` is null or a
+/// NUL-terminated string. The host doesn't touch the plane during the call.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_draw_text(
+    handle: *mut RawEditHandle,
+    text: *const c_char,
+    x: u32,
+    y: u32,
+    scale: f32,
+    value: u16,
+) -> RawEditStatus {
+    let text = match string(text) {
+        Some(text) => text,
+        None => return RawEditStatus::InvalidArgument,
+    };
+    with_handle(handle, |handle| {
+        let image = match handle.image.as_mut() {
+            Some(image) => image,
+            None => return handle.not_decoded(),
+        };
+        let edit = TextEdit {
+            text: text.to_owned(),
+            x,
+            y,
+            scale,
+            value,
+            fonts: FontChain::builtin(),
+        };
+        // the buffer takes over the pixels and gives the same allocation back, so the
+        // pointer the host holds stays valid
+        let pixels = std::mem::take(&mut image.pixels);
+        let mut img = RawBuffer::from_raw(image.width as u32, image.height as u32, pixels)
+            .expect("the plane matches its size");
+        edit.draw_raw(&mut img);
+        image.pixels = img.into_raw();
+        RawEditStatus::Ok
+    })
+}
+
+/// Replaces the raw data of a copy of the file with the plane, in the format of the file.
+/// If `data` and `len` aren't null, they are set to the encoded file, owned by the handle
+/// until the next `raw_edit_encode`, `raw_edit_decode` or `raw_edit_close`.
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `raw_edit_open` and not closed; `data` and `len` are
+/// null or point to a pointer and a size. The host doesn't touch the plane during the call.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_encode(
+    handle: *mut RawEditHandle,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> RawEditStatus {
+    with_handle(handle, |handle| {
+        handle.encoded = None;
+        let result = match &handle.image {
+            Some(image) => image.encode_file(&handle.original),
+            None => return handle.not_decoded(),
+        };
+        let encoded = match result {
+            Ok(encoded) => handle.encoded.insert(encoded),
+            Err(err) => return handle.fail_with(err),
+        };
+        if let Some(data) = data.as_mut() {
+            *data = encoded.as_ptr();
+        }
+        if let Some(len) = len.as_mut() {
+            *len = encoded.len();
+        }
+        RawEditStatus::Ok
+    })
+}
+
+/// Writes the file encoded by the last `raw_edit_encode` to `path`, atomically. Changes made
+/// to the plane since then are not saved. The file the handle was opened from is never
+/// written over.
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `raw_edit_open` and not closed; `path` is null or a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_save(
+    handle: *mut RawEditHandle,
+    path: *const c_char,
+) -> RawEditStatus {
+    let path = match string(path) {
+        Some(path) => path,
+        None => return RawEditStatus::InvalidArgument,
+    };
+    with_handle(handle, |handle| {
+        let result = match &handle.encoded {
+            Some(encoded) => OutputFile::new(path)
+                .protect(&handle.path)
+                .replace_existing(true)
+                .write_with(|out| out.write_all(encoded)),
+            None => {
+                return handle.fail(
+                    RawEditStatus::NotReady,
+                    "the image has not been encoded".to_owned(),
+                )
+            }
+        };
+        match result {
+            Ok(()) => RawEditStatus::Ok,
+            Err(err) => handle.fail_with(err.into()),
+        }
+    })
+}
+
+/// The message of the last failure of a call on the handle, empty if none failed; owned by
+/// the handle until the next failure or `raw_edit_close`. Null if `handle` is null.
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `raw_edit_open` and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_last_error(handle: *const RawEditHandle) -> *const c_char {
+    match handle.as_ref() {
+        Some(handle) => handle.error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Frees the handle, with its plane and encoded file
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `raw_edit_open` and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn raw_edit_close(handle: *mut RawEditHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
@@ -0,0 +1,454 @@
+//! The raw data formats the codec handles, and how each of them is decoded and encoded
+
+use std::{io::Write, ops::Range};
+
+use crate::dither;
+use crate::pipeline;
+use crate::progress::Control;
+use crate::raw::{RawImage, DEFAULT_BLACK_LEVEL};
+use crate::rawloader::cr2::{self, Cr2Layout};
+use crate::rawloader::nef::{self, NefLayout};
+use crate::rawloader::sony_lossless::{self, TileLayout};
+use crate::rawloader::{self, decode_arw2_with, ToneCurve};
+use crate::sony_legacy;
+use crate::source::ByteSource;
+use crate::variant::{self, RawInfo, RawVariant, VariantError};
+use crate::RawEditError;
+
+/// How the raw data of a file is stored
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    /// Sony's compressed raw, through the given tone curve and decoded with the given dither
+    /// generator
+    Arw2 {
+        dither: dither::Factory,
+        curve: ToneCurve,
+    },
+    /// The 16-bit big-endian data of the DSC-R1
+    Sr2,
+    /// Encrypted 16-bit data of the DSC-F828 and DSC-V3, with the key found in the file
+    Srf { key: u32 },
+    /// Uncompressed ARW, 14-bit samples in little-endian 16-bit words
+    Uncompressed,
+    /// Uncompressed 12-bit ARW, two pixels packed into three bytes
+    Packed12,
+    /// Sony's lossless compression, in tiles found through the raw IFD at `ifd_offset` rather
+    /// than at a data offset. The tiles have no fixed size, so the data is written back with
+    /// `sony_lossless::write_tiles` instead of `encode_into`.
+    SonyLossless { ifd_offset: usize },
+    /// Canon's CR2: one lossless JPEG in the raw IFD at `ifd_offset`, written back with
+    /// `cr2::write` for the same reason
+    Cr2 { ifd_offset: usize },
+    /// Nikon's NEF, a single strip of Huffman-coded data in the raw IFD at `ifd_offset`, also
+    /// written back on its own, with `nef::write`. Lossy data is decoded with the given dither
+    /// generator.
+    Nef {
+        ifd_offset: usize,
+        dither: dither::Factory,
+    },
+}
+
+/// Rows `Format::write_rows` copies at a time, and most rows of uncompressed data encoded at
+/// a time for it
+pub const STREAM_BAND_ROWS: usize = 256;
+
+/// The error for encoding data of no fixed size in place, naming the function that writes it
+fn not_in_place(write: &str) -> RawEditError {
+    RawEditError::Mismatch(format!(
+        "compressed data of no fixed size can't be encoded in place; write it with {}",
+        write
+    ))
+}
+
+impl Format {
+    /// The format of the raw data described by `info`, as told by its SonyRawFileType (or
+    /// compression); ARW2 data is decoded with the given dither generator, through the curve
+    /// of the file or the default one
+    pub fn detect(info: &RawInfo, dither: dither::Factory) -> Result<Format, VariantError> {
+        match info.variant {
+            RawVariant::Compressed => Ok(Format::Arw2 {
+                dither,
+                curve: info.tone_curve.unwrap_or_default(),
+            }),
+            RawVariant::Uncompressed14 => Ok(Format::Uncompressed),
+            // some bodies store 12-bit samples in 16-bit words all the same
+            RawVariant::Uncompressed12 if info.byte_count == info.width * info.height * 2 => {
+                Ok(Format::Uncompressed)
+            }
+            RawVariant::Uncompressed12 => Ok(Format::Packed12),
+            RawVariant::LosslessCompressed | RawVariant::LosslessCompressed2 => {
+                Ok(Format::SonyLossless {
+                    ifd_offset: info.ifd_offset,
+                })
+            }
+            RawVariant::Cr2 => Ok(Format::Cr2 {
+                ifd_offset: info.ifd_offset,
+            }),
+            RawVariant::Nef => Ok(Format::Nef {
+                ifd_offset: info.ifd_offset,
+                dither,
+            }),
+            other => Err(VariantError::Unsupported(format!("{:?} raw data", other))),
+        }
+    }
+
+    /// Checks that the raw data described by `info` is laid out the way the format expects
+    pub fn check(self, info: &RawInfo) -> Result<(), VariantError> {
+        match self {
+            Format::Arw2 { .. } => variant::check_arw2(info),
+            Format::Uncompressed | Format::Packed12 => {
+                variant::check_uncompressed(info, self.data_len(info.width, info.height))
+            }
+            Format::SonyLossless { .. } if !info.tiled => Err(VariantError::Unsupported(
+                "lossless compressed RAW without tiles".to_owned(),
+            )),
+            Format::Sr2
+            | Format::Srf { .. }
+            | Format::SonyLossless { .. }
+            | Format::Cr2 { .. }
+            | Format::Nef { .. } => Ok(()),
+        }
+    }
+
+    /// An image with the size and levels of raw data in this format but no pixels, for when
+    /// only its geometry is needed
+    pub fn blank(self, width: usize, height: usize) -> RawImage {
+        match self {
+            Format::Arw2 { curve, .. } => {
+                let curve = curve.table();
+                RawImage {
+                    curve: Some(curve.clone()),
+                    ..RawImage::new(vec![], width, height, curve.max_value())
+                }
+            }
+            Format::Sr2 | Format::Srf { .. } => {
+                RawImage::new(vec![], width, height, sony_legacy::WHITE_LEVEL)
+            }
+            Format::Uncompressed | Format::SonyLossless { .. } => {
+                RawImage::new(vec![], width, height, rawloader::UNCOMPRESSED_WHITE_LEVEL)
+            }
+            Format::Packed12 => RawImage {
+                black_level: DEFAULT_BLACK_LEVEL >> 2,
+                ..RawImage::new(vec![], width, height, rawloader::PACKED12_WHITE_LEVEL)
+            },
+            Format::Cr2 { .. } => RawImage::new(vec![], width, height, cr2::WHITE_LEVEL),
+            Format::Nef { .. } => RawImage::new(vec![], width, height, nef::WHITE_LEVEL),
+        }
+    }
+
+    /// Decodes `width` x `height` pixels of raw data starting at `offset` in `src`
+    pub fn decode_from<S: ByteSource>(
+        self,
+        src: &mut S,
+        offset: u64,
+        width: usize,
+        height: usize,
+    ) -> Result<RawImage, RawEditError> {
+        self.decode_from_with(src, offset, width, height, &Control::NONE)
+    }
+
+    /// Like `decode_from`, reporting progress and checking for cancellation. Only ARW2 data
+    /// is decoded band by band; the other formats report once, when they are done.
+    pub fn decode_from_with<S: ByteSource>(
+        self,
+        src: &mut S,
+        offset: u64,
+        width: usize,
+        height: usize,
+        control: &Control,
+    ) -> Result<RawImage, RawEditError> {
+        control.check()?;
+        let mut white_level = None;
+        let pixels = match self {
+            Format::Arw2 { dither, curve } => {
+                decode_arw2_with(src, offset, width, height, &curve.table(), dither, control)?
+            }
+            Format::Sr2 => {
+                let data = src.read_vec_at(offset, width * height * 2)?;
+                sony_legacy::decode_sr2(&data, width, height)?
+            }
+            Format::Srf { key } => {
+                let data = src.read_vec_at(offset, width * height * 2)?;
+                sony_legacy::decode_srf(&data, key, width, height)?
+            }
+            Format::Uncompressed => {
+                let data = src.read_vec_at(offset, self.data_len(width, height))?;
+                rawloader::decode_arw_uncompressed(&data, width, height)?
+            }
+            Format::Packed12 => {
+                let data = src.read_vec_at(offset, self.data_len(width, height))?;
+                rawloader::decode_arw_packed12(&data, width, height)?
+            }
+            Format::SonyLossless { ifd_offset } => {
+                let size = src.size()? as usize;
+                let file = src.read_vec_at(0, size)?;
+                let layout = TileLayout::read(&file, ifd_offset)?;
+                if (layout.width, layout.height) != (width, height) {
+                    return Err(RawEditError::Mismatch(format!(
+                        "the tiles make up a {}x{} image, not {}x{}",
+                        layout.width, layout.height, width, height
+                    )));
+                }
+                sony_lossless::decode(&file, &layout)?
+            }
+            Format::Cr2 { ifd_offset } => {
+                let size = src.size()? as usize;
+                let file = src.read_vec_at(0, size)?;
+                let layout = Cr2Layout::read(&file, ifd_offset)?;
+                if (layout.width, layout.height) != (width, height) {
+                    return Err(RawEditError::Mismatch(format!(
+                        "the lossless JPEG makes up a {}x{} image, not {}x{}",
+                        layout.width, layout.height, width, height
+                    )));
+                }
+                white_level = Some(((1u32 << layout.frame.precision) - 1) as u16);
+                cr2::decode(&file, &layout)?
+            }
+            Format::Nef { ifd_offset, dither } => {
+                let size = src.size()? as usize;
+                let file = src.read_vec_at(0, size)?;
+                let layout = NefLayout::read(&file, ifd_offset)?;
+                if (layout.width, layout.height) != (width, height) {
+                    return Err(RawEditError::Mismatch(format!(
+                        "the NEF data is {}x{}, not {}x{}",
+                        layout.width, layout.height, width, height
+                    )));
+                }
+                white_level = Some(layout.white_level());
+                nef::decode(&file, &layout, dither)?
+            }
+        };
+        control.check()?;
+        control.report(height, height);
+        let blank = self.blank(width, height);
+        Ok(RawImage {
+            pixels,
+            white_level: white_level.unwrap_or(blank.white_level),
+            ..blank
+        })
+    }
+
+    /// Size of the raw data of a `width` x `height` image; nothing for data of no fixed size,
+    /// which lives in tiles or a strip of its own
+    pub fn data_len(self, width: usize, height: usize) -> usize {
+        match self {
+            Format::Arw2 { .. } => width * height,
+            Format::Sr2 | Format::Srf { .. } | Format::Uncompressed => width * height * 2,
+            Format::Packed12 => width * height * 3 / 2,
+            Format::SonyLossless { .. } | Format::Cr2 { .. } | Format::Nef { .. } => 0,
+        }
+    }
+
+    /// Largest value the format can store where it is less than 16 bits; encoding clamps
+    /// pixels to it. CR2 and NEF clamp to the precision or curve of their data, which only
+    /// the file knows.
+    pub fn max_value(self) -> Option<u16> {
+        match self {
+            Format::Uncompressed | Format::SonyLossless { .. } => {
+                Some(rawloader::UNCOMPRESSED_WHITE_LEVEL)
+            }
+            Format::Packed12 => Some(rawloader::PACKED12_WHITE_LEVEL),
+            Format::Arw2 { .. }
+            | Format::Sr2
+            | Format::Srf { .. }
+            | Format::Cr2 { .. }
+            | Format::Nef { .. } => None,
+        }
+    }
+
+    /// Encodes the whole image into `out`, which has to hold `data_len` bytes
+    pub fn encode_into(
+        self,
+        img: &[u16],
+        width: usize,
+        out: &mut [u8],
+    ) -> Result<(), RawEditError> {
+        self.encode_into_with(img, width, out, &Control::NONE)
+    }
+
+    /// Like `encode_into`, reporting progress and checking for cancellation. Only ARW2 data
+    /// is encoded chunk by chunk; the other formats report once, when they are done.
+    pub fn encode_into_with(
+        self,
+        img: &[u16],
+        width: usize,
+        mut out: &mut [u8],
+        control: &Control,
+    ) -> Result<(), RawEditError> {
+        control.check()?;
+        let height = img.len() / width.max(1);
+        if out.len() < self.data_len(width, height) {
+            return Err(RawEditError::Truncated("the raw data".to_owned()));
+        }
+        match self {
+            Format::Arw2 { curve, .. } => {
+                return pipeline::encode_arw2_pipelined_with(
+                    img,
+                    width,
+                    &curve.table(),
+                    &pipeline::PipelineConfig::default(),
+                    &mut out,
+                    control,
+                )
+            }
+            Format::Sr2 => out.write_all(&sony_legacy::encode_sr2(img))?,
+            Format::Srf { key } => out.write_all(&sony_legacy::encode_srf(img, key))?,
+            Format::Uncompressed => out.write_all(&rawloader::encode_arw_uncompressed(img))?,
+            Format::Packed12 => out.write_all(&rawloader::encode_arw_packed12(img, width)?)?,
+            Format::SonyLossless { .. } => return Err(not_in_place("sony_lossless::write_tiles")),
+            Format::Cr2 { .. } => return Err(not_in_place("cr2::write")),
+            Format::Nef { .. } => return Err(not_in_place("nef::write")),
+        }
+        control.report(height, height);
+        Ok(())
+    }
+
+    /// Re-encodes only the given rows where the format allows it. ARW2 and uncompressed rows
+    /// always take the same number of bytes, so they can be replaced one by one; SRF's
+    /// keystream runs through the whole image, so it is always encoded in full.
+    pub fn encode_rows_into(
+        self,
+        img: &[u16],
+        width: usize,
+        rows: &[Range<usize>],
+        out: &mut [u8],
+    ) -> Result<(), RawEditError> {
+        match self {
+            Format::Arw2 { .. } | Format::Uncompressed | Format::Packed12 => {
+                let row_len = self.data_len(width, 1);
+                self.encode_bands(img, width, rows, |rows, encoded| {
+                    out.get_mut(rows.start * row_len..rows.end * row_len)
+                        .ok_or_else(|| RawEditError::Truncated("the raw data".to_owned()))?
+                        .copy_from_slice(encoded);
+                    Ok(())
+                })
+            }
+            Format::Sr2 | Format::Srf { .. } => self.encode_into(img, width, out),
+            Format::SonyLossless { .. } => Err(not_in_place("sony_lossless::write_tiles")),
+            Format::Cr2 { .. } => Err(not_in_place("cr2::write")),
+            Format::Nef { .. } => Err(not_in_place("nef::write")),
+        }
+    }
+
+    /// Writes all of the raw data to `out`, like `encode_rows_into` would leave it: the given
+    /// rows (in order) are encoded, and the others copied from `original`, which holds the
+    /// data as it was at `offset`. The rows left alone are copied `STREAM_BAND_ROWS` at a
+    /// time and the encoded ones written as they come, so neither the old data nor the new
+    /// one is ever held in full. Returns how many bytes differ from the original. Only
+    /// formats whose rows can be replaced one by one are written this way.
+    ///
+    /// ```
+    /// use raw_tiff_edit::format::Format;
+    /// use raw_tiff_edit::source::MemorySource;
+    ///
+    /// let (width, height) = (16, 600);
+    /// let format = Format::Packed12;
+    /// let img: Vec<u16> = (0..width * height).map(|i| (i * 37 % 4000) as u16).collect();
+    /// let mut data = vec![0; format.data_len(width, height)];
+    /// format.encode_into(&img, width, &mut data)?;
+    ///
+    /// let mut edited = img.clone();
+    /// edited[300 * width..400 * width].iter_mut().for_each(|pixel| *pixel /= 2);
+    /// let mut in_place = data.clone();
+    /// format.encode_rows_into(&edited, width, &[300..400], &mut in_place)?;
+    /// let mut streamed = vec![];
+    /// let source = &mut MemorySource::new(&data);
+    /// format.write_rows(&edited, width, &[300..400], source, 0, &mut streamed)?;
+    /// assert_eq!(streamed, in_place);
+    /// # Ok::<(), raw_tiff_edit::RawEditError>(())
+    /// ```
+    pub fn write_rows<S: ByteSource, W: Write>(
+        self,
+        img: &[u16],
+        width: usize,
+        rows: &[Range<usize>],
+        original: &mut S,
+        offset: u64,
+        out: &mut W,
+    ) -> Result<usize, RawEditError> {
+        let height = img.len() / width.max(1);
+        let row_len = self.data_len(width, 1);
+        let mut changed = 0;
+        let mut before = vec![];
+        let mut row = 0;
+        self.encode_bands(img, width, rows, |rows, after| {
+            copy_rows(original, offset, row_len, row..rows.start, &mut before, out)?;
+            before.resize(after.len(), 0);
+            original.read_exact_at(offset + (rows.start * row_len) as u64, &mut before)?;
+            changed += before.iter().zip(after).filter(|(b, a)| b != a).count();
+            out.write_all(after)?;
+            row = rows.end;
+            Ok(())
+        })?;
+        copy_rows(original, offset, row_len, row..height, &mut before, out)?;
+        Ok(changed)
+    }
+
+    /// Encodes the given rows (in order) of data whose rows can be replaced one by one,
+    /// handing `sink` the encoded data of a band of rows at a time. ARW2 data is encoded by
+    /// one pipeline for all of the rows.
+    fn encode_bands<F>(
+        self,
+        img: &[u16],
+        width: usize,
+        rows: &[Range<usize>],
+        mut sink: F,
+    ) -> Result<(), RawEditError>
+    where
+        F: FnMut(Range<usize>, &[u8]) -> Result<(), RawEditError>,
+    {
+        match self {
+            Format::Arw2 { curve, .. } => pipeline::encode_arw2_rows_pipelined_with(
+                img,
+                width,
+                rows,
+                &curve.table(),
+                &pipeline::PipelineConfig::default(),
+                &Control::NONE,
+                sink,
+            ),
+            Format::Uncompressed | Format::Packed12 => {
+                let mut encoded = vec![];
+                for rows in rows {
+                    for start in rows.clone().step_by(STREAM_BAND_ROWS) {
+                        let band = start..(start + STREAM_BAND_ROWS).min(rows.end);
+                        let pixels =
+                            img.get(band.start * width..band.end * width)
+                                .ok_or_else(|| {
+                                    RawEditError::Mismatch(format!(
+                                        "the image has no rows {} to {}",
+                                        rows.start, rows.end
+                                    ))
+                                })?;
+                        encoded.resize(self.data_len(width, band.len()), 0);
+                        self.encode_into(pixels, width, &mut encoded)?;
+                        sink(band, &encoded)?;
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(RawEditError::Mismatch(
+                "only data of fixed-size rows can be written row by row".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Copies `rows` of `row_len` bytes from `original`, where they start at `offset`, to `out`,
+/// `STREAM_BAND_ROWS` at a time through `buffer`
+fn copy_rows<S: ByteSource, W: Write>(
+    original: &mut S,
+    offset: u64,
+    row_len: usize,
+    rows: Range<usize>,
+    buffer: &mut Vec<u8>,
+    out: &mut W,
+) -> Result<(), RawEditError> {
+    for start in rows.clone().step_by(STREAM_BAND_ROWS) {
+        let end = (start + STREAM_BAND_ROWS).min(rows.end);
+        buffer.resize((end - start) * row_len, 0);
+        original.read_exact_at(offset + (start * row_len) as u64, buffer)?;
+        out.write_all(buffer)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+//! `RawFrame`, the form raw data takes outside of a raw file: the pixels of the sensor readout
+//! with everything needed to interpret them, and where they came from. It is what
+//! `--export-frame` writes and `--import-frame` reads, in CBOR or MessagePack, so other tools
+//! (in any language with a CBOR or MessagePack library) can work on the raw data and hand it
+//! back. The fields are plain numbers, strings and lists, and keep their names across versions;
+//! `version` changes when their meaning does.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::raw::{CfaPattern, RawImage};
+use crate::rawloader::LookupTable;
+use crate::tiled::Rect;
+
+/// Version of the frame layout written by this build
+pub const FRAME_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The data is not a frame in the encoding
+    Decode(String),
+    /// The frame was written by a newer version
+    Version(u32),
+    /// The frame decodes, but its fields don't fit together
+    Invalid(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Decode(reason) => write!(f, "not a raw frame: {}", reason),
+            FrameError::Version(version) => write!(
+                f,
+                "the frame has version {}, but only up to {} is supported",
+                version, FRAME_VERSION
+            ),
+            FrameError::Invalid(reason) => write!(f, "invalid raw frame: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// How a frame is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Cbor,
+    MessagePack,
+}
+
+impl Encoding {
+    /// The encoding a file name asks for: `.msgpack` or `.mp` for MessagePack, anything else
+    /// (`.cbor` above all) for CBOR
+    pub fn from_name(path: &str) -> Encoding {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".msgpack") || lower.ends_with(".mp") {
+            Encoding::MessagePack
+        } else {
+            Encoding::Cbor
+        }
+    }
+}
+
+/// The visible area of the readout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where the pixels of a frame came from; every field is informational
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The file the raw data was read from
+    pub source: Option<String>,
+    /// How the raw data was stored in it (`arw2`, `uncompressed`, ...)
+    pub format: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// The program that wrote the frame, and its version
+    pub writer: String,
+}
+
+impl Provenance {
+    /// Provenance naming this crate as the writer
+    pub fn new() -> Provenance {
+        Provenance {
+            writer: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            ..Provenance::default()
+        }
+    }
+}
+
+/// The raw data of one image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawFrame {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Linear sensor values, row by row
+    pub pixels: Vec<u16>,
+    /// The 2x2 colour filter array from the top-left pixel, row by row, as CFAPattern values
+    /// (0 = red, 1 = green, 2 = blue)
+    pub cfa: [u8; 4],
+    pub black_level: u16,
+    pub white_level: u16,
+    /// As-shot red, green and blue multipliers, normalized to green
+    pub white_balance: [f32; 3],
+    /// The tone curve the data was compressed with, as the values of its entries
+    pub curve: Option<Vec<u16>>,
+    pub crop: FrameRect,
+    pub provenance: Provenance,
+}
+
+impl RawFrame {
+    pub fn from_image(image: &RawImage, provenance: Provenance) -> RawFrame {
+        let mut cfa = [0; 4];
+        cfa.copy_from_slice(&image.cfa.tiff_values());
+        RawFrame {
+            version: FRAME_VERSION,
+            width: image.width as u32,
+            height: image.height as u32,
+            pixels: image.pixels.clone(),
+            cfa,
+            black_level: image.black_level,
+            white_level: image.white_level,
+            white_balance: image.white_balance,
+            curve: image.curve.as_ref().map(LookupTable::centers),
+            crop: FrameRect {
+                x: image.crop.x as u32,
+                y: image.crop.y as u32,
+                width: image.crop.width as u32,
+                height: image.crop.height as u32,
+            },
+            provenance,
+        }
+    }
+
+    /// The frame as an image, after checking that its fields fit together
+    pub fn to_image(&self) -> Result<RawImage, FrameError> {
+        let invalid = |reason: &str| FrameError::Invalid(reason.to_owned());
+        if self.version > FRAME_VERSION {
+            return Err(FrameError::Version(self.version));
+        }
+        let (width, height) = (self.width as usize, self.height as usize);
+        if width.checked_mul(height) != Some(self.pixels.len()) {
+            return Err(invalid("the number of pixels doesn't match the dimensions"));
+        }
+        let cfa_values: Vec<u32> = self.cfa.iter().map(|value| *value as u32).collect();
+        let cfa = CfaPattern::from_tiff(&[2, 2], &cfa_values)
+            .ok_or_else(|| invalid("the colour filter array has unknown colours"))?;
+        if self.black_level >= self.white_level {
+            return Err(invalid("the black level is not below the white level"));
+        }
+        let crop = Rect {
+            x: self.crop.x as usize,
+            y: self.crop.y as usize,
+            width: self.crop.width as usize,
+            height: self.crop.height as usize,
+        };
+        if crop.x + crop.width > width || crop.y + crop.height > height {
+            return Err(invalid("the visible area sticks out of the image"));
+        }
+        let curve = match &self.curve {
+            Some(centers) if centers.len() < 2 || centers.windows(2).any(|w| w[0] > w[1]) => {
+                return Err(invalid("the tone curve doesn't rise"));
+            }
+            Some(centers) => Some(LookupTable::new(centers)),
+            None => None,
+        };
+        let mut image = RawImage::new(self.pixels.clone(), width, height, self.white_level);
+        image.cfa = cfa;
+        image.black_level = self.black_level;
+        image.white_balance = self.white_balance;
+        image.curve = curve;
+        image.crop = crop;
+        Ok(image)
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        let mut out = vec![];
+        match encoding {
+            // neither can fail writing plain data into memory
+            Encoding::Cbor => ciborium::ser::into_writer(self, &mut out).unwrap(),
+            Encoding::MessagePack => rmp_serde::encode::write_named(&mut out, self).unwrap(),
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8], encoding: Encoding) -> Result<RawFrame, FrameError> {
+        let frame: RawFrame = match encoding {
+            Encoding::Cbor => ciborium::de::from_reader(data)
+                .map_err(|err| FrameError::Decode(err.to_string()))?,
+            Encoding::MessagePack => {
+                rmp_serde::from_slice(data).map_err(|err| FrameError::Decode(err.to_string()))?
+            }
+        };
+        if frame.version > FRAME_VERSION {
+            return Err(FrameError::Version(frame.version));
+        }
+        Ok(frame)
+    }
+}
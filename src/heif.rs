@@ -0,0 +1,142 @@
+//! Companion HEIF files. Bodies shooting RAW+HEIF write an `.HIF` next to every `.ARW`, and a
+//! stamp on the raw data should show up on the HEIF too. There is no HEIF codec in Rust, so
+//! the image goes through `heif-convert` and `heif-enc` from libheif, which have to be on the
+//! `PATH`. The re-encoded file keeps the pixels only; the metadata of the original is lost.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fmt, fs, io};
+
+use image::{ImageError, RgbImage};
+
+/// Extensions of companion files, in the order they are looked for
+const EXTENSIONS: [&str; 4] = ["HIF", "hif", "HEIF", "heif"];
+
+/// Quality `heif-enc` is asked for, on its scale of 0 to 100
+pub const DEFAULT_QUALITY: u8 = 90;
+
+#[derive(Debug)]
+pub enum HeifError {
+    /// One of the libheif tools couldn't be run or failed
+    Tool {
+        tool: &'static str,
+        message: String,
+    },
+    Image(ImageError),
+    Io(io::Error),
+}
+
+impl fmt::Display for HeifError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeifError::Tool { tool, message } => write!(f, "{}: {}", tool, message),
+            HeifError::Image(err) => write!(f, "failed to process the HEIF image: {}", err),
+            HeifError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for HeifError {}
+
+impl From<ImageError> for HeifError {
+    fn from(err: ImageError) -> HeifError {
+        HeifError::Image(err)
+    }
+}
+
+impl From<io::Error> for HeifError {
+    fn from(err: io::Error) -> HeifError {
+        HeifError::Io(err)
+    }
+}
+
+/// The HEIF file shot together with a raw file: the same name with a HEIF extension
+pub fn companion<P: AsRef<Path>>(raw_path: P) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|extension| raw_path.as_ref().with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+fn run(tool: &'static str, args: &[&OsStr]) -> Result<(), HeifError> {
+    let output = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|err| HeifError::Tool {
+            tool,
+            message: err.to_string(),
+        })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(HeifError::Tool {
+            tool,
+            message: format!(
+                "{} ({})",
+                String::from_utf8_lossy(&output.stderr).trim(),
+                output.status
+            ),
+        })
+    }
+}
+
+/// Intermediate files of one conversion, removed when dropped
+struct Scratch {
+    decoded: PathBuf,
+    encoded: PathBuf,
+}
+
+impl Scratch {
+    fn new() -> Scratch {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "raw-tiff-edit-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = std::env::temp_dir();
+        Scratch {
+            decoded: dir.join(format!("{}.png", name)),
+            encoded: dir.join(format!("{}.hif", name)),
+        }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.decoded);
+        let _ = fs::remove_file(&self.encoded);
+    }
+}
+
+/// Decodes the primary image of a HEIF file, lets `draw` edit it and returns the file
+/// encoded again at `quality`
+pub fn redraw<P, F>(path: P, quality: u8, draw: F) -> Result<Vec<u8>, HeifError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut RgbImage),
+{
+    let scratch = Scratch::new();
+    run(
+        "heif-convert",
+        &[path.as_ref().as_os_str(), scratch.decoded.as_os_str()],
+    )?;
+    let mut img = image::open(&scratch.decoded)?.to_rgb();
+    draw(&mut img);
+    img.save(&scratch.decoded)?;
+    let quality = quality.min(100).to_string();
+    run(
+        "heif-enc",
+        &[
+            OsStr::new("-q"),
+            OsStr::new(&quality),
+            OsStr::new("-o"),
+            scratch.encoded.as_os_str(),
+            scratch.decoded.as_os_str(),
+        ],
+    )?;
+    Ok(fs::read(&scratch.encoded)?)
+}
@@ -0,0 +1,155 @@
+//! Import of a 16-bit grayscale TIFF back into the raw data: the complement of `export`, for
+//! editing the mosaic in an external editor. The TIFF has to cover the visible area, the way
+//! `export` writes it, and its values are mapped back through the scaling it was exported
+//! with. Encoding then clamps and requantizes them like any other edit.
+
+use std::fmt;
+
+use image::ImageBuffer;
+
+use crate::edit::RawBuffer;
+use crate::export::Scaling;
+use crate::raw::RawImage;
+use crate::tiff::{self, Tiff};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    /// Not a TIFF, or one the importer doesn't read
+    Invalid(String),
+    /// The TIFF is not the size of the visible area
+    Size {
+        expected: (usize, usize),
+        found: (usize, usize),
+    },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Invalid(reason) => write!(f, "cannot import the TIFF: {}", reason),
+            ImportError::Size { expected, found } => write!(
+                f,
+                "the TIFF is {}x{}, but the visible area is {}x{}",
+                found.0, found.1, expected.0, expected.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// The pixels of a grayscale image
+#[derive(Debug, Clone)]
+pub struct Plane {
+    pub width: usize,
+    pub height: usize,
+    /// Row by row
+    pub values: Vec<u16>,
+}
+
+/// Reads an uncompressed 16-bit grayscale TIFF in strips, in either byte order: what `export`
+/// writes, and what image editors write when told not to compress
+pub fn read_tiff(data: &[u8]) -> Result<Plane, ImportError> {
+    let invalid = |reason: &str| ImportError::Invalid(reason.to_owned());
+    let tiff = Tiff::new(data).ok_or_else(|| invalid("not a TIFF file"))?;
+    let ifd = tiff.ifd0().ok_or_else(|| invalid("no image"))?;
+    let value = |tag| tiff.value(&ifd, tag);
+    let values = |tag| ifd.entry(tag).map_or(vec![], |entry| tiff.values(entry));
+
+    let width = value(tiff::IMAGE_WIDTH).ok_or_else(|| invalid("no width"))? as usize;
+    let height = value(tiff::IMAGE_LENGTH).ok_or_else(|| invalid("no height"))? as usize;
+    if values(tiff::BITS_PER_SAMPLE) != [16] {
+        return Err(invalid("not a 16-bit grayscale image"));
+    }
+    // WhiteIsZero (0) or BlackIsZero (1)
+    let photometric = value(tiff::PHOTOMETRIC_INTERPRETATION).unwrap_or(1);
+    if photometric > 1 {
+        return Err(invalid("not a grayscale image"));
+    }
+    if value(tiff::COMPRESSION).unwrap_or(1) != 1 {
+        return Err(invalid("compressed; save it without compression"));
+    }
+
+    let offsets = values(tiff::STRIP_OFFSETS);
+    let counts = values(tiff::STRIP_BYTE_COUNTS);
+    if offsets.is_empty() || offsets.len() != counts.len() {
+        return Err(invalid("no strips"));
+    }
+    let mut bytes = Vec::with_capacity(width * height * 2);
+    for (offset, count) in offsets.iter().zip(&counts) {
+        let (offset, count) = (*offset as usize, *count as usize);
+        let strip = offset
+            .checked_add(count)
+            .and_then(|end| data.get(offset..end))
+            .ok_or_else(|| invalid("a strip runs past the end of the file"))?;
+        bytes.extend_from_slice(strip);
+    }
+    if bytes.len() < width * height * 2 {
+        return Err(invalid("the strips hold less than the whole image"));
+    }
+    let values = bytes
+        .chunks_exact(2)
+        .take(width * height)
+        .map(|pair| {
+            let value = if tiff.is_little_endian() {
+                u16::from_le_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_be_bytes([pair[0], pair[1]])
+            };
+            if photometric == 0 {
+                u16::MAX - value
+            } else {
+                value
+            }
+        })
+        .collect();
+    Ok(Plane {
+        width,
+        height,
+        values,
+    })
+}
+
+/// Replaces the visible area of the image (described by `image`, whose pixels are not used)
+/// with the plane, undoing the scaling it was exported with. Nothing outside of the visible
+/// area changes.
+pub fn apply(
+    img: &mut RawBuffer,
+    image: &RawImage,
+    plane: &Plane,
+    scaling: Scaling,
+) -> Result<(), ImportError> {
+    let crop = image.crop;
+    if (plane.width, plane.height) != (crop.width, crop.height) {
+        return Err(ImportError::Size {
+            expected: (crop.width, crop.height),
+            found: (plane.width, plane.height),
+        });
+    }
+    for (y, row) in plane.values.chunks(plane.width.max(1)).enumerate() {
+        for (x, value) in row.iter().enumerate() {
+            let pixel = img.get_pixel_mut((crop.x + x) as u32, (crop.y + y) as u32);
+            pixel.0[0] = scaling.invert(*value, image);
+        }
+    }
+    Ok(())
+}
+
+impl RawImage {
+    /// Replaces the visible area with the contents of a TIFF exported from it (by
+    /// `RawImage::export` with the same `scaling`, say) and edited elsewhere
+    pub fn import(&mut self, tiff: &[u8], scaling: Scaling) -> Result<(), ImportError> {
+        let plane = read_tiff(tiff)?;
+        if self.pixels.len() != self.width * self.height {
+            return Err(ImportError::Invalid(
+                "the image has the wrong number of pixels".to_owned(),
+            ));
+        }
+        let pixels = std::mem::take(&mut self.pixels);
+        let mut img: RawBuffer =
+            ImageBuffer::from_raw(self.width as u32, self.height as u32, pixels).unwrap();
+        let result = apply(&mut img, self, &plane, scaling);
+        self.pixels = img.into_raw();
+        result
+    }
+}
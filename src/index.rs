@@ -0,0 +1,287 @@
+//! An index of where every 32-pixel group of an ARW2 bitstream starts, together with the
+//! dither state at that point. Groups are normally 32 bytes each, but a malformed group (one
+//! with imax == imin) carries an extra delta and shifts everything after it within the row, so
+//! the only way to find a group is to walk the row once. With the index, arbitrary rectangles
+//! can be decoded and single groups re-encoded without walking the rows again.
+//!
+//! An index saved to a file records where the raw strip it was built from lies and a hash of
+//! its bytes, so that an index of another file, or of an earlier version of the same one, is
+//! recognized as such instead of patching groups at the wrong places.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::dither::{CameraDither, Factory};
+use crate::rawloader::{
+    calculate_curve, decode_arw2_group, encode_arw2, BitPump, BitPumpLSB, LookupTable,
+};
+use crate::tiled::Rect;
+use crate::RawEditError;
+
+const MAGIC: &[u8; 8] = b"ARW2IDX2";
+/// Number of bits in a well-formed group
+const GROUP_BITS: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupEntry {
+    /// Bit offset of the group from the start of its row
+    pub bit_offset: u32,
+    /// Length of the group in bits
+    pub bits: u32,
+    /// State of the camera's generator (`CameraDither`) when starting the group
+    pub random: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockIndex {
+    /// Offset of the raw strip in its file
+    offset: u64,
+    /// Length of the raw strip, `width * height` bytes or less if the file ends before
+    len: u64,
+    /// `strip_hash` of the raw strip
+    hash: u64,
+    width: usize,
+    height: usize,
+    groups_per_row: usize,
+    entries: Vec<GroupEntry>,
+}
+
+impl BlockIndex {
+    /// Walks the whole bitstream once, recording every group. `buf` starts with the raw strip,
+    /// which is at `offset` in its file.
+    pub fn build(buf: &[u8], offset: u64, width: usize, height: usize) -> BlockIndex {
+        // only the lengths of the groups are kept, so any curve does for decoding them
+        let curve = calculate_curve();
+        let groups_per_row = width.div_ceil(32);
+        let mut entries = Vec::with_capacity(groups_per_row * height);
+        let mut scratch = [0u16; 32];
+        for row in 0..height {
+            let mut pump = BitPumpLSB::new(buf.get(row * width..).unwrap_or(&[]));
+            let mut random = CameraDither(pump.peek_bits(16));
+            for _ in 0..groups_per_row {
+                let start = pump.bit_position();
+                let entry_random = random.0;
+                decode_arw2_group(&mut pump, &curve, &mut random, &mut scratch);
+                entries.push(GroupEntry {
+                    bit_offset: start as u32,
+                    bits: (pump.bit_position() - start) as u32,
+                    random: entry_random,
+                });
+            }
+        }
+        let strip = strip(buf, width, height);
+        BlockIndex {
+            offset,
+            len: strip.len() as u64,
+            hash: strip_hash(strip),
+            width,
+            height,
+            groups_per_row,
+            entries,
+        }
+    }
+
+    /// Whether the index was built from this raw strip: the same size of image, at the same
+    /// offset, and the same bytes
+    ///
+    /// ```
+    /// use raw_tiff_edit::index::BlockIndex;
+    /// use raw_tiff_edit::rawloader::{calculate_curve, encode_arw2};
+    ///
+    /// let (width, height) = (64, 4);
+    /// let curve = calculate_curve();
+    /// let flat = encode_arw2(&vec![1000; width * height], width, &curve)?;
+    /// let ramp: Vec<u16> = (0..width * height).map(|i| (i * 50) as u16).collect();
+    /// let ramp = encode_arw2(&ramp, width, &curve)?;
+    ///
+    /// let index = BlockIndex::build(&flat, 512, width, height);
+    /// assert!(index.matches(&flat, 512, width, height));
+    /// assert!(!index.matches(&flat, 1024, width, height));
+    /// assert!(!index.matches(&flat, 512, width / 2, height * 2));
+    /// assert!(!index.matches(&ramp, 512, width, height));
+    /// # Ok::<(), raw_tiff_edit::RawEditError>(())
+    /// ```
+    pub fn matches(&self, buf: &[u8], offset: u64, width: usize, height: usize) -> bool {
+        let strip = strip(buf, width, height);
+        self.width == width
+            && self.height == height
+            && self.offset == offset
+            && self.len == strip.len() as u64
+            && self.hash == strip_hash(strip)
+    }
+
+    pub fn entry(&self, row: usize, group: usize) -> GroupEntry {
+        self.entries[row * self.groups_per_row + group]
+    }
+
+    /// Whether the group sits at its nominal position and has its nominal length, so that a
+    /// re-encoded group can be written over it without moving the rest of the row
+    pub fn is_aligned(&self, row: usize, group: usize) -> bool {
+        let entry = self.entry(row, group);
+        entry.bit_offset == group as u32 * GROUP_BITS
+            && entry.bits == GROUP_BITS
+            && group * 32 + 32 <= self.width
+    }
+
+    /// Re-encodes the groups covering the rectangles in place, through the tone curve of the
+    /// file. Returns the rows holding groups that couldn't be replaced on their own, which
+    /// need to be re-encoded in full.
+    pub fn encode_groups(
+        &self,
+        img: &[u16],
+        curve: &LookupTable,
+        rects: &[Rect],
+        out: &mut [u8],
+    ) -> Result<Vec<usize>, RawEditError> {
+        let mut full_rows = vec![];
+        for rect in rects {
+            let right = (rect.x + rect.width).min(self.width);
+            let bottom = (rect.y + rect.height).min(self.height);
+            for row in rect.y..bottom {
+                let groups = rect.x / 32..right.div_ceil(32);
+                if !groups.clone().all(|group| self.is_aligned(row, group)) {
+                    full_rows.push(row);
+                    continue;
+                }
+                for group in groups {
+                    let start = row * self.width + group * 32;
+                    let pixels = img.get(start..start + 32).ok_or_else(|| {
+                        RawEditError::Mismatch(format!(
+                            "the image has no pixels for group {} of row {}",
+                            group, row
+                        ))
+                    })?;
+                    let encoded = encode_arw2(pixels, 32, curve)?;
+                    out.get_mut(start..start + 32)
+                        .ok_or_else(|| RawEditError::Truncated("the raw data".to_owned()))?
+                        .copy_from_slice(&encoded);
+                }
+            }
+        }
+        full_rows.sort_unstable();
+        full_rows.dedup();
+        Ok(full_rows)
+    }
+
+    /// Decodes just the pixels of `rect`, starting every row at the first group it needs,
+    /// with generators from `dither`, like a full decode with the same generator would:
+    ///
+    /// ```
+    /// use raw_tiff_edit::dither;
+    /// use raw_tiff_edit::index::BlockIndex;
+    /// use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_from, encode_arw2};
+    /// use raw_tiff_edit::source::MemorySource;
+    /// use raw_tiff_edit::tiled::Rect;
+    ///
+    /// let (width, height) = (96, 8);
+    /// let curve = calculate_curve();
+    /// let pixels: Vec<u16> = (0..width * height).map(|i| (i * 613 % 15000) as u16).collect();
+    /// let data = encode_arw2(&pixels, width, &curve)?;
+    /// let index = BlockIndex::build(&data, 0, width, height);
+    /// let generator = dither::by_name("xorshift").unwrap();
+    /// let all = decode_arw2_from(&mut MemorySource::new(&data), 0, width, height, &curve, generator)?;
+    ///
+    /// let rect = Rect { x: 40, y: 2, width: 50, height: 5 };
+    /// let region = index.decode_region(&data, &curve, rect, generator);
+    /// for (y, row) in region.chunks(rect.width).enumerate() {
+    ///     let start = (rect.y + y) * width + rect.x;
+    ///     assert_eq!(row, &all[start..start + rect.width]);
+    /// }
+    /// # Ok::<(), raw_tiff_edit::RawEditError>(())
+    /// ```
+    pub fn decode_region(
+        &self,
+        buf: &[u8],
+        curve: &LookupTable,
+        rect: Rect,
+        dither: Factory,
+    ) -> Vec<u16> {
+        let right = (rect.x + rect.width).min(self.width);
+        let bottom = (rect.y + rect.height).min(self.height);
+        let mut result = Vec::with_capacity(rect.width * rect.height);
+        let mut group_pixels = [0u16; 32];
+        let mut random = dither();
+        for row in rect.y..bottom {
+            let row_data = buf.get(row * self.width..).unwrap_or(&[]);
+            let groups = rect.x / 32..right.div_ceil(32);
+            // the generator is only known at the start of the row, so it is stepped past
+            // the pixels of the groups left of the rectangle
+            random.reseed(BitPumpLSB::new(row_data).peek_bits(16));
+            for _ in 0..groups.start * 32 {
+                random.next_dither();
+            }
+            for group in groups {
+                let entry = self.entry(row, group);
+                let mut pump = BitPumpLSB::at_bit(row_data, entry.bit_offset as u64);
+                decode_arw2_group(&mut pump, curve, &mut *random, &mut group_pixels);
+                let start = (group * 32).max(rect.x);
+                let end = (group * 32 + 32).min(right);
+                result.extend_from_slice(&group_pixels[start - group * 32..end - group * 32]);
+            }
+        }
+        result
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_u64::<LittleEndian>(self.offset)?;
+        out.write_u64::<LittleEndian>(self.len)?;
+        out.write_u64::<LittleEndian>(self.hash)?;
+        out.write_u32::<LittleEndian>(self.width as u32)?;
+        out.write_u32::<LittleEndian>(self.height as u32)?;
+        for entry in &self.entries {
+            out.write_u32::<LittleEndian>(entry.bit_offset)?;
+            out.write_u32::<LittleEndian>(entry.bits)?;
+            out.write_u32::<LittleEndian>(entry.random)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<BlockIndex> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an ARW2 block index",
+            ));
+        }
+        let offset = input.read_u64::<LittleEndian>()?;
+        let len = input.read_u64::<LittleEndian>()?;
+        let hash = input.read_u64::<LittleEndian>()?;
+        let width = input.read_u32::<LittleEndian>()? as usize;
+        let height = input.read_u32::<LittleEndian>()? as usize;
+        let groups_per_row = width.div_ceil(32);
+        let mut entries = vec![];
+        for _ in 0..groups_per_row * height {
+            entries.push(GroupEntry {
+                bit_offset: input.read_u32::<LittleEndian>()?,
+                bits: input.read_u32::<LittleEndian>()?,
+                random: input.read_u32::<LittleEndian>()?,
+            });
+        }
+        Ok(BlockIndex {
+            offset,
+            len,
+            hash,
+            width,
+            height,
+            groups_per_row,
+            entries,
+        })
+    }
+}
+
+/// The raw strip at the start of `buf`
+fn strip(buf: &[u8], width: usize, height: usize) -> &[u8] {
+    &buf[..buf.len().min(width * height)]
+}
+
+/// 64-bit FNV-1a, which unlike the hashers of the standard library stays the same from one
+/// build to the next
+fn strip_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -0,0 +1,278 @@
+//! Sony's lens corrections: the distortion, chromatic aberration and vignetting parameters the
+//! camera stores with the raw data for converters to apply. Each is a list of signed 16-bit
+//! values, the number of knots followed by the knots, spread evenly from the centre of the
+//! image (radius 0) to its corners (radius 1). They can be read, edited and written back in
+//! place, and `dng` translates them into opcodes.
+
+use std::fmt;
+
+use crate::tiff::{self, Ifd, Tiff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrectionKind {
+    Distortion,
+    ChromaticAberration,
+    Vignetting,
+}
+
+impl CorrectionKind {
+    pub const ALL: [CorrectionKind; 3] = [
+        CorrectionKind::Distortion,
+        CorrectionKind::ChromaticAberration,
+        CorrectionKind::Vignetting,
+    ];
+
+    pub fn parse(name: &str) -> Option<CorrectionKind> {
+        match name {
+            "distortion" => Some(CorrectionKind::Distortion),
+            "ca" => Some(CorrectionKind::ChromaticAberration),
+            "vignetting" => Some(CorrectionKind::Vignetting),
+            _ => None,
+        }
+    }
+
+    fn tag(self) -> u16 {
+        match self {
+            CorrectionKind::Distortion => tiff::SONY_DISTORTION_CORR_PARAMS,
+            CorrectionKind::ChromaticAberration => tiff::SONY_CA_CORR_PARAMS,
+            CorrectionKind::Vignetting => tiff::SONY_VIGNETTING_CORR_PARAMS,
+        }
+    }
+}
+
+impl fmt::Display for CorrectionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CorrectionKind::Distortion => "distortion",
+            CorrectionKind::ChromaticAberration => "chromatic aberration",
+            CorrectionKind::Vignetting => "vignetting",
+        })
+    }
+}
+
+/// One of the corrections as stored in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correction {
+    pub kind: CorrectionKind,
+    /// The knots, without the count in front of them. Chromatic aberration has those of red
+    /// followed by those of blue. Zero is no correction.
+    pub knots: Vec<i16>,
+    /// Position of the stored values in the file
+    value_pos: usize,
+    little_endian: bool,
+}
+
+impl Correction {
+    fn read(tiff: &Tiff, ifd: &Ifd, kind: CorrectionKind) -> Option<Correction> {
+        let entry = ifd.entry(kind.tag())?;
+        // SSHORT, though some bodies write SHORT
+        if entry.field_type != 3 && entry.field_type != 8 {
+            return None;
+        }
+        let values: Vec<_> = (0..entry.count as usize)
+            .map(|i| {
+                tiff.read_u16(entry.value_pos + 2 * i)
+                    .map(|value| value as i16)
+            })
+            .collect::<Option<_>>()?;
+        let (count, knots) = values.split_first()?;
+        let count = *count as usize;
+        let per_plane = match kind {
+            CorrectionKind::ChromaticAberration => count / 2,
+            _ => count,
+        };
+        if per_plane < 2 || count > knots.len() {
+            return None;
+        }
+        Some(Correction {
+            kind,
+            knots: knots[..count].to_vec(),
+            value_pos: entry.value_pos + 2,
+            little_endian: tiff.is_little_endian(),
+        })
+    }
+
+    /// Whether the correction changes anything
+    pub fn is_active(&self) -> bool {
+        self.knots.iter().any(|knot| *knot != 0)
+    }
+
+    /// Turns the correction off
+    pub fn neutralize(&mut self) {
+        for knot in &mut self.knots {
+            *knot = 0;
+        }
+    }
+
+    /// Scales the strength of the correction; 0 turns it off, 1 leaves it as it is
+    pub fn scale(&mut self, factor: f64) {
+        for knot in &mut self.knots {
+            let scaled = (*knot as f64 * factor).round();
+            *knot = scaled.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+        }
+    }
+
+    /// Radii of the knots
+    fn radii(n: usize) -> impl Iterator<Item = f64> {
+        (0..n).map(move |i| i as f64 / (n - 1) as f64)
+    }
+
+    /// The correction as curves over the radius, one per colour plane (red, green and blue for
+    /// chromatic aberration, a single one otherwise). For distortion and chromatic aberration
+    /// the curve is the factor the radius of a pixel of the corrected image is multiplied by
+    /// to find it in the uncorrected one; for vignetting it is the gain applied to the pixel.
+    pub fn curves(&self) -> Vec<Vec<(f64, f64)>> {
+        let knots = &self.knots;
+        match self.kind {
+            CorrectionKind::Distortion => vec![Correction::radii(knots.len())
+                .zip(knots)
+                .map(|(r, knot)| (r, 1.0 + *knot as f64 / 16384.0))
+                .collect()],
+            CorrectionKind::ChromaticAberration => {
+                let (red, blue) = knots.split_at(knots.len() / 2);
+                let plane = |knots: &[i16]| {
+                    Correction::radii(knots.len())
+                        .zip(knots)
+                        .map(|(r, knot)| (r, 1.0 + *knot as f64 / 2_097_152.0))
+                        .collect::<Vec<_>>()
+                };
+                let green = Correction::radii(red.len()).map(|r| (r, 1.0)).collect();
+                vec![plane(red), green, plane(blue)]
+            }
+            CorrectionKind::Vignetting => vec![Correction::radii(knots.len())
+                .zip(knots)
+                .map(|(r, knot)| {
+                    // the falloff of the lens is 2^(0.5 - 2^(knot / 8192 - 1))
+                    let falloff = 2f64.powf(0.5 - 2f64.powf(*knot as f64 / 8192.0 - 1.0));
+                    (r, 1.0 / falloff)
+                })
+                .collect()],
+        }
+    }
+}
+
+/// The lens corrections of a file; any of them may be missing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LensCorrections {
+    pub corrections: Vec<Correction>,
+}
+
+impl LensCorrections {
+    /// Reads the corrections from the IFD of the raw data, or IFD 0 of the file if they are
+    /// not there
+    pub fn read(tiff: &Tiff, raw_ifd: Option<&Ifd>) -> LensCorrections {
+        let ifd0 = tiff.ifd0();
+        let corrections = CorrectionKind::ALL
+            .iter()
+            .filter_map(|kind| {
+                raw_ifd
+                    .and_then(|ifd| Correction::read(tiff, ifd, *kind))
+                    .or_else(|| Correction::read(tiff, ifd0.as_ref()?, *kind))
+            })
+            .collect();
+        LensCorrections { corrections }
+    }
+
+    pub fn get(&self, kind: CorrectionKind) -> Option<&Correction> {
+        self.corrections
+            .iter()
+            .find(|correction| correction.kind == kind)
+    }
+
+    pub fn get_mut(&mut self, kind: CorrectionKind) -> Option<&mut Correction> {
+        self.corrections
+            .iter_mut()
+            .find(|correction| correction.kind == kind)
+    }
+
+    /// Turns off all corrections but those listed
+    pub fn keep_only(&mut self, kinds: &[CorrectionKind]) {
+        for correction in &mut self.corrections {
+            if !kinds.contains(&correction.kind) {
+                correction.neutralize();
+            }
+        }
+    }
+
+    /// Writes the knots back over the ones in the file they were read from
+    pub fn write_into(&self, file: &mut [u8]) {
+        for correction in &self.corrections {
+            for (i, knot) in correction.knots.iter().enumerate() {
+                let pos = correction.value_pos + 2 * i;
+                tiff::write_u16(file, pos, *knot as u16, correction.little_endian);
+            }
+        }
+    }
+}
+
+/// Value of a curve given as knots at `r`, linearly interpolated
+pub fn interpolate(curve: &[(f64, f64)], r: f64) -> f64 {
+    match curve.windows(2).find(|pair| r <= pair[1].0) {
+        Some(pair) if pair[1].0 > pair[0].0 => {
+            let ((r0, v0), (r1, v1)) = (pair[0], pair[1]);
+            v0 + (v1 - v0) * (r - r0) / (r1 - r0)
+        }
+        Some(pair) => pair[0].1,
+        None => curve.last().map_or(1.0, |knot| knot.1),
+    }
+}
+
+/// Least-squares fit of `1 + k[0] r^2 + k[1] r^4 + ...` (or, with `constant`, of
+/// `k[0] + k[1] r^2 + ...`) with `terms` coefficients to a curve given as knots, sampled
+/// between them by linear interpolation
+pub fn fit_even_polynomial(curve: &[(f64, f64)], terms: usize, constant: bool) -> Vec<f64> {
+    const SAMPLES: usize = 128;
+    let power = |r: f64, term: usize| {
+        let exponent = if constant { term } else { term + 1 };
+        (r * r).powi(exponent as i32)
+    };
+
+    // normal equations, solved by Gaussian elimination with partial pivoting
+    let mut matrix = vec![vec![0.0; terms + 1]; terms];
+    for sample in 0..=SAMPLES {
+        let r = sample as f64 / SAMPLES as f64;
+        let target = if constant {
+            interpolate(curve, r)
+        } else {
+            interpolate(curve, r) - 1.0
+        };
+        for (row, equation) in matrix.iter_mut().enumerate() {
+            let (coefficients, right) = equation.split_at_mut(terms);
+            for (column, coefficient) in coefficients.iter_mut().enumerate() {
+                *coefficient += power(r, row) * power(r, column);
+            }
+            right[0] += power(r, row) * target;
+        }
+    }
+    for column in 0..terms {
+        let pivot = (column..terms)
+            .max_by(|a, b| {
+                matrix[*a][column]
+                    .abs()
+                    .total_cmp(&matrix[*b][column].abs())
+            })
+            .unwrap();
+        matrix.swap(column, pivot);
+        if matrix[column][column].abs() < 1e-12 {
+            continue;
+        }
+        let pivot_row = matrix[column].clone();
+        for (row, equation) in matrix.iter_mut().enumerate() {
+            if row != column {
+                let factor = equation[column] / pivot_row[column];
+                for (value, pivot_value) in equation.iter_mut().zip(&pivot_row).skip(column) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+    }
+    (0..terms)
+        .map(|row| {
+            if matrix[row][row].abs() < 1e-12 {
+                0.0
+            } else {
+                matrix[row][terms] / matrix[row][row]
+            }
+        })
+        .collect()
+}
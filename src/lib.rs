@@ -0,0 +1,137 @@
+//! Decoding, editing and re-encoding of Sony raw files (and Canon's CR2 and Nikon's NEF)
+//! without leaving the raw domain.
+//!
+//! The stable part of the API is the codec and what sits directly on top of it:
+//!
+//! * `RawImage::decode`, `RawImage::encode`, `RawImage::encode_file` and `RawImage::save` for
+//!   whole ARW, CR2 and NEF files,
+//! * `output::OutputFile`, for writing files atomically without overwriting the originals,
+//! * `format::Format` for decoding and encoding raw data at a known location,
+//! * `rawloader` (the ARW2 codec itself, with its block structure in `rawloader::blocks`),
+//!   `dither` and `source`,
+//! * `progress`, for reporting on and cancelling decoding and encoding,
+//! * `test_vectors`, with the `test-vectors` feature, to check other implementations against.
+//!
+//! The `heif` feature adds `heif`, for stamping the HEIF files shot together with raw files,
+//! the `frame` feature adds `frame`, the format raw data is handed to other tools in, and the
+//! `ffi` feature adds `ffi`, a C interface to decoding, stamping and encoding files.
+//!
+//! The other modules make up the command line tool and may change between versions.
+//!
+//! ```no_run
+//! use raw_tiff_edit::RawImage;
+//!
+//! let original = std::fs::read("DSC00001.ARW")?;
+//! let mut image = RawImage::decode(&original)?;
+//! for pixel in &mut image.pixels {
+//!     *pixel = pixel.saturating_sub(16);
+//! }
+//! image.save(&original, "DSC00001-edited.ARW")?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+// `ffi` is the only module with `unsafe` code, and it only exists with the `ffi` feature
+#![cfg_attr(not(feature = "ffi"), forbid(unsafe_code))]
+#![cfg_attr(feature = "ffi", deny(unsafe_code))]
+
+use std::{fmt, io};
+
+pub mod burnin;
+pub mod chart;
+pub mod container;
+pub mod defects;
+pub mod dither;
+pub mod dng;
+pub mod edit;
+pub mod editor;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
+#[cfg(feature = "frame")]
+pub mod frame;
+#[cfg(feature = "heif")]
+pub mod heif;
+pub mod import;
+pub mod index;
+pub mod lens;
+pub mod metadata;
+pub mod ops;
+pub mod output;
+pub mod overlay;
+pub mod pipeline;
+pub mod preview;
+pub mod probe;
+pub mod progress;
+pub mod raw;
+pub mod rawloader;
+pub mod recipe;
+pub mod redact;
+pub mod report;
+pub mod sanity;
+pub mod sony_legacy;
+pub mod source;
+pub mod sr2;
+pub mod stats;
+pub mod survey;
+pub mod tags;
+pub mod template;
+#[cfg(feature = "test-vectors")]
+pub mod test_vectors;
+pub mod tiff;
+pub mod tiled;
+pub mod unstamp;
+pub mod validate;
+pub mod variant;
+pub mod watermark;
+
+pub use format::Format;
+pub use raw::RawImage;
+
+/// Errors of decoding, encoding and saving raw data
+#[derive(Debug)]
+pub enum RawEditError {
+    /// The file has no raw data the codec understands
+    Variant(variant::VariantError),
+    /// The raw data ends before all of the image has been read
+    Truncated(String),
+    /// The dimensions of an image don't fit its pixels or the raw data it is written into
+    Mismatch(String),
+    Output(output::OutputError),
+    Io(io::Error),
+    /// The operation was cancelled through its `progress::CancelToken`
+    Cancelled,
+}
+
+impl fmt::Display for RawEditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawEditError::Variant(err) => write!(f, "{}", err),
+            RawEditError::Truncated(what) => write!(f, "{} is truncated", what),
+            RawEditError::Mismatch(reason) => write!(f, "{}", reason),
+            RawEditError::Output(err) => write!(f, "{}", err),
+            RawEditError::Io(err) => write!(f, "{}", err),
+            RawEditError::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for RawEditError {}
+
+impl From<variant::VariantError> for RawEditError {
+    fn from(err: variant::VariantError) -> RawEditError {
+        RawEditError::Variant(err)
+    }
+}
+
+impl From<output::OutputError> for RawEditError {
+    fn from(err: output::OutputError) -> RawEditError {
+        RawEditError::Output(err)
+    }
+}
+
+impl From<io::Error> for RawEditError {
+    fn from(err: io::Error) -> RawEditError {
+        RawEditError::Io(err)
+    }
+}
@@ -1,68 +1,2175 @@
+//! Command line front-end of the raw editor
+
+#![forbid(unsafe_code)]
+
 use std::{
+    borrow::Cow,
     fs::File,
-    io::{Read, Write},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::Instant,
 };
 
-mod rawloader;
+mod batch;
+mod cli;
+mod failure;
 
-use image::{ImageBuffer, Luma, Pixel};
-use imageproc::drawing::draw_text_mut;
-use rawloader::*;
-use rusttype::{FontCollection, Scale};
+use batch::{Outcome, Verification};
+use cli::Frames;
+use failure::{Failed, Failure};
+use image::ImageError;
+use raw_tiff_edit::burnin::BurnIn;
+use raw_tiff_edit::edit::{FontChain, FontError, TextEdit};
+use raw_tiff_edit::format::Format;
+#[cfg(feature = "frame")]
+use raw_tiff_edit::frame;
+#[cfg(feature = "heif")]
+use raw_tiff_edit::heif;
+use raw_tiff_edit::metadata::{MetadataEdit, MetadataError};
+use raw_tiff_edit::overlay::ImageOverlay;
+use raw_tiff_edit::raw::{RawImage, Readout};
+use raw_tiff_edit::rawloader::cr2::{self, Cr2Layout};
+use raw_tiff_edit::rawloader::nef::{self, NefLayout};
+use raw_tiff_edit::rawloader::sony_lossless::{self, TileLayout};
+use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_roi, LookupTable};
+use raw_tiff_edit::redact::Redaction;
+use raw_tiff_edit::source::MemorySource;
+use raw_tiff_edit::RawEditError;
+use raw_tiff_edit::{
+    chart, container, defects, dither, dng, editor, export, import, index, lens, ops, output,
+    overlay, preview, probe, recipe, report, sanity, sony_legacy, stats, survey, tags, template,
+    tiff, tiled, validate, variant, watermark,
+};
 
-static FONT: &[u8] = include_bytes!("DejaVuSans.ttf");
+/// Sets the tags asked for in the file about to be written
+fn edit_metadata(buffer: &mut Vec<u8>, edit: &MetadataEdit, path: &str) -> Result<(), Failed> {
+    edit.apply(buffer).map_err(|err| {
+        let failure = match err {
+            MetadataError::Invalid | MetadataError::NoExifIfd => Failure::UnsupportedFormat,
+            MetadataError::NotAscii { .. } | MetadataError::NotANumber { .. } => Failure::Usage,
+        };
+        Failed::new(
+            failure,
+            format!("cannot edit the metadata of {}: {}", path, err),
+        )
+    })
+}
+
+/// Lowers the scheduling priority of the process, the way `nice` would have
+fn lower_priority() -> Result<(), String> {
+    let status = Command::new("renice")
+        .args(["-n", "10", "-p", &std::process::id().to_string()])
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|err| format!("cannot run renice: {}", err))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("renice failed with {}", status))
+    }
+}
 
-fn draw_text(img: &mut ImageBuffer<Luma<u16>, Vec<<Luma<u16> as Pixel>::Subpixel>>) {
-    let font = FontCollection::from_bytes(FONT)
-        .unwrap()
-        .into_font()
-        .unwrap();
-    let scale = Scale { x: 400.0, y: 400.0 };
-    draw_text_mut(
-        img,
-        Luma([17216]),
-        1000,
-        1800,
-        scale,
-        &font,
-        &format!("EDITED BY SIO"),
-    );
+/// Files edited at once by default; every one of them holds several copies of its raw data
+const MAX_DEFAULT_JOBS: usize = 4;
+
+/// Decodes the given rows of ARW2 data, taking the pixels of all others from `unchanged`
+fn read_back_rows(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    unchanged: &[u16],
+    rows: &[Range<usize>],
+    curve: &LookupTable,
+    dither: dither::Factory,
+) -> Result<Vec<u16>, RawEditError> {
+    let mut pixels = unchanged.to_vec();
+    for rows in rows {
+        let roi = tiled::Rect {
+            x: 0,
+            y: rows.start,
+            width,
+            height: rows.len(),
+        };
+        let decoded = decode_arw2_roi(data, width, height, roi, curve, dither)?;
+        pixels[rows.start * width..rows.end * width].copy_from_slice(&decoded);
+    }
+    Ok(pixels)
+}
+
+/// Reads the block index of the raw data from the cache file, building it if there is no
+/// usable one, or the one there was built from other raw data (and saving it for next time,
+/// if `save` is set). `data` starts with the raw data, at `offset` in the file.
+fn load_block_index(
+    path: &str,
+    data: &[u8],
+    offset: u64,
+    width: usize,
+    height: usize,
+    save: bool,
+    warnings: &mut Vec<String>,
+) -> index::BlockIndex {
+    let cached = File::open(path)
+        .and_then(|file| index::BlockIndex::read_from(&mut io::BufReader::new(file)))
+        .ok()
+        .filter(|index| index.matches(data, offset, width, height));
+    cached.unwrap_or_else(|| {
+        let index = index::BlockIndex::build(data, offset, width, height);
+        if !save {
+            return index;
+        }
+        let saved = File::create(path).and_then(|file| index.write_to(&mut BufWriter::new(file)));
+        if let Err(err) = saved {
+            let warning = format!("cannot save the block index to {}: {}", path, err);
+            eprintln!("{}", warning);
+            warnings.push(warning);
+        }
+        index
+    })
+}
+
+/// Everything the command line says about how to process a file
+#[derive(Clone)]
+struct Options {
+    validate_tolerance: Option<u16>,
+    dng_path: Option<String>,
+    dng_layout: dng::Layout,
+    embed_original: bool,
+    all_renditions: bool,
+    previews_only: bool,
+    upright_previews: bool,
+    regenerate_previews: bool,
+    heif: bool,
+    text: Option<String>,
+    locale: template::Locale,
+    bit_report: bool,
+    column_order: bool,
+    full_sensor: bool,
+    print_stats: bool,
+    stats_json: bool,
+    calibrate_black: bool,
+    stretch: Option<(f64, f64)>,
+    recipe: Option<recipe::Recipe>,
+    chart: Option<chart::Chart>,
+    probe: Option<(usize, usize, usize)>,
+    verify: bool,
+    strict: bool,
+    export_path: Option<String>,
+    export_preview_path: Option<String>,
+    export_only: bool,
+    import: Option<import::Plane>,
+    export_frame_path: Option<String>,
+    import_frame: Option<RawImage>,
+    lens_corrections: Option<Vec<lens::CorrectionKind>>,
+    remove_columns: bool,
+    fix_hot_pixels: Option<f64>,
+    defect_map: Option<defects::DefectMap>,
+    save_defect_map: Option<String>,
+    blur: Option<f64>,
+    sharpen: Option<(f64, f64)>,
+    redactions: Vec<Redaction>,
+    flip: (bool, bool),
+    crop_rect: Option<tiled::Rect>,
+    bias_path: Option<String>,
+    block_index_path: Option<String>,
+    ifd_index: Option<usize>,
+    frames: Option<Frames>,
+    print_info: bool,
+    /// Tags to print instead of editing the file
+    print_tags: Vec<&'static tags::TagInfo>,
+    in_place: bool,
+    export_scaling: export::Scaling,
+    container: container::Container,
+    strip_size: Option<usize>,
+    metadata_edit: MetadataEdit,
+    format_name: Option<String>,
+    dither: dither::Factory,
+    raw_geometry: Option<(usize, usize, usize)>,
+    stamp: TextEdit,
+    overlay: Option<ImageOverlay>,
+    burn_in: Option<overlay::Anchor>,
+    watermark: Option<String>,
+    extract_watermark: bool,
+    neutral: bool,
+    unstamp: bool,
+    dry_run: bool,
+    /// The first option given that only applies to the edited file, refused when a DNG is
+    /// written instead
+    edited_file_only: Option<String>,
+}
+
+impl Options {
+    /// Whether the raw data can be left out of the buffer the file is read into: decoded from
+    /// the input, and copied from it again (the rows left alone) as the output is written.
+    /// Every option is named here, so that a new one has to be placed: those ignored only
+    /// need the decoded image and the rest of the file, and the others stream only when left
+    /// as they are by default.
+    fn may_stream(&self) -> bool {
+        let Options {
+            validate_tolerance: _,
+            dng_path: _,
+            dng_layout: _,
+            all_renditions: _,
+            upright_previews: _,
+            regenerate_previews: _,
+            heif: _,
+            text: _,
+            locale: _,
+            column_order: _,
+            full_sensor: _,
+            print_stats: _,
+            stats_json: _,
+            calibrate_black: _,
+            stretch: _,
+            recipe: _,
+            chart: _,
+            probe: _,
+            strict: _,
+            export_path: _,
+            export_preview_path: _,
+            export_only: _,
+            import: _,
+            export_frame_path: _,
+            import_frame: _,
+            lens_corrections: _,
+            remove_columns: _,
+            fix_hot_pixels: _,
+            defect_map: _,
+            save_defect_map: _,
+            blur: _,
+            sharpen: _,
+            redactions: _,
+            flip: _,
+            crop_rect: _,
+            bias_path: _,
+            ifd_index: _,
+            frames: _,
+            in_place: _,
+            export_scaling: _,
+            metadata_edit: _,
+            dither: _,
+            raw_geometry: _,
+            stamp: _,
+            overlay: _,
+            burn_in: _,
+            watermark: _,
+            extract_watermark: _,
+            neutral: _,
+            unstamp: _,
+            edited_file_only: _,
+            // these read the raw data from the buffer, or write the buffer as it is
+            embed_original,
+            previews_only,
+            bit_report,
+            verify,
+            block_index_path,
+            print_info,
+            print_tags,
+            container,
+            strip_size,
+            // the key of SRF data is read before the raw data is
+            format_name,
+            dry_run,
+        } = self;
+        !embed_original
+            && !previews_only
+            && !bit_report
+            && !verify
+            && block_index_path.is_none()
+            && !print_info
+            && print_tags.is_empty()
+            && *container == container::Container::Original
+            && strip_size.is_none()
+            && format_name
+                .as_deref()
+                .is_none_or(|name| ["arw2", "uncompressed", "packed12"].contains(&name))
+            && !dry_run
+    }
 }
 
 fn main() {
-    let mut file = File::open("Y-DP-105mm-9480.ARW").unwrap();
-    let mut buffer = vec![];
-    file.read_to_end(&mut buffer).unwrap();
+    let args = cli::normalize(std::env::args().skip(1));
+    failure::use_json(cli::json_errors(&args));
+    let args = match cli::parse(args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", cli::usage());
+            return;
+        }
+        Err(failed) => failure::exit(failed.failure, failed.message),
+    };
+    let edited_file_only = args.first_in(cli::Group::EditedFile);
+    // only applies once the inputs are known to make a batch
+    let batch_check = args.check_batch();
+    let cli::Args {
+        validate_tolerance,
+        dng_path,
+        dng_layout,
+        embed_original,
+        all_renditions,
+        previews_only,
+        upright_previews,
+        regenerate_previews,
+        heif,
+        text,
+        locale,
+        bit_report,
+        column_order,
+        full_sensor,
+        print_stats,
+        stats_json,
+        calibrate_black,
+        stretch,
+        recipe_path,
+        chart_path,
+        probe_point,
+        probe_radius,
+        verify,
+        strict,
+        export_path,
+        export_preview_path,
+        export_frame_path,
+        import_frame_path,
+        export_only,
+        import_path,
+        lens_corrections,
+        remove_columns,
+        fix_hot_pixels,
+        defect_map_path,
+        save_defect_map,
+        blur,
+        sharpen,
+        redactions,
+        flip,
+        crop_rect,
+        bias_path,
+        block_index_path,
+        ifd_index,
+        frames,
+        print_info,
+        print_tags,
+        survey,
+        output_path,
+        in_place,
+        export_scaling,
+        container,
+        strip_size,
+        metadata_edit,
+        format_name,
+        dither,
+        raw_geometry,
+        inputs,
+        output_dir,
+        output_name,
+        jobs,
+        threads,
+        report_path,
+        mut stamp,
+        font_paths,
+        overlay_path,
+        opacity,
+        anchor,
+        tile,
+        burn_in,
+        watermark,
+        extract_watermark,
+        neutral,
+        unstamp,
+        dry_run,
+        nice,
+        ..
+    } = args;
+    // directories and patterns stand for the files in them, and make a batch even if there
+    // is only one
+    let mut files: Vec<String> = vec![];
+    let mut expanded = false;
+    for input in &inputs {
+        let found = batch::expand_input(input)
+            .unwrap_or_else(|failed| failure::exit(failed.failure, failed.message));
+        expanded |= found[..] != [input.clone()];
+        for file in found {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    let inputs = files;
+    let batch = inputs.len() > 1 || expanded;
+    if let (true, Err(failed)) = (batch, batch_check) {
+        failure::exit(failed.failure, failed.message);
+    }
+    let jobs = jobs.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(MAX_DEFAULT_JOBS)
+    });
+    // the codec decodes on the rayon pool it is called from, and encodes with as many threads
+    // as that pool has
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .build()
+        .unwrap_or_else(|err| {
+            failure::exit(Failure::Io, format!("cannot start the threads: {}", err))
+        });
+    if survey {
+        let files = batch::run(jobs, inputs.len(), |index| {
+            let path = &inputs[index];
+            match std::fs::read(path) {
+                Ok(buffer) => survey::FileSurvey::of(path, &buffer),
+                Err(err) => survey::FileSurvey {
+                    path: path.clone(),
+                    camera: None,
+                    dimensions: None,
+                    variant: None,
+                    problem: Some(format!("cannot read: {}", err)),
+                },
+            }
+        });
+        println!("{}", survey::Survey::new(files));
+        return;
+    }
+    stamp.fonts = FontChain::load(&font_paths).unwrap_or_else(|err| {
+        let failure = match err {
+            FontError::Io(..) => Failure::Io,
+            FontError::Invalid(_) => Failure::Parse,
+        };
+        failure::exit(failure, err)
+    });
+    let recipe = recipe_path.map(|path| {
+        let source = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            failure::exit(Failure::Io, format!("cannot read recipe {}: {}", path, err))
+        });
+        recipe::Recipe::parse(&source).unwrap_or_else(|err| failure::exit(Failure::Parse, err))
+    });
+    let import_frame = import_frame_path.map(|path| read_frame(&path));
+    let import = import_path.map(|path| {
+        let data = std::fs::read(&path).unwrap_or_else(|err| {
+            failure::exit(Failure::Io, format!("cannot read {}: {}", path, err))
+        });
+        import::read_tiff(&data)
+            .unwrap_or_else(|err| failure::exit(Failure::Parse, format!("{}: {}", path, err)))
+    });
+    let defect_map = defect_map_path.map(|path| {
+        let source = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            failure::exit(
+                Failure::Io,
+                format!("cannot read defect map {}: {}", path, err),
+            )
+        });
+        defects::DefectMap::parse(&source).unwrap_or_else(|err| failure::exit(Failure::Parse, err))
+    });
+    let overlay = overlay_path.map(|path| {
+        let mut overlay = ImageOverlay::open(&path).unwrap_or_else(|err| {
+            let failure = match err.err {
+                ImageError::IoError(_) => Failure::Io,
+                _ => Failure::Parse,
+            };
+            failure::exit(failure, err)
+        });
+        overlay.opacity = opacity.unwrap_or(1.0);
+        if tile {
+            overlay.placement = overlay::Placement::Tiled;
+        } else if let Some(anchor) = anchor {
+            overlay.placement = overlay::Placement::Anchored(anchor);
+        }
+        overlay
+    });
+    let probe = probe_point.map(|(x, y)| (x, y, probe_radius.unwrap_or(0)));
+    let chart = chart_path.map(|path| {
+        let source = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+            failure::exit(Failure::Io, format!("cannot read chart {}: {}", path, err))
+        });
+        chart::Chart::parse(&source).unwrap_or_else(|err| failure::exit(Failure::Parse, err))
+    });
+
+    let options = Options {
+        validate_tolerance,
+        dng_path,
+        dng_layout,
+        embed_original,
+        all_renditions,
+        previews_only,
+        upright_previews,
+        regenerate_previews,
+        heif,
+        text,
+        locale: locale.unwrap_or_else(template::Locale::from_env),
+        bit_report,
+        column_order,
+        full_sensor,
+        print_stats,
+        stats_json,
+        calibrate_black,
+        stretch,
+        recipe,
+        chart,
+        probe,
+        verify,
+        strict,
+        export_path,
+        export_preview_path,
+        export_only,
+        import,
+        export_frame_path,
+        import_frame,
+        lens_corrections,
+        remove_columns,
+        fix_hot_pixels,
+        defect_map,
+        save_defect_map,
+        blur,
+        sharpen,
+        redactions,
+        flip,
+        crop_rect,
+        bias_path,
+        block_index_path,
+        ifd_index,
+        frames,
+        print_info,
+        print_tags,
+        in_place,
+        export_scaling: export_scaling.unwrap_or(export::Scaling::Native),
+        container: container.unwrap_or(container::Container::Original),
+        strip_size,
+        metadata_edit,
+        format_name,
+        dither: dither.unwrap_or(dither::camera),
+        raw_geometry,
+        stamp,
+        overlay,
+        burn_in,
+        watermark,
+        extract_watermark,
+        neutral,
+        unstamp,
+        dry_run,
+        edited_file_only,
+    };
+
+    if nice {
+        if let Err(err) = lower_priority() {
+            eprintln!("cannot lower the priority: {}", err);
+        }
+    }
+    let outputs: Vec<_> = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            if in_place {
+                input.clone()
+            } else if let Some(dir) = &output_dir {
+                let name = match &output_name {
+                    Some(template) => batch::output_name(template, input, index, inputs.len()),
+                    None => {
+                        let name = Path::new(input).file_name().unwrap_or_default();
+                        name.to_string_lossy().into_owned()
+                    }
+                };
+                Path::new(dir).join(name).to_string_lossy().into_owned()
+            } else {
+                output_path
+                    .clone()
+                    .unwrap_or_else(|| "edited.arw".to_owned())
+            }
+        })
+        .collect();
+    if output_dir.is_some() {
+        for (i, output) in outputs.iter().enumerate() {
+            if let Some(other) = outputs[..i].iter().position(|other| other == output) {
+                failure::exit(
+                    Failure::Usage,
+                    format!(
+                        "{} and {} would both be written to {}",
+                        inputs[other], inputs[i], output
+                    ),
+                );
+            }
+        }
+    }
+    let entries = batch::run(jobs, inputs.len(), |index| {
+        let input = &inputs[index];
+        let started = Instant::now();
+        let result = pool.install(|| process_frames(&options, input, &outputs[index], &inputs));
+        if let (true, Err(failed)) = (batch, &result) {
+            failure::print(failed.failure, format!("{}: {}", input, failed.message));
+        }
+        batch::Entry {
+            input: input.clone(),
+            result,
+            elapsed: started.elapsed(),
+        }
+    });
+
+    if let Some(report_path) = report_path {
+        let mut report = output::OutputFile::new(&report_path).replace_existing(true);
+        for input in &inputs {
+            report = report.protect(input);
+        }
+        let result = report.write_with(|out| {
+            if batch::is_csv_name(&report_path) {
+                batch::write_csv(out, &entries)
+            } else {
+                batch::write_json(out, &entries)
+            }
+        });
+        if let Err(err) = result {
+            failure::exit(
+                Failure::Io,
+                format!("cannot write the report {}: {}", report_path, err),
+            );
+        }
+    }
+
+    let failed: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| entry.result.as_ref().err())
+        .collect();
+    match failed[..] {
+        [] => {}
+        [failed] if !batch => failure::exit(failed.failure, &failed.message),
+        _ => failure::exit(
+            Failure::PartialBatch,
+            format!("{} of {} files failed", failed.len(), entries.len()),
+        ),
+    }
+}
+
+/// The stamp given on the command line, with its text rendered for the file
+fn default_stamp(
+    stamp: &TextEdit,
+    text: Option<&str>,
+    metadata: &template::Metadata,
+    locale: template::Locale,
+) -> TextEdit {
+    let mut stamp = stamp.clone();
+    if let Some(text) = text {
+        stamp.text = template::render(text, metadata, locale);
+    }
+    stamp
+}
+
+const NO_HEIF: &str = "--heif needs a build with the heif feature";
+
+const NO_FRAME: &str = "--export-frame and --import-frame need a build with the frame feature";
+
+/// Reads the raw frame given to --import-frame, as an image in display orientation
+#[cfg(feature = "frame")]
+fn read_frame(path: &str) -> RawImage {
+    let data = std::fs::read(path)
+        .unwrap_or_else(|err| failure::exit(Failure::Io, format!("cannot read {}: {}", path, err)));
+    frame::RawFrame::decode(&data, frame::Encoding::from_name(path))
+        .and_then(|frame| frame.to_image())
+        .unwrap_or_else(|err| failure::exit(Failure::Parse, format!("{}: {}", path, err)))
+}
+
+#[cfg(not(feature = "frame"))]
+fn read_frame(_path: &str) -> RawImage {
+    failure::exit(Failure::Usage, NO_FRAME)
+}
+
+/// Reads the bias frame given to --bias-frame, with the layout and format of its own raw
+/// data, in display orientation
+fn read_bias_frame(
+    path: &str,
+    dither: dither::Factory,
+    column_order: bool,
+) -> Result<RawImage, Failed> {
+    let data = std::fs::read(path).map_err(|err| {
+        Failed::new(
+            Failure::Io,
+            format!("cannot read bias frame {}: {}", path, err),
+        )
+    })?;
+    let unsupported = |err: variant::VariantError| {
+        Failed::new(
+            Failure::UnsupportedFormat,
+            format!("bias frame {}: {}", path, err),
+        )
+    };
+    let info = variant::detect(&data, None)
+        .map_err(unsupported)?
+        .ok_or_else(|| {
+            Failed::new(
+                Failure::UnsupportedFormat,
+                format!("cannot find the raw data in bias frame {}", path),
+            )
+        })?;
+    let format = Format::detect(&info, dither).map_err(unsupported)?;
+    format.check(&info).map_err(unsupported)?;
+    let readout = if column_order {
+        Readout::Columns
+    } else {
+        info.readout
+    };
+    let bias = format
+        .decode_from(
+            &mut MemorySource::new(&data),
+            info.offset as u64,
+            info.width,
+            info.height,
+        )
+        .map_err(|err| {
+            Failed::new(
+                Failure::of(&err),
+                format!("cannot read bias frame {}: {}", path, err),
+            )
+        })?;
+    Ok(bias.reoriented(readout))
+}
+
+/// Bytes read from the start and from the end of a file to find its raw data in before
+/// reading the rest
+const HEAD_BYTES: usize = 1 << 20;
+
+/// The raw data the options pick, of the frame `--frames` asks for or the IFD `--ifd` does
+fn find_raw(
+    buffer: &[u8],
+    frames: Option<Frames>,
+    ifd_index: Option<usize>,
+) -> Option<variant::RawInfo> {
+    match frames {
+        Some(Frames::Only(frame)) => variant::frames(buffer).get(frame - 1)?.raw,
+        _ => variant::detect(buffer, ifd_index).ok()?,
+    }
+}
+
+/// Reads a file with the strip of the raw data `find` picks left out, as zeros, returning the
+/// range left out. The strip is looked for in the first and the last `HEAD_BYTES`, where the
+/// IFDs are, and the whole file has to agree with those on where it is; otherwise nothing is
+/// left out.
+fn read_without_raw<F>(file: &mut File, find: F) -> io::Result<(Vec<u8>, Option<Range<usize>>)>
+where
+    F: Fn(&[u8]) -> Option<variant::RawInfo>,
+{
+    let len = file.metadata()?.len() as usize;
+    // untouched pages of a large zeroed allocation take no memory
+    let mut buffer = vec![0; len];
+    if len <= 2 * HEAD_BYTES {
+        file.read_exact(&mut buffer)?;
+        return Ok((buffer, None));
+    }
+    let (head, tail) = (0..HEAD_BYTES, len - HEAD_BYTES..len);
+    let middle = head.end..tail.start;
+    read_range(file, &mut buffer, head.clone())?;
+    read_range(file, &mut buffer, tail.clone())?;
+    let strip = |buffer: &[u8]| {
+        find(buffer).map(|info| info.offset..info.offset.saturating_add(info.byte_count))
+    };
+    let range = match strip(&buffer) {
+        Some(range) if range.start < range.end && range.end <= len => range,
+        _ => {
+            read_range(file, &mut buffer, middle)?;
+            return Ok((buffer, None));
+        }
+    };
+    // what the head and the tail took in of the strip is dropped again
+    for part in [head, tail] {
+        buffer[overlap(&part, &range)].fill(0);
+    }
+    let (before, after) = (range.start.clamp(middle.start, middle.end), range.end);
+    read_range(file, &mut buffer, middle.start..before)?;
+    read_range(
+        file,
+        &mut buffer,
+        after.clamp(middle.start, middle.end)..middle.end,
+    )?;
+    if strip(&buffer) != Some(range.clone()) {
+        read_range(file, &mut buffer, range)?;
+        return Ok((buffer, None));
+    }
+    Ok((buffer, Some(range)))
+}
+
+/// The bytes two ranges share, empty if none
+fn overlap(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
+    let start = a.start.max(b.start);
+    start..a.end.min(b.end).max(start)
+}
+
+/// Reads `range` of a file into the same range of `buffer`
+fn read_range(file: &mut File, buffer: &mut [u8], range: Range<usize>) -> io::Result<()> {
+    file.seek(SeekFrom::Start(range.start as u64))?;
+    file.read_exact(&mut buffer[range])
+}
+
+/// The file with the bytes of `range` left out, as zeros. A large zeroed allocation is made
+/// of untouched pages, which take no memory until they are written to.
+fn without_range(buffer: Vec<u8>, range: Range<usize>) -> Vec<u8> {
+    let mut hollow = vec![0; buffer.len()];
+    hollow[..range.start].copy_from_slice(&buffer[..range.start]);
+    hollow[range.end..].copy_from_slice(&buffer[range.end..]);
+    hollow
+}
+
+/// The image as a raw frame for --export-frame, in the encoding `frame_path` asks for
+#[cfg(feature = "frame")]
+fn frame_data(
+    image: &RawImage,
+    frame_path: &str,
+    input_path: &str,
+    format: &Format,
+    buffer: &[u8],
+) -> Vec<u8> {
+    let metadata = template::Metadata::from_file(buffer);
+    let format = match format {
+        Format::Arw2 { .. } => "arw2",
+        Format::Sr2 => "sr2",
+        Format::Srf { .. } => "srf",
+        Format::Uncompressed => "uncompressed",
+        Format::Packed12 => "packed12",
+        Format::SonyLossless { .. } => "lossless",
+        Format::Cr2 { .. } => "cr2",
+        Format::Nef { .. } => "nef",
+    };
+    let provenance = frame::Provenance {
+        source: Some(input_path.to_owned()),
+        format: Some(format.to_owned()),
+        make: metadata.get("Make").map(str::to_owned),
+        model: metadata.get("Model").map(str::to_owned),
+        ..frame::Provenance::new()
+    };
+    frame::RawFrame::from_image(image, provenance).encode(frame::Encoding::from_name(frame_path))
+}
+
+#[cfg(not(feature = "frame"))]
+fn frame_data(_: &RawImage, _: &str, _: &str, _: &Format, _: &[u8]) -> Vec<u8> {
+    failure::exit(Failure::Usage, NO_FRAME)
+}
+
+/// Draws the stamps into the HEIF file shot together with the input, placed by the visible
+/// area `crop` of the raw data. Returns the companion, where to write it (next to the output,
+/// under the same name) and its new contents, or `None` if the input has no companion.
+#[cfg(feature = "heif")]
+fn redraw_companion(
+    input_path: &str,
+    output_path: &str,
+    stamps: &[TextEdit],
+    crop: tiled::Rect,
+    white_level: u16,
+) -> Result<Option<(PathBuf, PathBuf, Vec<u8>)>, Failed> {
+    let companion = match heif::companion(input_path) {
+        Some(companion) => companion,
+        None => return Ok(None),
+    };
+    let path = Path::new(output_path).with_extension(companion.extension().unwrap_or_default());
+    let data = heif::redraw(&companion, heif::DEFAULT_QUALITY, |img| {
+        for stamp in stamps {
+            stamp.draw_rendition(img, crop.width, crop.height, white_level);
+        }
+    })
+    .map_err(|err| {
+        let failure = match err {
+            heif::HeifError::Image(_) => Failure::Parse,
+            _ => Failure::Io,
+        };
+        Failed::new(
+            failure,
+            format!("cannot redraw {}: {}", companion.display(), err),
+        )
+    })?;
+    Ok(Some((companion, path, data)))
+}
+
+#[cfg(not(feature = "heif"))]
+fn redraw_companion(
+    _input_path: &str,
+    _output_path: &str,
+    _stamps: &[TextEdit],
+    _crop: tiled::Rect,
+    _white_level: u16,
+) -> Result<Option<(PathBuf, PathBuf, Vec<u8>)>, Failed> {
+    Err(Failed::new(Failure::Usage, NO_HEIF))
+}
+
+/// Warns about the characters of the stamps that no font can draw
+fn check_glyphs(stamps: &[TextEdit], outcome: &mut Outcome) {
+    for stamp in stamps {
+        let missing = stamp.fonts.missing(&stamp.text);
+        if !missing.is_empty() {
+            let characters: Vec<_> = missing
+                .iter()
+                .map(|c| format!("{} (U+{:04X})", c, *c as u32))
+                .collect();
+            let warning = format!(
+                "no font has glyphs for {}; they are left out of \"{}\"",
+                characters.join(", "),
+                stamp.text
+            );
+            eprintln!("{}", warning);
+            outcome.warnings.push(warning);
+        }
+    }
+}
+
+/// A file to write, checked against all the inputs
+fn output_file(path: &str, inputs: &[String]) -> output::OutputFile {
+    inputs
+        .iter()
+        .fold(output::OutputFile::new(path), |file, input| {
+            file.protect(input)
+        })
+        .replace_existing(true)
+}
+
+fn written_or_failed(result: Result<(), output::OutputError>, path: &str) -> Result<(), Failed> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(err @ output::OutputError::WouldOverwriteOriginal(_)) => Err(Failed::new(
+            Failure::Refused,
+            format!(
+                "cannot write {}: {} (use --in-place --yes-i-know)",
+                path, err
+            ),
+        )),
+        Err(err) => Err(Failed::new(
+            Failure::Io,
+            format!("cannot write {}: {}", path, err),
+        )),
+    }
+}
+
+/// The file the frames of `--frames all` are edited in, one after the other, to be written
+/// once all of them are
+struct Staged {
+    file: Vec<u8>,
+    /// Whether a frame has been edited into `file`, which is then written
+    edited: bool,
+}
+
+/// Processes every frame of a file with several raw images for `--frames all`: each frame is
+/// edited in turn in the file in memory, and the file is written once all of them are, so
+/// that a failure in any of them leaves nothing written. Everything else in the file, the
+/// metadata that lines the frames up included, is written back as it was.
+fn process_frames(
+    options: &Options,
+    input_path: &str,
+    output_path: &str,
+    inputs: &[String],
+) -> Result<Outcome, Failed> {
+    if options.frames != Some(Frames::All) {
+        return process(options, input_path, output_path, inputs, None);
+    }
+    let file = match std::fs::read(input_path) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(Failed::new(
+                Failure::Io,
+                format!("cannot read {}: {}", input_path, err),
+            ));
+        }
+    };
+    let frames = variant::frames(&file);
+    if frames.len() < 2 {
+        return process(options, input_path, output_path, inputs, None);
+    }
+    let mut staged = Staged {
+        file,
+        edited: false,
+    };
+    let mut outcome = Outcome::default();
+    for (i, frame) in frames.iter().enumerate() {
+        let frame_options = Options {
+            ifd_index: Some(frame.index),
+            // the companion is stamped once, not once per frame
+            heif: options.heif && i == 0,
+            ..options.clone()
+        };
+        let frame_outcome = process(
+            &frame_options,
+            input_path,
+            output_path,
+            inputs,
+            Some(&mut staged),
+        )
+        .map_err(|failed| Failed {
+            message: format!("frame {}: {}", i + 1, failed.message),
+            ..failed
+        })?;
+        outcome.raw_bytes_changed += frame_outcome.raw_bytes_changed;
+        outcome.verification = frame_outcome.verification;
+        outcome.warnings.extend(
+            frame_outcome
+                .warnings
+                .into_iter()
+                .map(|warning| format!("frame {}: {}", i + 1, warning)),
+        );
+    }
+    if !staged.edited {
+        return Ok(outcome);
+    }
+    if options.dry_run {
+        println!("dry run: not writing {}", output_path);
+    } else {
+        let edited = output_file(output_path, inputs).allow_overwrite_original(options.in_place);
+        written_or_failed(
+            edited.write_with(|out| out.write_all(&staged.file)),
+            output_path,
+        )?;
+    }
+    Ok(outcome)
+}
+
+/// Edits a single file. Nothing is written if it fails, except for outputs written before the
+/// failure (the export and the DNG).
+///
+/// With `staged`, the file is edited in there rather than read from `input_path`, and left
+/// there rather than written to `output_path`.
+fn process(
+    options: &Options,
+    input_path: &str,
+    output_path: &str,
+    inputs: &[String],
+    staged: Option<&mut Staged>,
+) -> Result<Outcome, Failed> {
+    let Options {
+        validate_tolerance,
+        ref dng_path,
+        dng_layout,
+        embed_original,
+        all_renditions,
+        previews_only,
+        upright_previews,
+        regenerate_previews,
+        heif,
+        ref text,
+        locale,
+        bit_report,
+        column_order,
+        full_sensor,
+        print_stats,
+        stats_json,
+        calibrate_black,
+        stretch,
+        ref recipe,
+        ref chart,
+        probe,
+        verify,
+        strict,
+        ref export_path,
+        ref export_preview_path,
+        export_only,
+        ref import,
+        ref export_frame_path,
+        ref import_frame,
+        ref lens_corrections,
+        remove_columns,
+        fix_hot_pixels,
+        ref defect_map,
+        ref save_defect_map,
+        blur,
+        sharpen,
+        ref redactions,
+        flip,
+        crop_rect,
+        ref bias_path,
+        ref block_index_path,
+        ifd_index,
+        frames,
+        print_info,
+        ref print_tags,
+        in_place,
+        export_scaling,
+        container,
+        strip_size,
+        ref metadata_edit,
+        ref format_name,
+        dither,
+        raw_geometry,
+        ref stamp,
+        ref overlay,
+        burn_in,
+        ref watermark,
+        extract_watermark,
+        neutral,
+        unstamp,
+        dry_run,
+        ref edited_file_only,
+    } = *options;
+    let mut outcome = Outcome::default();
+    let output = |path: &str| output_file(path, inputs);
+    let skipped = |path: &str| {
+        if dry_run {
+            println!("dry run: not writing {}", path);
+        }
+        dry_run
+    };
+
+    // a DNG is written instead of the edited file, so what only applies to that is refused
+    let dng_output = dng::is_dng_name(output_path);
+    if let (true, Some(option)) = (dng_output, edited_file_only) {
+        return Err(Failed::new(
+            Failure::Usage,
+            format!(
+                "{} cannot be used when writing a DNG ({})",
+                option, output_path
+            ),
+        ));
+    }
 
-    let width: usize = 6048;
-    let height: usize = 4024;
-    let start = 839680;
+    // a staged file is edited where it is
+    let may_stream = options.may_stream() && staged.is_none();
+    let read = File::open(input_path).and_then(|mut file| {
+        let (buffer, left_out) = if let Some(staged) = &staged {
+            (staged.file.clone(), None)
+        } else if may_stream && raw_geometry.is_none() {
+            read_without_raw(&mut file, |buffer| find_raw(buffer, frames, ifd_index))?
+        } else {
+            let mut buffer = vec![];
+            file.read_to_end(&mut buffer)?;
+            (buffer, None)
+        };
+        Ok((file, buffer, left_out))
+    });
+    // the file is kept open to read the raw data from when it isn't kept in memory
+    let (mut input_file, mut buffer, left_out) = match read {
+        Ok(read) => read,
+        Err(err) => {
+            return Err(Failed::new(
+                Failure::Io,
+                format!("cannot read {}: {}", input_path, err),
+            ));
+        }
+    };
+
+    if print_info {
+        for candidate in variant::candidates(&buffer) {
+            println!("{}", candidate);
+        }
+        return Ok(outcome);
+    }
+    if !print_tags.is_empty() {
+        let prefix = if inputs.len() > 1 {
+            format!("{}: ", input_path)
+        } else {
+            String::new()
+        };
+        for info in print_tags.iter() {
+            let value = tags::read(&buffer, info);
+            println!("{}{}: {}", prefix, info, value.as_deref().unwrap_or("-"));
+        }
+        return Ok(outcome);
+    }
+
+    let ifd_index = match frames {
+        Some(Frames::Only(frame)) => match variant::frames(&buffer).get(frame - 1) {
+            Some(ifd) => Some(ifd.index),
+            None => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("{} has no frame {}", input_path, frame),
+                ));
+            }
+        },
+        _ => ifd_index,
+    };
+    let raw_info = variant::detect(&buffer, ifd_index)
+        .map_err(|err| Failed::new(Failure::UnsupportedFormat, err))?;
+    // ARW2 data goes through the tone curve of the file where it has one
+    let curve = raw_info
+        .and_then(|info| info.tone_curve)
+        .unwrap_or_default();
+    let format = match format_name.as_deref() {
+        Some("arw2") => Format::Arw2 { dither, curve },
+        Some("sr2") => Format::Sr2,
+        Some("srf") => match sony_legacy::srf_key(&buffer) {
+            Some(key) => Format::Srf { key },
+            None => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("{} is too short to be an SRF file", input_path),
+                ))
+            }
+        },
+        Some("uncompressed") => Format::Uncompressed,
+        Some("packed12") => Format::Packed12,
+        Some("lossless") => match raw_info {
+            Some(info) => Format::SonyLossless {
+                ifd_offset: info.ifd_offset,
+            },
+            None => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("cannot find the raw IFD of {}", input_path),
+                ))
+            }
+        },
+        Some("cr2") => match raw_info {
+            Some(info) => Format::Cr2 {
+                ifd_offset: info.ifd_offset,
+            },
+            None => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("cannot find the raw IFD of {}", input_path),
+                ))
+            }
+        },
+        Some("nef") => match raw_info {
+            Some(info) => Format::Nef {
+                ifd_offset: info.ifd_offset,
+                dither,
+            },
+            None => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("cannot find the raw IFD of {}", input_path),
+                ))
+            }
+        },
+        Some(name) => {
+            return Err(Failed::new(
+                Failure::Usage,
+                format!("unknown format: {}", name),
+            ))
+        }
+        // what the file says, unless the layout of the data is given by hand
+        None => match (raw_geometry, raw_info) {
+            (None, Some(info)) => Format::detect(&info, dither)
+                .map_err(|err| Failed::new(Failure::UnsupportedFormat, err))?,
+            _ => Format::Arw2 { dither, curve },
+        },
+    };
+    if container == container::Container::Minimal && !matches!(format, Format::Arw2 { .. }) {
+        return Err(Failed::new(
+            Failure::Usage,
+            "the minimal container is only supported for ARW2 data",
+        ));
+    }
+    let (width, height, start) = match (raw_geometry, raw_info) {
+        (Some(geometry), _) => geometry,
+        (None, Some(info)) => {
+            if let Err(err) = format.check(&info) {
+                return Err(Failed::new(Failure::UnsupportedFormat, err));
+            }
+            (info.width, info.height, info.offset)
+        }
+        (None, None) => {
+            return Err(Failed::new(Failure::UnsupportedFormat,
+                format!(
+                    "cannot find the raw data in {}; give its layout with --raw-geometry=WIDTHxHEIGHT@OFFSET",
+                    input_path
+                )));
+        }
+    };
+    if start + format.data_len(width, height) > buffer.len() {
+        return Err(Failed::new(
+            Failure::Parse,
+            format!(
+                "the {}x{} raw data at offset {} runs past the end of {}",
+                width, height, start, input_path
+            ),
+        ));
+    }
+    let raw_range = start..start + format.data_len(width, height);
+    let raw_streamed = may_stream
+        && matches!(
+            format,
+            Format::Arw2 { .. } | Format::Uncompressed | Format::Packed12
+        );
+    // what was left out of the buffer is read after all unless it is exactly the raw data
+    // that is streamed
+    let filled = match &left_out {
+        Some(left_out) if raw_streamed && *left_out == raw_range => Ok(()),
+        Some(left_out) => read_range(&mut input_file, &mut buffer, left_out.clone()),
+        None => Ok(()),
+    };
+    if let Err(err) = filled {
+        return Err(Failed::new(
+            Failure::Io,
+            format!("cannot read {}: {}", input_path, err),
+        ));
+    }
+    if raw_streamed && left_out.as_ref() != Some(&raw_range) {
+        buffer = without_range(buffer, raw_range.clone());
+    }
+
+    let readout = if column_order {
+        Readout::Columns
+    } else {
+        raw_info.map_or(Readout::Rows, |info| info.readout)
+    };
+    let calibration = tiff::Tiff::new(&buffer).and_then(|tiff| {
+        let (ifd, _) = tiff.read_ifd(raw_info?.ifd_offset)?;
+        Some((tiff, ifd))
+    });
+    let mut lens = calibration
+        .as_ref()
+        .map(|(tiff, ifd)| lens::LensCorrections::read(tiff, Some(ifd)))
+        .unwrap_or_default();
+    if let Some(kinds) = lens_corrections {
+        lens.keep_only(kinds);
+    }
+
+    // only the geometry of the raw data is needed to place the stamps in the renditions, so
+    // the data is never decoded
+    if previews_only {
+        let mut image = format.blank(width, height);
+        if let Some((tiff, ifd)) = &calibration {
+            image.read_calibration(tiff, ifd);
+        }
+        if full_sensor {
+            image.crop = image.full_rect();
+        }
+        let crop = match readout {
+            Readout::Rows => image.crop,
+            Readout::Columns => image.crop.transposed(),
+        };
+        let metadata = template::Metadata::from_file(&buffer);
+        let stamps = match recipe {
+            Some(recipe) => {
+                let skipped_steps = recipe.pixel_steps(&metadata);
+                if skipped_steps > 0 {
+                    let warning = format!(
+                        "{} recipe steps edit the raw data and are left out with --previews-only",
+                        skipped_steps
+                    );
+                    eprintln!("{}", warning);
+                    outcome.warnings.push(warning);
+                }
+                recipe
+                    .stamps(&metadata, locale, &stamp.fonts)
+                    .map_err(|err| Failed::new(Failure::Parse, err))?
+            }
+            None => vec![default_stamp(stamp, text.as_deref(), &metadata, locale)],
+        };
+        check_glyphs(&stamps, &mut outcome);
+        let renditions = preview::find_renditions(&buffer);
+        if renditions.is_empty() {
+            let warning = format!("no embedded previews in {}", input_path);
+            eprintln!("{}", warning);
+            outcome.warnings.push(warning);
+        }
+        for rendition in renditions {
+            preview::redraw(
+                &mut buffer,
+                &rendition,
+                &stamps,
+                crop.width,
+                crop.height,
+                image.white_level,
+                upright_previews,
+            )
+            .map_err(|err| Failed::new(Failure::Parse, err))?;
+        }
+        if lens_corrections.is_some() {
+            lens.write_into(&mut buffer);
+        }
+        edit_metadata(&mut buffer, metadata_edit, input_path)?;
+        let edited = output(output_path).allow_overwrite_original(in_place);
+        if let Some(staged) = staged {
+            staged.file = buffer;
+            staged.edited = true;
+        } else if !skipped(output_path) {
+            written_or_failed(edited.write_with(|out| out.write_all(&buffer)), output_path)?;
+        }
+        if heif {
+            let white_level = image.white_level;
+            if let Some((companion, path, data)) =
+                redraw_companion(input_path, output_path, &stamps, crop, white_level)?
+            {
+                let path = path.to_string_lossy();
+                let edited = output(&path)
+                    .protect(companion)
+                    .allow_overwrite_original(in_place);
+                if !skipped(&path) {
+                    written_or_failed(edited.write_with(|out| out.write_all(&data)), &path)?;
+                }
+            }
+        }
+        return Ok(outcome);
+    }
+
+    let decoded = if raw_streamed {
+        format.decode_from(&mut input_file, start as u64, width, height)
+    } else {
+        format.decode_from(&mut MemorySource::new(&buffer), start as u64, width, height)
+    };
+    let mut decoded = decoded.map_err(|err| {
+        Failed::new(
+            Failure::of(&err),
+            format!("cannot decode {}: {}", input_path, err),
+        )
+    })?;
+    if let Some((tiff, ifd)) = &calibration {
+        decoded.read_calibration(tiff, ifd);
+    }
+    // a model the decoder doesn't support tends to decode into garbage without an error
+    let mut suspicions = sanity::check_image(&decoded);
+    if let Format::Arw2 { .. } = format {
+        let checked = if raw_streamed {
+            sanity::check_arw2_from(&mut input_file, start as u64, width, height)
+        } else {
+            Ok(sanity::check_arw2(&buffer[start..], width, height))
+        };
+        match checked {
+            Ok(found) => suspicions.extend(found),
+            Err(err) => {
+                return Err(Failed::new(
+                    Failure::Io,
+                    format!("cannot read {}: {}", input_path, err),
+                ));
+            }
+        }
+    }
+    if strict && !suspicions.is_empty() {
+        let reasons: Vec<_> = suspicions.iter().map(|s| s.to_string()).collect();
+        return Err(Failed::new(
+            Failure::UnsupportedFormat,
+            format!(
+                "the raw data of {} looks misdecoded: {}",
+                input_path,
+                reasons.join("; ")
+            ),
+        ));
+    }
+    for suspicion in suspicions {
+        let warning = format!("suspicious raw data in {}: {}", input_path, suspicion);
+        eprintln!("{}", warning);
+        outcome.warnings.push(warning);
+    }
+    if verify {
+        let round_trip =
+            validate::round_trip(&buffer, format, start, &decoded.pixels, width, height).map_err(
+                |err| {
+                    Failed::new(
+                        Failure::of(&err),
+                        format!("cannot re-encode {}: {}", input_path, err),
+                    )
+                },
+            )?;
+        println!("{}", round_trip);
+        if !round_trip.is_lossless() {
+            return Err(Failed::new(
+                Failure::Verification,
+                format!(
+                    "the raw data of {} does not survive re-encoding unchanged",
+                    input_path
+                ),
+            ));
+        }
+        outcome.verification = Verification::Passed;
+        return Ok(outcome);
+    }
+    if extract_watermark {
+        let curve = match format {
+            Format::Arw2 { curve, .. } => curve.table(),
+            _ => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("{} holds no ARW2 data to carry a watermark", input_path),
+                ));
+            }
+        };
+        match watermark::extract(&decoded.pixels, width, &curve) {
+            Some(found) => println!("{}", found),
+            None => println!("no watermark found"),
+        }
+        return Ok(outcome);
+    }
+    // measure the masked borders before --full-sensor makes them part of the image
+    let black_stats = stats::optical_black(&decoded);
+    if print_stats {
+        let raw_stats = stats::analyze(&decoded);
+        if stats_json {
+            println!(
+                "{{\"input\":{},\"stats\":{}}}",
+                failure::json_string(input_path),
+                raw_stats.to_json()
+            );
+        } else if inputs.len() > 1 {
+            println!("{}:\n{}", input_path, raw_stats);
+        } else {
+            println!("{}", raw_stats);
+        }
+        return Ok(outcome);
+    }
+    if calibrate_black {
+        match black_stats {
+            Some(black_stats) => decoded.black_level = black_stats.black_level(),
+            None => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    "cannot calibrate the black level: no optical black area",
+                ));
+            }
+        }
+    }
+    if full_sensor {
+        decoded.crop = decoded.full_rect();
+    }
+    let original = decoded.pixels.clone();
+
+    // edits are placed in display coordinates, the codecs work in stored order
+    let display = decoded.reoriented(readout);
+    let write_exports = |image: &RawImage| -> Result<(), Failed> {
+        if let Some(export_path) = export_path.as_deref().filter(|path| !skipped(path)) {
+            let as_tiff = export::is_tiff_name(export_path);
+            written_or_failed(
+                output(export_path)
+                    .write_with(|out| export::export(out, as_tiff, image, export_scaling)),
+                export_path,
+            )?;
+        }
+        if let Some(preview_path) = export_preview_path.as_deref().filter(|path| !skipped(path)) {
+            written_or_failed(
+                output(preview_path).write_with(|out| export::export_preview(out, image)),
+                preview_path,
+            )?;
+        }
+        if let Some(frame_path) = export_frame_path.as_deref().filter(|path| !skipped(path)) {
+            let data = frame_data(image, frame_path, input_path, &format, &buffer);
+            written_or_failed(
+                output(frame_path).write_with(|out| out.write_all(&data)),
+                frame_path,
+            )?;
+        }
+        Ok(())
+    };
+    if export_only {
+        write_exports(&display)?;
+        return Ok(outcome);
+    }
+    if let Some(chart) = chart {
+        let report = chart::analyze(&display, chart).map_err(|err| {
+            Failed::new(
+                Failure::Parse,
+                format!("cannot measure the chart in {}: {}", input_path, err),
+            )
+        })?;
+        println!("{}", report);
+        if !report.fits() {
+            outcome
+                .warnings
+                .push("the tone curve does not fit the camera".to_owned());
+        }
+        return Ok(outcome);
+    }
+    if let Some((x, y, radius)) = probe {
+        let probe = probe::probe(&display, x, y, radius).map_err(|err| {
+            Failed::new(
+                Failure::Usage,
+                format!("cannot probe {}: {}", input_path, err),
+            )
+        })?;
+        println!("{}", probe);
+        return Ok(outcome);
+    }
+
+    // fixed-pattern column offsets, measured on a bias frame taken with the same camera or
+    // on the masked rows above (or below) the visible area
+    let column_offsets = if let Some(bias_path) = bias_path {
+        let bias = read_bias_frame(bias_path, dither, column_order)?;
+        if (bias.width, bias.height) != (display.width, display.height) {
+            return Err(Failed::new(
+                Failure::Usage,
+                format!(
+                    "bias frame {} is {}x{}, but {} is {}x{}",
+                    bias_path, bias.width, bias.height, input_path, display.width, display.height
+                ),
+            ));
+        }
+        Some(ops::column_offsets(
+            &bias.pixels,
+            bias.width,
+            0..bias.height,
+        ))
+    } else if remove_columns {
+        let crop = display.crop;
+        let rows = if crop.y > 0 {
+            0..crop.y
+        } else {
+            crop.y + crop.height..display.height
+        };
+        if rows.is_empty() {
+            return Err(Failed::new(
+                Failure::UnsupportedFormat,
+                "cannot remove the column pattern: no masked rows and no bias frame",
+            ));
+        }
+        Some(ops::column_offsets(&display.pixels, display.width, rows))
+    } else {
+        None
+    };
 
-    let mut decoded = decode_arw2(&buffer[start..], width, height);
+    let mut editor = editor::RawEditor::new(display);
+    editor.set_neutral(neutral);
+    if let Some(offsets) = column_offsets {
+        editor.apply("column pattern", |img| {
+            ops::remove_column_pattern(img, &offsets)
+        });
+    }
+    if fix_hot_pixels.is_some() || defect_map.is_some() {
+        let image = editor.image().clone();
+        let mut map = defect_map
+            .clone()
+            .unwrap_or_else(|| defects::DefectMap::new(image.width, image.height));
+        if !map.matches(image.width, image.height) {
+            return Err(Failed::new(
+                Failure::Parse,
+                format!(
+                    "the defect map is of another size than the raw data of {}, {}x{}",
+                    input_path, image.width, image.height
+                ),
+            ));
+        }
+        editor.apply("defects", |img| {
+            if let Some(threshold) = fix_hot_pixels {
+                for defect in defects::detect(img, &image, threshold) {
+                    map.insert(defect);
+                }
+            }
+            defects::repair(img, &image, &map);
+        });
+        if let Some(path) = save_defect_map.as_deref().filter(|path| !skipped(path)) {
+            written_or_failed(output(path).write_with(|out| write!(out, "{}", map)), path)?;
+        }
+    }
 
-    let mut img: ImageBuffer<Luma<u16>, Vec<<Luma<u16> as Pixel>::Subpixel>> =
-        ImageBuffer::new(width as u32, height as u32);
+    if let Some(frame) = import_frame {
+        let image = editor.image();
+        if (frame.width, frame.height) != (image.width, image.height) {
+            return Err(Failed::new(
+                Failure::Parse,
+                format!(
+                    "the raw frame is {}x{}, but the raw data of {} is {}x{}",
+                    frame.width, frame.height, input_path, image.width, image.height
+                ),
+            ));
+        }
+        editor.apply("import frame", |img| {
+            img.copy_from_slice(&frame.pixels);
+        });
+    }
+    if let Some(plane) = import {
+        let image = editor.image().clone();
+        let mut imported = Ok(());
+        editor.apply("import", |img| {
+            imported = import::apply(img, &image, plane, export_scaling)
+        });
+        imported.map_err(|err| {
+            Failed::new(
+                Failure::Parse,
+                format!("cannot import into {}: {}", input_path, err),
+            )
+        })?;
+    }
+
+    if flip != (false, false) {
+        let crop = editor.image().crop;
+        let name = match flip {
+            (true, true) => "rotate 180",
+            (true, false) => "flip horizontally",
+            _ => "flip vertically",
+        };
+        editor.apply(name, |img| ops::flip(img, crop, flip.0, flip.1));
+    }
 
-    for y in 0..height {
-        for x in 0..width {
-            let val = decoded[y * width + x];
-            img.put_pixel(x as u32, y as u32, Luma([val]));
+    let metadata = template::Metadata::from_file(&buffer);
+    if let Some(percentiles) = stretch {
+        let image = editor.image().clone();
+        editor.apply("stretch", |img| {
+            ops::contrast_stretch(img, &image, percentiles)
+        });
+    }
+    if let Some(sigma) = blur {
+        let crop = editor.image().crop;
+        editor.apply("blur", |img| ops::blur(img, crop, sigma));
+    }
+    if let Some((sigma, amount)) = sharpen {
+        let image = editor.image().clone();
+        editor.apply("sharpen", |img| {
+            ops::unsharp_mask(img, image.crop, sigma, amount, image.white_level)
+        });
+    }
+    for redaction in redactions {
+        editor.redact(redaction);
+    }
+    let stamps = if unstamp {
+        let stamps = match recipe {
+            Some(recipe) => {
+                let skipped_steps = recipe.pixel_steps(&metadata);
+                if skipped_steps > 0 {
+                    let warning = format!(
+                        "{} recipe steps edit the raw data and cannot be undone with --unstamp",
+                        skipped_steps
+                    );
+                    eprintln!("{}", warning);
+                    outcome.warnings.push(warning);
+                }
+                recipe
+                    .stamps(&metadata, locale, &stamp.fonts)
+                    .map_err(|err| Failed::new(Failure::Parse, err))?
+            }
+            None => vec![default_stamp(stamp, text.as_deref(), &metadata, locale)],
+        };
+        for stamp in &stamps {
+            let unstamped = editor.remove_text(stamp);
+            println!(
+                "unstamped {:?}: {} photosites unblended, {} inpainted",
+                stamp.text, unstamped.unblended, unstamped.inpainted
+            );
+        }
+        // nothing is drawn
+        vec![]
+    } else {
+        // a recipe replaces the default stamp
+        match recipe {
+            Some(recipe) => recipe
+                .run(&mut editor, &metadata, locale, &stamp.fonts)
+                .map_err(|err| Failed::new(Failure::Parse, err))?,
+            None => {
+                let stamp = default_stamp(stamp, text.as_deref(), &metadata, locale);
+                editor.draw_text(&stamp);
+                vec![stamp]
+            }
+        }
+    };
+    if let Some(overlay) = overlay {
+        editor.draw_overlay(overlay);
+    }
+    if let Some(corner) = burn_in {
+        let position = inputs.iter().position(|input| input == input_path);
+        let frame = BurnIn::frame_number(input_path, position.unwrap_or(0));
+        let burn_in = BurnIn {
+            text: BurnIn::text(&metadata, &frame),
+            corner,
+            fonts: stamp.fonts.clone(),
+        };
+        editor.burn_in(&burn_in);
+    }
+    if let Some(text) = watermark {
+        // last, so that no other edit takes it away, and in the stored layout of the blocks
+        let curve = match format {
+            Format::Arw2 { curve, .. } => curve.table(),
+            _ => {
+                return Err(Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("{} holds no ARW2 data to carry a watermark", input_path),
+                ));
+            }
+        };
+        let image = editor.image().clone();
+        let mut embedded = Ok(());
+        editor.apply("watermark", |img| {
+            let mut stored = RawImage {
+                pixels: img.to_vec(),
+                ..image.clone()
+            }
+            .reoriented(readout);
+            embedded = watermark::embed(&mut stored.pixels, stored.width, &curve, text.as_bytes());
+            img.copy_from_slice(&stored.reoriented(readout).pixels);
+        });
+        embedded.map_err(|err| {
+            Failed::new(
+                Failure::UnsupportedFormat,
+                format!("cannot watermark {}: {}", input_path, err),
+            )
+        })?;
+    }
+    check_glyphs(&stamps, &mut outcome);
+    // the parts of the stored data touched by the edits
+    let dirty_rects: Vec<_> = editor
+        .dirty_rects()
+        .into_iter()
+        .map(|rect| match readout {
+            Readout::Rows => rect,
+            Readout::Columns => rect.transposed(),
+        })
+        .collect();
+    let mut visual = editor.into_image();
+    if let Some(rect) = crop_rect {
+        let visible = visual.crop;
+        if rect.x + rect.width > visible.width || rect.y + rect.height > visible.height {
+            return Err(Failed::new(
+                Failure::Usage,
+                format!(
+                    "--crop does not fit into the {}x{} visible area of {}",
+                    visible.width, visible.height, input_path
+                ),
+            ));
         }
+        visual.crop = tiled::Rect {
+            x: visible.x + rect.x,
+            y: visible.y + rect.y,
+            ..rect
+        };
+    }
+    let mut decoded = visual.reoriented(readout);
+
+    write_exports(&visual)?;
+
+    let dng_path = if dng_output {
+        Some(output_path)
+    } else {
+        dng_path.as_deref()
+    };
+    if let Some(dng_path) = dng_path.filter(|path| !skipped(path)) {
+        let options = dng::DngOptions {
+            layout: dng_layout,
+            // the buffer still holds the untouched file at this point
+            original: if embed_original {
+                Some(dng::OriginalRaw {
+                    name: input_path,
+                    data: &buffer,
+                })
+            } else {
+                None
+            },
+            lens: Some(&lens),
+            model: metadata.get("Model"),
+        };
+        written_or_failed(
+            output(dng_path).write_with(|out| dng::write_dng(out, &visual, &options)),
+            dng_path,
+        )?;
+    }
+    if dng_output {
+        return Ok(outcome);
     }
 
-    draw_text(&mut img);
+    // the strips of the raw IFD follow each other from the start of the raw data; each is
+    // the offset of its data and its number of rows
+    let stored_rows_per_strip = raw_info
+        .filter(|_| raw_geometry.is_none())
+        .and_then(|info| container::stored_rows_per_strip(&buffer, info.ifd_offset))
+        .map_or(height, |rows| rows.min(height));
+    let strips: Vec<(usize, usize)> = container::strips(height, stored_rows_per_strip, |rows| {
+        format.data_len(width, rows)
+    })
+    .into_iter()
+    .enumerate()
+    .map(|(i, (offset, _))| {
+        let rows = stored_rows_per_strip.min(height - i * stored_rows_per_strip);
+        (start + offset as usize, rows)
+    })
+    .collect();
+    let bit_report = bit_report && {
+        let arw2 = matches!(format, Format::Arw2 { .. });
+        if !arw2 {
+            let warning = "--bit-report only applies to ARW2 data".to_owned();
+            eprintln!("{}", warning);
+            outcome.warnings.push(warning);
+        }
+        arw2
+    };
+    let budget_before: Vec<_> = if bit_report {
+        strips
+            .iter()
+            .map(|(offset, rows)| report::analyze_arw2(&buffer[*offset..], width, *rows))
+            .collect()
+    } else {
+        vec![]
+    };
 
-    for y in 0..height {
-        for x in 0..width {
-            let pixel = img.get_pixel(x as u32, y as u32);
-            decoded[y * width + x] = pixel.0[0];
+    // with a block index, ARW2 groups can be replaced one by one instead of whole rows
+    let block_index = match (block_index_path, format) {
+        (Some(path), Format::Arw2 { .. }) => Some(load_block_index(
+            path,
+            &buffer[start..],
+            start as u64,
+            width,
+            height,
+            !dry_run,
+            &mut outcome.warnings,
+        )),
+        _ => None,
+    };
+    // validate what the encoder will actually write
+    if let Some(max_value) = format.max_value() {
+        for pixel in &mut decoded.pixels {
+            *pixel = (*pixel).min(max_value);
         }
     }
+    if let Format::SonyLossless { ifd_offset } = format {
+        // the tiles are rewritten where they are, or appended to the file if they grew
+        let layout = TileLayout::read(&buffer, ifd_offset).map_err(Failed::from)?;
+        let tiles = layout.tiles_in(&dirty_rects);
+        outcome.raw_bytes_changed =
+            sony_lossless::write_tiles(&mut buffer, &layout, &decoded.pixels, &tiles)
+                .map_err(Failed::from)?;
+    } else if let Format::Cr2 { ifd_offset } = format {
+        // the whole JPEG is re-encoded, in place if it still fits
+        let layout = Cr2Layout::read(&buffer, ifd_offset).map_err(Failed::from)?;
+        let max_value = ((1u32 << layout.frame.precision) - 1) as u16;
+        for pixel in &mut decoded.pixels {
+            *pixel = (*pixel).min(max_value);
+        }
+        outcome.raw_bytes_changed =
+            cr2::write(&mut buffer, &layout, &decoded.pixels).map_err(Failed::from)?;
+    } else if let Format::Nef { ifd_offset, dither } = format {
+        // likewise the whole strip, through the curve of lossy data
+        let layout = NefLayout::read(&buffer, ifd_offset).map_err(Failed::from)?;
+        outcome.raw_bytes_changed =
+            nef::write(&mut buffer, &layout, &decoded.pixels, dither).map_err(Failed::from)?;
+    } else if !raw_streamed {
+        let raw_before = buffer[start..start + format.data_len(width, height)].to_vec();
+        let dirty_rows = match block_index {
+            Some(index) => {
+                let curve = decoded.curve.clone().unwrap_or_else(calculate_curve);
+                let full_rows = index
+                    .encode_groups(&decoded.pixels, &curve, &dirty_rects, &mut buffer[start..])
+                    .map_err(Failed::from)?;
+                let rects: Vec<_> = full_rows
+                    .into_iter()
+                    .map(|row| tiled::Rect {
+                        x: 0,
+                        y: row,
+                        width,
+                        height: 1,
+                    })
+                    .collect();
+                tiled::spans(&rects)
+            }
+            None => tiled::spans(&dirty_rects),
+        };
+        format
+            .encode_rows_into(&decoded.pixels, width, &dirty_rows, &mut buffer[start..])
+            .map_err(Failed::from)?;
+        outcome.raw_bytes_changed = raw_before
+            .iter()
+            .zip(&buffer[start..])
+            .filter(|(before, after)| before != after)
+            .count();
+    }
+
+    if bit_report {
+        let mut total_before = report::BitBudget::default();
+        let mut total_after = report::BitBudget::default();
+        for (i, ((offset, rows), before)) in strips.iter().zip(budget_before).enumerate() {
+            let after = report::analyze_arw2(&buffer[*offset..], width, *rows);
+            println!("strip {} at offset {}, before:\n{}", i, offset, before);
+            println!("strip {} at offset {}, after:\n{}", i, offset, after);
+            total_before += before;
+            total_after += after;
+        }
+        println!("overall, before:\n{}", total_before);
+        println!("overall, after:\n{}", total_after);
+    }
+
+    if all_renditions {
+        for rendition in preview::find_renditions(&buffer) {
+            if let Err(err) = preview::redraw(
+                &mut buffer,
+                &rendition,
+                &stamps,
+                visual.crop.width,
+                visual.crop.height,
+                visual.white_level,
+                upright_previews,
+            ) {
+                return Err(Failed::new(Failure::Parse, err));
+            }
+        }
+    } else if regenerate_previews {
+        let renditions = preview::find_renditions(&buffer);
+        if renditions.is_empty() {
+            let warning = format!("no embedded previews in {}", input_path);
+            eprintln!("{}", warning);
+            outcome.warnings.push(warning);
+        } else {
+            let developed = export::develop(&visual);
+            for rendition in renditions {
+                preview::regenerate(&mut buffer, &rendition, &developed, upright_previews)
+                    .map_err(|err| Failed::new(Failure::Parse, err))?;
+            }
+        }
+    }
+
+    if lens_corrections.is_some() {
+        lens.write_into(&mut buffer);
+    }
+
+    let rows_per_strip = match strip_size {
+        Some(size) => {
+            if !matches!(
+                format,
+                Format::Arw2 { .. } | Format::Uncompressed | Format::Packed12
+            ) {
+                return Err(Failed::new(
+                    Failure::Usage,
+                    "--strip-size only applies to ARW2, uncompressed and packed 12-bit data",
+                ));
+            }
+            container::rows_per_strip(size, format.data_len(width, 1), height)
+        }
+        None => height,
+    };
+    if let (Some(_), container::Container::Original) = (strip_size, container) {
+        let ifd_offset = raw_info.map(|info| info.ifd_offset).ok_or_else(|| {
+            Failed::new(
+                Failure::UnsupportedFormat,
+                "--strip-size needs the IFD of the raw data",
+            )
+        })?;
+        let strips = container::strips(height, rows_per_strip, |rows| format.data_len(width, rows));
+        container::split_strips(&mut buffer, ifd_offset, start, &strips, rows_per_strip).map_err(
+            |err| {
+                Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("cannot split the raw data of {}: {}", input_path, err),
+                )
+            },
+        )?;
+    }
+
+    // the minimal container copies the tags it keeps from the edited original
+    edit_metadata(&mut buffer, metadata_edit, input_path)?;
+    let write_crop = |file: &mut Vec<u8>, ifd_offset: Option<usize>| match crop_rect {
+        Some(_) => {
+            let ifd_offset = ifd_offset.ok_or_else(|| {
+                Failed::new(
+                    Failure::UnsupportedFormat,
+                    "--crop needs the IFD of the raw data",
+                )
+            })?;
+            container::write_default_crop(file, ifd_offset, decoded.crop).map_err(|err| {
+                Failed::new(
+                    Failure::UnsupportedFormat,
+                    format!("cannot crop {}: {}", input_path, err),
+                )
+            })
+        }
+        None => Ok(()),
+    };
+    if container == container::Container::Original {
+        write_crop(&mut buffer, raw_info.map(|info| info.ifd_offset))?;
+    }
 
-    for (i, byte) in encode_arw2(&decoded, width).into_iter().enumerate() {
-        buffer[start + i] = byte;
+    // where the raw data ends up in the written file
+    let edited = output(output_path).allow_overwrite_original(in_place);
+    let (written, written_start) = match container {
+        container::Container::Original => (Cow::Borrowed(&buffer[..]), start as u64),
+        container::Container::Minimal => {
+            let mut data = vec![];
+            container::write_minimal_arw2(
+                &mut data,
+                &buffer,
+                &decoded,
+                &buffer[start..start + width * height],
+                rows_per_strip,
+            )
+            .map_err(|err| Failed::new(Failure::Io, err))?;
+            let ifd0 = tiff::Tiff::new(&data).and_then(|tiff| tiff.read_u32(4));
+            write_crop(&mut data, ifd0.map(|offset| offset as usize))?;
+            (Cow::Owned(data), tiff::DATA_OFFSET as u64)
+        }
+    };
+    if staged.is_none() && !skipped(output_path) {
+        let result = edited.write_with(|out| {
+            if !raw_streamed {
+                return out.write_all(&written);
+            }
+            out.write_all(&written[..raw_range.start])?;
+            outcome.raw_bytes_changed = format
+                .write_rows(
+                    &decoded.pixels,
+                    width,
+                    &tiled::spans(&dirty_rects),
+                    &mut input_file,
+                    raw_range.start as u64,
+                    out,
+                )
+                .map_err(|err| match err {
+                    RawEditError::Io(err) => err,
+                    err => io::Error::other(err.to_string()),
+                })?;
+            out.write_all(&written[raw_range.end..])
+        });
+        written_or_failed(result, output_path)?;
+    }
+    if heif {
+        let (crop, white_level) = (visual.crop, visual.white_level);
+        if let Some((companion, path, data)) =
+            redraw_companion(input_path, output_path, &stamps, crop, white_level)?
+        {
+            let path = path.to_string_lossy();
+            let edited = output(&path)
+                .protect(companion)
+                .allow_overwrite_original(in_place);
+            if !skipped(&path) {
+                written_or_failed(edited.write_with(|out| out.write_all(&data)), &path)?;
+            }
+        }
     }
 
-    let mut file = File::create("edited.arw").unwrap();
-    file.write(&buffer[..]).unwrap();
+    // a dry run always checks what it would have written
+    let validate_tolerance =
+        validate_tolerance.or(Some(validate::DEFAULT_TOLERANCE).filter(|_| dry_run));
+    // what isn't written yet is read back from memory
+    let in_memory = dry_run || staged.is_some();
+    if let Some(tolerance) = validate_tolerance {
+        let rewritten = if let (true, Format::Arw2 { dither, curve }) = (in_memory, format) {
+            // the rows the edits left alone still hold the data they were decoded from, so
+            // only the rewritten ones are read back
+            read_back_rows(
+                &written[written_start as usize..],
+                width,
+                height,
+                &original,
+                &tiled::spans(&dirty_rects),
+                &curve.table(),
+                dither,
+            )
+            .map(|pixels| RawImage::new(pixels, width, height, decoded.white_level))
+        } else if in_memory {
+            format.decode_from(
+                &mut MemorySource::new(&written),
+                written_start,
+                width,
+                height,
+            )
+        } else {
+            File::open(output_path)
+                .map_err(RawEditError::from)
+                .and_then(|mut file| format.decode_from(&mut file, written_start, width, height))
+        }
+        .map_err(|err| {
+            Failed::new(
+                Failure::of(&err),
+                format!("cannot read back {}: {}", output_path, err),
+            )
+        })?;
+        let storage = validate::Storage::of(format, &buffer).map_err(|err| {
+            Failed::new(
+                Failure::of(&err),
+                format!("cannot read the layout of {}: {}", input_path, err),
+            )
+        })?;
+        let report = validate::validate(
+            &storage,
+            &original,
+            &decoded.pixels,
+            &rewritten.pixels,
+            width,
+            tolerance,
+        );
+        println!(
+            "validation: edited {}/{} mismatched (max deviation {}), untouched {}/{} mismatched (max deviation {})",
+            report.edited.mismatched,
+            report.edited.checked,
+            report.edited.max_deviation,
+            report.untouched.mismatched,
+            report.untouched.checked,
+            report.untouched.max_deviation,
+        );
+        if !report.passed() {
+            let message = match report.first_mismatch {
+                Some((x, y)) => format!("validation FAILED: first mismatch at ({}, {})", x, y),
+                None => "validation FAILED".to_owned(),
+            };
+            return Err(Failed::new(Failure::Verification, message));
+        }
+        outcome.verification = Verification::Passed;
+    }
+    if let Some(staged) = staged {
+        staged.file = match written {
+            Cow::Owned(data) => data,
+            Cow::Borrowed(_) => buffer,
+        };
+        staged.edited = true;
+    }
+    Ok(outcome)
 }
@@ -0,0 +1,152 @@
+//! Editing the descriptive tags of IFD 0 and the Exif IFD while rewriting a file: Artist,
+//! Copyright and ImageDescription, the writable tags of the `tags` dictionary, and removing
+//! the GPS IFD. New values rarely fit where the old ones were, so the IFDs are rewritten at
+//! the end of the file by `tiff::rewrite_ifd` and everything else stays where it is.
+
+use std::fmt;
+
+use crate::tags::{Directory, TagInfo, Writable};
+use crate::tiff::{self, Tiff, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The file is not a TIFF or its IFD 0 can't be read
+    Invalid,
+    /// A value can't be stored in an ASCII tag
+    NotAscii { tag: &'static str },
+    /// A value of a numeric tag is not a number it can hold
+    NotANumber { tag: &'static str, value: String },
+    /// A tag is in the Exif IFD, but the file has none
+    NoExifIfd,
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataError::Invalid => write!(f, "cannot read IFD 0 of the file"),
+            MetadataError::NotAscii { tag } => {
+                write!(f, "{} must be ASCII text without NUL characters", tag)
+            }
+            MetadataError::NotANumber { tag, value } => {
+                write!(f, "{} must be a number from 0 to 65535, not {}", tag, value)
+            }
+            MetadataError::NoExifIfd => write!(f, "the file has no Exif IFD"),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// Tags to set, and whether to remove the GPS data; what is `None` stays as it is
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataEdit {
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+    pub description: Option<String>,
+    pub strip_gps: bool,
+    /// Tags set by name, each of them writable
+    pub tags: Vec<(&'static TagInfo, String)>,
+}
+
+impl MetadataEdit {
+    pub fn is_empty(&self) -> bool {
+        self.artist.is_none()
+            && self.copyright.is_none()
+            && self.description.is_none()
+            && !self.strip_gps
+            && self.tags.is_empty()
+    }
+
+    /// Applies the edit to a TIFF-based file. The GPS IFD and the values it points to are
+    /// zeroed, not only unlinked, so the coordinates don't survive in the file.
+    pub fn apply(&self, file: &mut Vec<u8>) -> Result<(), MetadataError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let mut changes = vec![];
+        let mut exif_changes = vec![];
+        for (info, text) in &self.tags {
+            let value = match info.writable {
+                Some(Writable::Text) => {
+                    if !text.is_ascii() || text.contains('\0') {
+                        return Err(MetadataError::NotAscii { tag: info.name });
+                    }
+                    Value::Ascii(text.clone())
+                }
+                Some(Writable::Short) => match text.trim().parse() {
+                    Ok(number) => Value::Short(vec![number]),
+                    Err(_) => {
+                        return Err(MetadataError::NotANumber {
+                            tag: info.name,
+                            value: text.clone(),
+                        })
+                    }
+                },
+                None => continue,
+            };
+            match info.directory {
+                Directory::Ifd0 => changes.push((info.tag, Some(value))),
+                Directory::Exif => exif_changes.push((info.tag, Some(value))),
+                Directory::Gps | Directory::Sony => {}
+            }
+        }
+        // before IFD 0, which is then rewritten with the new offset of the Exif IFD
+        if !exif_changes.is_empty() {
+            let tiff = Tiff::new(file).ok_or(MetadataError::Invalid)?;
+            let ifd0 = tiff.ifd0().ok_or(MetadataError::Invalid)?;
+            let pointer = ifd0
+                .entry(tiff::EXIF_IFD)
+                .filter(|entry| entry.field_type == 4 || entry.field_type == 13)
+                .ok_or(MetadataError::NoExifIfd)?;
+            tiff::rewrite_ifd(file, pointer.value_pos, &exif_changes)
+                .ok_or(MetadataError::NoExifIfd)?;
+        }
+
+        let texts = [
+            ("Artist", tiff::ARTIST, &self.artist),
+            ("Copyright", tiff::COPYRIGHT, &self.copyright),
+            (
+                "ImageDescription",
+                tiff::IMAGE_DESCRIPTION,
+                &self.description,
+            ),
+        ];
+        for (name, tag, text) in texts.iter() {
+            if let Some(text) = text {
+                if !text.is_ascii() || text.contains('\0') {
+                    return Err(MetadataError::NotAscii { tag: name });
+                }
+                changes.retain(|(changed, _)| changed != tag);
+                changes.push((*tag, Some(Value::Ascii(text.clone()))));
+            }
+        }
+
+        let mut erased = vec![];
+        if self.strip_gps {
+            let tiff = Tiff::new(file).ok_or(MetadataError::Invalid)?;
+            let ifd0 = tiff.ifd0().ok_or(MetadataError::Invalid)?;
+            if let Some(offset) = tiff.value(&ifd0, tiff::GPS_IFD) {
+                let offset = offset as usize;
+                if let Some((gps, _)) = tiff.read_ifd(offset) {
+                    erased.push(offset..offset + 2 + 12 * gps.entries.len() + 4);
+                    for entry in gps.entries.iter().filter(|entry| entry.byte_len() > 4) {
+                        erased.push(entry.value_pos..entry.value_pos + entry.byte_len());
+                    }
+                }
+                changes.push((tiff::GPS_IFD, None));
+            }
+        }
+        for range in erased {
+            let end = range.end.min(file.len());
+            if let Some(bytes) = file.get_mut(range.start..end) {
+                bytes.iter_mut().for_each(|byte| *byte = 0);
+            }
+        }
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+        tiff::rewrite_ifd(file, 4, &changes).ok_or(MetadataError::Invalid)?;
+        Ok(())
+    }
+}
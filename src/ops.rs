@@ -0,0 +1,409 @@
+//! Operations on the linear raw values, run through `RawEditor::apply`
+
+use std::ops::Range;
+
+use crate::edit::RawBuffer;
+use crate::raw::{CfaColor, RawImage};
+use crate::tiled::Rect;
+
+/// Percentiles clipped by default by `contrast_stretch`
+pub const DEFAULT_STRETCH: (f64, f64) = (0.1, 99.9);
+
+/// Parses `low,high` percentiles
+pub fn parse_percentiles(value: &str) -> Option<(f64, f64)> {
+    let mut parts = value.split(',');
+    let low: f64 = parts.next()?.trim().parse().ok()?;
+    let high: f64 = parts.next()?.trim().parse().ok()?;
+    if parts.next().is_some() || !(0.0..high).contains(&low) || high > 100.0 {
+        return None;
+    }
+    Some((low, high))
+}
+
+/// The value below which `percentile` percent of the histogram lies
+fn percentile_value(histogram: &[usize], total: usize, percentile: f64) -> u16 {
+    let target = (total as f64 * percentile / 100.0) as usize;
+    let mut seen = 0;
+    for (value, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen > target {
+            return value as u16;
+        }
+    }
+    (histogram.len() - 1) as u16
+}
+
+/// Linearly stretches every colour channel of the visible area so that its `low` and `high`
+/// percentiles end up at the black and the white level. Values beyond the percentiles are
+/// clipped.
+pub fn contrast_stretch(img: &mut RawBuffer, image: &RawImage, (low, high): (f64, f64)) {
+    let crop = image.crop;
+    let width = img.width() as usize;
+    let channels = [CfaColor::Red, CfaColor::Green, CfaColor::Blue];
+    let channel = |x: usize, y: usize| {
+        let color = image.cfa.colors[(y % 2) * 2 + x % 2];
+        channels.iter().position(|c| *c == color).unwrap_or(0)
+    };
+    let visible = |index: usize| {
+        let (x, y) = (index % width, index / width);
+        x >= crop.x && x < crop.x + crop.width && y >= crop.y && y < crop.y + crop.height
+    };
+
+    let mut histograms = vec![vec![0usize; 65536]; channels.len()];
+    let mut totals = [0usize; 3];
+    for (index, value) in img.iter().enumerate() {
+        if visible(index) {
+            let c = channel(index % width, index / width);
+            histograms[c][*value as usize] += 1;
+            totals[c] += 1;
+        }
+    }
+
+    let black = image.black_level as f64;
+    let white = image.white_level as f64;
+    let ranges: Vec<_> = histograms
+        .iter()
+        .zip(&totals)
+        .map(|(histogram, total)| {
+            let lo = percentile_value(histogram, *total, low) as f64;
+            let hi = percentile_value(histogram, *total, high) as f64;
+            (lo, (hi - lo).max(1.0))
+        })
+        .collect();
+
+    for (index, value) in img.iter_mut().enumerate() {
+        if !visible(index) {
+            continue;
+        }
+        let (lo, span) = ranges[channel(index % width, index / width)];
+        let stretched = black + (*value as f64 - lo) / span * (white - black);
+        *value = stretched.round().clamp(black, white) as u16;
+    }
+}
+
+/// Sets every pixel of the rectangle (clipped to the image) to `value`
+pub fn fill(img: &mut RawBuffer, rect: Rect, value: u16) {
+    let right = (rect.x + rect.width).min(img.width() as usize);
+    let bottom = (rect.y + rect.height).min(img.height() as usize);
+    for y in rect.y..bottom {
+        for x in rect.x..right {
+            img.get_pixel_mut(x as u32, y as u32).0[0] = value;
+        }
+    }
+}
+
+/// Width of the ring of photosites around a redacted region that its fill is matched to
+const REDACT_BORDER: usize = 16;
+
+/// xorshift64*, seeded so that redacting the same region twice gives the same result
+struct NoiseSource(u64);
+
+impl NoiseSource {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A standard normal sample (Box-Muller)
+    fn gaussian(&mut self) -> f64 {
+        let u = self.next_f64().max(f64::MIN_POSITIVE);
+        let v = self.next_f64();
+        (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+    }
+}
+
+/// Replaces the rectangle with noise matching the photosites around it: every position of the
+/// CFA pattern gets the mean and standard deviation of the same position in the surrounding
+/// ring. The content is destroyed just like with a solid fill, but the patch blends in.
+pub fn redact_matched(img: &mut RawBuffer, rect: Rect) {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let right = (rect.x + rect.width).min(width);
+    let bottom = (rect.y + rect.height).min(height);
+    let inside = |x: usize, y: usize| x >= rect.x && x < right && y >= rect.y && y < bottom;
+
+    let mut sums = [0.0f64; 4];
+    let mut squares = [0.0f64; 4];
+    let mut counts = [0usize; 4];
+    let ring_x = rect.x.saturating_sub(REDACT_BORDER)..(right + REDACT_BORDER).min(width);
+    let ring_y = rect.y.saturating_sub(REDACT_BORDER)..(bottom + REDACT_BORDER).min(height);
+    for y in ring_y {
+        for x in ring_x.clone() {
+            if inside(x, y) {
+                continue;
+            }
+            let value = img.get_pixel(x as u32, y as u32).0[0] as f64;
+            let index = (y % 2) * 2 + x % 2;
+            sums[index] += value;
+            squares[index] += value * value;
+            counts[index] += 1;
+        }
+    }
+
+    let mut noise = NoiseSource(
+        (rect.x as u64) << 48
+            ^ (rect.y as u64) << 32
+            ^ (rect.width as u64) << 16
+            ^ rect.height as u64
+            | 1,
+    );
+    for y in rect.y..bottom {
+        for x in rect.x..right {
+            let index = (y % 2) * 2 + x % 2;
+            let count = counts[index].max(1) as f64;
+            let mean = sums[index] / count;
+            let deviation = (squares[index] / count - mean * mean).max(0.0).sqrt();
+            let value = mean + noise.gaussian() * deviation;
+            img.get_pixel_mut(x as u32, y as u32).0[0] =
+                value.round().clamp(0.0, u16::MAX as f64) as u16;
+        }
+    }
+}
+
+/// Estimates fixed-pattern column offsets from the given rows of row-major pixels: the mean of
+/// every column relative to the mean of all columns of the same CFA parity. Dark rows (the
+/// masked borders, or a bias frame) show nothing but this pattern and noise.
+pub fn column_offsets(pixels: &[u16], width: usize, rows: Range<usize>) -> Vec<f64> {
+    let mut sums = vec![0u64; width];
+    let mut count = 0;
+    for row in pixels.chunks(width).skip(rows.start).take(rows.len()) {
+        for (sum, value) in sums.iter_mut().zip(row) {
+            *sum += *value as u64;
+        }
+        count += 1;
+    }
+    let means: Vec<_> = sums
+        .iter()
+        .map(|sum| *sum as f64 / count.max(1) as f64)
+        .collect();
+    let mut parity_means = [0.0; 2];
+    for (parity, parity_mean) in parity_means.iter_mut().enumerate() {
+        let columns: Vec<_> = means.iter().skip(parity).step_by(2).collect();
+        *parity_mean = columns.iter().copied().sum::<f64>() / columns.len().max(1) as f64;
+    }
+    means
+        .iter()
+        .enumerate()
+        .map(|(x, mean)| mean - parity_means[x % 2])
+        .collect()
+}
+
+/// Subtracts per-column offsets from every pixel
+pub fn remove_column_pattern(img: &mut RawBuffer, offsets: &[f64]) {
+    for (x, _, pixel) in img.enumerate_pixels_mut() {
+        if let Some(offset) = offsets.get(x as usize) {
+            let value = pixel.0[0] as f64 - offset;
+            pixel.0[0] = value.round().clamp(0.0, u16::MAX as f64) as u16;
+        }
+    }
+}
+
+/// Index into `0..len` mirrored at both ends (without repeating the edge sample)
+fn mirror(index: isize, len: usize) -> usize {
+    let len = len as isize;
+    if len == 1 {
+        return 0;
+    }
+    let period = 2 * (len - 1);
+    let index = index.rem_euclid(period);
+    (if index < len { index } else { period - index }) as usize
+}
+
+/// Normalized Gaussian kernel reaching out to three standard deviations
+fn gaussian_kernel(sigma: f64) -> Vec<f64> {
+    let radius = (3.0 * sigma).ceil().max(1.0) as isize;
+    let kernel: Vec<_> = (-radius..=radius)
+        .map(|i| (-(i * i) as f64 / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = kernel.iter().sum();
+    kernel.into_iter().map(|weight| weight / sum).collect()
+}
+
+/// Convolves a `width` x `height` plane with `kernel` along both axes, mirroring at the edges
+fn convolve_separable(plane: &[f64], width: usize, height: usize, kernel: &[f64]) -> Vec<f64> {
+    let radius = (kernel.len() / 2) as isize;
+    let mut rows = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            rows[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(i, weight)| {
+                    weight * plane[y * width + mirror(x as isize + i as isize - radius, width)]
+                })
+                .sum();
+        }
+    }
+    let mut result = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            result[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(i, weight)| {
+                    weight * rows[mirror(y as isize + i as isize - radius, height) * width + x]
+                })
+                .sum();
+        }
+    }
+    result
+}
+
+/// Runs `filter` on each of the four CFA positions of the rectangle separately. Neighbouring
+/// photosites of the same colour are two apart, so every position forms its own plane at half
+/// the resolution; `filter` gets that plane, its width and height, and returns the new values.
+fn filter_cfa_planes<F>(img: &mut RawBuffer, rect: Rect, mut filter: F)
+where
+    F: FnMut(&[f64], usize, usize) -> Vec<f64>,
+{
+    let right = (rect.x + rect.width).min(img.width() as usize);
+    let bottom = (rect.y + rect.height).min(img.height() as usize);
+    for parity_y in 0..2 {
+        for parity_x in 0..2 {
+            let xs: Vec<_> = (rect.x..right).filter(|x| x % 2 == parity_x).collect();
+            let ys: Vec<_> = (rect.y..bottom).filter(|y| y % 2 == parity_y).collect();
+            if xs.is_empty() || ys.is_empty() {
+                continue;
+            }
+            let plane: Vec<_> = ys
+                .iter()
+                .flat_map(|y| xs.iter().map(move |x| (*x, *y)))
+                .map(|(x, y)| img.get_pixel(x as u32, y as u32).0[0] as f64)
+                .collect();
+            let filtered = filter(&plane, xs.len(), ys.len());
+            let positions = ys.iter().flat_map(|y| xs.iter().map(move |x| (*x, *y)));
+            for ((x, y), value) in positions.zip(filtered) {
+                img.get_pixel_mut(x as u32, y as u32).0[0] =
+                    value.round().clamp(0.0, u16::MAX as f64) as u16;
+            }
+        }
+    }
+}
+
+/// Gaussian blur of the rectangle, each CFA colour on its own. `sigma` is in photosites of
+/// the full mosaic; the rectangle's edges are mirrored rather than blurred into what is
+/// around it.
+pub fn blur(img: &mut RawBuffer, rect: Rect, sigma: f64) {
+    let kernel = gaussian_kernel(sigma / 2.0);
+    filter_cfa_planes(img, rect, |plane, width, height| {
+        convolve_separable(plane, width, height, &kernel)
+    });
+}
+
+/// Unsharp mask of the rectangle, each CFA colour on its own: adds `amount` times the
+/// difference between the photosites and their Gaussian blur. Results are clipped to
+/// `white_level`.
+pub fn unsharp_mask(img: &mut RawBuffer, rect: Rect, sigma: f64, amount: f64, white_level: u16) {
+    let kernel = gaussian_kernel(sigma / 2.0);
+    filter_cfa_planes(img, rect, |plane, width, height| {
+        let blurred = convolve_separable(plane, width, height, &kernel);
+        plane
+            .iter()
+            .zip(blurred)
+            .map(|(value, blurred)| {
+                (value + amount * (value - blurred)).clamp(0.0, white_level as f64)
+            })
+            .collect()
+    });
+}
+
+/// Pixelates the rectangle: every block of `block` photosites square (rounded up to an even
+/// number, to hold whole CFA patterns) gets the mean of each CFA position within it
+pub fn pixelate(img: &mut RawBuffer, rect: Rect, block: usize) {
+    let block = block.max(2).next_multiple_of(2);
+    let right = (rect.x + rect.width).min(img.width() as usize);
+    let bottom = (rect.y + rect.height).min(img.height() as usize);
+    for top in (rect.y..bottom).step_by(block) {
+        for left in (rect.x..right).step_by(block) {
+            let xs = left..(left + block).min(right);
+            let ys = top..(top + block).min(bottom);
+            let mut sums = [0.0f64; 4];
+            let mut counts = [0usize; 4];
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let index = (y % 2) * 2 + x % 2;
+                    sums[index] += img.get_pixel(x as u32, y as u32).0[0] as f64;
+                    counts[index] += 1;
+                }
+            }
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let index = (y % 2) * 2 + x % 2;
+                    let mean = sums[index] / counts[index] as f64;
+                    img.get_pixel_mut(x as u32, y as u32).0[0] = mean.round() as u16;
+                }
+            }
+        }
+    }
+}
+
+/// Where the photosite at `index` of `0..len` comes from when the axis is mirrored, keeping
+/// the CFA phase: with an even length a plain mirror would swap the colours of neighbouring
+/// photosites, so the mirror is shifted by one, and the photosite shifted in at the edge is
+/// taken from two further in
+fn mirrored(index: usize, len: usize) -> usize {
+    let source = len - 1 - index + (1 - len % 2);
+    if source >= len {
+        source - 2
+    } else {
+        source
+    }
+}
+
+/// Mirrors the rectangle (clipped to the image) left to right, top to bottom, or both (which
+/// turns it by 180 degrees), keeping every photosite on a photosite of its own colour
+pub fn flip(img: &mut RawBuffer, rect: Rect, horizontal: bool, vertical: bool) {
+    let right = (rect.x + rect.width).min(img.width() as usize);
+    let bottom = (rect.y + rect.height).min(img.height() as usize);
+    let (width, height) = (right.saturating_sub(rect.x), bottom.saturating_sub(rect.y));
+    if width < 2 && height < 2 {
+        return;
+    }
+    let mut before = Vec::with_capacity(width * height);
+    for y in rect.y..bottom {
+        before.extend((rect.x..right).map(|x| img.get_pixel(x as u32, y as u32).0[0]));
+    }
+    for y in 0..height {
+        let source_y = if vertical && height > 1 {
+            mirrored(y, height)
+        } else {
+            y
+        };
+        for x in 0..width {
+            let source_x = if horizontal && width > 1 {
+                mirrored(x, width)
+            } else {
+                x
+            };
+            img.get_pixel_mut((rect.x + x) as u32, (rect.y + y) as u32)
+                .0[0] = before[source_y * width + source_x];
+        }
+    }
+}
+
+/// Largest blur radius accepted, in photosites
+const MAX_SIGMA: f64 = 64.0;
+
+/// Parses the `sigma` of a Gaussian blur
+pub fn parse_sigma(value: &str) -> Option<f64> {
+    let sigma: f64 = value.trim().parse().ok()?;
+    if sigma > 0.0 && sigma <= MAX_SIGMA {
+        Some(sigma)
+    } else {
+        None
+    }
+}
+
+/// Parses unsharp mask settings, `sigma[,amount]`, with an amount of 1 if none is given
+pub fn parse_sharpen(value: &str) -> Option<(f64, f64)> {
+    let (sigma, amount) = match value.split_once(',') {
+        Some((sigma, amount)) => (sigma, amount.trim().parse().ok()?),
+        None => (value, 1.0),
+    };
+    if (0.0..=16.0).contains(&amount) {
+        Some((parse_sigma(sigma)?, amount))
+    } else {
+        None
+    }
+}
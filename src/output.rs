@@ -0,0 +1,141 @@
+//! Writing output files. Every file is written next to its destination first and moved into
+//! place once complete, so an interrupted run never leaves a half-written file behind.
+//!
+//! Originals are protected: writing over one of them is refused unless explicitly allowed
+//! with `allow_overwrite_original`. Unless told which files are the originals, an output file
+//! takes any file already at its destination for one, and so only ever writes new files;
+//! with `replace_existing`, only the files marked with `protect` are.
+
+use std::{
+    fmt, fs,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum OutputError {
+    /// The destination is one of the protected originals
+    WouldOverwriteOriginal(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputError::WouldOverwriteOriginal(path) => {
+                write!(f, "refusing to overwrite the original {}", path.display())
+            }
+            OutputError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+impl From<io::Error> for OutputError {
+    fn from(err: io::Error) -> OutputError {
+        OutputError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputFile {
+    path: PathBuf,
+    originals: Vec<PathBuf>,
+    /// Whether an existing file that isn't one of `originals` may be written over
+    replace_existing: bool,
+    allow_overwrite_original: bool,
+}
+
+impl OutputFile {
+    /// An output file that refuses to write over any existing file:
+    ///
+    /// ```
+    /// use raw_tiff_edit::output::{OutputError, OutputFile};
+    /// use std::io::Write;
+    ///
+    /// let dir = std::env::temp_dir().join(format!("output-doc-{}", std::process::id()));
+    /// std::fs::create_dir_all(&dir)?;
+    /// let (original, edited) = (dir.join("a.ARW"), dir.join("b.ARW"));
+    /// std::fs::write(&original, b"original")?;
+    /// std::fs::write(&edited, b"edited before")?;
+    ///
+    /// let write = |output: OutputFile| output.write_with(|out| out.write_all(b"edited"));
+    /// assert!(matches!(
+    ///     write(OutputFile::new(&edited)),
+    ///     Err(OutputError::WouldOverwriteOriginal(_))
+    /// ));
+    /// // once the originals are known, other files can be replaced
+    /// write(OutputFile::new(&edited).protect(&original).replace_existing(true))?;
+    /// assert!(write(OutputFile::new(&original).protect(&original).replace_existing(true)).is_err());
+    /// write(OutputFile::new(&original).allow_overwrite_original(true))?;
+    /// assert_eq!(std::fs::read(&original)?, b"edited");
+    /// # std::fs::remove_dir_all(&dir)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn new<P: AsRef<Path>>(path: P) -> OutputFile {
+        OutputFile {
+            path: path.as_ref().to_owned(),
+            originals: vec![],
+            replace_existing: false,
+            allow_overwrite_original: false,
+        }
+    }
+
+    /// Marks a file as an original that must not be written over
+    pub fn protect<P: AsRef<Path>>(mut self, original: P) -> OutputFile {
+        self.originals.push(original.as_ref().to_owned());
+        self
+    }
+
+    /// Allows writing over an existing file that hasn't been marked as an original with
+    /// `protect`, for callers that mark all of them
+    pub fn replace_existing(mut self, replace: bool) -> OutputFile {
+        self.replace_existing = replace;
+        self
+    }
+
+    /// Lifts the protection of the originals, whether marked or not
+    pub fn allow_overwrite_original(mut self, allow: bool) -> OutputFile {
+        self.allow_overwrite_original = allow;
+        self
+    }
+
+    fn is_original(&self) -> bool {
+        // paths that don't exist (yet) can't be an existing original
+        let target = match fs::canonicalize(&self.path) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+        !self.replace_existing
+            || self
+                .originals
+                .iter()
+                .any(|original| fs::canonicalize(original).is_ok_and(|original| original == target))
+    }
+
+    /// Checks the destination, then writes the file through `write`
+    pub fn write_with<F>(&self, write: F) -> Result<(), OutputError>
+    where
+        F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+    {
+        if !self.allow_overwrite_original && self.is_original() {
+            return Err(OutputError::WouldOverwriteOriginal(self.path.clone()));
+        }
+
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".partial");
+        let temp = self.path.with_file_name(temp_name);
+        let result = File::create(&temp).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            write(&mut out)?;
+            out.flush()
+        });
+        if let Err(err) = result.and_then(|_| fs::rename(&temp, &self.path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
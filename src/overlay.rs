@@ -0,0 +1,193 @@
+//! Compositing images (logos, watermarks) onto the raw data, either once at an anchor of the
+//! visible area or repeated over all of it. The image is taken as gamma-encoded grey with
+//! alpha, and its levels are put between the black and the white level of the raw data.
+
+use std::{fmt, path::Path};
+
+use image::{DynamicImage, ImageError};
+
+use crate::edit::{Neutral, RawBuffer};
+use crate::tiled::Rect;
+
+/// Where an overlay that is not tiled sits in the visible area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl Anchor {
+    pub fn parse(name: &str) -> Option<Anchor> {
+        match name {
+            "top-left" => Some(Anchor::TopLeft),
+            "top-right" => Some(Anchor::TopRight),
+            "bottom-left" => Some(Anchor::BottomLeft),
+            "bottom-right" => Some(Anchor::BottomRight),
+            "center" => Some(Anchor::Center),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Anchored(Anchor),
+    /// Repeated from the top-left corner of the visible area to its edges
+    Tiled,
+}
+
+#[derive(Debug)]
+pub struct OverlayError {
+    pub path: String,
+    pub err: ImageError,
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot read overlay {}: {}", self.path, self.err)
+    }
+}
+
+impl std::error::Error for OverlayError {}
+
+/// An image to composite onto the raw data, one image pixel per photosite
+#[derive(Debug, Clone)]
+pub struct ImageOverlay {
+    width: usize,
+    height: usize,
+    /// Linear level and alpha of every pixel, both in 0..=1, row by row
+    pixels: Vec<(f32, f32)>,
+    /// Multiplies the alpha of the image
+    pub opacity: f32,
+    pub placement: Placement,
+}
+
+impl ImageOverlay {
+    /// An overlay of the image at full opacity in the bottom-right corner. Colour images are
+    /// converted to grey; images without alpha are opaque.
+    pub fn new(image: &DynamicImage) -> ImageOverlay {
+        let image = image.to_luma_alpha();
+        ImageOverlay {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            pixels: image
+                .pixels()
+                .map(|pixel| {
+                    let [level, alpha] = pixel.0;
+                    ((level as f32 / 255.0).powf(2.2), alpha as f32 / 255.0)
+                })
+                .collect(),
+            opacity: 1.0,
+            placement: Placement::Anchored(Anchor::BottomRight),
+        }
+    }
+
+    /// Reads a PNG (or any other format the `image` crate knows)
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ImageOverlay, OverlayError> {
+        let path = path.as_ref();
+        image::open(path)
+            .map(|image| ImageOverlay::new(&image))
+            .map_err(|err| OverlayError {
+                path: path.display().to_string(),
+                err,
+            })
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Positions of the top-left corner of every copy of the image, relative to the image
+    /// the visible area `crop` belongs to. Copies may stick out of the visible area.
+    fn origins(&self, crop: Rect) -> Vec<(i64, i64)> {
+        let (x, y, width, height) = (
+            crop.x as i64,
+            crop.y as i64,
+            crop.width as i64,
+            crop.height as i64,
+        );
+        let (own_width, own_height) = (self.width as i64, self.height as i64);
+        match self.placement {
+            Placement::Anchored(anchor) => {
+                let left = x + width - own_width;
+                let top = y + height - own_height;
+                vec![match anchor {
+                    Anchor::TopLeft => (x, y),
+                    Anchor::TopRight => (left, y),
+                    Anchor::BottomLeft => (x, top),
+                    Anchor::BottomRight => (left, top),
+                    Anchor::Center => (x + (width - own_width) / 2, y + (height - own_height) / 2),
+                }]
+            }
+            Placement::Tiled if own_width == 0 || own_height == 0 => vec![],
+            Placement::Tiled => {
+                let mut origins = vec![];
+                for row in (y..y + height).step_by(self.height) {
+                    for column in (x..x + width).step_by(self.width) {
+                        origins.push((column, row));
+                    }
+                }
+                origins
+            }
+        }
+    }
+
+    /// Composites the overlay onto the visible area `crop` of the image, with black in the
+    /// overlay at `black_level` and white at `white_level`. Nothing outside of `crop` changes.
+    pub fn draw(&self, img: &mut RawBuffer, crop: Rect, black_level: u16, white_level: u16) {
+        self.composite(img, crop, black_level, white_level, None);
+    }
+
+    /// Like `draw`, with the levels of the overlay on green photosites and the values of the
+    /// others set for the overlay to develop neutral grey
+    pub fn draw_neutral(&self, img: &mut RawBuffer, crop: Rect, neutral: &Neutral) {
+        let (black_level, white_level) = (neutral.black_level, neutral.white_level);
+        self.composite(img, crop, black_level, white_level, Some(neutral));
+    }
+
+    fn composite(
+        &self,
+        img: &mut RawBuffer,
+        crop: Rect,
+        black_level: u16,
+        white_level: u16,
+        neutral: Option<&Neutral>,
+    ) {
+        let range = white_level.saturating_sub(black_level) as f32;
+        let opacity = self.opacity.clamp(0.0, 1.0);
+        let rows = crop.y as i64..(crop.y + crop.height).min(img.height() as usize) as i64;
+        let columns = crop.x as i64..(crop.x + crop.width).min(img.width() as usize) as i64;
+        for (left, top) in self.origins(crop) {
+            for (row, pixels) in self.pixels.chunks(self.width.max(1)).enumerate() {
+                let img_y = top + row as i64;
+                if !rows.contains(&img_y) {
+                    continue;
+                }
+                for (column, (level, alpha)) in pixels.iter().enumerate() {
+                    let img_x = left + column as i64;
+                    let v = alpha * opacity;
+                    if v == 0.0 || !columns.contains(&img_x) {
+                        continue;
+                    }
+                    let (img_x, img_y) = (img_x as u32, img_y as u32);
+                    let value = black_level as f32 + level * range;
+                    let value = match neutral {
+                        Some(neutral) => neutral.value(value, img_x, img_y),
+                        None => value,
+                    };
+                    let pixel = img.get_pixel_mut(img_x, img_y);
+                    let blended = pixel.0[0] as f32 * (1.0 - v) + value * v;
+                    pixel.0[0] = blended.round().clamp(0.0, u16::MAX as f32) as u16;
+                }
+            }
+        }
+    }
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ImageOverlay>();
+};
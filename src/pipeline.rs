@@ -0,0 +1,208 @@
+//! Producer/consumer encoding: row chunks flow through a bounded channel to a pool of encoder
+//! threads, and an ordered writer puts the encoded chunks back together. The producer needs a
+//! credit for every chunk it sends and the writer hands credits back as it writes, so no more
+//! than `queue_depth` chunks are ever in flight, no matter how large the image is.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    ops::Range,
+    slice,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use crate::progress::Control;
+use crate::rawloader::{encode_arw2_row, LookupTable};
+use crate::RawEditError;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// Number of image rows handed to a worker at once
+    pub rows_per_chunk: usize,
+    /// Number of encoder threads
+    pub workers: usize,
+    /// Capacity of each of the bounded channels
+    pub queue_depth: usize,
+}
+
+/// As many encoder threads as the rayon pool the call runs in has: one per CPU on the global
+/// pool, or the size of a pool of the caller's own that the codec is run in with
+/// `ThreadPool::install`, which is also where decoding runs.
+impl Default for PipelineConfig {
+    fn default() -> PipelineConfig {
+        let workers = rayon::current_num_threads();
+        PipelineConfig {
+            rows_per_chunk: 64,
+            workers,
+            queue_depth: 2 * workers,
+        }
+    }
+}
+
+/// Encodes the image like `encode_arw2` does, streaming the result to `out` in row order
+pub fn encode_arw2_pipelined<W: Write>(
+    img: &[u16],
+    width: usize,
+    curve: &LookupTable,
+    config: &PipelineConfig,
+    out: &mut W,
+) -> Result<(), RawEditError> {
+    encode_arw2_pipelined_with(img, width, curve, config, out, &Control::NONE)
+}
+
+/// Like `encode_arw2_pipelined`, reporting progress and checking for cancellation whenever a
+/// chunk has been written. A cancelled encode leaves `out` with the chunks written so far.
+pub fn encode_arw2_pipelined_with<W: Write>(
+    img: &[u16],
+    width: usize,
+    curve: &LookupTable,
+    config: &PipelineConfig,
+    out: &mut W,
+    control: &Control,
+) -> Result<(), RawEditError> {
+    let all = 0..img.len() / width.max(1);
+    encode_arw2_rows_pipelined_with(
+        img,
+        width,
+        slice::from_ref(&all),
+        curve,
+        config,
+        control,
+        |_, encoded| Ok(out.write_all(encoded)?),
+    )
+}
+
+/// Encodes the given ranges of rows, in order, all through the same threads: `sink` gets the
+/// encoded rows of a chunk at a time, along with which rows they are. Progress is counted in
+/// the rows of the ranges.
+///
+/// ```
+/// use raw_tiff_edit::pipeline::{encode_arw2_rows_pipelined_with, PipelineConfig};
+/// use raw_tiff_edit::progress::Control;
+/// use raw_tiff_edit::rawloader::{calculate_curve, encode_arw2};
+///
+/// let width = 64;
+/// let img: Vec<u16> = (0..width * 20).map(|i| (i * 97 % 8000) as u16).collect();
+/// let curve = calculate_curve();
+/// let config = PipelineConfig {
+///     rows_per_chunk: 3,
+///     ..PipelineConfig::default()
+/// };
+/// let mut chunks = vec![];
+/// encode_arw2_rows_pipelined_with(
+///     &img,
+///     width,
+///     &[2..9, 15..20],
+///     &curve,
+///     &config,
+///     &Control::NONE,
+///     |rows, encoded| {
+///         chunks.push((rows, encoded.to_vec()));
+///         Ok(())
+///     },
+/// )?;
+/// let rows: Vec<_> = chunks.iter().map(|(rows, _)| rows.clone()).collect();
+/// assert_eq!(rows, [2..5, 5..8, 8..9, 15..18, 18..20]);
+/// let whole = encode_arw2(&img, width, &curve)?;
+/// for (rows, encoded) in chunks {
+///     assert_eq!(encoded, whole[rows.start * width..rows.end * width]);
+/// }
+/// # Ok::<(), raw_tiff_edit::RawEditError>(())
+/// ```
+pub fn encode_arw2_rows_pipelined_with<F>(
+    img: &[u16],
+    width: usize,
+    rows: &[Range<usize>],
+    curve: &LookupTable,
+    config: &PipelineConfig,
+    control: &Control,
+    mut sink: F,
+) -> Result<(), RawEditError>
+where
+    F: FnMut(Range<usize>, &[u8]) -> Result<(), RawEditError>,
+{
+    if width == 0 || !img.len().is_multiple_of(width) {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up full rows of {}",
+            img.len(),
+            width
+        )));
+    }
+    let height = img.len() / width;
+    if let Some(rows) = rows.iter().find(|rows| rows.end > height) {
+        return Err(RawEditError::Mismatch(format!(
+            "the image has no rows {} to {}",
+            rows.start, rows.end
+        )));
+    }
+    control.check()?;
+    let total: usize = rows.iter().map(|rows| rows.len()).sum();
+    let rows_per_chunk = config.rows_per_chunk.max(1);
+    let queue_depth = config.queue_depth.max(1);
+    let chunks = rows.iter().flat_map(move |rows| {
+        rows.clone()
+            .step_by(rows_per_chunk)
+            .map(move |start| start..(start + rows_per_chunk).min(rows.end))
+    });
+
+    thread::scope(|scope| {
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(usize, Range<usize>)>(queue_depth);
+        let (encoded_tx, encoded_rx) =
+            mpsc::sync_channel::<(usize, Range<usize>, Vec<u8>)>(queue_depth);
+        let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+        let (credit_tx, credit_rx) = mpsc::sync_channel::<()>(queue_depth);
+        for _ in 0..queue_depth {
+            credit_tx.send(()).unwrap();
+        }
+
+        for _ in 0..config.workers.max(1) {
+            let chunk_rx = Arc::clone(&chunk_rx);
+            let encoded_tx = encoded_tx.clone();
+            scope.spawn(move || loop {
+                let next = chunk_rx.lock().unwrap().recv();
+                let (index, rows) = match next {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // the workers are the parallelism, so each encodes its rows one by one
+                let encoded = img[rows.start * width..rows.end * width]
+                    .chunks(width)
+                    .flat_map(|row| encode_arw2_row(row, curve))
+                    .collect();
+                if encoded_tx.send((index, rows, encoded)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(encoded_tx);
+
+        scope.spawn(move || {
+            for job in chunks.enumerate() {
+                if credit_rx.recv().is_err() || chunk_tx.send(job).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Chunks can arrive out of order; hold on to the early ones until their turn comes.
+        // The credits limit how many can pile up here.
+        let mut pending = BTreeMap::new();
+        let mut next_index = 0;
+        let mut done = 0;
+        for (index, rows, encoded) in encoded_rx {
+            pending.insert(index, (rows, encoded));
+            while let Some((rows, encoded)) = pending.remove(&next_index) {
+                done += rows.len();
+                sink(rows, &encoded)?;
+                next_index += 1;
+                control.report(done, total);
+                // dropping the channels on the way out stops the workers and the producer
+                control.check()?;
+                // the producer may already be gone, which is fine
+                let _ = credit_tx.send(());
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,210 @@
+use std::{fmt, io};
+
+use image::{imageops, jpeg::JPEGEncoder, ColorType, ImageError, ImageFormat, RgbImage};
+
+use crate::edit::TextEdit;
+use crate::tiff::{self, Tiff};
+
+/// Qualities tried in turn until the re-encoded JPEG fits into the space of the original
+const QUALITIES: [u8; 5] = [90, 80, 70, 60, 50];
+
+/// An embedded JPEG rendition (preview or thumbnail) of the raw image
+#[derive(Debug, Clone, Copy)]
+pub struct Rendition {
+    pub offset: usize,
+    pub length: usize,
+    /// Position of the JPEGInterchangeFormatLength value, updated when rewriting
+    length_pos: usize,
+    /// TIFF Orientation of the rendition, from its own IFD or IFD 0
+    pub orientation: u32,
+    /// Position of the Orientation value the orientation comes from, if there is one
+    orientation_pos: Option<usize>,
+}
+
+/// Turns an image as stored into the way it is displayed, as described by a TIFF Orientation
+/// value: 2 to 4 mirror or turn it around, 5 to 8 swap its axes
+pub fn to_display(img: &RgbImage, orientation: u32) -> RgbImage {
+    match orientation {
+        2 => imageops::flip_horizontal(img),
+        3 => imageops::rotate180(img),
+        4 => imageops::flip_vertical(img),
+        5 => imageops::flip_horizontal(&imageops::rotate90(img)),
+        6 => imageops::rotate90(img),
+        7 => imageops::flip_horizontal(&imageops::rotate270(img)),
+        8 => imageops::rotate270(img),
+        _ => img.clone(),
+    }
+}
+
+/// The inverse of `to_display`: turns a displayed image back into the way it is stored
+pub fn from_display(img: &RgbImage, orientation: u32) -> RgbImage {
+    match orientation {
+        6 => imageops::rotate270(img),
+        8 => imageops::rotate90(img),
+        // the others are their own inverse
+        _ => to_display(img, orientation),
+    }
+}
+
+#[derive(Debug)]
+pub enum PreviewError {
+    Image(ImageError),
+    Io(io::Error),
+    TooLarge { offset: usize, available: usize },
+}
+
+impl fmt::Display for PreviewError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreviewError::Image(err) => write!(f, "failed to process rendition: {}", err),
+            PreviewError::Io(err) => write!(f, "failed to encode rendition: {}", err),
+            PreviewError::TooLarge { offset, available } => write!(
+                f,
+                "re-encoded rendition at offset {} does not fit into {} bytes",
+                offset, available
+            ),
+        }
+    }
+}
+
+impl From<ImageError> for PreviewError {
+    fn from(err: ImageError) -> PreviewError {
+        PreviewError::Image(err)
+    }
+}
+
+impl From<io::Error> for PreviewError {
+    fn from(err: io::Error) -> PreviewError {
+        PreviewError::Io(err)
+    }
+}
+
+/// Lists the JPEG renditions referenced from the IFDs of the file
+pub fn find_renditions(buf: &[u8]) -> Vec<Rendition> {
+    let tiff = match Tiff::new(buf) {
+        Some(tiff) => tiff,
+        None => return vec![],
+    };
+    let ifd0 = tiff.ifd0();
+    tiff.ifds()
+        .iter()
+        .filter_map(|ifd| {
+            let offset = tiff.value(ifd, tiff::JPEG_INTERCHANGE_FORMAT)? as usize;
+            let length_entry = ifd
+                .entry(tiff::JPEG_INTERCHANGE_FORMAT_LENGTH)
+                .filter(|entry| entry.field_type == 4)?;
+            let length = *tiff.values(length_entry).first()? as usize;
+            if length == 0 || offset + length > buf.len() {
+                return None;
+            }
+            let orientation_entry = ifd
+                .entry(tiff::ORIENTATION)
+                .or_else(|| ifd0.as_ref()?.entry(tiff::ORIENTATION))
+                .filter(|entry| entry.field_type == 3);
+            Some(Rendition {
+                offset,
+                length,
+                length_pos: length_entry.value_pos,
+                orientation: orientation_entry
+                    .and_then(|entry| tiff.values(entry).first().copied())
+                    .unwrap_or(1),
+                orientation_pos: orientation_entry.map(|entry| entry.value_pos),
+            })
+        })
+        .collect()
+}
+
+/// Renders the edits into the rendition and writes it back in place. The edits are placed in
+/// display orientation, with the rendition turned as its Orientation says. With `upright`,
+/// the rendition is stored turned that way and its Orientation set to 1; otherwise it is
+/// turned back. The new JPEG has to fit into the space taken by the original one, so the
+/// quality is lowered until it does.
+pub fn redraw(
+    buf: &mut [u8],
+    rendition: &Rendition,
+    edits: &[TextEdit],
+    raw_width: usize,
+    raw_height: usize,
+    white_level: u16,
+    upright: bool,
+) -> Result<(), PreviewError> {
+    let data = &buf[rendition.offset..rendition.offset + rendition.length];
+    let stored = image::load_from_memory_with_format(data, ImageFormat::JPEG)?.to_rgb();
+    let mut img = to_display(&stored, rendition.orientation);
+    for edit in edits {
+        edit.draw_rendition(&mut img, raw_width, raw_height, white_level);
+    }
+    store(buf, rendition, &img, upright)
+}
+
+/// Replaces the rendition with a new picture of the raw image, `developed` in the orientation
+/// the raw data is stored in (which the rendition is stored in as well), scaled to the size of
+/// the original rendition. `upright` is as for `redraw`.
+pub fn regenerate(
+    buf: &mut [u8],
+    rendition: &Rendition,
+    developed: &RgbImage,
+    upright: bool,
+) -> Result<(), PreviewError> {
+    let data = &buf[rendition.offset..rendition.offset + rendition.length];
+    let (width, height) = image::load_from_memory_with_format(data, ImageFormat::JPEG)?
+        .to_rgb()
+        .dimensions();
+    let img = imageops::resize(developed, width, height, imageops::FilterType::Triangle);
+    store(
+        buf,
+        rendition,
+        &to_display(&img, rendition.orientation),
+        upright,
+    )
+}
+
+/// Writes a rendition, given in display orientation, over the original one
+fn store(
+    buf: &mut [u8],
+    rendition: &Rendition,
+    img: &RgbImage,
+    upright: bool,
+) -> Result<(), PreviewError> {
+    let img = if upright {
+        img.clone()
+    } else {
+        from_display(img, rendition.orientation)
+    };
+
+    let mut encoded = None;
+    for quality in QUALITIES.iter() {
+        let mut out = vec![];
+        JPEGEncoder::new_with_quality(&mut out, *quality).encode(
+            &img,
+            img.width(),
+            img.height(),
+            ColorType::RGB(8),
+        )?;
+        if out.len() <= rendition.length {
+            encoded = Some(out);
+            break;
+        }
+    }
+    let encoded = encoded.ok_or(PreviewError::TooLarge {
+        offset: rendition.offset,
+        available: rendition.length,
+    })?;
+
+    let little_endian = Tiff::new(buf).is_none_or(|tiff| tiff.is_little_endian());
+    let region = &mut buf[rendition.offset..rendition.offset + rendition.length];
+    region[..encoded.len()].copy_from_slice(&encoded);
+    for byte in &mut region[encoded.len()..] {
+        *byte = 0;
+    }
+    tiff::write_u32(
+        buf,
+        rendition.length_pos,
+        encoded.len() as u32,
+        little_endian,
+    );
+    if let (true, Some(pos)) = (upright, rendition.orientation_pos) {
+        tiff::write_u16(buf, pos, 1, little_endian);
+    }
+    Ok(())
+}
@@ -0,0 +1,138 @@
+//! Reading the raw values around a point of the visible area, for picking the levels of
+//! stamps and for checking what the decoder makes of a file.
+
+use std::fmt;
+
+use crate::raw::{CfaColor, RawImage};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// Position relative to the visible area
+    pub x: usize,
+    pub y: usize,
+    pub value: u16,
+    pub color: CfaColor,
+    /// The 11-bit code the value is stored as, for formats with a tone curve
+    pub code: Option<u16>,
+    /// Exposure relative to the white level; `None` at or below black
+    pub ev: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub black_level: u16,
+    pub white_level: u16,
+    pub samples: Vec<Sample>,
+}
+
+impl Probe {
+    /// Mean value of the samples of each colour found
+    pub fn means(&self) -> Vec<(CfaColor, f64)> {
+        [CfaColor::Red, CfaColor::Green, CfaColor::Blue]
+            .iter()
+            .filter_map(|color| {
+                let values: Vec<_> = self
+                    .samples
+                    .iter()
+                    .filter(|sample| sample.color == *color)
+                    .map(|sample| sample.value as f64)
+                    .collect();
+                if values.is_empty() {
+                    None
+                } else {
+                    Some((*color, values.iter().sum::<f64>() / values.len() as f64))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Exposure of a value above black, in stops below the white level
+fn exposure(value: f64, black_level: u16, white_level: u16) -> Option<f64> {
+    let range = white_level.saturating_sub(black_level) as f64;
+    let level = value - black_level as f64;
+    if level > 0.0 && range > 0.0 {
+        Some((level / range).log2())
+    } else {
+        None
+    }
+}
+
+fn ev_text(ev: Option<f64>) -> String {
+    ev.map_or_else(|| "black".to_owned(), |ev| format!("{:+.2} EV", ev))
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "probe ({} pixels, black level {}, white level {}):",
+            self.samples.len(),
+            self.black_level,
+            self.white_level
+        )?;
+        for sample in &self.samples {
+            let code = sample
+                .code
+                .map_or_else(|| "-".to_owned(), |code| code.to_string());
+            writeln!(
+                f,
+                "  {:5},{:<5} {:<5} {:5}  code {:>4}  {}",
+                sample.x,
+                sample.y,
+                format!("{:?}", sample.color),
+                sample.value,
+                code,
+                ev_text(sample.ev)
+            )?;
+        }
+        let means: Vec<_> = self
+            .means()
+            .into_iter()
+            .map(|(color, mean)| {
+                format!(
+                    "{:?} {:.1} ({})",
+                    color,
+                    mean,
+                    ev_text(exposure(mean, self.black_level, self.white_level))
+                )
+            })
+            .collect();
+        write!(f, "  mean: {}", means.join(", "))
+    }
+}
+
+/// Reads the pixels within `radius` of (`x`, `y`), both relative to the visible area of an
+/// image in display orientation. The window is cut off at the edges of the visible area.
+pub fn probe(image: &RawImage, x: usize, y: usize, radius: usize) -> Result<Probe, String> {
+    let crop = image.crop;
+    if x >= crop.width || y >= crop.height {
+        return Err(format!(
+            "{},{} lies outside of the {}x{} visible area",
+            x, y, crop.width, crop.height
+        ));
+    }
+    let mut samples = vec![];
+    for y in y.saturating_sub(radius)..(y + radius + 1).min(crop.height) {
+        for x in x.saturating_sub(radius)..(x + radius + 1).min(crop.width) {
+            let (column, row) = (crop.x + x, crop.y + y);
+            let value = image.pixels[row * image.width + column];
+            samples.push(Sample {
+                x,
+                y,
+                value,
+                color: image.cfa.colors[(row % 2) * 2 + column % 2],
+                code: image
+                    .curve
+                    .as_ref()
+                    .map(|curve| curve.reverse_lookup(value) >> 1),
+                ev: exposure(value as f64, image.black_level, image.white_level),
+            });
+        }
+    }
+    Ok(Probe {
+        black_level: image.black_level,
+        white_level: image.white_level,
+        samples,
+    })
+}
@@ -0,0 +1,106 @@
+//! Progress reports and cancellation for the long-running parts of the codec, for hosts (GUIs
+//! above all) that run them on a thread of their own. The `_with` variants of decoding and
+//! encoding take a `Control`; the plain ones run to the end without reporting.
+//!
+//! ```no_run
+//! use raw_tiff_edit::progress::{CancelToken, Control};
+//! use raw_tiff_edit::{RawEditError, RawImage};
+//!
+//! let token = CancelToken::new();
+//! // a clone goes to the UI thread, which calls `token.cancel()` when asked to
+//! let ui_token = token.clone();
+//! let report = |done: usize, total: usize| println!("{}/{} rows", done, total);
+//! let control = Control {
+//!     cancel: Some(&token),
+//!     progress: Some(&report),
+//! };
+//!
+//! let original = std::fs::read("DSC00001.ARW")?;
+//! match RawImage::decode_with(&original, &control) {
+//!     Ok(image) => image.save_with(&original, "DSC00001-edited.ARW", &control)?,
+//!     Err(RawEditError::Cancelled) => println!("cancelled"),
+//!     Err(err) => return Err(err.into()),
+//! }
+//! # drop(ui_token);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::RawEditError;
+
+/// Asks an operation running on another thread to stop. Clones share their state: the host
+/// keeps one and hands another to the operation, which then fails with
+/// `RawEditError::Cancelled` at the next point it checks. A token stays cancelled, so one
+/// token given to every file of a batch stops the whole batch.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Receives how much of an operation is done, in rows of the image out of its height. It is
+/// called from the codec's threads, as often as once per band of rows, so it should return
+/// quickly (by handing the numbers to the UI thread, say).
+pub trait ProgressSink: Send + Sync {
+    fn progress(&self, done: usize, total: usize);
+}
+
+impl<F: Fn(usize, usize) + Send + Sync> ProgressSink for F {
+    fn progress(&self, done: usize, total: usize) {
+        self(done, total)
+    }
+}
+
+/// What an operation reports its progress to and is cancelled by; both are optional
+#[derive(Clone, Copy, Default)]
+pub struct Control<'a> {
+    pub cancel: Option<&'a CancelToken>,
+    pub progress: Option<&'a dyn ProgressSink>,
+}
+
+impl fmt::Debug for Control<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Control")
+            .field("cancel", &self.cancel)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+impl Control<'_> {
+    /// Neither reports nor cancels
+    pub const NONE: Control<'static> = Control {
+        cancel: None,
+        progress: None,
+    };
+
+    /// Fails if the operation has been cancelled
+    pub fn check(&self) -> Result<(), RawEditError> {
+        match self.cancel {
+            Some(token) if token.is_cancelled() => Err(RawEditError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn report(&self, done: usize, total: usize) {
+        if let Some(progress) = self.progress {
+            progress.progress(done, total);
+        }
+    }
+}
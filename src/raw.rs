@@ -0,0 +1,433 @@
+//! Decoded raw data together with what is needed to interpret it: geometry, colour filter
+//! layout and calibration.
+
+use std::{io::Write, path::Path};
+
+use crate::dither;
+use crate::format::Format;
+use crate::output::OutputFile;
+use crate::pipeline;
+use crate::progress::Control;
+use crate::rawloader::cr2::{self, Cr2Layout};
+use crate::rawloader::nef::{self, NefLayout};
+use crate::rawloader::sony_lossless::{self, TileLayout};
+use crate::rawloader::{calculate_curve, LookupTable};
+use crate::source::MemorySource;
+use crate::sr2;
+use crate::tiff::{self, Ifd, Tiff};
+use crate::tiled::Rect;
+use crate::variant::{self, RawInfo, VariantError};
+use crate::RawEditError;
+
+/// Black level of the Sony sensors supported so far
+pub const DEFAULT_BLACK_LEVEL: u16 = 512;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfaColor {
+    Red,
+    Green,
+    Blue,
+}
+
+/// A 2x2 colour filter array, in row-major order starting at the top-left pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CfaPattern {
+    pub colors: [CfaColor; 4],
+}
+
+impl CfaPattern {
+    pub const RGGB: CfaPattern = CfaPattern {
+        colors: [
+            CfaColor::Red,
+            CfaColor::Green,
+            CfaColor::Green,
+            CfaColor::Blue,
+        ],
+    };
+
+    /// Reads the pattern from TIFF/EP CFARepeatPatternDim and CFAPattern values
+    pub fn from_tiff(dim: &[u32], pattern: &[u32]) -> Option<CfaPattern> {
+        if dim != [2, 2] || pattern.len() != 4 {
+            return None;
+        }
+        let mut colors = [CfaColor::Green; 4];
+        for (color, value) in colors.iter_mut().zip(pattern) {
+            *color = match value {
+                0 => CfaColor::Red,
+                1 => CfaColor::Green,
+                2 => CfaColor::Blue,
+                _ => return None,
+            };
+        }
+        Some(CfaPattern { colors })
+    }
+
+    /// The pattern as CFAPattern values (0 = red, 1 = green, 2 = blue)
+    pub fn tiff_values(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .map(|color| match color {
+                CfaColor::Red => 0,
+                CfaColor::Green => 1,
+                CfaColor::Blue => 2,
+            })
+            .collect()
+    }
+}
+
+/// How the lines of the stored raw data relate to the displayed image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readout {
+    /// Every stored line is a row of the image
+    Rows,
+    /// Every stored line is a column of the image, as written by bodies whose sensor is read
+    /// out in portrait orientation
+    Columns,
+}
+
+impl Readout {
+    /// Derives the readout from a TIFF Orientation value; orientations 5 to 8 swap the axes
+    pub fn from_orientation(orientation: u32) -> Readout {
+        match orientation {
+            5..=8 => Readout::Columns,
+            _ => Readout::Rows,
+        }
+    }
+}
+
+/// A decoded raw image
+#[derive(Debug, Clone)]
+pub struct RawImage {
+    /// Linear sensor values, row by row
+    pub pixels: Vec<u16>,
+    pub width: usize,
+    pub height: usize,
+    pub cfa: CfaPattern,
+    pub black_level: u16,
+    pub white_level: u16,
+    /// As-shot red, green and blue multipliers, normalized to green
+    pub white_balance: [f32; 3],
+    /// The tone curve the data was compressed with, for formats that use one
+    pub curve: Option<LookupTable>,
+    /// The visible part of the sensor readout, without the masked (optical black) borders
+    pub crop: Rect,
+}
+
+impl RawImage {
+    pub fn new(pixels: Vec<u16>, width: usize, height: usize, white_level: u16) -> RawImage {
+        RawImage {
+            pixels,
+            width,
+            height,
+            cfa: CfaPattern::RGGB,
+            black_level: DEFAULT_BLACK_LEVEL,
+            white_level,
+            white_balance: [1.0; 3],
+            curve: None,
+            crop: Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+        }
+    }
+
+    /// The whole sensor readout
+    pub fn full_rect(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Fills in the calibration found in the IFD holding the raw data, or else in the
+    /// SR2SubIFD (or the maker notes, for a CR2 or a NEF), keeping the current values for
+    /// anything the file doesn't specify
+    pub fn read_calibration(&mut self, tiff: &Tiff, ifd: &Ifd) {
+        let private = sr2::SubIfd::read(tiff);
+        let values = |tag| match (ifd.entry(tag), &private) {
+            (Some(entry), _) => tiff.values(entry),
+            (None, Some(private)) => private.values(tag),
+            (None, None) => vec![],
+        };
+        if let Some(cfa) = CfaPattern::from_tiff(
+            &values(tiff::CFA_REPEAT_PATTERN_DIM),
+            &values(tiff::CFA_PATTERN),
+        ) {
+            self.cfa = cfa;
+        }
+        if let Some(black_level) = values(tiff::SONY_BLACK_LEVEL).first() {
+            self.black_level = *black_level as u16;
+        }
+        let white_level = values(tiff::SONY_WHITE_LEVEL)
+            .first()
+            .or(values(tiff::SR2_WHITE_LEVEL).first())
+            .copied();
+        if let Some(white_level) = white_level {
+            self.white_level = white_level as u16;
+        }
+        let levels = values(tiff::SONY_WB_RGGB_LEVELS);
+        if levels.len() == 4 && levels[1] != 0 {
+            let green = levels[1] as f32;
+            self.white_balance = [levels[0] as f32 / green, 1.0, levels[3] as f32 / green];
+        }
+
+        // DNG-style ActiveArea (top, left, bottom, right) with a DefaultCrop inside of it take
+        // precedence over Sony's own crop tags ((top, left) and (width, height))
+        let mut crop = self.full_rect();
+        let mut cropped = false;
+        if let [top, left, bottom, right] = values(tiff::ACTIVE_AREA)[..] {
+            crop = Rect {
+                x: left as usize,
+                y: top as usize,
+                width: right.saturating_sub(left) as usize,
+                height: bottom.saturating_sub(top) as usize,
+            };
+            cropped = true;
+        }
+        if let ([x, y], [width, height]) = (
+            &values(tiff::DEFAULT_CROP_ORIGIN)[..],
+            &values(tiff::DEFAULT_CROP_SIZE)[..],
+        ) {
+            crop = Rect {
+                x: crop.x + *x as usize,
+                y: crop.y + *y as usize,
+                width: *width as usize,
+                height: *height as usize,
+            };
+            cropped = true;
+        }
+        if let (false, [top, left], [width, height]) = (
+            cropped,
+            &values(tiff::SONY_CROP_TOP_LEFT)[..],
+            &values(tiff::SONY_CROP_SIZE)[..],
+        ) {
+            crop = Rect {
+                x: *left as usize,
+                y: *top as usize,
+                width: *width as usize,
+                height: *height as usize,
+            };
+        }
+        // ignore crops that don't fit, rather than indexing past the image later
+        if crop.x + crop.width <= self.width && crop.y + crop.height <= self.height {
+            self.crop = crop;
+        }
+        if cr2::is_cr2(tiff.data()) {
+            cr2::read_calibration(tiff, self);
+        } else if nef::is_nef(tiff) {
+            nef::read_calibration(tiff, self);
+        }
+    }
+
+    /// Swaps the axes of the image, along with its CFA pattern
+    pub fn transposed(&self) -> RawImage {
+        let mut pixels = Vec::with_capacity(self.pixels.len());
+        for x in 0..self.width {
+            pixels.extend((0..self.height).map(|y| self.pixels[y * self.width + x]));
+        }
+        let [a, b, c, d] = self.cfa.colors;
+        RawImage {
+            pixels,
+            width: self.height,
+            height: self.width,
+            cfa: CfaPattern {
+                colors: [a, c, b, d],
+            },
+            curve: self.curve.clone(),
+            crop: self.crop.transposed(),
+            ..*self
+        }
+    }
+
+    /// Converts between the stored and the displayed layout; the conversion is its own inverse
+    pub fn reoriented(&self, readout: Readout) -> RawImage {
+        match readout {
+            Readout::Rows => self.clone(),
+            Readout::Columns => self.transposed(),
+        }
+    }
+}
+
+/// Finds the raw data of a file and its format, making sure the codec can handle it
+fn locate(file: &[u8]) -> Result<(RawInfo, Format), RawEditError> {
+    let info = variant::detect(file, None)?
+        .ok_or_else(|| VariantError::Unsupported("no raw data found".to_owned()))?;
+    let format = Format::detect(&info, dither::camera)?;
+    format.check(&info)?;
+    if info.offset + format.data_len(info.width, info.height) > file.len() {
+        return Err(RawEditError::Mismatch(
+            "the raw data runs past the end of the file".to_owned(),
+        ));
+    }
+    Ok((info, format))
+}
+
+impl RawImage {
+    fn check_pixels(&self) -> Result<(), RawEditError> {
+        if self.pixels.len() != self.width * self.height {
+            return Err(RawEditError::Mismatch(format!(
+                "{} pixels don't make up a {}x{} image",
+                self.pixels.len(),
+                self.width,
+                self.height
+            )));
+        }
+        Ok(())
+    }
+
+    /// Decodes the raw data of an ARW file, compressed (dithered the way the camera does it)
+    /// or not, along with its calibration. The image is in the stored layout; see
+    /// `reoriented`.
+    pub fn decode(file: &[u8]) -> Result<RawImage, RawEditError> {
+        RawImage::decode_with(file, &Control::NONE)
+    }
+
+    /// Like `decode`, reporting progress and checking for cancellation
+    pub fn decode_with(file: &[u8], control: &Control) -> Result<RawImage, RawEditError> {
+        let (info, format) = locate(file)?;
+        let mut image = format.decode_from_with(
+            &mut MemorySource::new(file),
+            info.offset as u64,
+            info.width,
+            info.height,
+            control,
+        )?;
+        if let Some(tiff) = Tiff::new(file) {
+            if let Some((ifd, _)) = tiff.read_ifd(info.ifd_offset) {
+                image.read_calibration(&tiff, &ifd);
+            }
+        }
+        Ok(image)
+    }
+
+    /// Compresses the pixels into ARW2 data, `width` bytes per row, through the tone curve the
+    /// image was decoded with (the default one for an image made up from scratch)
+    pub fn encode(&self) -> Result<Vec<u8>, RawEditError> {
+        self.encode_with(&Control::NONE)
+    }
+
+    /// Like `encode`, reporting progress and checking for cancellation
+    pub fn encode_with(&self, control: &Control) -> Result<Vec<u8>, RawEditError> {
+        self.check_pixels()?;
+        let mut data = vec![0; self.width * self.height];
+        let curve = self.curve.clone().unwrap_or_else(calculate_curve);
+        pipeline::encode_arw2_pipelined_with(
+            &self.pixels,
+            self.width,
+            &curve,
+            &pipeline::PipelineConfig::default(),
+            &mut &mut data[..],
+            control,
+        )?;
+        Ok(data)
+    }
+
+    /// Writes a copy of `original`, the file the image was decoded from, with its raw data
+    /// replaced by the image, in the format of the original. The file is written atomically.
+    /// Only the bytes of the original are known here, so any file already at `path` is taken
+    /// for the original and left alone, with an `OutputError::WouldOverwriteOriginal`;
+    /// `save_with_output` can name the originals and replace other files.
+    pub fn save<P: AsRef<Path>>(&self, original: &[u8], path: P) -> Result<(), RawEditError> {
+        self.save_with(original, path, &Control::NONE)
+    }
+
+    /// Like `save`, reporting progress and checking for cancellation; nothing is written if
+    /// the encode is cancelled
+    pub fn save_with<P: AsRef<Path>>(
+        &self,
+        original: &[u8],
+        path: P,
+        control: &Control,
+    ) -> Result<(), RawEditError> {
+        self.save_with_output(original, &OutputFile::new(path), control)
+    }
+
+    /// Like `save_with`, writing through `output`, which refuses to write over the originals
+    /// it protects, or over any existing file unless it is told to replace them:
+    ///
+    /// ```no_run
+    /// use raw_tiff_edit::output::OutputFile;
+    /// use raw_tiff_edit::progress::Control;
+    /// use raw_tiff_edit::RawImage;
+    ///
+    /// let original = std::fs::read("DSC00001.ARW")?;
+    /// let image = RawImage::decode(&original)?;
+    /// let output = OutputFile::new("DSC00001.ARW")
+    ///     .protect("DSC00001.ARW")
+    ///     .replace_existing(true);
+    /// assert!(image
+    ///     .save_with_output(&original, &output, &Control::NONE)
+    ///     .is_err());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn save_with_output(
+        &self,
+        original: &[u8],
+        output: &OutputFile,
+        control: &Control,
+    ) -> Result<(), RawEditError> {
+        let file = self.encode_file_with(original, control)?;
+        output.write_with(|out| out.write_all(&file))?;
+        Ok(())
+    }
+
+    /// A copy of `original`, the file the image was decoded from, with its raw data replaced
+    /// by the image, as `save` writes it
+    pub fn encode_file(&self, original: &[u8]) -> Result<Vec<u8>, RawEditError> {
+        self.encode_file_with(original, &Control::NONE)
+    }
+
+    /// Like `encode_file`, reporting progress and checking for cancellation
+    pub fn encode_file_with(
+        &self,
+        original: &[u8],
+        control: &Control,
+    ) -> Result<Vec<u8>, RawEditError> {
+        let (info, format) = locate(original)?;
+        if (info.width, info.height) != (self.width, self.height) {
+            return Err(RawEditError::Mismatch(format!(
+                "the image is {}x{}, but the raw data of the file is {}x{}",
+                self.width, self.height, info.width, info.height
+            )));
+        }
+        self.check_pixels()?;
+        let mut file = original.to_vec();
+        match format {
+            Format::SonyLossless { ifd_offset } => {
+                let layout = TileLayout::read(&file, ifd_offset)?;
+                let tiles: Vec<_> = (0..layout.tiles_across() * layout.tiles_down()).collect();
+                control.check()?;
+                sony_lossless::write_tiles(&mut file, &layout, &self.pixels, &tiles)?;
+                control.report(self.height, self.height);
+            }
+            Format::Cr2 { ifd_offset } => {
+                let layout = Cr2Layout::read(&file, ifd_offset)?;
+                control.check()?;
+                cr2::write(&mut file, &layout, &self.pixels)?;
+                control.report(self.height, self.height);
+            }
+            Format::Nef { ifd_offset, dither } => {
+                let layout = NefLayout::read(&file, ifd_offset)?;
+                control.check()?;
+                nef::write(&mut file, &layout, &self.pixels, dither)?;
+                control.report(self.height, self.height);
+            }
+            _ => {
+                let end = info.offset + format.data_len(info.width, info.height);
+                let out = &mut file[info.offset..end];
+                format.encode_into_with(&self.pixels, self.width, out, control)?;
+            }
+        }
+        Ok(file)
+    }
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RawImage>();
+};
@@ -1,14 +1,61 @@
+//! Sony ARW2 codec and the bit pumps it is built on, along with the uncompressed layouts
+//! (14-bit samples in 16-bit words, and 12-bit packed) that some bodies write instead.
+//!
+//! The decoding side never panics on malformed input: the bit pumps read zeros past the end
+//! of their buffer, requests for more than 32 bits are clamped, and blocks that don't fit the
+//! row are dropped instead of indexing out of bounds. Corrupt data decodes into garbage
+//! pixels, not a crash. Like the rest of the crate, none of this uses `unsafe`.
+//!
+//! The codec has no global state. The curve, the pumps and the decoded data are plain values
+//! that are `Send + Sync`, so any number of threads can decode and encode at the same time,
+//! sharing a `LookupTable` or each using their own.
+
 use std::cmp;
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use rayon::prelude::*;
+
+use crate::dither::{DitherSource, Factory};
+use crate::progress::Control;
+use crate::source::ByteSource;
+use crate::tiled::Rect;
+use crate::RawEditError;
+
+mod bits;
+pub mod blocks;
+pub mod cr2;
+pub mod ljpeg;
+pub mod nef;
+pub mod sony_lossless;
+mod strip;
+
+pub use self::bits::{
+    BEu32_padded, BitPump, BitPumpLSB, BitPumpMSB, BitWriter, BitWriterLSB, BitWriterMSB,
+    LEu32_padded,
+};
+pub use self::strip::Strip;
+
+/// A tone curve as the decoder applies it: every entry decodes to a value dithered between the
+/// midpoints to its neighbours, and every value maps back to the entry it decodes from
 #[derive(Debug, Clone)]
 pub struct LookupTable {
     table: Vec<(u16, u16, u16)>,
+    /// The entry every value maps back to, up to the largest value any entry decodes to
+    inverse: Vec<u16>,
 }
 
 impl LookupTable {
+    /// The table of an ARW2 curve, of which only the even entries are stored
     pub fn new(table: &[u16]) -> LookupTable {
+        LookupTable::with_stride(table, 2)
+    }
+
+    /// The table of a rising curve of which only every `stride`-th entry is stored, every one
+    /// for NEF's. The dither spreads an entry over a quarter of the way to the entries next to
+    /// it for every entry between stored ones, so the values of stored entries never overlap.
+    pub fn with_stride(table: &[u16], stride: usize) -> LookupTable {
+        let stride = stride.max(1);
         let mut tbl = vec![(0, 0, 0); table.len()];
         for i in 0..table.len() {
             let center = table[i];
@@ -18,242 +65,607 @@ impl LookupTable {
             } else {
                 center
             };
+            let delta = ((upper - lower) as usize * stride / 2) as u16;
             let base = if center == 0 {
                 0
             } else {
-                center - ((upper - lower + 2) / 4)
+                center.saturating_sub((delta + 2) / 4)
             };
-            let delta = upper - lower;
             tbl[i] = (center, base, delta);
         }
-        LookupTable { table: tbl }
+        let inverse = LookupTable::invert(&tbl, stride);
+        LookupTable {
+            table: tbl,
+            inverse,
+        }
+    }
+
+    /// Lowest and highest value the entry decodes to, over every dither value
+    fn decoded_range(entry: (u16, u16, u16)) -> (usize, usize) {
+        let (_, base, delta) = entry;
+        let spread = (delta as usize * 2047 + 1024) >> 12;
+        (base as usize, base as usize + spread)
+    }
+
+    /// Only the stored entries (the even ones, for ARW2's 11-bit codes) are mapped to. A
+    /// value maps to the entry it decodes from, whatever the dither, and any other value to
+    /// the entry with the nearest center (the higher one on a tie).
+    fn invert(table: &[(u16, u16, u16)], stride: usize) -> Vec<u16> {
+        let top = table
+            .iter()
+            .map(|entry| LookupTable::decoded_range(*entry).1.max(entry.0 as usize))
+            .max()
+            .unwrap_or(0);
+        let mut inverse = vec![0u16; top + 1];
+        let distance = |entry: usize, value: usize| (table[entry].0 as usize).abs_diff(value);
+        // the nearest center only ever moves up as the value does
+        let mut nearest = 0;
+        for (value, entry) in inverse.iter_mut().enumerate() {
+            while nearest + stride < table.len()
+                && distance(nearest + stride, value) <= distance(nearest, value)
+            {
+                nearest += stride;
+            }
+            *entry = nearest as u16;
+        }
+        for i in (0..table.len()).step_by(stride) {
+            let (lowest, highest) = LookupTable::decoded_range(table[i]);
+            for entry in &mut inverse[lowest..=highest] {
+                *entry = i as u16;
+            }
+        }
+        inverse
+    }
+
+    /// The curve the table was made from, as `new` takes it
+    pub fn centers(&self) -> Vec<u16> {
+        self.table.iter().map(|entry| entry.0).collect()
+    }
+
+    /// The largest value the curve can produce
+    pub fn max_value(&self) -> u16 {
+        self.table.last().map(|entry| entry.0).unwrap_or(0)
     }
 
     #[inline(always)]
-    pub fn dither(&self, value: u16, rand: &mut u32) -> u16 {
+    pub fn dither<D: DitherSource + ?Sized>(&self, value: u16, rand: &mut D) -> u16 {
         let (_, sbase, sdelta) = self.table[value as usize];
         let base = sbase as u32;
         let delta = sdelta as u32;
-        let pixel = base + ((delta * (*rand & 2047) + 1024) >> 12);
-        *rand = 15700 * (*rand & 65535) + (*rand >> 16);
+        let pixel = base + ((delta * rand.next_dither() + 1024) >> 12);
         pixel as u16
     }
 
+    /// The (stored) entry a value is encoded as. Every value an entry decodes to maps back to
+    /// that entry, so decoded data encodes to the same codes again:
+    ///
+    /// ```
+    /// use raw_tiff_edit::dither::{CameraDither, DitherSource};
+    /// use raw_tiff_edit::rawloader::calculate_curve;
+    ///
+    /// let curve = calculate_curve();
+    /// let mut dither = CameraDither::default();
+    /// dither.reseed(0x1234);
+    /// for code in 0..0x800u16 {
+    ///     for _ in 0..16 {
+    ///         let value = curve.dither(code << 1, &mut dither);
+    ///         assert_eq!(curve.reverse_lookup(value) >> 1, code);
+    ///     }
+    /// }
+    /// ```
     #[inline(always)]
     pub fn reverse_lookup(&self, value: u16) -> u16 {
-        let start_index = match self.table.binary_search_by_key(&value, |entry| entry.1) {
-            Ok(i) => i,
-            Err(i) => i,
+        match self.inverse.get(value as usize) {
+            Some(entry) => *entry,
+            None => self.inverse.last().copied().unwrap_or(0),
         }
-        .saturating_sub(RANGE);
-        const RANGE: usize = 2;
-        let end_index = cmp::min(start_index + 2 * RANGE, self.table.len() - 1);
-        let entry = self.table[start_index..end_index]
-            .iter()
-            .enumerate()
-            .min_by_key(|(_, (center, _, _))| {
-                let center = *center as i32;
-                (center - value as i32).abs()
-            })
-            .map(|(i, _)| i + start_index)
-            .unwrap();
-        entry as u16
     }
 }
 
-pub fn calculate_curve() -> LookupTable {
-    let centry = [8000, 10400, 12900, 14100];
-    let mut curve: [usize; 6] = [0, 0, 0, 0, 0, 4095];
+/// Sony's tone curve, given by the four 14-bit values (SonyToneCurve, 0x7010) at which the
+/// step between consecutive codes doubles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToneCurve {
+    pub knots: [u16; 4],
+}
 
-    for i in 0..4 {
-        curve[i + 1] = ((centry[i] >> 2) & 0xfff) as usize;
-    }
+impl ToneCurve {
+    /// The curve of the bodies the codec was first written for, taken for files that don't
+    /// carry their own
+    pub const DEFAULT: ToneCurve = ToneCurve {
+        knots: [8000, 10400, 12900, 14100],
+    };
 
-    let mut out = vec![0 as u16; curve[5] + 1];
-    for i in 0..5 {
-        for j in (curve[i] + 1)..(curve[i + 1] + 1) {
-            out[j] = out[j - 1] + (1 << i);
+    /// A curve with the given knots, if they rise and stay within 14 bits
+    pub fn new(knots: [u16; 4]) -> Option<ToneCurve> {
+        if knots.windows(2).all(|pair| pair[0] <= pair[1]) && knots[3] <= 0x3fff {
+            Some(ToneCurve { knots })
+        } else {
+            None
         }
     }
 
-    LookupTable::new(&out)
-}
+    pub fn table(self) -> LookupTable {
+        let mut curve: [usize; 6] = [0, 0, 0, 0, 0, 4095];
 
-#[derive(Debug, Copy, Clone)]
-pub struct BitPumpLSB<'a> {
-    buffer: &'a [u8],
-    pos: usize,
-    bits: u64,
-    nbits: u32,
-}
+        for i in 0..4 {
+            curve[i + 1] = ((self.knots[i] >> 2) & 0xfff) as usize;
+        }
 
-impl<'a> BitPumpLSB<'a> {
-    pub fn new(src: &'a [u8]) -> BitPumpLSB {
-        BitPumpLSB {
-            buffer: src,
-            pos: 0,
-            bits: 0,
-            nbits: 0,
+        let mut out = vec![0u16; curve[5] + 1];
+        for i in 0..5 {
+            for j in (curve[i] + 1)..(curve[i + 1] + 1) {
+                out[j] = out[j - 1] + (1 << i);
+            }
         }
+
+        LookupTable::new(&out)
     }
+}
 
-    #[inline(always)]
-    pub fn peek_bits(&mut self, num: u32) -> u32 {
-        if num > self.nbits {
-            let inbits: u64 = LEu32(self.buffer, self.pos) as u64;
-            self.bits = ((inbits << 32) | (self.bits << (32 - self.nbits))) >> (32 - self.nbits);
-            self.pos += 4;
-            self.nbits += 32;
-        }
-        (self.bits & (0x0ffffffffu64 >> (32 - num))) as u32
+impl Default for ToneCurve {
+    fn default() -> ToneCurve {
+        ToneCurve::DEFAULT
     }
+}
 
-    #[inline(always)]
-    pub fn consume_bits(&mut self, num: u32) {
-        self.nbits -= num;
-        self.bits >>= num;
+/// The table of the default tone curve
+pub fn calculate_curve() -> LookupTable {
+    ToneCurve::DEFAULT.table()
+}
+
+/// Rows of compressed data read from the source at a time, and then decoded in parallel
+const DECODE_BAND_ROWS: usize = 64;
+
+/// Decodes ARW2 data read from a source through the file's tone curve, keeping only a band
+/// of rows of compressed data in memory at a time. The rows of a band are decoded in
+/// parallel, each thread with its own generator from `dither`, which is reseeded at the start
+/// of every row; the result doesn't depend on how the rows are spread over the threads.
+pub fn decode_arw2_from<S: ByteSource>(
+    src: &mut S,
+    offset: u64,
+    width: usize,
+    height: usize,
+    curve: &LookupTable,
+    dither: Factory,
+) -> Result<Vec<u16>, RawEditError> {
+    decode_arw2_with(src, offset, width, height, curve, dither, &Control::NONE)
+}
+
+/// Like `decode_arw2_from`, checking for cancellation before every band of rows and
+/// reporting progress after it
+pub fn decode_arw2_with<S: ByteSource>(
+    src: &mut S,
+    offset: u64,
+    width: usize,
+    height: usize,
+    curve: &LookupTable,
+    dither: Factory,
+    control: &Control,
+) -> Result<Vec<u16>, RawEditError> {
+    let size = src.size()?;
+    let mut result: Vec<u16> = vec![0; width * height];
+    if width == 0 {
+        return Ok(result);
     }
+    // The bit pump reads ahead past the end of the row, and a corrupt block (with imax ==
+    // imin) holds an extra delta, in which case the row runs into the next one
+    let row_len = width + width / 16 + 8;
+    let mut band_buf = vec![0u8; row_len * DECODE_BAND_ROWS];
 
-    #[inline(always)]
-    fn get_bits(&mut self, num: u32) -> u32 {
-        if num == 0 {
-            return 0;
+    for (band, out) in result.chunks_mut(width * DECODE_BAND_ROWS).enumerate() {
+        control.check()?;
+        let rows = out.len() / width;
+        for (i, row_buf) in band_buf.chunks_mut(row_len).take(rows).enumerate() {
+            let row = band * DECODE_BAND_ROWS + i;
+            let row_offset = offset + (row * width) as u64;
+            let available = size.saturating_sub(row_offset).min(row_len as u64) as usize;
+            if available < width {
+                return Err(RawEditError::Truncated(format!(
+                    "the raw data (row {} of {})",
+                    row + 1,
+                    height
+                )));
+            }
+            for byte in &mut row_buf[available..] {
+                *byte = 0;
+            }
+            src.read_exact_at(row_offset, &mut row_buf[..available])?;
         }
+        out.par_chunks_mut(width)
+            .zip(band_buf.par_chunks(row_len))
+            .for_each_init(dither, |dither, (out, row_buf)| {
+                decode_arw2_row(row_buf, curve, &mut **dither, out)
+            });
+        control.report(band * DECODE_BAND_ROWS + rows, height);
+    }
+
+    Ok(result)
+}
 
-        let val = self.peek_bits(num);
-        self.consume_bits(num);
+fn decode_arw2_row(
+    buf: &[u8],
+    curve: &LookupTable,
+    dither: &mut dyn DitherSource,
+    out: &mut [u16],
+) {
+    let mut pump = BitPumpLSB::new(buf);
 
-        val
+    dither.reseed(pump.peek_bits(16));
+    for out in out.chunks_mut(32) {
+        decode_arw2_group(&mut pump, curve, dither, out);
     }
 }
 
-#[allow(non_snake_case)]
-#[inline]
-pub fn LEu32(buf: &[u8], pos: usize) -> u32 {
-    LittleEndian::read_u32(&buf[pos..pos + 4])
+/// Decodes just the pixels of `roi` from ARW2 data held in memory, the same as decoding all
+/// of it would. Every row starts `width` bytes after the one before, so the rows above and
+/// below the rectangle aren't read at all; in its rows, the groups left of it are only
+/// skipped over (reading what it takes to find where the next one starts, and stepping the
+/// dither past their pixels), and the ones right of it aren't read. The rows are decoded in
+/// parallel, as by `decode_arw2_from`.
+///
+/// ```
+/// use raw_tiff_edit::dither;
+/// use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_from, decode_arw2_roi, encode_arw2};
+/// use raw_tiff_edit::source::MemorySource;
+/// use raw_tiff_edit::tiled::Rect;
+///
+/// let (width, height) = (128, 16);
+/// let curve = calculate_curve();
+/// let pixels: Vec<u16> = (0..width * height).map(|i| (i * 997 % 16000) as u16).collect();
+/// let data = encode_arw2(&pixels, width, &curve)?;
+/// let mut src = MemorySource::new(&data);
+/// let all = decode_arw2_from(&mut src, 0, width, height, &curve, dither::camera)?;
+///
+/// let roi = Rect { x: 40, y: 3, width: 50, height: 7 };
+/// let part = decode_arw2_roi(&data, width, height, roi, &curve, dither::camera)?;
+/// for (y, row) in part.chunks(roi.width).enumerate() {
+///     let start = (roi.y + y) * width + roi.x;
+///     assert_eq!(row, &all[start..start + roi.width]);
+/// }
+/// # Ok::<(), raw_tiff_edit::RawEditError>(())
+/// ```
+pub fn decode_arw2_roi(
+    buf: &[u8],
+    width: usize,
+    height: usize,
+    roi: Rect,
+    curve: &LookupTable,
+    dither: Factory,
+) -> Result<Vec<u16>, RawEditError> {
+    if roi.x + roi.width > width || roi.y + roi.height > height {
+        return Err(RawEditError::Mismatch(format!(
+            "a {}x{} region at ({}, {}) doesn't fit into a {}x{} image",
+            roi.width, roi.height, roi.x, roi.y, width, height
+        )));
+    }
+    let mut result = vec![0u16; roi.width * roi.height];
+    if roi.width == 0 {
+        return Ok(result);
+    }
+    if buf.len() < (roi.y + roi.height) * width {
+        return Err(RawEditError::Truncated("the raw data".to_owned()));
+    }
+    let first_group = roi.x / 32;
+    let last_group = (roi.x + roi.width).div_ceil(32);
+    result
+        .par_chunks_mut(roi.width)
+        .enumerate()
+        .for_each_init(dither, |dither, (y, out)| {
+            let mut pump = BitPumpLSB::new(&buf[(roi.y + y) * width..]);
+            dither.reseed(pump.peek_bits(16));
+            for _ in 0..first_group {
+                skip_arw2_group(&mut pump, &mut **dither);
+            }
+            let mut group_pixels = [0u16; 32];
+            for group in first_group..last_group {
+                decode_arw2_group(&mut pump, curve, &mut **dither, &mut group_pixels);
+                let start = (group * 32).max(roi.x);
+                let end = (group * 32 + 32).min(roi.x + roi.width);
+                out[start - roi.x..end - roi.x]
+                    .copy_from_slice(&group_pixels[start - group * 32..end - group * 32]);
+            }
+        });
+    Ok(result)
 }
 
-struct ReverseBitPump {
-    data: Vec<u8>,
-    bits: u64,
-    n_bits: u8,
+/// Moves the pump and the dither past a group of 32 pixels without decoding it
+fn skip_arw2_group(pump: &mut BitPumpLSB, random: &mut dyn DitherSource) {
+    for _ in 0..2 {
+        pump.get_bits(22);
+        let imax = pump.get_bits(4);
+        let imin = pump.get_bits(4);
+        let deltas = if imax == imin { 15 } else { 14 };
+        for _ in 0..deltas {
+            pump.get_bits(7);
+        }
+        for _ in 0..16 {
+            random.next_dither();
+        }
+    }
 }
 
-impl ReverseBitPump {
-    fn new() -> Self {
-        Self {
-            data: vec![],
-            bits: 0,
-            n_bits: 0,
+/// Decodes one group of 32 pixels (two interleaved blocks) from the pump. `random` is the
+/// dither generator, whose state carries over from group to group within a row. `out` may be
+/// shorter than 32 pixels for the last group of a row.
+///
+/// A block is read with a few wide reads and its 16 values are worked out lane by lane, for
+/// the compiler to vectorize; only the curve lookups and the dither, which is drawn in pixel
+/// order, stay one pixel at a time. It decodes bit for bit what `decode_arw2_group_scalar`
+/// does.
+pub fn decode_arw2_group<D: DitherSource + ?Sized>(
+    pump: &mut BitPumpLSB,
+    curve: &LookupTable,
+    random: &mut D,
+    out: &mut [u16],
+) {
+    for j in 0..2 {
+        let values = decode_arw2_block(pump);
+        for (i, &val) in values.iter().enumerate() {
+            let val = curve.dither((val << 1) as u16, random);
+            // the last block of a row that isn't a multiple of 32 pixels wide is cut short
+            if let Some(out) = out.get_mut(j + (i * 2)) {
+                *out = val;
+            }
         }
     }
+}
 
-    fn push_bits(&mut self, val: u32, n_bits: u8) {
-        self.bits |= (val as u64 & (0xFFFFFFFF >> (32 - n_bits))) << self.n_bits;
-        self.n_bits += n_bits;
-        while self.n_bits >= 8 {
-            let byte = self.bits & 0xFF;
-            self.n_bits -= 8;
-            self.bits >>= 8;
-            self.data.push(byte as u8);
-        }
+/// Reads a block of 16 pixels and works out their (code) values before the curve
+#[inline]
+fn decode_arw2_block(pump: &mut BitPumpLSB) -> [u32; 16] {
+    let max = pump.get_bits(11);
+    let min = pump.get_bits(11);
+    let delta = max.saturating_sub(min);
+    let delta_shift: u32 = cmp::max(0, (32 - (delta.leading_zeros() as i32)) - 7) as u32;
+    let imax = pump.get_bits(4) as usize;
+    let imin = pump.get_bits(4) as usize;
+
+    // the 7-bit deltas of the pixels other than the maximum and the minimum, in order; when
+    // both are the same pixel, there is one more
+    let count = if imax == imin { 15 } else { 14 };
+    let low = pump.get_bits(32) as u64 | (pump.get_bits(31) as u64) << 32;
+    let high = pump.get_bits(32) as u64 | (pump.get_bits(count * 7 - 95) as u64) << 32;
+    let mut deltas = [0u32; 16];
+    for (k, delta) in deltas.iter_mut().enumerate() {
+        let bits = if k < 9 {
+            low >> (7 * k)
+        } else {
+            high >> (7 * (k - 9))
+        };
+        *delta = bits as u32 & 0x7f;
     }
 
-    fn into_data(self) -> Vec<u8> {
-        assert_eq!(self.n_bits, 0);
-        self.data
+    let mut values = [0u32; 16];
+    for (i, value) in values.iter_mut().enumerate() {
+        let k = i - (i > imax) as usize - (imin != imax && i > imin) as usize;
+        *value = cmp::min(0x7ff, (deltas[k] << delta_shift) + min);
     }
+    values[imin] = min;
+    values[imax] = max;
+    values
 }
 
-pub fn decode_arw2(buf: &[u8], width: usize, height: usize) -> Vec<u16> {
-    let curve = calculate_curve();
-    let mut result: Vec<u16> = vec![0; width * height];
+/// `decode_arw2_group` one pixel at a time, reading every delta on its own, as the format
+/// describes the data. It is the reference the block-wise decoding is checked against.
+pub fn decode_arw2_group_scalar<D: DitherSource + ?Sized>(
+    pump: &mut BitPumpLSB,
+    curve: &LookupTable,
+    random: &mut D,
+    out: &mut [u16],
+) {
+    // Process 32 pixels at a time in interleaved fashion
+    for j in 0..2 {
+        let max = pump.get_bits(11);
+        let min = pump.get_bits(11);
+        let delta = max.saturating_sub(min);
+        // Calculate the size of the data shift needed by how large the delta is
+        // A delta with 11 bits requires a shift of 4, 10 bits of 3, etc
+        let delta_shift: u32 = cmp::max(0, (32 - (delta.leading_zeros() as i32)) - 7) as u32;
+        let imax = pump.get_bits(4) as usize;
+        let imin = pump.get_bits(4) as usize;
 
-    for (row, out) in result.chunks_mut(width).enumerate() {
-        let mut pump = BitPumpLSB::new(&buf[(row * width)..]);
-
-        let mut random = pump.peek_bits(16);
-        for out in out.chunks_mut(32) {
-            // Process 32 pixels at a time in interleaved fashion
-            for j in 0..2 {
-                let max = pump.get_bits(11);
-                let min = pump.get_bits(11);
-                let delta = max - min;
-                // Calculate the size of the data shift needed by how large the delta is
-                // A delta with 11 bits requires a shift of 4, 10 bits of 3, etc
-                let delta_shift: u32 =
-                    cmp::max(0, (32 - (delta.leading_zeros() as i32)) - 7) as u32;
-                let imax = pump.get_bits(4) as usize;
-                let imin = pump.get_bits(4) as usize;
-
-                for i in 0..16 {
-                    let val = if i == imax {
-                        max
-                    } else if i == imin {
-                        min
-                    } else {
-                        cmp::min(0x7ff, (pump.get_bits(7) << delta_shift) + min)
-                    };
-                    out[j + (i * 2)] = curve.dither((val << 1) as u16, &mut random);
-                }
+        for i in 0..16 {
+            let val = if i == imax {
+                max
+            } else if i == imin {
+                min
+            } else {
+                cmp::min(0x7ff, (pump.get_bits(7) << delta_shift) + min)
+            };
+            let val = curve.dither((val << 1) as u16, random);
+            // the last block of a row that isn't a multiple of 32 pixels wide is cut short
+            if let Some(out) = out.get_mut(j + (i * 2)) {
+                *out = val;
             }
         }
     }
+}
 
-    result
+/// Shift applied to the 7-bit deltas of a block whose (code) values span `delta`
+#[inline]
+pub fn encode_delta_shift(delta: u16) -> u32 {
+    cmp::max(0, (16 - (delta.leading_zeros() as i32)) - 7) as u32
+}
+
+/// Encodes full rows of `width` pixels through the tone curve the data is to be decoded with;
+/// every row takes `width` bytes. Decoded data encodes to the same bytes again, as long as the
+/// blocks place their maximum and minimum where this encoder would, which it does for data
+/// it wrote itself:
+///
+/// ```
+/// use raw_tiff_edit::dither;
+/// use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_from, encode_arw2};
+/// use raw_tiff_edit::source::MemorySource;
+///
+/// let (width, height) = (64, 4);
+/// let curve = calculate_curve();
+/// let pixels: Vec<u16> = (0..width * height).map(|i| (i * 997 % 16000) as u16).collect();
+/// let encoded = encode_arw2(&pixels, width, &curve)?;
+/// let decoded = decode_arw2_from(
+///     &mut MemorySource::new(&encoded),
+///     0,
+///     width,
+///     height,
+///     &curve,
+///     dither::camera,
+/// )?;
+/// assert_eq!(encode_arw2(&decoded, width, &curve)?, encoded);
+/// # Ok::<(), raw_tiff_edit::RawEditError>(())
+/// ```
+pub fn encode_arw2(
+    img: &[u16],
+    width: usize,
+    curve: &LookupTable,
+) -> Result<Vec<u8>, RawEditError> {
+    if width == 0 || !img.len().is_multiple_of(width) {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up full rows of {}",
+            img.len(),
+            width
+        )));
+    }
+    // the rows are independent of each other, so they are encoded in parallel
+    let rows: Vec<Vec<u8>> = img
+        .par_chunks(width)
+        .map(|row| encode_arw2_row(row, curve))
+        .collect();
+    Ok(rows.concat())
+}
+
+/// Encodes one row, on the calling thread
+pub(crate) fn encode_arw2_row(row: &[u16], curve: &LookupTable) -> Vec<u8> {
+    row.chunks(32)
+        .flat_map(|block| encode_arw2_block(block, curve))
+        .collect()
 }
 
-pub fn encode_arw2(img: &[u16], width: usize) -> Vec<u8> {
-    let curve = calculate_curve();
-    let mut result: Vec<u8> = vec![];
-
-    for input in img.chunks(width) {
-        for input in input.chunks(32) {
-            let mut pump = ReverseBitPump::new();
-            let vals: Vec<_> = input
-                .iter()
-                .map(|value| curve.reverse_lookup(*value) >> 1)
-                .collect();
-            for j in 0..2 {
-                let (mut imax, max) = vals
-                    .iter()
-                    .enumerate()
-                    .filter(|(i, _)| *i % 2 == j)
-                    .max_by_key(|v| v.1)
-                    .unwrap();
-                let (mut imin, min) = vals
-                    .iter()
-                    .enumerate()
-                    .filter(|(i, _)| *i % 2 == j)
-                    .min_by_key(|v| v.1)
-                    .unwrap();
-                imax /= 2;
-                imin /= 2;
-                if imax == imin && imin > 0 {
-                    imin -= 1;
-                } else if imax == imin {
-                    imin += 1;
-                }
-                pump.push_bits((max & 0x7ff) as u32, 11);
-                pump.push_bits((min & 0x7ff) as u32, 11);
-                let delta = max - min;
-                let delta_shift: u32 =
-                    cmp::max(0, (16 - (delta.leading_zeros() as i32)) - 7) as u32;
-                pump.push_bits((imax & 0xf) as u32, 4);
-                pump.push_bits((imin & 0xf) as u32, 4);
-
-                for i in 0..16 {
-                    if i != imax && i != imin {
-                        let val = vals[2 * i + j];
-                        let val = (val - min) >> delta_shift;
-                        pump.push_bits((val & 0x7f) as u32, 7);
-                    }
-                }
+/// Encodes up to 32 pixels into a group of two interleaved blocks
+fn encode_arw2_block(input: &[u16], curve: &LookupTable) -> Vec<u8> {
+    let mut pump = BitWriterLSB::new();
+    let mut vals: Vec<_> = input
+        .iter()
+        .map(|value| curve.reverse_lookup(*value) >> 1)
+        .collect();
+    // a short block at the end of the row is padded by repeating its last pixel, which
+    // the decoder drops again
+    let last = vals.last().copied().unwrap_or(0);
+    vals.resize(32, last);
+    for j in 0..2 {
+        let (mut imax, max) = vals
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i % 2 == j)
+            .max_by_key(|v| v.1)
+            .unwrap();
+        let (mut imin, min) = vals
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i % 2 == j)
+            .min_by_key(|v| v.1)
+            .unwrap();
+        imax /= 2;
+        imin /= 2;
+        if imax == imin && imin > 0 {
+            imin -= 1;
+        } else if imax == imin {
+            imin += 1;
+        }
+        pump.push_bits((max & 0x7ff) as u32, 11);
+        pump.push_bits((min & 0x7ff) as u32, 11);
+        let delta_shift = encode_delta_shift(max - min);
+        pump.push_bits((imax & 0xf) as u32, 4);
+        pump.push_bits((imin & 0xf) as u32, 4);
+
+        for i in 0..16 {
+            if i != imax && i != imin {
+                let val = vals[2 * i + j];
+                let val = (val - min) >> delta_shift;
+                pump.push_bits((val & 0x7f) as u32, 7);
             }
-            let result_row = pump.into_data();
-            result.extend(result_row);
         }
     }
+    pump.into_data()
+}
+
+/// Largest value of uncompressed 14-bit data
+pub const UNCOMPRESSED_WHITE_LEVEL: u16 = 0x3fff;
+/// Largest value of 12-bit packed data
+pub const PACKED12_WHITE_LEVEL: u16 = 0x0fff;
+
+/// Decodes uncompressed data: one little endian 16-bit word per pixel
+pub fn decode_arw_uncompressed(
+    src: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<Vec<u16>, RawEditError> {
+    let data = src
+        .get(..width * height * 2)
+        .ok_or_else(|| RawEditError::Truncated("the uncompressed raw data".to_owned()))?;
+    Ok(data.chunks_exact(2).map(LittleEndian::read_u16).collect())
+}
 
+/// Encodes pixels as uncompressed data, clamping them to 14 bits
+pub fn encode_arw_uncompressed(img: &[u16]) -> Vec<u8> {
+    let mut result = vec![0; img.len() * 2];
+    for (out, value) in result.chunks_exact_mut(2).zip(img) {
+        LittleEndian::write_u16(out, (*value).min(UNCOMPRESSED_WHITE_LEVEL));
+    }
     result
 }
+
+fn check_packed12_width(width: usize) -> Result<(), RawEditError> {
+    if width == 0 || !width.is_multiple_of(2) {
+        return Err(RawEditError::Mismatch(format!(
+            "12-bit packed rows need an even width, not {}",
+            width
+        )));
+    }
+    Ok(())
+}
+
+/// Decodes 12-bit packed data, where every three bytes hold two pixels, low bits first
+pub fn decode_arw_packed12(
+    src: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<Vec<u16>, RawEditError> {
+    check_packed12_width(width)?;
+    let data = src
+        .get(..width * height * 3 / 2)
+        .ok_or_else(|| RawEditError::Truncated("the 12-bit packed raw data".to_owned()))?;
+    let mut pixels = Vec::with_capacity(width * height);
+    for bytes in data.chunks_exact(3) {
+        let (b0, b1, b2) = (bytes[0] as u16, bytes[1] as u16, bytes[2] as u16);
+        pixels.push(b0 | (b1 & 0x0f) << 8);
+        pixels.push(b1 >> 4 | b2 << 4);
+    }
+    Ok(pixels)
+}
+
+/// Encodes full rows of `width` pixels as 12-bit packed data, clamping them to 12 bits
+pub fn encode_arw_packed12(img: &[u16], width: usize) -> Result<Vec<u8>, RawEditError> {
+    check_packed12_width(width)?;
+    if !img.len().is_multiple_of(width) {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up full rows of {}",
+            img.len(),
+            width
+        )));
+    }
+    let mut result = Vec::with_capacity(img.len() * 3 / 2);
+    for pair in img.chunks_exact(2) {
+        let a = pair[0].min(PACKED12_WHITE_LEVEL);
+        let b = pair[1].min(PACKED12_WHITE_LEVEL);
+        result.push(a as u8);
+        result.push((a >> 8 | (b & 0x0f) << 4) as u8);
+        result.push((b >> 4) as u8);
+    }
+    Ok(result)
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<LookupTable>();
+    assert_send_sync::<BitPumpLSB>();
+};
@@ -0,0 +1,310 @@
+//! Bit pumps, which read a buffer a few bits at a time, and the bit writers that produce what
+//! they read. Sony packs the bits of a byte from the least significant one (`BitPumpLSB`,
+//! `BitWriterLSB`); Canon, Nikon and lossless JPEG from the most significant one
+//! (`BitPumpMSB`, `BitWriterMSB`). Either way a pump reads zeros past the end of its buffer
+//! and clamps requests for more than 32 bits, and a writer pads its last byte with zeros.
+
+use std::cmp;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+/// Reads bits from a buffer
+pub trait BitPump {
+    /// Returns the next `num` bits (at most 32) without consuming them. Reading past the end
+    /// of the buffer yields zeros.
+    fn peek_bits(&mut self, num: u32) -> u32;
+
+    /// Drops `num` bits; consuming more than has been peeked drops everything buffered
+    fn consume_bits(&mut self, num: u32);
+
+    /// Number of bits consumed since the start of the slice the pump reads
+    fn bit_position(&self) -> u64;
+
+    /// Returns the next `num` bits (at most 32) and consumes them
+    ///
+    /// Values come back the way they were written, whether or not they straddle the words
+    /// the pump refills itself with:
+    ///
+    /// ```
+    /// use raw_tiff_edit::rawloader::{BitPump, BitPumpLSB, BitPumpMSB};
+    /// use raw_tiff_edit::rawloader::{BitWriter, BitWriterLSB, BitWriterMSB};
+    ///
+    /// // 11-bit values cross every 32-bit boundary at a different offset
+    /// let values: Vec<u32> = (0..100).map(|i| i * 37 % 2048).collect();
+    /// let mut lsb = BitWriterLSB::new();
+    /// let mut msb = BitWriterMSB::new();
+    /// for value in &values {
+    ///     lsb.push_bits(*value, 11);
+    ///     msb.push_bits(*value, 11);
+    /// }
+    /// let (lsb, msb) = (lsb.into_data(), msb.into_data());
+    /// let mut lsb_pump = BitPumpLSB::new(&lsb);
+    /// let mut msb_pump = BitPumpMSB::new(&msb);
+    /// for value in &values {
+    ///     assert_eq!(lsb_pump.get_bits(11), *value);
+    ///     assert_eq!(msb_pump.get_bits(11), *value);
+    /// }
+    /// assert_eq!(lsb_pump.bit_position(), 1100);
+    /// assert_eq!(msb_pump.bit_position(), 1100);
+    /// ```
+    #[inline(always)]
+    fn get_bits(&mut self, num: u32) -> u32 {
+        if num == 0 {
+            return 0;
+        }
+
+        let val = self.peek_bits(num);
+        self.consume_bits(num);
+
+        val
+    }
+}
+
+/// Writes bits for a `BitPump` to read
+pub trait BitWriter {
+    /// Appends the low `num` (at most 32) bits of `val`
+    fn push_bits(&mut self, val: u32, num: u32);
+
+    /// The bytes written so far, with a trailing partial byte padded with zero bits
+    ///
+    /// ```
+    /// use raw_tiff_edit::rawloader::{BitWriter, BitWriterLSB, BitWriterMSB};
+    ///
+    /// let mut lsb = BitWriterLSB::new();
+    /// let mut msb = BitWriterMSB::new();
+    /// for writer in [&mut lsb as &mut dyn BitWriter, &mut msb] {
+    ///     writer.push_bits(0b1_1111_1111, 9);
+    ///     writer.push_bits(0b101, 3);
+    /// }
+    /// assert_eq!(lsb.into_data(), [0xff, 0b0000_1011]);
+    /// assert_eq!(msb.into_data(), [0xff, 0b1101_0000]);
+    /// ```
+    fn into_data(self) -> Vec<u8>
+    where
+        Self: Sized;
+}
+
+/// The low `num` (at most 32) bits of `val`
+#[inline(always)]
+fn low_bits(val: u32, num: u32) -> u64 {
+    val as u64 & (0xFFFFFFFF >> (32 - num))
+}
+
+/// Reads bits from the least significant one of each byte up, as ARW2 stores them
+#[derive(Debug, Copy, Clone)]
+pub struct BitPumpLSB<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+    bits: u64,
+    nbits: u32,
+}
+
+impl<'a> BitPumpLSB<'a> {
+    pub fn new(src: &'a [u8]) -> BitPumpLSB<'a> {
+        BitPumpLSB {
+            buffer: src,
+            pos: 0,
+            bits: 0,
+            nbits: 0,
+        }
+    }
+
+    /// A pump positioned `bit` bits into the buffer
+    pub fn at_bit(src: &'a [u8], bit: u64) -> BitPumpLSB<'a> {
+        let byte = cmp::min((bit / 8) as usize, src.len());
+        let mut pump = BitPumpLSB::new(&src[byte..]);
+        pump.get_bits((bit % 8) as u32);
+        pump
+    }
+}
+
+impl BitPump for BitPumpLSB<'_> {
+    /// Requests for more than 32 bits get 32:
+    ///
+    /// ```
+    /// use raw_tiff_edit::rawloader::{BitPump, BitPumpLSB};
+    ///
+    /// let data = [0x12, 0x34, 0x56, 0x78, 0x9a];
+    /// let mut pump = BitPumpLSB::new(&data);
+    /// assert_eq!(pump.peek_bits(40), 0x78563412);
+    /// pump.consume_bits(40);
+    /// assert_eq!(pump.bit_position(), 32);
+    /// assert_eq!(pump.get_bits(12), 0x09a);
+    /// ```
+    #[inline(always)]
+    fn peek_bits(&mut self, num: u32) -> u32 {
+        let num = cmp::min(num, 32);
+        if num > self.nbits {
+            let inbits: u64 = LEu32_padded(self.buffer, self.pos) as u64;
+            self.bits = ((inbits << 32) | (self.bits << (32 - self.nbits))) >> (32 - self.nbits);
+            self.pos = self.pos.saturating_add(4);
+            self.nbits += 32;
+        }
+        (self.bits & (0x0ffffffffu64 >> (32 - num))) as u32
+    }
+
+    #[inline(always)]
+    fn consume_bits(&mut self, num: u32) {
+        let num = cmp::min(num, self.nbits);
+        self.nbits -= num;
+        self.bits = self.bits.checked_shr(num).unwrap_or(0);
+    }
+
+    fn bit_position(&self) -> u64 {
+        self.pos as u64 * 8 - self.nbits as u64
+    }
+}
+
+/// Reads bits from the most significant one of each byte down
+#[derive(Debug, Copy, Clone)]
+pub struct BitPumpMSB<'a> {
+    buffer: &'a [u8],
+    pos: usize,
+    /// The buffered bits, from the most significant one down
+    bits: u64,
+    nbits: u32,
+}
+
+impl<'a> BitPumpMSB<'a> {
+    pub fn new(src: &'a [u8]) -> BitPumpMSB<'a> {
+        BitPumpMSB {
+            buffer: src,
+            pos: 0,
+            bits: 0,
+            nbits: 0,
+        }
+    }
+}
+
+impl BitPump for BitPumpMSB<'_> {
+    /// Requests for more than 32 bits get 32:
+    ///
+    /// ```
+    /// use raw_tiff_edit::rawloader::{BitPump, BitPumpMSB};
+    ///
+    /// let data = [0x12, 0x34, 0x56, 0x78, 0x9a];
+    /// let mut pump = BitPumpMSB::new(&data);
+    /// assert_eq!(pump.peek_bits(40), 0x12345678);
+    /// pump.consume_bits(40);
+    /// assert_eq!(pump.bit_position(), 32);
+    /// assert_eq!(pump.get_bits(12), 0x9a0);
+    /// ```
+    #[inline(always)]
+    fn peek_bits(&mut self, num: u32) -> u32 {
+        let num = cmp::min(num, 32);
+        if num == 0 {
+            return 0;
+        }
+        if num > self.nbits {
+            let inbits: u64 = BEu32_padded(self.buffer, self.pos) as u64;
+            self.bits |= inbits << (32 - self.nbits);
+            self.pos = self.pos.saturating_add(4);
+            self.nbits += 32;
+        }
+        (self.bits >> (64 - num)) as u32
+    }
+
+    #[inline(always)]
+    fn consume_bits(&mut self, num: u32) {
+        let num = cmp::min(num, self.nbits);
+        self.nbits -= num;
+        self.bits = self.bits.checked_shl(num).unwrap_or(0);
+    }
+
+    fn bit_position(&self) -> u64 {
+        self.pos as u64 * 8 - self.nbits as u64
+    }
+}
+
+/// Writes bits for `BitPumpLSB`, from the least significant one of each byte up
+#[derive(Debug, Clone, Default)]
+pub struct BitWriterLSB {
+    data: Vec<u8>,
+    bits: u64,
+    n_bits: u32,
+}
+
+impl BitWriterLSB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BitWriter for BitWriterLSB {
+    fn push_bits(&mut self, val: u32, num: u32) {
+        let num = cmp::min(num, 32);
+        self.bits |= low_bits(val, num) << self.n_bits;
+        self.n_bits += num;
+        while self.n_bits >= 8 {
+            self.data.push(self.bits as u8);
+            self.n_bits -= 8;
+            self.bits >>= 8;
+        }
+    }
+
+    fn into_data(mut self) -> Vec<u8> {
+        if self.n_bits > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+/// Writes bits for `BitPumpMSB`, from the most significant one of each byte down
+#[derive(Debug, Clone, Default)]
+pub struct BitWriterMSB {
+    data: Vec<u8>,
+    /// The pending bits, in the low `n_bits` bits
+    bits: u64,
+    n_bits: u32,
+}
+
+impl BitWriterMSB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BitWriter for BitWriterMSB {
+    fn push_bits(&mut self, val: u32, num: u32) {
+        let num = cmp::min(num, 32);
+        self.bits = self.bits << num | low_bits(val, num);
+        self.n_bits += num;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            self.data.push((self.bits >> self.n_bits) as u8);
+        }
+        self.bits &= (1 << self.n_bits) - 1;
+    }
+
+    fn into_data(mut self) -> Vec<u8> {
+        if self.n_bits > 0 {
+            self.data.push((self.bits << (8 - self.n_bits)) as u8);
+        }
+        self.data
+    }
+}
+
+/// Reads a little endian u32, treating bytes past the end of the buffer as zeros
+#[allow(non_snake_case)]
+#[inline]
+pub fn LEu32_padded(buf: &[u8], pos: usize) -> u32 {
+    LittleEndian::read_u32(&padded_word(buf, pos))
+}
+
+/// Reads a big endian u32, treating bytes past the end of the buffer as zeros
+#[allow(non_snake_case)]
+#[inline]
+pub fn BEu32_padded(buf: &[u8], pos: usize) -> u32 {
+    BigEndian::read_u32(&padded_word(buf, pos))
+}
+
+#[inline]
+fn padded_word(buf: &[u8], pos: usize) -> [u8; 4] {
+    let mut bytes = [0u8; 4];
+    if let Some(rest) = buf.get(pos..) {
+        let len = cmp::min(rest.len(), 4);
+        bytes[..len].copy_from_slice(&rest[..len]);
+    }
+    bytes
+}
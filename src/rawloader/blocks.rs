@@ -0,0 +1,300 @@
+//! The block structure of an ARW2 bitstream, for experimenting on the stored data itself
+//! rather than on decoded pixels. Every row is cut into groups of 32 pixels, each holding two
+//! interleaved blocks of 16: the even pixels of the group, then the odd ones. A block stores
+//! the 11-bit codes of its largest and smallest pixel, where in the block they are, and a
+//! 7-bit delta from the smallest for each of the 14 others, shifted left when the block spans
+//! more than 128 codes.
+//!
+//! `Bitstream::parse` reads the blocks the way the decoder does, `Bitstream::serialize` writes
+//! them back, and anything in between is up to the caller: the codes are the ones stored,
+//! before the tone curve and the dither. Serializing blocks that were parsed and not touched
+//! gives back the same bytes.
+
+use std::cmp;
+
+use super::{BitPump, BitPumpLSB, BitWriter, BitWriterLSB};
+use crate::RawEditError;
+
+/// Pixels of a group, two interleaved blocks
+pub const GROUP_PIXELS: usize = 32;
+/// Pixels of a block
+pub const BLOCK_PIXELS: usize = 16;
+
+/// One block of 16 pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    /// Code of the largest pixel, 11 bits
+    pub max: u16,
+    /// Code of the smallest pixel, 11 bits
+    pub min: u16,
+    /// Where the largest pixel is, 4 bits
+    pub imax: u8,
+    /// Where the smallest pixel is, 4 bits
+    pub imin: u8,
+    /// The 7-bit delta of every pixel, by position; the ones at `imax` and `imin` aren't
+    /// stored and are ignored
+    pub deltas: [u8; BLOCK_PIXELS],
+}
+
+impl Block {
+    /// Reads a block from the pump. A block with `imax` equal to `imin` holds 15 deltas, as
+    /// the decoder reads it, and pushes everything after it one delta further.
+    pub fn read(pump: &mut BitPumpLSB) -> Block {
+        let max = pump.get_bits(11) as u16;
+        let min = pump.get_bits(11) as u16;
+        let imax = pump.get_bits(4) as u8;
+        let imin = pump.get_bits(4) as u8;
+        let mut deltas = [0; BLOCK_PIXELS];
+        for (i, delta) in deltas.iter_mut().enumerate() {
+            if i != imax as usize && i != imin as usize {
+                *delta = pump.get_bits(7) as u8;
+            }
+        }
+        Block {
+            max,
+            min,
+            imax,
+            imin,
+            deltas,
+        }
+    }
+
+    /// Writes the block, keeping the low 11, 4 and 7 bits of the fields
+    pub fn write(&self, writer: &mut BitWriterLSB) {
+        writer.push_bits(self.max as u32 & 0x7ff, 11);
+        writer.push_bits(self.min as u32 & 0x7ff, 11);
+        writer.push_bits(self.imax as u32 & 0xf, 4);
+        writer.push_bits(self.imin as u32 & 0xf, 4);
+        for (i, delta) in self.deltas.iter().enumerate() {
+            if !self.is_extreme(i) {
+                writer.push_bits(*delta as u32 & 0x7f, 7);
+            }
+        }
+    }
+
+    fn is_extreme(&self, i: usize) -> bool {
+        i == self.imax as usize & 0xf || i == self.imin as usize & 0xf
+    }
+
+    /// Number of bits the block takes: 128, or 135 when `imax` equals `imin`
+    pub fn bits(&self) -> usize {
+        let stored = (0..BLOCK_PIXELS).filter(|i| !self.is_extreme(*i)).count();
+        30 + stored * 7
+    }
+
+    /// How far the deltas are shifted left, as the span from `min` to `max` asks for
+    pub fn shift(&self) -> u32 {
+        let delta = self.max.saturating_sub(self.min) as u32;
+        cmp::max(0, (32 - (delta.leading_zeros() as i32)) - 7) as u32
+    }
+
+    /// The codes the block decodes to, by position, before the tone curve and the dither
+    pub fn codes(&self) -> [u16; BLOCK_PIXELS] {
+        let mut codes = [0; BLOCK_PIXELS];
+        for (i, code) in codes.iter_mut().enumerate() {
+            *code = if i == self.imax as usize {
+                self.max
+            } else if i == self.imin as usize {
+                self.min
+            } else {
+                cmp::min(
+                    0x7ff,
+                    ((self.deltas[i] as u32) << self.shift()) + self.min as u32,
+                ) as u16
+            };
+        }
+        codes
+    }
+}
+
+/// Where a block is in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockPosition {
+    pub row: usize,
+    /// The group of 32 pixels in the row
+    pub group: usize,
+    /// 0 for the block of the even pixels of the group, 1 for the odd ones
+    pub half: usize,
+}
+
+impl BlockPosition {
+    /// The columns of the pixels of the block, by position in the block
+    pub fn columns(self) -> impl Iterator<Item = usize> {
+        let start = self.group * GROUP_PIXELS + self.half;
+        (0..BLOCK_PIXELS).map(move |i| start + i * 2)
+    }
+}
+
+/// The position of the block at `index` in the blocks of rows of `width` pixels
+fn position(width: usize, index: usize) -> BlockPosition {
+    let groups = width / GROUP_PIXELS;
+    BlockPosition {
+        row: index / 2 / groups,
+        group: index / 2 % groups,
+        half: index % 2,
+    }
+}
+
+/// All blocks of ARW2 data, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitstream {
+    width: usize,
+    height: usize,
+    /// Two per group, group by group, row by row
+    blocks: Vec<Block>,
+}
+
+impl Bitstream {
+    /// Reads the blocks of `width` x `height` pixels of ARW2 data. The width has to be a
+    /// multiple of 32, as it is in every body, for the rows to be made of whole groups.
+    ///
+    /// ```
+    /// use raw_tiff_edit::dither;
+    /// use raw_tiff_edit::rawloader::blocks::{Bitstream, BlockPosition};
+    /// use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_from, encode_arw2};
+    /// use raw_tiff_edit::source::MemorySource;
+    ///
+    /// let (width, height) = (64, 2);
+    /// let curve = calculate_curve();
+    /// let pixels: Vec<u16> = (0..width * height).map(|i| (i * 131 % 9000) as u16).collect();
+    /// let data = encode_arw2(&pixels, width, &curve)?;
+    ///
+    /// let mut stream = Bitstream::parse(&data, width, height)?;
+    /// assert_eq!(stream.blocks().count(), 8);
+    /// assert_eq!(stream.serialize()?, data);
+    ///
+    /// // flatten the even pixels of the second group of the first row to their minimum
+    /// let position = BlockPosition { row: 0, group: 1, half: 0 };
+    /// let block = stream.block_mut(position).unwrap();
+    /// let min = block.min;
+    /// block.max = min;
+    /// block.deltas = [0; 16];
+    /// let edited = stream.serialize()?;
+    /// assert_eq!(edited.len(), data.len());
+    ///
+    /// let decode = |data: &[u8]| {
+    ///     let mut src = MemorySource::new(data);
+    ///     let no_dither = dither::by_name("none").unwrap();
+    ///     decode_arw2_from(&mut src, 0, width, height, &curve, no_dither)
+    /// };
+    /// let (before, after) = (decode(&data)?, decode(&edited)?);
+    /// // the block is in the first row, so its columns are indices into the pixels as well
+    /// let columns: Vec<usize> = position.columns().collect();
+    /// for i in 0..width * height {
+    ///     if columns.contains(&i) {
+    ///         assert_eq!(after[i], after[columns[0]]);
+    ///     } else {
+    ///         assert_eq!(after[i], before[i]);
+    ///     }
+    /// }
+    /// # Ok::<(), raw_tiff_edit::RawEditError>(())
+    /// ```
+    pub fn parse(buf: &[u8], width: usize, height: usize) -> Result<Bitstream, RawEditError> {
+        if !width.is_multiple_of(GROUP_PIXELS) {
+            return Err(RawEditError::Mismatch(format!(
+                "rows of {} pixels aren't made of whole groups of {}",
+                width, GROUP_PIXELS
+            )));
+        }
+        if buf.len() < width * height {
+            return Err(RawEditError::Truncated("the raw data".to_owned()));
+        }
+        let mut blocks = Vec::with_capacity(width * height / BLOCK_PIXELS);
+        for row in 0..height {
+            // like the decoder, read on into the next row if a block runs past its end
+            let mut pump = BitPumpLSB::new(&buf[row * width..]);
+            for _ in 0..width / BLOCK_PIXELS {
+                blocks.push(Block::read(&mut pump));
+            }
+        }
+        Ok(Bitstream {
+            width,
+            height,
+            blocks,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn index(&self, position: BlockPosition) -> Option<usize> {
+        let groups = self.width / GROUP_PIXELS;
+        if position.row >= self.height || position.group >= groups || position.half > 1 {
+            return None;
+        }
+        Some((position.row * groups + position.group) * 2 + position.half)
+    }
+
+    pub fn block(&self, position: BlockPosition) -> Option<&Block> {
+        self.index(position).map(|index| &self.blocks[index])
+    }
+
+    pub fn block_mut(&mut self, position: BlockPosition) -> Option<&mut Block> {
+        self.index(position)
+            .map(move |index| &mut self.blocks[index])
+    }
+
+    /// Every block with its position, row by row
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockPosition, &Block)> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(move |(index, block)| (position(self.width, index), block))
+    }
+
+    /// Every block with its position, for changing them
+    pub fn blocks_mut(&mut self) -> impl Iterator<Item = (BlockPosition, &mut Block)> + '_ {
+        let width = self.width;
+        self.blocks
+            .iter_mut()
+            .enumerate()
+            .map(move |(index, block)| (position(width, index), block))
+    }
+
+    /// Writes the blocks back into ARW2 data, `width` bytes per row. A row holding a block
+    /// with `imax` equal to `imin` takes more than that, and is refused.
+    pub fn serialize(&self) -> Result<Vec<u8>, RawEditError> {
+        let mut out = vec![0; self.width * self.height];
+        self.write_into(&mut out)?;
+        Ok(out)
+    }
+
+    /// Like `serialize`, into `out`, which has to hold `width` x `height` bytes. Rows are
+    /// written as they are done, so a row that is refused leaves the ones before it written.
+    pub fn write_into(&self, out: &mut [u8]) -> Result<(), RawEditError> {
+        if out.len() < self.width * self.height {
+            return Err(RawEditError::Truncated("the raw data".to_owned()));
+        }
+        let blocks_per_row = self.width / BLOCK_PIXELS;
+        if blocks_per_row == 0 {
+            return Ok(());
+        }
+        for (row, (blocks, out)) in self
+            .blocks
+            .chunks(blocks_per_row)
+            .zip(out.chunks_mut(self.width))
+            .enumerate()
+        {
+            let mut writer = BitWriterLSB::new();
+            for block in blocks {
+                block.write(&mut writer);
+            }
+            let data = writer.into_data();
+            if data.len() != self.width {
+                return Err(RawEditError::Mismatch(format!(
+                    "row {} takes {} bytes instead of {}; a block has imax equal to imin",
+                    row,
+                    data.len(),
+                    self.width
+                )));
+            }
+            out.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Canon's CR2. The raw data is a single lossless JPEG (see `ljpeg`) in the fourth IFD of the
+//! file, whose frame is two or four components wide per line. Most bodies cut the image into
+//! vertical slices before compressing it: the JPEG holds the first slice from top to bottom,
+//! then the second, and so on, as told by the CR2Slice tag (the number of slices before the
+//! last one, their width, and the width of the last one).
+//!
+//! Encoding re-encodes the whole JPEG with the frame of the original. It is written where the
+//! original was if it fits, and appended to the file if not.
+//!
+//! The visible area comes from the SensorInfo of Canon's maker notes, and the black level is
+//! measured on the masked columns left of it. Canon keeps its white balance in a different
+//! place in every body, so it is left neutral.
+
+use super::ljpeg::{self, Frame};
+use super::Strip;
+use crate::raw::RawImage;
+use crate::tiff::{self, Tiff};
+use crate::tiled::Rect;
+use crate::variant::VariantError;
+use crate::RawEditError;
+
+/// Compression of CR2's raw data and of its large preview: old-style JPEG
+pub const COMPRESSION_OLD_JPEG: u32 = 6;
+/// White level for when the precision of the data is not known
+pub const WHITE_LEVEL: u16 = 0x3fff;
+
+const CANON_SENSOR_INFO: u16 = 0x00e0;
+
+fn unsupported(reason: &str) -> RawEditError {
+    RawEditError::Variant(VariantError::Unsupported(reason.to_owned()))
+}
+
+/// Whether a file is a CR2: a TIFF with "CR" after its header
+pub fn is_cr2(file: &[u8]) -> bool {
+    Tiff::new(file).is_some() && file.get(8..10) == Some(b"CR")
+}
+
+/// How the image is cut into slices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slices {
+    /// Number of slices before the last one
+    pub count: usize,
+    pub width: usize,
+    pub last_width: usize,
+}
+
+/// Where the raw data of a CR2 is, and how it is laid out
+#[derive(Debug, Clone)]
+pub struct Cr2Layout {
+    pub width: usize,
+    pub height: usize,
+    pub strip: Strip,
+    pub slices: Option<Slices>,
+    pub frame: Frame,
+}
+
+impl Cr2Layout {
+    /// Reads the layout of the raw data described by the IFD at `ifd_offset`
+    pub fn read(file: &[u8], ifd_offset: usize) -> Result<Cr2Layout, RawEditError> {
+        let tiff = Tiff::new(file).ok_or_else(|| unsupported("not a TIFF file"))?;
+        let (ifd, _) = tiff
+            .read_ifd(ifd_offset)
+            .ok_or_else(|| RawEditError::Truncated("the raw IFD".to_owned()))?;
+        let strip = Strip::read(&tiff, &ifd)?;
+        let frame = ljpeg::read_frame(strip.data(file)?)?;
+
+        let slices = match ifd.entry(tiff::CANON_CR2_SLICE).map(|e| tiff.values(e)) {
+            Some(values) => match values[..] {
+                [count, width, last_width] if width > 0 && last_width > 0 => Some(Slices {
+                    count: count as usize,
+                    width: width as usize,
+                    last_width: last_width as usize,
+                }),
+                // a single slice, as some bodies write it
+                [0, 0, _] | [0, _, 0] => None,
+                _ => return Err(unsupported("CR2 slices that aren't 3 widths")),
+            },
+            None => None,
+        };
+        let width = match slices {
+            Some(slices) => slices.count * slices.width + slices.last_width,
+            None => frame.samples * frame.components,
+        };
+        if width == 0 || !frame.len().is_multiple_of(width) {
+            return Err(RawEditError::Mismatch(format!(
+                "a lossless JPEG frame of {} samples doesn't make up rows of {} pixels",
+                frame.len(),
+                width
+            )));
+        }
+        Ok(Cr2Layout {
+            width,
+            height: frame.len() / width,
+            strip,
+            slices,
+            frame,
+        })
+    }
+
+    /// Where in the image each sample of the frame goes, in the order the frame holds them
+    fn positions(&self) -> Vec<usize> {
+        let slices = self.slices.unwrap_or(Slices {
+            count: 0,
+            width: 0,
+            last_width: self.width,
+        });
+        let mut positions = Vec::with_capacity(self.width * self.height);
+        for slice in 0..=slices.count {
+            let x = slice * slices.width;
+            let width = if slice < slices.count {
+                slices.width
+            } else {
+                slices.last_width
+            };
+            for y in 0..self.height {
+                positions.extend((x..x + width).map(|x| y * self.width + x));
+            }
+        }
+        positions
+    }
+
+    /// The compressed data
+    pub fn data<'a>(&self, file: &'a [u8]) -> Result<&'a [u8], RawEditError> {
+        self.strip.data(file)
+    }
+}
+
+/// Decodes the raw data of the file, row by row
+pub fn decode(file: &[u8], layout: &Cr2Layout) -> Result<Vec<u16>, RawEditError> {
+    decode_data(layout.data(file)?, layout)
+}
+
+/// Decodes compressed data with the layout of the file, row by row
+pub fn decode_data(data: &[u8], layout: &Cr2Layout) -> Result<Vec<u16>, RawEditError> {
+    let (frame, values) = ljpeg::decode(data)?;
+    if frame != layout.frame {
+        return Err(RawEditError::Mismatch(
+            "the lossless JPEG frame changed while decoding".to_owned(),
+        ));
+    }
+    let mut pixels = vec![0u16; layout.width * layout.height];
+    for (position, value) in layout.positions().into_iter().zip(values) {
+        pixels[position] = value;
+    }
+    Ok(pixels)
+}
+
+/// Encodes the image as raw data with the layout of the original
+pub fn encode(img: &[u16], layout: &Cr2Layout) -> Result<Vec<u8>, RawEditError> {
+    if img.len() != layout.width * layout.height {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up a {}x{} image",
+            img.len(),
+            layout.width,
+            layout.height
+        )));
+    }
+    let values: Vec<_> = layout.positions().into_iter().map(|i| img[i]).collect();
+    ljpeg::encode(&values, layout.frame)
+}
+
+/// Replaces the raw data of the file with the image, unless its pixels didn't change.
+/// Returns the number of bytes written.
+pub fn write(file: &mut Vec<u8>, layout: &Cr2Layout, img: &[u16]) -> Result<usize, RawEditError> {
+    if decode(file, layout)? == img {
+        return Ok(0);
+    }
+    let encoded = encode(img, layout)?;
+    layout.strip.replace(file, &encoded)?;
+    Ok(encoded.len())
+}
+
+/// Fills in the visible area from the SensorInfo of the maker notes, and the black level
+/// from the masked columns left of it, if the image has its pixels
+pub fn read_calibration(tiff: &Tiff, image: &mut RawImage) {
+    let maker_note = match tiff
+        .exif_ifd()
+        .and_then(|exif| exif.entry(tiff::MAKER_NOTE).copied())
+    {
+        Some(entry) => entry.value_pos,
+        None => return,
+    };
+    // Canon's maker notes are a plain IFD, with offsets from the start of the file
+    let sensor_info = match tiff
+        .read_ifd(maker_note)
+        .and_then(|(ifd, _)| Some(tiff.values(ifd.entry(CANON_SENSOR_INFO)?)))
+    {
+        Some(values) if values.len() >= 9 => values,
+        _ => return,
+    };
+    let (left, top) = (sensor_info[5] as usize, sensor_info[6] as usize);
+    let (right, bottom) = (sensor_info[7] as usize, sensor_info[8] as usize);
+    if right < left || bottom < top || right >= image.width || bottom >= image.height {
+        return;
+    }
+    image.crop = Rect {
+        x: left,
+        y: top,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    };
+    if left > 0 && image.pixels.len() == image.width * image.height {
+        let masked: Vec<u64> = (top..=bottom)
+            .flat_map(|y| (0..left).map(move |x| (x, y)))
+            .map(|(x, y)| image.pixels[y * image.width + x] as u64)
+            .collect();
+        image.black_level = (masked.iter().sum::<u64>() / masked.len() as u64) as u16;
+    }
+}
@@ -0,0 +1,569 @@
+//! Lossless JPEG (ITU T.81 process 14), the compression of Sony's lossless raw and of Canon's
+//! CR2. A frame holds `lines` x `samples` samples of each of its components, interleaved in
+//! the scan; what the components are, and how they map onto the sensor, is up to the format.
+//!
+//! Decoding handles any of the seven predictors and a point transform, but neither restart
+//! intervals nor subsampled components. Encoding writes predictor 1 with a single Huffman
+//! table fitted to the data, so that a frame decodes to exactly the samples it was encoded
+//! from.
+
+use crate::variant::VariantError;
+use crate::RawEditError;
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOF3: u8 = 0xc3;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+const DRI: u8 = 0xdd;
+
+fn unsupported(reason: &str) -> RawEditError {
+    RawEditError::Variant(VariantError::Unsupported(reason.to_owned()))
+}
+
+fn corrupt(what: &str) -> RawEditError {
+    RawEditError::Mismatch(format!("corrupt lossless JPEG data: {}", what))
+}
+
+/// The shape of a frame, from its SOF3 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Bits per sample
+    pub precision: u8,
+    pub lines: usize,
+    /// Samples per line, of each component
+    pub samples: usize,
+    pub components: usize,
+}
+
+impl Frame {
+    /// Number of samples of all components together
+    pub fn len(&self) -> usize {
+        self.lines * self.samples * self.components
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Huffman table for decoding, following Annex F.2.2.3 of T.81
+struct DecodeTable {
+    maxcode: [i32; 17],
+    valptr: [usize; 17],
+    mincode: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl DecodeTable {
+    fn new(bits: &[u8; 16], values: Vec<u8>) -> DecodeTable {
+        let mut table = DecodeTable {
+            maxcode: [-1; 17],
+            valptr: [0; 17],
+            mincode: [0; 17],
+            values,
+        };
+        let mut code = 0i32;
+        let mut k = 0;
+        for length in 1..=16 {
+            let count = bits[length - 1] as usize;
+            if count > 0 {
+                table.valptr[length] = k;
+                table.mincode[length] = code;
+                code += count as i32;
+                k += count;
+                table.maxcode[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u8, RawEditError> {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = code << 1 | bits.bit() as i32;
+            if code <= self.maxcode[length] {
+                let index = self.valptr[length] + (code - self.mincode[length]) as usize;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or_else(|| corrupt("Huffman code without a value"));
+            }
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+/// Reads the entropy-coded data MSB first, skipping stuffed zero bytes. Past the end of the
+/// data (or at a marker) it reads zeros.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    current: u8,
+    left: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            pos: 0,
+            current: 0,
+            left: 0,
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.left == 0 {
+            self.current = match self.data.get(self.pos) {
+                Some(0xff) if self.data.get(self.pos + 1) == Some(&0) => {
+                    self.pos += 2;
+                    0xff
+                }
+                Some(0xff) | None => 0,
+                Some(byte) => {
+                    self.pos += 1;
+                    *byte
+                }
+            };
+            self.left = 8;
+        }
+        self.left -= 1;
+        (self.current >> self.left) as u32 & 1
+    }
+
+    fn bits(&mut self, count: u32) -> u32 {
+        (0..count).fold(0, |value, _| value << 1 | self.bit())
+    }
+}
+
+/// Writes bits MSB first, stuffing a zero byte after every 0xff
+struct BitWriter {
+    out: Vec<u8>,
+    current: u32,
+    used: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.current = self.current << 1 | (value >> i & 1);
+            self.used += 1;
+            if self.used == 8 {
+                self.out.push(self.current as u8);
+                if self.current == 0xff {
+                    self.out.push(0);
+                }
+                self.current = 0;
+                self.used = 0;
+            }
+        }
+    }
+
+    /// Pads the last byte with ones
+    fn finish(mut self) -> Vec<u8> {
+        if self.used > 0 {
+            self.push(0x7f, 8 - self.used);
+        }
+        self.out
+    }
+}
+
+/// The value predicted for a sample from its neighbours (left `a`, above `b`, above left
+/// `c`), as in table H.1 of T.81
+fn predict(predictor: u8, a: i32, b: i32, c: i32) -> i32 {
+    match predictor {
+        1 => a,
+        2 => b,
+        3 => c,
+        4 => a + b - c,
+        5 => a + ((b - c) >> 1),
+        6 => b + ((a - c) >> 1),
+        _ => (a + b) >> 1,
+    }
+}
+
+/// The difference category of a sample and the extra bits that follow its Huffman code
+fn category(diff: i32) -> (u8, u32) {
+    if diff == 0 {
+        return (0, 0);
+    }
+    if diff == 32768 {
+        return (16, 0);
+    }
+    let size = 32 - diff.unsigned_abs().leading_zeros();
+    let bits = if diff < 0 { diff - 1 } else { diff };
+    (size as u8, bits as u32 & ((1 << size) - 1))
+}
+
+fn segment(data: &[u8], pos: usize) -> Result<&[u8], RawEditError> {
+    let length = data
+        .get(pos..pos + 2)
+        .map(|bytes| (bytes[0] as usize) << 8 | bytes[1] as usize)
+        .filter(|length| *length >= 2)
+        .ok_or_else(|| corrupt("truncated segment"))?;
+    data.get(pos + 2..pos + length)
+        .ok_or_else(|| corrupt("truncated segment"))
+}
+
+/// The headers of a lossless JPEG, up to its scan
+struct Headers<'a> {
+    tables: [Option<DecodeTable>; 4],
+    frame: Frame,
+    /// The body of the scan header
+    scan: &'a [u8],
+    /// Where the entropy-coded data starts
+    data_start: usize,
+}
+
+fn headers(data: &[u8]) -> Result<Headers<'_>, RawEditError> {
+    if data.get(0..2) != Some(&[0xff, SOI]) {
+        return Err(corrupt("no start of image"));
+    }
+    let mut tables: [Option<DecodeTable>; 4] = [None, None, None, None];
+    let mut frame = None;
+    let mut pos = 2;
+    let scan = loop {
+        let marker = match data.get(pos..pos + 2) {
+            Some([0xff, marker]) => *marker,
+            _ => return Err(corrupt("expected a marker")),
+        };
+        pos += 2;
+        if marker == 0xff {
+            // fill byte
+            pos -= 1;
+            continue;
+        }
+        let body = segment(data, pos)?;
+        pos += 2 + body.len();
+        match marker {
+            DHT => {
+                let mut rest = body;
+                while let [class_and_id, ..] = rest {
+                    let counts = rest
+                        .get(1..17)
+                        .ok_or_else(|| corrupt("truncated Huffman table"))?;
+                    let mut bits = [0u8; 16];
+                    bits.copy_from_slice(counts);
+                    let total: usize = bits.iter().map(|count| *count as usize).sum();
+                    let values = rest
+                        .get(17..17 + total)
+                        .ok_or_else(|| corrupt("truncated Huffman table"))?;
+                    let id = (class_and_id & 0x0f) as usize;
+                    *tables
+                        .get_mut(id)
+                        .ok_or_else(|| corrupt("Huffman table id out of range"))? =
+                        Some(DecodeTable::new(&bits, values.to_vec()));
+                    rest = &rest[17 + total..];
+                }
+            }
+            SOF3 => {
+                if body.len() < 6 {
+                    return Err(corrupt("truncated frame header"));
+                }
+                let components = body[5] as usize;
+                let sampling = body
+                    .get(6..6 + 3 * components)
+                    .ok_or_else(|| corrupt("truncated frame header"))?;
+                if sampling.chunks(3).any(|component| component[1] != 0x11) {
+                    return Err(unsupported("lossless JPEG with subsampled components"));
+                }
+                frame = Some(Frame {
+                    precision: body[0],
+                    lines: (body[1] as usize) << 8 | body[2] as usize,
+                    samples: (body[3] as usize) << 8 | body[4] as usize,
+                    components,
+                });
+            }
+            DRI if body.get(0..2) != Some(&[0, 0]) => {
+                return Err(unsupported("lossless JPEG with restart intervals"));
+            }
+            SOS => break body,
+            0xc0..=0xcf => return Err(unsupported("JPEG data that isn't lossless")),
+            _ => {}
+        }
+    };
+    let frame = frame.ok_or_else(|| corrupt("no frame header"))?;
+    if !(2..=16).contains(&frame.precision) {
+        return Err(corrupt("invalid sample precision"));
+    }
+    if !(1..=4).contains(&frame.components) {
+        return Err(corrupt("invalid number of components"));
+    }
+    Ok(Headers {
+        tables,
+        frame,
+        scan,
+        data_start: pos,
+    })
+}
+
+/// Reads the frame header of a lossless JPEG without decoding it
+pub fn read_frame(data: &[u8]) -> Result<Frame, RawEditError> {
+    headers(data).map(|headers| headers.frame)
+}
+
+/// Decodes a lossless JPEG into its samples, line by line with the components interleaved
+pub fn decode(data: &[u8]) -> Result<(Frame, Vec<u16>), RawEditError> {
+    let Headers {
+        tables,
+        frame,
+        scan,
+        data_start,
+    } = headers(data)?;
+    let Frame {
+        precision,
+        lines,
+        samples,
+        components,
+    } = frame;
+    let scan_components = *scan.first().ok_or_else(|| corrupt("empty scan header"))? as usize;
+    if scan_components != components {
+        return Err(unsupported(
+            "lossless JPEG with components in several scans",
+        ));
+    }
+    let mut component_tables = vec![];
+    for i in 0..components {
+        let selector = *scan
+            .get(2 + 2 * i)
+            .ok_or_else(|| corrupt("truncated scan header"))?;
+        let table = tables
+            .get((selector >> 4) as usize)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| corrupt("scan uses an undefined Huffman table"))?;
+        component_tables.push(table);
+    }
+    let predictor = *scan
+        .get(1 + 2 * scan_components)
+        .ok_or_else(|| corrupt("truncated scan header"))?;
+    let point_transform = scan
+        .get(3 + 2 * scan_components)
+        .map(|byte| byte & 0x0f)
+        .ok_or_else(|| corrupt("truncated scan header"))?;
+    if !(1..=7).contains(&predictor) || point_transform >= precision {
+        return Err(corrupt("invalid predictor or point transform"));
+    }
+
+    let mut bits = BitReader::new(data.get(data_start..).unwrap_or(&[]));
+    let initial = 1i32 << (precision - point_transform - 1);
+    let mut values = vec![0u16; frame.len()];
+    let stride = samples * components;
+    for y in 0..lines {
+        for x in 0..samples {
+            for (c, table) in component_tables.iter().enumerate() {
+                let size = table.decode(&mut bits)?;
+                let diff = match size {
+                    0 => 0,
+                    16 => 32768,
+                    1..=15 => {
+                        let value = bits.bits(size as u32) as i32;
+                        if value < 1 << (size - 1) {
+                            value - (1 << size) + 1
+                        } else {
+                            value
+                        }
+                    }
+                    _ => return Err(corrupt("difference category out of range")),
+                };
+                let at = |dx: usize, dy: usize| {
+                    values[(y - dy) * stride + (x - dx) * components + c] as i32
+                };
+                let prediction = match (x, y) {
+                    (0, 0) => initial,
+                    (_, 0) => at(1, 0),
+                    (0, _) => at(0, 1),
+                    _ => predict(predictor, at(1, 0), at(0, 1), at(1, 1)),
+                };
+                values[y * stride + x * components + c] = (prediction + diff) as u16;
+            }
+        }
+    }
+    if point_transform > 0 {
+        for value in &mut values {
+            *value <<= point_transform;
+        }
+    }
+    Ok((frame, values))
+}
+
+/// Code lengths of an optimal Huffman code for the difference categories, limited to 16
+/// bits, as `BITS` and `HUFFVAL` of a DHT segment (Annex K.2 of T.81)
+fn optimal_table(frequencies: &[u32; 17]) -> ([u8; 16], Vec<u8>) {
+    // a reserved symbol with the lowest frequency keeps any code from being all ones
+    let mut freq = [0u64; 18];
+    for (f, count) in freq.iter_mut().zip(frequencies) {
+        *f = *count as u64;
+    }
+    freq[17] = 1;
+    let mut code_size = [0usize; 18];
+    let mut others = [None::<usize>; 18];
+    loop {
+        let smallest = |exclude: Option<usize>| {
+            (0..18)
+                .filter(|i| freq[*i] > 0 && Some(*i) != exclude)
+                .min_by_key(|i| (freq[*i], usize::MAX - i))
+        };
+        let (c1, c2) = match smallest(None).and_then(|c1| Some((c1, smallest(Some(c1))?))) {
+            Some(pair) => pair,
+            None => break,
+        };
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+        let mut c = c1;
+        code_size[c] += 1;
+        while let Some(next) = others[c] {
+            c = next;
+            code_size[c] += 1;
+        }
+        others[c] = Some(c2);
+        let mut c = c2;
+        code_size[c] += 1;
+        while let Some(next) = others[c] {
+            c = next;
+            code_size[c] += 1;
+        }
+    }
+    let mut bits = [0usize; 33];
+    for size in code_size.iter().filter(|size| **size > 0) {
+        bits[*size] += 1;
+    }
+    for i in (17..=32).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    // drop the reserved symbol, which has the longest code
+    let mut i = 16;
+    while bits[i] == 0 {
+        i -= 1;
+    }
+    bits[i] -= 1;
+
+    let mut values = vec![];
+    for size in 1..=32 {
+        for (symbol, _) in code_size[..17]
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == size)
+        {
+            values.push(symbol as u8);
+        }
+    }
+    let mut counts = [0u8; 16];
+    for (count, bits) in counts.iter_mut().zip(&bits[1..=16]) {
+        *count = *bits as u8;
+    }
+    (counts, values)
+}
+
+/// Encodes samples, line by line with the components interleaved, as a lossless JPEG of the
+/// shape of `frame`. Samples that don't fit the precision are clamped.
+pub fn encode(values: &[u16], frame: Frame) -> Result<Vec<u8>, RawEditError> {
+    let Frame {
+        precision,
+        lines,
+        samples,
+        components,
+    } = frame;
+    if !(2..=16).contains(&precision) {
+        return Err(RawEditError::Mismatch(format!(
+            "invalid sample precision {}",
+            precision
+        )));
+    }
+    if !(1..=4).contains(&components) || values.len() != frame.len() {
+        return Err(RawEditError::Mismatch(format!(
+            "{} samples don't make up a {}x{} frame of {} components",
+            values.len(),
+            samples,
+            lines,
+            components
+        )));
+    }
+    let max = ((1u32 << precision) - 1) as u16;
+    let stride = samples * components;
+    let sample =
+        |x: usize, y: usize, c: usize| values[y * stride + x * components + c].min(max) as i32;
+    let initial = 1i32 << (precision - 1);
+    let mut diffs = Vec::with_capacity(values.len());
+    for y in 0..lines {
+        for x in 0..samples {
+            for c in 0..components {
+                let prediction = match (x, y) {
+                    (0, 0) => initial,
+                    (0, _) => sample(0, y - 1, c),
+                    _ => sample(x - 1, y, c),
+                };
+                // differences are taken modulo 2^16
+                let diff = (sample(x, y, c) - prediction) & 0xffff;
+                diffs.push(if diff > 32768 { diff - 65536 } else { diff });
+            }
+        }
+    }
+
+    let mut frequencies = [0u32; 17];
+    for diff in &diffs {
+        frequencies[category(*diff).0 as usize] += 1;
+    }
+    let (bits, huffman_values) = optimal_table(&frequencies);
+    let mut codes = [(0u32, 0u32); 17];
+    let mut code = 0u32;
+    let mut k = 0;
+    for (length, count) in bits.iter().enumerate() {
+        for _ in 0..*count {
+            codes[huffman_values[k] as usize] = (code, length as u32 + 1);
+            code += 1;
+            k += 1;
+        }
+        code <<= 1;
+    }
+
+    let mut out = vec![0xff, SOI];
+    out.extend_from_slice(&[0xff, DHT]);
+    let dht_len = 2 + 1 + 16 + huffman_values.len();
+    out.extend_from_slice(&[(dht_len >> 8) as u8, dht_len as u8, 0x00]);
+    out.extend_from_slice(&bits);
+    out.extend_from_slice(&huffman_values);
+    let sof_len = 8 + 3 * components;
+    out.extend_from_slice(&[0xff, SOF3, (sof_len >> 8) as u8, sof_len as u8, precision]);
+    out.extend_from_slice(&[(lines >> 8) as u8, lines as u8]);
+    out.extend_from_slice(&[(samples >> 8) as u8, samples as u8, components as u8]);
+    for c in 0..components as u8 {
+        out.extend_from_slice(&[c, 0x11, 0]);
+    }
+    let sos_len = 6 + 2 * components;
+    out.extend_from_slice(&[0xff, SOS, (sos_len >> 8) as u8, sos_len as u8]);
+    out.push(components as u8);
+    for c in 0..components as u8 {
+        out.extend_from_slice(&[c, 0x00]);
+    }
+    // predictor 1, no point transform
+    out.extend_from_slice(&[1, 0, 0]);
+
+    let mut writer = BitWriter {
+        out,
+        current: 0,
+        used: 0,
+    };
+    for diff in diffs {
+        let (size, extra) = category(diff);
+        let (code, length) = codes[size as usize];
+        writer.push(code, length);
+        if (1..16).contains(&size) {
+            writer.push(extra, size as u32);
+        }
+    }
+    let mut out = writer.finish();
+    out.extend_from_slice(&[0xff, EOI]);
+    Ok(out)
+}
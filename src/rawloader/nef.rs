@@ -0,0 +1,440 @@
+//! Nikon's compressed NEF, 12 or 14 bits, lossy or lossless. The raw data is a single strip of
+//! Huffman-coded differences, read MSB first, between each pixel and the one two columns to
+//! its left (or, in the first two columns, two rows up). Which of six fixed Huffman trees codes
+//! them, the predictions the first rows start from and the curve of lossy data are in the
+//! NEFLinearizationTable of Nikon's maker notes.
+//!
+//! Lossy data stores an index into the curve. It is decoded through a `LookupTable` with the
+//! dither of ARW2, and encoded back through its reverse lookup, so decoded data encodes to the
+//! same indices again. Lossless data stores the values themselves.
+//!
+//! Encoding writes the whole strip again with the tree of the original, where it was if it
+//! fits and appended to the file if not. The lossy data of older bodies switches to a second
+//! tree, which quantizes the differences, partway down the image; such data is decoded but
+//! not encoded.
+
+use super::{BitPump, BitPumpMSB, BitWriter, BitWriterMSB, LookupTable, Strip};
+use crate::dither::Factory;
+use crate::raw::RawImage;
+use crate::tiff::{self, Tiff};
+use crate::variant::VariantError;
+use crate::RawEditError;
+
+/// Compression of Nikon's compressed NEF data
+pub const COMPRESSION_NIKON: u32 = 34713;
+/// White level for when the curve or the bit depth of the data is not known
+pub const WHITE_LEVEL: u16 = 0x3fff;
+
+const NIKON_WB_RB_LEVELS: u16 = 0x000c;
+const NIKON_BLACK_LEVEL: u16 = 0x003d;
+const NIKON_LINEARIZATION_TABLE: u16 = 0x0096;
+
+/// The Huffman trees, as dcraw has them: the number of codes of each length from 1 to 16 bits,
+/// then the symbols in the order of their codes. A symbol is the number of bits of the
+/// difference, less the number of low bits dropped from it, which is in its high nibble.
+const TREES: [[u8; 32]; 6] = [
+    // 12-bit lossy
+    [
+        0, 1, 5, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 0, 5, 4, 3, 6, 2, 7, 1, 0, 8, 9, 11, 10, 12,
+        0, 0, 0,
+    ],
+    // 12-bit lossy after the split
+    [
+        0, 1, 5, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 0, 0x39, 0x5a, 0x38, 0x27, 0x16, 5, 4, 3, 2,
+        1, 0, 11, 12, 12, 0, 0,
+    ],
+    // 12-bit lossless
+    [
+        0, 1, 4, 2, 3, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 4, 6, 3, 7, 2, 8, 1, 9, 0, 10, 11, 12,
+        0, 0, 0,
+    ],
+    // 14-bit lossy
+    [
+        0, 1, 4, 3, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 0, 5, 6, 4, 7, 8, 3, 9, 2, 1, 0, 10, 11, 12,
+        13, 14, 0,
+    ],
+    // 14-bit lossy after the split
+    [
+        0, 1, 5, 1, 1, 1, 1, 1, 1, 1, 2, 0, 0, 0, 0, 0, 8, 0x5c, 0x4b, 0x3a, 0x29, 7, 6, 5, 4, 3,
+        2, 1, 0, 13, 14, 0,
+    ],
+    // 14-bit lossless
+    [
+        0, 1, 4, 2, 2, 3, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 7, 6, 8, 5, 9, 4, 10, 3, 11, 12, 2, 0, 1,
+        13, 14, 0,
+    ],
+];
+
+fn unsupported(reason: &str) -> RawEditError {
+    RawEditError::Variant(VariantError::Unsupported(reason.to_owned()))
+}
+
+fn corrupt(what: &str) -> RawEditError {
+    RawEditError::Mismatch(format!("corrupt NEF data: {}", what))
+}
+
+/// A canonical Huffman code, as `TREES` give them
+#[derive(Debug, Clone)]
+struct Huffman {
+    maxcode: [i32; 17],
+    mincode: [i32; 17],
+    valptr: [usize; 17],
+    symbols: Vec<u8>,
+    /// The code and its length of every symbol, the first one for symbols with several
+    codes: Vec<Option<(u32, u32)>>,
+}
+
+impl Huffman {
+    fn new(tree: &[u8; 32]) -> Huffman {
+        let mut huffman = Huffman {
+            maxcode: [-1; 17],
+            mincode: [0; 17],
+            valptr: [0; 17],
+            symbols: vec![],
+            codes: vec![None; 256],
+        };
+        let mut code = 0i32;
+        for length in 1..=16 {
+            let count = tree[length - 1] as usize;
+            if count > 0 {
+                huffman.valptr[length] = huffman.symbols.len();
+                huffman.mincode[length] = code;
+                for i in 0..count {
+                    let symbol = tree.get(16 + huffman.symbols.len()).copied().unwrap_or(0);
+                    let slot = &mut huffman.codes[symbol as usize];
+                    if slot.is_none() {
+                        *slot = Some(((code + i as i32) as u32, length as u32));
+                    }
+                    huffman.symbols.push(symbol);
+                }
+                code += count as i32;
+                huffman.maxcode[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        huffman
+    }
+
+    fn decode(&self, pump: &mut BitPumpMSB) -> Result<u8, RawEditError> {
+        let bits = pump.peek_bits(16) as i32;
+        for length in 1..=16 {
+            let code = bits >> (16 - length);
+            if code <= self.maxcode[length] {
+                pump.consume_bits(length as u32);
+                return Ok(
+                    self.symbols[self.valptr[length] + (code - self.mincode[length]) as usize]
+                );
+            }
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+
+    /// Reads the difference a symbol stands for
+    fn difference(pump: &mut BitPumpMSB, symbol: u8) -> i32 {
+        let length = (symbol & 15) as u32;
+        let shift = (symbol >> 4) as u32;
+        if length == 0 {
+            return 0;
+        }
+        let bits = pump.get_bits(length.saturating_sub(shift)) as i32;
+        let mut diff = ((bits << 1) + 1) << shift >> 1;
+        if diff & (1 << (length - 1)) == 0 {
+            diff -= (1 << length) - (shift == 0) as i32;
+        }
+        diff
+    }
+
+    /// Writes a difference, which the tree has to have a symbol for
+    fn encode(&self, writer: &mut BitWriterMSB, diff: i32) -> Result<(), RawEditError> {
+        let length = 32 - diff.unsigned_abs().leading_zeros();
+        let (code, code_length) = self.codes[length as usize].ok_or_else(|| {
+            RawEditError::Mismatch(format!(
+                "the NEF Huffman tree has no code for a difference of {}",
+                diff
+            ))
+        })?;
+        writer.push_bits(code, code_length);
+        if length > 0 {
+            let bits = if diff < 0 {
+                diff + (1 << length) - 1
+            } else {
+                diff
+            };
+            writer.push_bits(bits as u32, length);
+        }
+        Ok(())
+    }
+}
+
+/// Whether a file is a NEF: a TIFF made by Nikon
+pub fn is_nef(tiff: &Tiff) -> bool {
+    tiff.ifd0()
+        .and_then(|ifd| tiff.string(ifd.entry(tiff::MAKE)?))
+        .is_some_and(|make| make.to_ascii_uppercase().starts_with("NIKON"))
+}
+
+/// Nikon's maker notes, a TIFF of their own after "Nikon\0" and a version
+fn maker_notes<'a>(tiff: &Tiff<'a>) -> Option<Tiff<'a>> {
+    let start = tiff.exif_ifd()?.entry(tiff::MAKER_NOTE)?.value_pos;
+    let data = tiff.data().get(start..)?;
+    if !data.starts_with(b"Nikon\0") {
+        return None;
+    }
+    Tiff::new(data.get(10..)?)
+}
+
+/// Where the raw data of a NEF is, and how it is coded
+#[derive(Debug, Clone)]
+pub struct NefLayout {
+    pub width: usize,
+    pub height: usize,
+    pub bits: u32,
+    pub strip: Strip,
+    /// Whether the data holds indices into `curve` rather than values
+    pub lossy: bool,
+    /// Index into `TREES`
+    tree: usize,
+    /// The row from which on the next tree codes the data
+    pub split: Option<usize>,
+    /// What the first two columns of even and odd rows are predicted from
+    vpred: [[u16; 2]; 2],
+    /// The curve of lossy data
+    pub curve: Option<LookupTable>,
+}
+
+impl NefLayout {
+    /// Reads the layout of the raw data described by the IFD at `ifd_offset`, and the
+    /// linearization table of the maker notes
+    pub fn read(file: &[u8], ifd_offset: usize) -> Result<NefLayout, RawEditError> {
+        let tiff = Tiff::new(file).ok_or_else(|| unsupported("not a TIFF file"))?;
+        let (ifd, _) = tiff
+            .read_ifd(ifd_offset)
+            .ok_or_else(|| RawEditError::Truncated("the raw IFD".to_owned()))?;
+        let dimension = |tag| tiff.value(&ifd, tag).map(|value| value as usize);
+        let (width, height) = match (dimension(tiff::IMAGE_WIDTH), dimension(tiff::IMAGE_LENGTH)) {
+            (Some(width), Some(height)) => (width, height),
+            _ => return Err(unsupported("NEF raw data without dimensions")),
+        };
+        let bits = match tiff.value(&ifd, tiff::BITS_PER_SAMPLE) {
+            Some(bits @ 12) | Some(bits @ 14) => bits,
+            other => {
+                return Err(unsupported(&format!(
+                    "NEF data of {} bits",
+                    other.unwrap_or(0)
+                )))
+            }
+        };
+        let strip = Strip::read(&tiff, &ifd)?;
+
+        let notes =
+            maker_notes(&tiff).ok_or_else(|| unsupported("NEF without Nikon maker notes"))?;
+        let table = notes
+            .ifd0()
+            .and_then(|ifd| ifd.entry(NIKON_LINEARIZATION_TABLE).copied())
+            .ok_or_else(|| unsupported("NEF without a linearization table"))?;
+        let meta = table.value_pos;
+        let byte = |pos: usize| notes.data().get(pos).copied();
+        let short = |pos: usize| notes.read_u16(pos);
+        let truncated = || RawEditError::Truncated("the NEF linearization table".to_owned());
+        let (ver0, ver1) = (
+            byte(meta).ok_or_else(truncated)?,
+            byte(meta + 1).ok_or_else(truncated)?,
+        );
+        let mut pos = meta + 2;
+        if ver0 == 0x49 || ver1 == 0x58 {
+            pos += 2110;
+        }
+        let mut vpred = [[0u16; 2]; 2];
+        for (i, value) in vpred.iter_mut().flatten().enumerate() {
+            *value = short(pos + i * 2).ok_or_else(truncated)?;
+        }
+        pos += 8;
+        let lossy = ver0 != 0x46;
+        let tree = if lossy { 0 } else { 2 } + if bits == 14 { 3 } else { 0 };
+
+        let max = (1usize << bits) & 0x7fff;
+        let csize = short(pos).ok_or_else(truncated)? as usize;
+        pos += 2;
+        let step = if csize > 1 { max / (csize - 1) } else { 0 };
+        let mut split = None;
+        let mut values = vec![];
+        if ver0 == 0x44 && ver1 == 0x20 && step > 0 {
+            // the curve at every `step`th index, and straight lines in between
+            let points: Vec<u16> = (0..csize)
+                .map(|i| short(pos + i * 2).ok_or_else(truncated))
+                .collect::<Result<_, _>>()?;
+            let point = |i: usize| points[(i / step).min(csize - 1)] as usize;
+            values = (0..max)
+                .map(|i| {
+                    let offset = i % step;
+                    let (low, high) = (point(i - offset), point(i - offset + step));
+                    ((low * (step - offset) + high * offset) / step) as u16
+                })
+                .collect();
+            split = short(meta + 562)
+                .filter(|split| *split > 0)
+                .map(|split| split as usize);
+        } else if lossy && csize <= 0x4001 {
+            values = (0..csize)
+                .map(|i| short(pos + i * 2).ok_or_else(truncated))
+                .collect::<Result<_, _>>()?;
+        }
+        if values.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(unsupported("a NEF curve that doesn't rise"));
+        }
+        let curve = if lossy && values.len() > 1 {
+            Some(LookupTable::with_stride(&values, 1))
+        } else {
+            None
+        };
+        Ok(NefLayout {
+            width,
+            height,
+            bits,
+            strip,
+            lossy,
+            tree,
+            split,
+            vpred,
+            curve,
+        })
+    }
+
+    /// Largest value the data decodes to
+    pub fn white_level(&self) -> u16 {
+        match &self.curve {
+            Some(curve) => curve.max_value(),
+            None => ((1u32 << self.bits) - 1) as u16,
+        }
+    }
+
+    /// Largest index the data can hold
+    fn max_index(&self) -> u16 {
+        match &self.curve {
+            Some(curve) => curve.centers().len().saturating_sub(1).min(0x3fff) as u16,
+            None => ((1u32 << self.bits) - 1) as u16,
+        }
+    }
+}
+
+/// Decodes the raw data, lossy data through its curve with the dither from `dither`
+pub fn decode(file: &[u8], layout: &NefLayout, dither: Factory) -> Result<Vec<u16>, RawEditError> {
+    let data = layout.strip.data(file)?;
+    let (width, height) = (layout.width, layout.height);
+    let mut pixels = vec![0u16; width * height];
+    let mut pump = BitPumpMSB::new(data);
+    let mut random = dither();
+    random.reseed(pump.peek_bits(16));
+    let mut huffman = Huffman::new(&TREES[layout.tree]);
+    let max_index = layout.max_index();
+    let mut vpred = layout.vpred;
+    let mut hpred = [0u16; 2];
+    for row in 0..height {
+        if Some(row) == layout.split {
+            huffman = Huffman::new(&TREES[layout.tree + 1]);
+        }
+        for col in 0..width {
+            let symbol = huffman.decode(&mut pump)?;
+            let diff = Huffman::difference(&mut pump, symbol) as u16;
+            if col < 2 {
+                vpred[row & 1][col] = vpred[row & 1][col].wrapping_add(diff);
+                hpred[col] = vpred[row & 1][col];
+            } else {
+                hpred[col & 1] = hpred[col & 1].wrapping_add(diff);
+            }
+            let index = (hpred[col & 1] as i16).clamp(0, max_index as i16) as u16;
+            pixels[row * width + col] = match &layout.curve {
+                Some(curve) => curve.dither(index, &mut *random),
+                None => index,
+            };
+        }
+    }
+    Ok(pixels)
+}
+
+/// Encodes the image as raw data with the tree, predictions and curve of the original
+pub fn encode(img: &[u16], layout: &NefLayout) -> Result<Vec<u8>, RawEditError> {
+    let (width, height) = (layout.width, layout.height);
+    if img.len() != width * height {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up a {}x{} image",
+            img.len(),
+            width,
+            height
+        )));
+    }
+    if layout.split.is_some() {
+        return Err(unsupported("encoding NEF data that switches Huffman trees"));
+    }
+    let huffman = Huffman::new(&TREES[layout.tree]);
+    let max_index = layout.max_index();
+    let mut writer = BitWriterMSB::new();
+    let mut vpred = layout.vpred.map(|row| row.map(|value| value as i32));
+    let mut hpred = [0i32; 2];
+    for (row, pixels) in img.chunks(width).enumerate() {
+        for (col, value) in pixels.iter().enumerate() {
+            let index = match &layout.curve {
+                Some(curve) => curve.reverse_lookup(*value),
+                None => *value,
+            }
+            .min(max_index) as i32;
+            let prediction = if col < 2 {
+                vpred[row & 1][col]
+            } else {
+                hpred[col & 1]
+            };
+            huffman.encode(&mut writer, index - prediction)?;
+            if col < 2 {
+                vpred[row & 1][col] = index;
+            }
+            hpred[col & 1] = index;
+        }
+    }
+    Ok(writer.into_data())
+}
+
+/// Replaces the raw data of the file with the image, unless its pixels didn't change.
+/// Returns the number of bytes written.
+pub fn write(
+    file: &mut Vec<u8>,
+    layout: &NefLayout,
+    img: &[u16],
+    dither: Factory,
+) -> Result<usize, RawEditError> {
+    if decode(file, layout, dither)? == img {
+        return Ok(0);
+    }
+    let encoded = encode(img, layout)?;
+    layout.strip.replace(file, &encoded)?;
+    Ok(encoded.len())
+}
+
+/// Fills in the black level and the white balance from the maker notes
+pub fn read_calibration(tiff: &Tiff, image: &mut RawImage) {
+    let notes = match maker_notes(tiff) {
+        Some(notes) => notes,
+        None => return,
+    };
+    let ifd = match notes.ifd0() {
+        Some(ifd) => ifd,
+        None => return,
+    };
+    if let Some(entry) = ifd.entry(NIKON_BLACK_LEVEL) {
+        let levels = notes.values(entry);
+        if !levels.is_empty() {
+            image.black_level = (levels.iter().sum::<u32>() / levels.len() as u32) as u16;
+        }
+    }
+    // red and blue as rationals, relative to green
+    if let Some(entry) = ifd.entry(NIKON_WB_RB_LEVELS).filter(|e| e.field_type == 5) {
+        let rational = |i: usize| {
+            let pos = entry.value_pos + i * 8;
+            let numerator = notes.read_u32(pos)? as f32;
+            let denominator = notes.read_u32(pos + 4)? as f32;
+            Some(numerator / denominator).filter(|value| value.is_finite() && *value > 0.0)
+        };
+        if let (Some(red), Some(blue)) = (rational(0), rational(1)) {
+            image.white_balance = [red, 1.0, blue];
+        }
+    }
+}
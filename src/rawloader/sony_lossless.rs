@@ -0,0 +1,332 @@
+//! Sony's lossless compressed raw, as written by the A7R IV, A1 and newer bodies.
+//!
+//! The raw data is cut into tiles, each of them a lossless JPEG (ITU T.81 process 14) of
+//! half the width and half the height of the tile with four components: the four positions
+//! of a 2x2 block of the CFA. The tiles are located through the TileOffsets and
+//! TileByteCounts of the raw IFD.
+//!
+//! Encoding writes one lossless JPEG per tile (see `ljpeg`), so that a tile decodes to exactly
+//! the pixels it was encoded from. A re-encoded tile that no longer fits into the space of the
+//! original one is appended to the file.
+
+use std::convert::TryFrom;
+
+use super::ljpeg::{self, Frame};
+use crate::tiff::{self, Tiff};
+use crate::tiled::Rect;
+use crate::variant::VariantError;
+use crate::RawEditError;
+
+const TILE_WIDTH: u16 = 0x0142;
+const TILE_LENGTH: u16 = 0x0143;
+const TILE_OFFSETS: u16 = 0x0144;
+const TILE_BYTE_COUNTS: u16 = 0x0145;
+
+/// Where the tiles of the raw data are and how large they are
+#[derive(Debug, Clone)]
+pub struct TileLayout {
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: usize,
+    pub tile_length: usize,
+    pub offsets: Vec<usize>,
+    pub byte_counts: Vec<usize>,
+    /// The TileOffsets and TileByteCounts entries, for writing them back
+    offsets_entry: tiff::Entry,
+    byte_counts_entry: tiff::Entry,
+    little_endian: bool,
+}
+
+fn unsupported(reason: &str) -> RawEditError {
+    RawEditError::Variant(VariantError::Unsupported(reason.to_owned()))
+}
+
+impl TileLayout {
+    /// Reads the tile layout of the raw IFD at `ifd_offset`
+    pub fn read(file: &[u8], ifd_offset: usize) -> Result<TileLayout, RawEditError> {
+        let tiff = Tiff::new(file).ok_or_else(|| unsupported("not a TIFF file"))?;
+        let (ifd, _) = tiff
+            .read_ifd(ifd_offset)
+            .ok_or_else(|| RawEditError::Truncated("the raw IFD".to_owned()))?;
+        let value = |tag| tiff.value(&ifd, tag).map(|v| v as usize);
+        let entry = |tag| {
+            ifd.entry(tag)
+                .copied()
+                .ok_or_else(|| unsupported("lossless compressed raw data without tiles"))
+        };
+        let (width, height, tile_width, tile_length) = match (
+            value(tiff::IMAGE_WIDTH),
+            value(tiff::IMAGE_LENGTH),
+            value(TILE_WIDTH),
+            value(TILE_LENGTH),
+        ) {
+            (Some(w), Some(h), Some(tw), Some(tl)) if tw > 0 && tl > 0 => (w, h, tw, tl),
+            _ => return Err(unsupported("lossless compressed raw data without tiles")),
+        };
+        if !tile_width.is_multiple_of(2) || !tile_length.is_multiple_of(2) {
+            return Err(unsupported("lossless compressed tiles of odd size"));
+        }
+        let offsets_entry = entry(TILE_OFFSETS)?;
+        let byte_counts_entry = entry(TILE_BYTE_COUNTS)?;
+        let offsets: Vec<_> = tiff
+            .values(&offsets_entry)
+            .into_iter()
+            .map(|v| v as usize)
+            .collect();
+        let byte_counts: Vec<_> = tiff
+            .values(&byte_counts_entry)
+            .into_iter()
+            .map(|v| v as usize)
+            .collect();
+        let layout = TileLayout {
+            width,
+            height,
+            tile_width,
+            tile_length,
+            offsets,
+            byte_counts,
+            offsets_entry,
+            byte_counts_entry,
+            little_endian: tiff.is_little_endian(),
+        };
+        let tiles = layout.tiles_across() * layout.tiles_down();
+        if layout.offsets.len() < tiles || layout.byte_counts.len() < tiles {
+            return Err(RawEditError::Mismatch(format!(
+                "{}x{} raw data needs {} tiles, the file lists {}",
+                width,
+                height,
+                tiles,
+                layout.offsets.len().min(layout.byte_counts.len())
+            )));
+        }
+        Ok(layout)
+    }
+
+    pub fn tiles_across(&self) -> usize {
+        self.width.div_ceil(self.tile_width)
+    }
+
+    pub fn tiles_down(&self) -> usize {
+        self.height.div_ceil(self.tile_length)
+    }
+
+    /// The part of the image covered by a tile, without what it holds past the edges
+    pub fn tile_rect(&self, tile: usize) -> Rect {
+        let x = tile % self.tiles_across() * self.tile_width;
+        let y = tile / self.tiles_across() * self.tile_length;
+        Rect {
+            x,
+            y,
+            width: self.tile_width.min(self.width - x),
+            height: self.tile_length.min(self.height - y),
+        }
+    }
+
+    /// Tiles touching any of the rectangles, in order
+    pub fn tiles_in(&self, rects: &[Rect]) -> Vec<usize> {
+        (0..self.tiles_across() * self.tiles_down())
+            .filter(|tile| {
+                let tile = self.tile_rect(*tile);
+                rects.iter().any(|rect| {
+                    rect.x < tile.x + tile.width
+                        && tile.x < rect.x + rect.width
+                        && rect.y < tile.y + tile.height
+                        && tile.y < rect.y + rect.height
+                })
+            })
+            .collect()
+    }
+}
+
+/// Decodes a tile into `tile_width` x `tile_length` pixels, returning them along with the
+/// sample precision of the tile
+pub fn decode_tile(
+    data: &[u8],
+    tile_width: usize,
+    tile_length: usize,
+) -> Result<(Vec<u16>, u8), RawEditError> {
+    let (frame, values) = ljpeg::decode(data)?;
+    if frame.components != 4 {
+        return Err(unsupported(
+            "lossless JPEG tiles with other than four components",
+        ));
+    }
+    let (lines, samples) = (frame.lines, frame.samples);
+    if samples * 2 != tile_width || lines * 2 != tile_length {
+        return Err(RawEditError::Mismatch(format!(
+            "a {}x{} lossless JPEG frame doesn't fill a {}x{} tile",
+            samples, lines, tile_width, tile_length
+        )));
+    }
+    let mut pixels = vec![0u16; tile_width * tile_length];
+    for y in 0..lines {
+        for x in 0..samples {
+            for c in 0..4 {
+                let value = values[(y * samples + x) * 4 + c];
+                pixels[(2 * y + c / 2) * tile_width + 2 * x + c % 2] = value;
+            }
+        }
+    }
+    Ok((pixels, frame.precision))
+}
+
+/// Encodes `tile_width` x `tile_length` pixels as a lossless JPEG of the given precision.
+/// Pixels that don't fit the precision are clamped.
+pub fn encode_tile(
+    pixels: &[u16],
+    tile_width: usize,
+    tile_length: usize,
+    precision: u8,
+) -> Result<Vec<u8>, RawEditError> {
+    if !tile_width.is_multiple_of(2)
+        || !tile_length.is_multiple_of(2)
+        || pixels.len() != tile_width * tile_length
+    {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up a {}x{} tile",
+            pixels.len(),
+            tile_width,
+            tile_length
+        )));
+    }
+    let frame = Frame {
+        precision,
+        lines: tile_length / 2,
+        samples: tile_width / 2,
+        components: 4,
+    };
+    let mut values = Vec::with_capacity(pixels.len());
+    for y in 0..frame.lines {
+        for x in 0..frame.samples {
+            for c in 0..4 {
+                values.push(pixels[(2 * y + c / 2) * tile_width + 2 * x + c % 2]);
+            }
+        }
+    }
+    ljpeg::encode(&values, frame)
+}
+
+/// The compressed data of a tile
+pub fn tile_data<'a>(
+    file: &'a [u8],
+    layout: &TileLayout,
+    tile: usize,
+) -> Result<&'a [u8], RawEditError> {
+    let offset = layout.offsets[tile];
+    file.get(offset..offset.saturating_add(layout.byte_counts[tile]))
+        .ok_or_else(|| RawEditError::Truncated(format!("tile {}", tile)))
+}
+
+/// Decodes the whole raw data, row by row
+pub fn decode(file: &[u8], layout: &TileLayout) -> Result<Vec<u16>, RawEditError> {
+    let mut pixels = vec![0u16; layout.width * layout.height];
+    for tile in 0..layout.tiles_across() * layout.tiles_down() {
+        let (decoded, _) = decode_tile(
+            tile_data(file, layout, tile)?,
+            layout.tile_width,
+            layout.tile_length,
+        )?;
+        let rect = layout.tile_rect(tile);
+        for row in 0..rect.height {
+            let start = (rect.y + row) * layout.width + rect.x;
+            pixels[start..start + rect.width].copy_from_slice(
+                &decoded[row * layout.tile_width..row * layout.tile_width + rect.width],
+            );
+        }
+    }
+    Ok(pixels)
+}
+
+/// Re-encodes the given tiles of the image into the file, keeping the precision of the
+/// original tiles. Tiles whose pixels didn't change are left alone, and parts of a tile past
+/// the edges of the image keep what they held. Returns the number of bytes written.
+pub fn write_tiles(
+    file: &mut Vec<u8>,
+    layout: &TileLayout,
+    img: &[u16],
+    tiles: &[usize],
+) -> Result<usize, RawEditError> {
+    if img.len() != layout.width * layout.height {
+        return Err(RawEditError::Mismatch(format!(
+            "{} pixels don't make up a {}x{} image",
+            img.len(),
+            layout.width,
+            layout.height
+        )));
+    }
+    let mut written = 0;
+    for &tile in tiles {
+        let (mut pixels, precision) = decode_tile(
+            tile_data(file, layout, tile)?,
+            layout.tile_width,
+            layout.tile_length,
+        )?;
+        let rect = layout.tile_rect(tile);
+        let mut changed = false;
+        for row in 0..rect.height {
+            let start = (rect.y + row) * layout.width + rect.x;
+            let pixels = &mut pixels[row * layout.tile_width..row * layout.tile_width + rect.width];
+            let img = &img[start..start + rect.width];
+            changed |= pixels != img;
+            pixels.copy_from_slice(img);
+        }
+        if !changed {
+            continue;
+        }
+        let encoded = encode_tile(&pixels, layout.tile_width, layout.tile_length, precision)?;
+
+        let offset = if encoded.len() <= layout.byte_counts[tile] {
+            layout.offsets[tile]
+        } else {
+            // TIFF wants data to start on a word boundary
+            if !file.len().is_multiple_of(2) {
+                file.push(0);
+            }
+            file.len()
+        };
+        if offset + encoded.len() > file.len() {
+            file.resize(offset + encoded.len(), 0);
+        }
+        file[offset..offset + encoded.len()].copy_from_slice(&encoded);
+        write_value(file, layout, &layout.offsets_entry, tile, offset)?;
+        write_value(file, layout, &layout.byte_counts_entry, tile, encoded.len())?;
+        written += encoded.len();
+    }
+    Ok(written)
+}
+
+/// Overwrites one value of a SHORT or LONG array entry
+fn write_value(
+    file: &mut [u8],
+    layout: &TileLayout,
+    entry: &tiff::Entry,
+    index: usize,
+    value: usize,
+) -> Result<(), RawEditError> {
+    let too_large = || {
+        RawEditError::Mismatch(format!(
+            "{} does not fit into the tile entry {:#06x}",
+            value, entry.tag
+        ))
+    };
+    match entry.field_type {
+        3 => {
+            let pos = entry.value_pos + 2 * index;
+            if pos + 2 > file.len() {
+                return Err(RawEditError::Truncated("the tile entries".to_owned()));
+            }
+            let value = u16::try_from(value).map_err(|_| too_large())?;
+            tiff::write_u16(file, pos, value, layout.little_endian);
+        }
+        4 => {
+            let pos = entry.value_pos + 4 * index;
+            if pos + 4 > file.len() {
+                return Err(RawEditError::Truncated("the tile entries".to_owned()));
+            }
+            let value = u32::try_from(value).map_err(|_| too_large())?;
+            tiff::write_u32(file, pos, value, layout.little_endian);
+        }
+        _ => return Err(unsupported("tile entries that aren't SHORT or LONG")),
+    }
+    Ok(())
+}
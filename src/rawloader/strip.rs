@@ -0,0 +1,99 @@
+use std::convert::TryFrom;
+
+use crate::tiff::{self, Entry, Ifd, Tiff};
+use crate::variant::VariantError;
+use crate::RawEditError;
+
+/// Raw data stored as a single strip of no fixed size, as compressed data of other makes is.
+/// Data that was re-encoded is written where the strip was if it fits, and appended to the
+/// file if not.
+#[derive(Debug, Clone, Copy)]
+pub struct Strip {
+    pub offset: usize,
+    pub byte_count: usize,
+    /// The StripOffsets and StripByteCounts entries, for writing them back
+    offset_entry: Entry,
+    byte_count_entry: Entry,
+    little_endian: bool,
+}
+
+impl Strip {
+    /// The strip of an IFD, if it has exactly one
+    pub fn read(tiff: &Tiff, ifd: &Ifd) -> Result<Strip, RawEditError> {
+        let entry = |tag| {
+            ifd.entry(tag)
+                .copied()
+                .filter(|entry| entry.count == 1)
+                .ok_or_else(|| {
+                    RawEditError::Variant(VariantError::Unsupported(
+                        "compressed raw data in other than one strip".to_owned(),
+                    ))
+                })
+        };
+        let offset_entry = entry(tiff::STRIP_OFFSETS)?;
+        let byte_count_entry = entry(tiff::STRIP_BYTE_COUNTS)?;
+        Ok(Strip {
+            offset: tiff.values(&offset_entry)[0] as usize,
+            byte_count: tiff.values(&byte_count_entry)[0] as usize,
+            offset_entry,
+            byte_count_entry,
+            little_endian: tiff.is_little_endian(),
+        })
+    }
+
+    /// The data of the strip
+    pub fn data<'a>(&self, file: &'a [u8]) -> Result<&'a [u8], RawEditError> {
+        file.get(self.offset..self.offset.saturating_add(self.byte_count))
+            .ok_or_else(|| RawEditError::Truncated("the raw data".to_owned()))
+    }
+
+    /// Replaces the data of the strip
+    pub fn replace(&self, file: &mut Vec<u8>, data: &[u8]) -> Result<(), RawEditError> {
+        let offset = if data.len() <= self.byte_count {
+            self.offset
+        } else {
+            // TIFF wants data to start on a word boundary
+            if !file.len().is_multiple_of(2) {
+                file.push(0);
+            }
+            file.len()
+        };
+        if offset + data.len() > file.len() {
+            file.resize(offset + data.len(), 0);
+        }
+        file[offset..offset + data.len()].copy_from_slice(data);
+        self.write_value(file, &self.offset_entry, offset)?;
+        self.write_value(file, &self.byte_count_entry, data.len())
+    }
+
+    /// Overwrites the value of a SHORT or LONG entry
+    fn write_value(
+        &self,
+        file: &mut [u8],
+        entry: &Entry,
+        value: usize,
+    ) -> Result<(), RawEditError> {
+        let too_large = || {
+            RawEditError::Mismatch(format!(
+                "{} does not fit into the strip entry {:#06x}",
+                value, entry.tag
+            ))
+        };
+        match entry.field_type {
+            3 => {
+                let value = u16::try_from(value).map_err(|_| too_large())?;
+                tiff::write_u16(file, entry.value_pos, value, self.little_endian);
+            }
+            4 => {
+                let value = u32::try_from(value).map_err(|_| too_large())?;
+                tiff::write_u32(file, entry.value_pos, value, self.little_endian);
+            }
+            _ => {
+                return Err(RawEditError::Variant(VariantError::Unsupported(
+                    "strip entries that aren't SHORT or LONG".to_owned(),
+                )))
+            }
+        }
+        Ok(())
+    }
+}
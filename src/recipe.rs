@@ -0,0 +1,484 @@
+//! Recipes: a list of operations read from a file, one per line, with named regions that can
+//! be defined once and used by several operations.
+//!
+//! ```text
+//! # regions are x,y,width,height relative to the visible area
+//! region plate_area = 1200,2600,900,300
+//! # a region can be overridden for a camera model
+//! region plate_area [ILCE-7M3] = 1180,2640,900,300
+//! region exif_corner = 100,3700,2000,200
+//!
+//! stretch 0.5,99.5
+//! redact plate_area
+//! blur plate_area 4
+//! sharpen exif_corner 1.5,0.8
+//! text exif_corner Shot on {Model}
+//!
+//! # steps can depend on the metadata of the file
+//! if ISO > 3200: text exif_corner High ISO {ISO}
+//! if Model == ILCE-7RM4 and ISO <= 800: stretch
+//! ```
+
+use std::fmt;
+
+use crate::edit::{FontChain, TextEdit};
+use crate::editor::RawEditor;
+use crate::ops;
+use crate::template::{self, Locale, Metadata};
+use crate::tiled::Rect;
+
+#[derive(Debug, Clone)]
+struct RegionDef {
+    name: String,
+    /// Camera model the definition is specific to
+    model: Option<String>,
+    rect: Rect,
+}
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Stamps a text template into a region, with the text as tall as the region
+    Text { region: String, template: String },
+    /// Fills a region with a constant value (the black level if none is given)
+    Fill { region: String, value: Option<u16> },
+    /// Replaces a region with noise matched to its surroundings
+    Redact { region: String },
+    /// Gaussian blur of a region, each CFA colour on its own
+    Blur { region: String, sigma: f64 },
+    /// Unsharp mask of a region, each CFA colour on its own
+    Sharpen {
+        region: String,
+        sigma: f64,
+        amount: f64,
+    },
+    /// Contrast-stretches the whole visible area
+    Stretch { percentiles: (f64, f64) },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Contains,
+}
+
+/// A predicate on a metadata field, like `ISO > 3200` or `Model == ILCE-7M3`
+#[derive(Debug, Clone)]
+struct Condition {
+    field: String,
+    comparison: Comparison,
+    value: String,
+}
+
+impl Condition {
+    fn parse(source: &str) -> Option<Condition> {
+        // longer operators first, so that `<=` isn't taken for `<`
+        const OPERATORS: [(&str, Comparison); 7] = [
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+            ("~", Comparison::Contains),
+        ];
+        let (pos, op, comparison) = OPERATORS
+            .iter()
+            .filter_map(|(op, comparison)| source.find(op).map(|pos| (pos, *op, *comparison)))
+            .min_by_key(|(pos, op, _)| (*pos, usize::MAX - op.len()))?;
+        let field = source[..pos].trim();
+        let value = source[pos + op.len()..].trim();
+        if field.is_empty() || field.contains(char::is_whitespace) {
+            return None;
+        }
+        Some(Condition {
+            field: field.to_owned(),
+            comparison,
+            value: value.to_owned(),
+        })
+    }
+
+    /// Compares numerically when both sides are numbers and as text otherwise. A missing
+    /// field only satisfies `!=`.
+    fn matches(&self, metadata: &Metadata) -> bool {
+        let actual = match metadata.get(&self.field) {
+            Some(actual) => actual,
+            None => return self.comparison == Comparison::NotEqual,
+        };
+        if self.comparison == Comparison::Contains {
+            return actual.contains(&self.value[..]);
+        }
+        let ordering = match (actual.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(actual), Ok(value)) => actual.partial_cmp(&value),
+            _ => Some(actual.cmp(&self.value[..])),
+        };
+        let ordering = match ordering {
+            Some(ordering) => ordering,
+            None => return false,
+        };
+        match self.comparison {
+            Comparison::Equal => ordering.is_eq(),
+            Comparison::NotEqual => ordering.is_ne(),
+            Comparison::Less => ordering.is_lt(),
+            Comparison::LessOrEqual => ordering.is_le(),
+            Comparison::Greater => ordering.is_gt(),
+            Comparison::GreaterOrEqual => ordering.is_ge(),
+            Comparison::Contains => unreachable!(),
+        }
+    }
+}
+
+/// A step that only runs if all of its conditions hold
+#[derive(Debug, Clone)]
+struct GatedStep {
+    conditions: Vec<Condition>,
+    step: Step,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Recipe {
+    regions: Vec<RegionDef>,
+    steps: Vec<GatedStep>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecipeError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RecipeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "recipe line {}: {}", self.line, self.message)
+    }
+}
+
+/// Parses a rectangle given as `x,y,width,height`
+pub fn parse_rect(value: &str) -> Option<Rect> {
+    let values: Vec<usize> = value
+        .split(',')
+        .map(|part| part.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match values[..] {
+        [x, y, width, height] => Some(Rect {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => None,
+    }
+}
+
+impl Recipe {
+    pub fn parse(source: &str) -> Result<Recipe, RecipeError> {
+        let mut recipe = Recipe::default();
+        for (i, line) in source.lines().enumerate() {
+            let error = |message: &str| RecipeError {
+                line: i + 1,
+                message: message.to_owned(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (conditions, line) = match line.strip_prefix("if ") {
+                Some(rest) => {
+                    let (conditions, step) = rest
+                        .split_once(':')
+                        .ok_or_else(|| error("expected `if <condition>: <operation>`"))?;
+                    let conditions = conditions
+                        .split(" and ")
+                        .map(Condition::parse)
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| error("invalid condition"))?;
+                    (conditions, step.trim())
+                }
+                None => (vec![], line),
+            };
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let step = match keyword {
+                "region" => {
+                    let (head, rect) = rest
+                        .split_once('=')
+                        .ok_or_else(|| error("expected `region <name> [model] = x,y,w,h`"))?;
+                    let rect = parse_rect(rect).ok_or_else(|| error("invalid rectangle"))?;
+                    let head = head.trim();
+                    let (name, model) = match head.split_once('[') {
+                        Some((name, model)) => {
+                            let model = model
+                                .strip_suffix(']')
+                                .ok_or_else(|| error("unterminated camera model"))?;
+                            (name.trim(), Some(model.trim().to_owned()))
+                        }
+                        None => (head, None),
+                    };
+                    if name.is_empty() || name.contains(char::is_whitespace) {
+                        return Err(error("invalid region name"));
+                    }
+                    recipe.regions.push(RegionDef {
+                        name: name.to_owned(),
+                        model,
+                        rect,
+                    });
+                    None
+                }
+                "text" => {
+                    let (region, template) = rest
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| error("expected `text <region> <template>`"))?;
+                    recipe.check_region(region).map_err(|e| error(&e))?;
+                    Some(Step::Text {
+                        region: region.to_owned(),
+                        template: template.trim().to_owned(),
+                    })
+                }
+                "fill" => {
+                    let mut parts = rest.split_whitespace();
+                    let region = parts
+                        .next()
+                        .ok_or_else(|| error("expected `fill <region> [value]`"))?;
+                    let value = match parts.next() {
+                        Some(value) => Some(value.parse().map_err(|_| error("invalid value"))?),
+                        None => None,
+                    };
+                    recipe.check_region(region).map_err(|e| error(&e))?;
+                    Some(Step::Fill {
+                        region: region.to_owned(),
+                        value,
+                    })
+                }
+                "redact" => {
+                    if rest.is_empty() || rest.contains(char::is_whitespace) {
+                        return Err(error("expected `redact <region>`"));
+                    }
+                    recipe.check_region(rest).map_err(|e| error(&e))?;
+                    Some(Step::Redact {
+                        region: rest.to_owned(),
+                    })
+                }
+                "blur" => {
+                    let (region, sigma) = rest
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| error("expected `blur <region> <sigma>`"))?;
+                    let sigma = ops::parse_sigma(sigma).ok_or_else(|| error("invalid sigma"))?;
+                    recipe.check_region(region).map_err(|e| error(&e))?;
+                    Some(Step::Blur {
+                        region: region.to_owned(),
+                        sigma,
+                    })
+                }
+                "sharpen" => {
+                    let (region, settings) = rest
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| error("expected `sharpen <region> <sigma>[,amount]`"))?;
+                    let (sigma, amount) = ops::parse_sharpen(settings)
+                        .ok_or_else(|| error("invalid sharpening settings"))?;
+                    recipe.check_region(region).map_err(|e| error(&e))?;
+                    Some(Step::Sharpen {
+                        region: region.to_owned(),
+                        sigma,
+                        amount,
+                    })
+                }
+                "stretch" => {
+                    let percentiles = if rest.is_empty() {
+                        ops::DEFAULT_STRETCH
+                    } else {
+                        ops::parse_percentiles(rest).ok_or_else(|| error("invalid percentiles"))?
+                    };
+                    Some(Step::Stretch { percentiles })
+                }
+                _ => return Err(error(&format!("unknown operation `{}`", keyword))),
+            };
+            match step {
+                Some(step) => recipe.steps.push(GatedStep { conditions, step }),
+                None if !conditions.is_empty() => {
+                    return Err(error("region definitions cannot be conditional"))
+                }
+                None => {}
+            }
+        }
+        Ok(recipe)
+    }
+
+    /// Regions have to be defined before they are used
+    fn check_region(&self, name: &str) -> Result<(), String> {
+        if self.regions.iter().any(|def| def.name == name) {
+            Ok(())
+        } else {
+            Err(format!("undefined region `{}`", name))
+        }
+    }
+
+    /// Looks up a region, preferring a definition specific to the camera model
+    pub fn region(&self, name: &str, model: Option<&str>) -> Option<Rect> {
+        let mut matching = self.regions.iter().filter(|def| def.name == name);
+        let specific = matching
+            .clone()
+            .find(|def| def.model.is_some() && def.model.as_deref() == model);
+        specific
+            .or_else(|| matching.find(|def| def.model.is_none()))
+            .map(|def| def.rect)
+    }
+}
+
+impl Recipe {
+    /// The steps whose conditions the file meets, in order
+    fn active_steps<'a>(&'a self, metadata: &'a Metadata) -> impl Iterator<Item = &'a Step> + 'a {
+        self.steps
+            .iter()
+            .filter(move |gated| {
+                gated
+                    .conditions
+                    .iter()
+                    .all(|condition| condition.matches(metadata))
+            })
+            .map(|gated| &gated.step)
+    }
+
+    fn stamp(
+        &self,
+        rect: Rect,
+        template: &str,
+        metadata: &Metadata,
+        locale: Locale,
+        fonts: &FontChain,
+    ) -> TextEdit {
+        TextEdit {
+            text: template::render(template, metadata, locale),
+            x: rect.x as u32,
+            y: rect.y as u32,
+            scale: rect.height as f32,
+            fonts: fonts.clone(),
+            ..TextEdit::default()
+        }
+    }
+
+    /// The text stamps of the steps the file meets the conditions of, without applying
+    /// anything; for drawing into the embedded renditions alone
+    pub fn stamps(
+        &self,
+        metadata: &Metadata,
+        locale: Locale,
+        fonts: &FontChain,
+    ) -> Result<Vec<TextEdit>, String> {
+        let model = metadata.get("Model");
+        self.active_steps(metadata)
+            .filter_map(|step| match step {
+                Step::Text {
+                    region: name,
+                    template,
+                } => Some(
+                    self.region(name, model)
+                        .map(|rect| self.stamp(rect, template, metadata, locale, fonts))
+                        .ok_or_else(|| format!("no definition of region `{}`", name)),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of the steps the file meets the conditions of that edit the raw data itself
+    /// rather than stamping text
+    pub fn pixel_steps(&self, metadata: &Metadata) -> usize {
+        self.active_steps(metadata)
+            .filter(|step| !matches!(step, Step::Text { .. }))
+            .count()
+    }
+
+    /// Applies the steps in order, each as its own undoable step, drawing text with `fonts`.
+    /// Returns the text stamps, for drawing them into the embedded renditions as well.
+    pub fn run(
+        &self,
+        editor: &mut RawEditor,
+        metadata: &Metadata,
+        locale: Locale,
+        fonts: &FontChain,
+    ) -> Result<Vec<TextEdit>, String> {
+        let model = metadata.get("Model");
+        let region = |name: &str| {
+            self.region(name, model)
+                .ok_or_else(|| format!("no definition of region `{}`", name))
+        };
+        let mut stamps = vec![];
+        for step in self.active_steps(metadata) {
+            match step {
+                Step::Text {
+                    region: name,
+                    template,
+                } => {
+                    let edit = self.stamp(region(name)?, template, metadata, locale, fonts);
+                    editor.draw_text(&edit);
+                    stamps.push(edit);
+                }
+                Step::Fill {
+                    region: name,
+                    value,
+                } => {
+                    let rect = region(name)?;
+                    let image = editor.image();
+                    let value = value.unwrap_or(image.black_level);
+                    let rect = Rect {
+                        x: rect.x + image.crop.x,
+                        y: rect.y + image.crop.y,
+                        ..rect
+                    };
+                    editor.apply("fill", |img| ops::fill(img, rect, value));
+                }
+                Step::Redact { region: name } => {
+                    let rect = region(name)?;
+                    let crop = editor.image().crop;
+                    let rect = Rect {
+                        x: rect.x + crop.x,
+                        y: rect.y + crop.y,
+                        ..rect
+                    };
+                    editor.apply("redact", |img| ops::redact_matched(img, rect));
+                }
+                Step::Blur {
+                    region: name,
+                    sigma,
+                } => {
+                    let rect = region(name)?;
+                    let crop = editor.image().crop;
+                    let rect = Rect {
+                        x: rect.x + crop.x,
+                        y: rect.y + crop.y,
+                        ..rect
+                    };
+                    editor.apply("blur", |img| ops::blur(img, rect, *sigma));
+                }
+                Step::Sharpen {
+                    region: name,
+                    sigma,
+                    amount,
+                } => {
+                    let rect = region(name)?;
+                    let image = editor.image();
+                    let white_level = image.white_level;
+                    let rect = Rect {
+                        x: rect.x + image.crop.x,
+                        y: rect.y + image.crop.y,
+                        ..rect
+                    };
+                    editor.apply("sharpen", |img| {
+                        ops::unsharp_mask(img, rect, *sigma, *amount, white_level)
+                    });
+                }
+                Step::Stretch { percentiles } => {
+                    let image = editor.image().clone();
+                    editor.apply("stretch", |img| {
+                        ops::contrast_stretch(img, &image, *percentiles)
+                    });
+                }
+            }
+        }
+        Ok(stamps)
+    }
+}
@@ -0,0 +1,87 @@
+//! Redactions for privacy edits (licence plates, faces): filling, blurring or pixelating
+//! regions of the visible area. Blurring and pixelating work on every CFA colour on its own,
+//! so a region keeps its colours and loses only its detail.
+
+use crate::edit::RawBuffer;
+use crate::ops;
+use crate::recipe::parse_rect;
+use crate::tiled::Rect;
+
+/// Largest pixelation block accepted, in photosites
+const MAX_BLOCK: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Redaction {
+    /// Fills the region with the black level
+    Fill(Rect),
+    /// Gaussian blur of the region, `sigma` in photosites
+    Blur { rect: Rect, sigma: f64 },
+    /// Replaces the region with blocks of `block` photosites square
+    Pixelate { rect: Rect, block: usize },
+}
+
+impl Redaction {
+    /// Parses `x,y,width,height`
+    pub fn parse_fill(value: &str) -> Option<Redaction> {
+        parse_rect(value).map(Redaction::Fill)
+    }
+
+    /// Parses `x,y,width,height,sigma`
+    pub fn parse_blur(value: &str) -> Option<Redaction> {
+        let (rect, sigma) = value.rsplit_once(',')?;
+        Some(Redaction::Blur {
+            rect: parse_rect(rect)?,
+            sigma: ops::parse_sigma(sigma)?,
+        })
+    }
+
+    /// Parses `x,y,width,height,block`
+    pub fn parse_pixelate(value: &str) -> Option<Redaction> {
+        let (rect, block) = value.rsplit_once(',')?;
+        let block = block
+            .trim()
+            .parse()
+            .ok()
+            .filter(|block| (2..=MAX_BLOCK).contains(block))?;
+        Some(Redaction::Pixelate {
+            rect: parse_rect(rect)?,
+            block,
+        })
+    }
+
+    /// The region, relative to the visible area
+    pub fn rect(&self) -> Rect {
+        match *self {
+            Redaction::Fill(rect) => rect,
+            Redaction::Blur { rect, .. } | Redaction::Pixelate { rect, .. } => rect,
+        }
+    }
+
+    /// Short name of the operation, for the history of an editor
+    pub fn name(&self) -> &'static str {
+        match self {
+            Redaction::Fill(_) => "fill",
+            Redaction::Blur { .. } => "blur",
+            Redaction::Pixelate { .. } => "pixelate",
+        }
+    }
+
+    /// Applies the redaction with the region placed relative to the visible area `crop` and
+    /// cut off at its edges
+    pub fn apply(&self, img: &mut RawBuffer, crop: Rect, black_level: u16) {
+        let rect = self.rect();
+        let x = crop.x + rect.x.min(crop.width);
+        let y = crop.y + rect.y.min(crop.height);
+        let rect = Rect {
+            x,
+            y,
+            width: rect.width.min(crop.x + crop.width - x),
+            height: rect.height.min(crop.y + crop.height - y),
+        };
+        match *self {
+            Redaction::Fill(_) => ops::fill(img, rect, black_level),
+            Redaction::Blur { sigma, .. } => ops::blur(img, rect, sigma),
+            Redaction::Pixelate { block, .. } => ops::pixelate(img, rect, block),
+        }
+    }
+}
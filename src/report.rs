@@ -0,0 +1,121 @@
+use std::{cmp, fmt, ops::AddAssign};
+
+use crate::rawloader::{BitPump, BitPumpLSB};
+
+/// Where the bits of an ARW2 bitstream go
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BitBudget {
+    pub bytes: usize,
+    pub blocks: usize,
+    pub max_min_bits: usize,
+    pub index_bits: usize,
+    pub delta_bits: usize,
+    /// Number of blocks by the shift applied to their 7-bit deltas
+    pub shift_histogram: [usize; 8],
+    /// Number of pixels whose decoded value got clamped to 0x7ff
+    pub clipped: usize,
+}
+
+impl BitBudget {
+    pub fn shifted_blocks(&self) -> usize {
+        self.shift_histogram[1..].iter().sum()
+    }
+}
+
+impl AddAssign for BitBudget {
+    fn add_assign(&mut self, other: BitBudget) {
+        self.bytes += other.bytes;
+        self.blocks += other.blocks;
+        self.max_min_bits += other.max_min_bits;
+        self.index_bits += other.index_bits;
+        self.delta_bits += other.delta_bits;
+        for (count, other) in self.shift_histogram.iter_mut().zip(&other.shift_histogram) {
+            *count += other;
+        }
+        self.clipped += other.clipped;
+    }
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / total as f64
+    }
+}
+
+impl fmt::Display for BitBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bits = self.bytes * 8;
+        writeln!(
+            f,
+            "  size:       {} bytes ({} blocks)",
+            self.bytes, self.blocks
+        )?;
+        writeln!(
+            f,
+            "  max/min:    {} bits ({:.1}%)",
+            self.max_min_bits,
+            percent(self.max_min_bits, bits)
+        )?;
+        writeln!(
+            f,
+            "  indices:    {} bits ({:.1}%)",
+            self.index_bits,
+            percent(self.index_bits, bits)
+        )?;
+        writeln!(
+            f,
+            "  deltas:     {} bits ({:.1}%)",
+            self.delta_bits,
+            percent(self.delta_bits, bits)
+        )?;
+        writeln!(
+            f,
+            "  shifted:    {} blocks ({:.1}%), by shift {:?}",
+            self.shifted_blocks(),
+            percent(self.shifted_blocks(), self.blocks),
+            self.shift_histogram
+        )?;
+        write!(f, "  clipped:    {} pixels", self.clipped)
+    }
+}
+
+/// Walks an ARW2 bitstream the same way `decode_arw2` does, tallying up the fields
+pub fn analyze_arw2(buf: &[u8], width: usize, height: usize) -> BitBudget {
+    let mut budget = BitBudget {
+        bytes: width * height,
+        ..BitBudget::default()
+    };
+
+    for row in 0..height {
+        let mut pump = BitPumpLSB::new(buf.get((row * width)..).unwrap_or(&[]));
+        for _ in 0..(width / 32) {
+            for _ in 0..2 {
+                let max = pump.get_bits(11);
+                let min = pump.get_bits(11);
+                let delta = max.saturating_sub(min);
+                let shift = cmp::max(0, (32 - (delta.leading_zeros() as i32)) - 7) as usize;
+                let imax = pump.get_bits(4);
+                let imin = pump.get_bits(4);
+
+                budget.blocks += 1;
+                budget.max_min_bits += 22;
+                budget.index_bits += 8;
+                budget.shift_histogram[cmp::min(shift, 7)] += 1;
+
+                for i in 0..16 {
+                    if i == imax || i == imin {
+                        continue;
+                    }
+                    budget.delta_bits += 7;
+                    if (pump.get_bits(7) << shift) + min > 0x7ff {
+                        budget.clipped += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    budget
+}
@@ -0,0 +1,236 @@
+//! Plausibility checks on decoded raw data. A model the decoder doesn't really support often
+//! decodes without an error into garbage: the wrong bit depth, a shifted offset or a codec
+//! that loses sync. These heuristics look for the usual signs, so that such a file is noticed
+//! before it is rewritten.
+
+use std::{fmt, io};
+
+use crate::raw::RawImage;
+use crate::rawloader::{BitPump, BitPumpLSB};
+use crate::source::ByteSource;
+
+/// Rows of ARW2 data `check_arw2_from` reads at a time
+const CHECK_BAND_ROWS: usize = 256;
+/// Share of the visible area at zero above which the decode is suspicious. Real data sits on
+/// a black level, with noise around it, and is almost never zero.
+pub const MAX_ZERO_FRACTION: f64 = 0.01;
+/// Share of the visible area at or above the white level above which the decode is
+/// suspicious. Even badly overexposed frames keep some detail.
+pub const MAX_CLIPPED_FRACTION: f64 = 0.5;
+/// How much more a row has to differ from both of its neighbours of the same colour than the
+/// neighbours differ from each other to count as out of sync
+const DESYNC_RATIO: f64 = 8.0;
+/// Smallest median difference to the neighbours, relative to the range between black and
+/// white, of a row that counts as out of sync; below it, rows are just noise
+const DESYNC_LEVEL: f64 = 0.05;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suspicion {
+    /// Too much of the visible area is zero
+    Zeros { fraction: f64 },
+    /// Too much of the visible area is at or above the white level
+    Clipped { fraction: f64 },
+    /// ARW2 blocks whose maximum is below their minimum, or whose maximum and minimum share
+    /// a position; no encoder writes these
+    InvalidBlocks {
+        count: usize,
+        total: usize,
+        first_row: usize,
+    },
+    /// Rows unlike both of their neighbours, as left behind by a decoder losing its place
+    /// in the data
+    RowDesync { count: usize, first_row: usize },
+}
+
+impl fmt::Display for Suspicion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Suspicion::Zeros { fraction } => write!(
+                f,
+                "{:.1}% of the visible area decodes to zero",
+                fraction * 100.0
+            ),
+            Suspicion::Clipped { fraction } => write!(
+                f,
+                "{:.1}% of the visible area decodes at or above the white level",
+                fraction * 100.0
+            ),
+            Suspicion::InvalidBlocks {
+                count,
+                total,
+                first_row,
+            } => write!(
+                f,
+                "{} of {} ARW2 blocks are invalid, the first in row {}",
+                count, total, first_row
+            ),
+            Suspicion::RowDesync { count, first_row } => write!(
+                f,
+                "{} rows look out of sync with their neighbours, the first is row {}",
+                count, first_row
+            ),
+        }
+    }
+}
+
+/// Median absolute difference of two rows. Unlike the mean, it isn't moved by edges or
+/// small bright objects, which only cover part of a row.
+fn row_difference(a: &[u16], b: &[u16]) -> f64 {
+    let mut differences: Vec<_> = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs())
+        .collect();
+    if differences.is_empty() {
+        return 0.0;
+    }
+    let middle = differences.len() / 2;
+    *differences.select_nth_unstable(middle).1 as f64
+}
+
+/// Checks the decoded pixels of an image in stored order, with its levels and crop known
+pub fn check_image(image: &RawImage) -> Vec<Suspicion> {
+    let mut suspicions = vec![];
+    let crop = image.crop;
+    let mut zeros = 0usize;
+    let mut clipped = 0usize;
+    for y in crop.y..crop.y + crop.height {
+        let row = &image.pixels[y * image.width + crop.x..y * image.width + crop.x + crop.width];
+        zeros += row.iter().filter(|value| **value == 0).count();
+        clipped += row
+            .iter()
+            .filter(|value| **value >= image.white_level)
+            .count();
+    }
+    let total = (crop.width * crop.height).max(1) as f64;
+    if zeros as f64 / total > MAX_ZERO_FRACTION {
+        suspicions.push(Suspicion::Zeros {
+            fraction: zeros as f64 / total,
+        });
+    }
+    if clipped as f64 / total > MAX_CLIPPED_FRACTION {
+        suspicions.push(Suspicion::Clipped {
+            fraction: clipped as f64 / total,
+        });
+    }
+
+    // rows of the same colour are two apart
+    let range = image.white_level.saturating_sub(image.black_level) as f64;
+    let rows: Vec<_> = image.pixels.chunks(image.width.max(1)).collect();
+    let desynced: Vec<_> = (2..rows.len().saturating_sub(2))
+        .filter(|y| {
+            let (above, row, below) = (rows[y - 2], rows[*y], rows[y + 2]);
+            let own = row_difference(row, above).min(row_difference(row, below));
+            own > range * DESYNC_LEVEL && own > row_difference(above, below) * DESYNC_RATIO
+        })
+        .collect();
+    if let Some(first_row) = desynced.first() {
+        suspicions.push(Suspicion::RowDesync {
+            count: desynced.len(),
+            first_row: *first_row,
+        });
+    }
+    suspicions
+}
+
+/// Checks the block headers of ARW2 data. Rows are read on their own, so a bad block only
+/// spoils the rest of its row.
+pub fn check_arw2(data: &[u8], width: usize, height: usize) -> Vec<Suspicion> {
+    let (invalid, first_row) = invalid_blocks(data, width, height);
+    invalid_blocks_suspicion(invalid, first_row, width, height)
+}
+
+/// Like `check_arw2`, reading the data from `src` at `offset` a band of rows at a time. The
+/// last block of a row runs into the next one where the width isn't a multiple of 32, so
+/// each band is read with the row after it, which is carried over to the next band.
+///
+/// ```
+/// use raw_tiff_edit::rawloader::{calculate_curve, encode_arw2};
+/// use raw_tiff_edit::sanity::{self, Suspicion};
+/// use raw_tiff_edit::source::MemorySource;
+///
+/// // rows of 48 bytes of the same block, the last group of which runs 16 bytes into the
+/// // next row
+/// let ramp: Vec<u16> = (0..32).map(|i| 1000 + 10 * i).collect();
+/// let block = encode_arw2(&ramp, 32, &calculate_curve())?;
+/// let (width, height) = (48, 600);
+/// let mut data: Vec<u8> = block[..16].iter().copied().cycle().take(width * height).collect();
+/// let check = |data: &[u8]| -> std::io::Result<_> {
+///     let streamed = sanity::check_arw2_from(&mut MemorySource::new(data), 0, width, height)?;
+///     assert_eq!(streamed, sanity::check_arw2(data, width, height));
+///     Ok(streamed)
+/// };
+/// // only the last row runs past the end of the data
+/// let found = check(&data)?;
+/// assert!(matches!(found[..], [Suspicion::InvalidBlocks { count: 1, first_row: 599, .. }]));
+///
+/// // a block whose maximum and minimum share a position, at the start of a band, which the
+/// // last row of the band before runs into as well
+/// data[256 * width + 2] = 0;
+/// data[256 * width + 3] = 0;
+/// let found = check(&data)?;
+/// assert!(matches!(found[..], [Suspicion::InvalidBlocks { count: 3, first_row: 255, .. }]));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn check_arw2_from<S: ByteSource>(
+    src: &mut S,
+    offset: u64,
+    width: usize,
+    height: usize,
+) -> io::Result<Vec<Suspicion>> {
+    let (mut invalid, mut first_row) = (0, None);
+    let mut band = vec![];
+    for start in (0..height).step_by(CHECK_BAND_ROWS) {
+        let rows = CHECK_BAND_ROWS.min(height - start);
+        let carried = band.len();
+        band.resize(((start + rows + 1).min(height) - start) * width, 0);
+        let read_from = offset + (start * width + carried) as u64;
+        src.read_exact_at(read_from, &mut band[carried..])?;
+        let (band_invalid, band_first_row) = invalid_blocks(&band, width, rows);
+        invalid += band_invalid;
+        first_row = first_row.or(band_first_row.map(|row| start + row));
+        band.drain(..rows * width);
+    }
+    Ok(invalid_blocks_suspicion(invalid, first_row, width, height))
+}
+
+/// Number of rows with a bad block header, and the first of them
+fn invalid_blocks(data: &[u8], width: usize, height: usize) -> (usize, Option<usize>) {
+    let mut invalid = 0;
+    let mut first_row = None;
+    for row in 0..height {
+        let mut pump = BitPumpLSB::new(data.get(row * width..).unwrap_or(&[]));
+        for _ in 0..width.div_ceil(32) * 2 {
+            let max = pump.get_bits(11);
+            let min = pump.get_bits(11);
+            let imax = pump.get_bits(4);
+            let imin = pump.get_bits(4);
+            if max < min || imax == imin {
+                invalid += 1;
+                first_row.get_or_insert(row);
+                // the deltas can't be told apart from what follows them any more
+                break;
+            }
+            for _ in 0..14 {
+                pump.get_bits(7);
+            }
+        }
+    }
+    (invalid, first_row)
+}
+
+fn invalid_blocks_suspicion(
+    invalid: usize,
+    first_row: Option<usize>,
+    width: usize,
+    height: usize,
+) -> Vec<Suspicion> {
+    match first_row {
+        Some(first_row) => vec![Suspicion::InvalidBlocks {
+            count: invalid,
+            total: width.div_ceil(32) * 2 * height,
+            first_row,
+        }],
+        None => vec![],
+    }
+}
@@ -0,0 +1,118 @@
+//! Pre-ARW Sony raw formats: SRF (DSC-F828, DSC-V3) and SR2 (DSC-R1).
+//!
+//! Both store plain 16-bit big-endian samples. SRF additionally XORs the raw data with a
+//! keystream derived from a key hidden in the file, which makes re-encoding a matter of
+//! applying the same keystream again.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::RawEditError;
+
+/// Largest value of the 14-bit samples
+pub const WHITE_LEVEL: u16 = 0x3fff;
+
+/// Offset of the byte holding the position of the SRF master key
+const SRF_KEY_POINTER: usize = 200896;
+/// Offset of the encrypted block holding the SRF raw data key
+const SRF_KEY_BLOCK: usize = 164600;
+
+/// Sony's keystream cipher used in SRF files (and in SR2 private metadata)
+#[derive(Clone)]
+pub struct SonyDecrypt {
+    pad: [u32; 128],
+    p: usize,
+}
+
+impl SonyDecrypt {
+    pub fn new(mut key: u32) -> SonyDecrypt {
+        let mut pad = [0u32; 128];
+        for entry in pad.iter_mut().take(4) {
+            key = key.wrapping_mul(48828125).wrapping_add(1);
+            *entry = key;
+        }
+        pad[3] = pad[3] << 1 | (pad[0] ^ pad[2]) >> 31;
+        for p in 4..127 {
+            pad[p] = (pad[p - 4] ^ pad[p - 2]) << 1 | (pad[p - 3] ^ pad[p - 1]) >> 31;
+        }
+        SonyDecrypt { pad, p: 127 }
+    }
+
+    /// XORs the data with the keystream, 4 bytes at a time. A trailing partial word is left
+    /// untouched. Encryption and decryption are the same operation.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for word in data.chunks_exact_mut(4) {
+            self.p += 1;
+            let p = self.p;
+            let pad = self.pad[p & 127] ^ self.pad[(p + 64) & 127];
+            self.pad[(p - 1) & 127] = pad;
+            let value = BigEndian::read_u32(word) ^ pad;
+            BigEndian::write_u32(word, value);
+        }
+    }
+}
+
+/// Extracts the key the raw data of an SRF file is encrypted with, or `None` if the file is
+/// too short to hold one (which rules out it being an SRF file)
+///
+/// ```
+/// use raw_tiff_edit::sony_legacy::srf_key;
+///
+/// assert_eq!(srf_key(&[]), None);
+/// assert_eq!(srf_key(&[0xff; 4096]), None);
+/// // the pointer to the master key leads past the end of the file
+/// assert_eq!(srf_key(&[0xff; 200897]), None);
+/// assert!(srf_key(&[0; 200900]).is_some());
+/// ```
+pub fn srf_key(file: &[u8]) -> Option<u32> {
+    let pos = SRF_KEY_POINTER + *file.get(SRF_KEY_POINTER)? as usize * 4;
+    let master_key = BigEndian::read_u32(file.get(pos..pos + 4)?);
+
+    let mut head = [0u8; 40];
+    head.copy_from_slice(file.get(SRF_KEY_BLOCK..SRF_KEY_BLOCK + 40)?);
+    SonyDecrypt::new(master_key).apply(&mut head);
+
+    Some(
+        head[22..26]
+            .iter()
+            .rev()
+            .fold(0u32, |key, byte| key << 8 | *byte as u32),
+    )
+}
+
+/// The `width * height * 2` bytes of raw data at the start of `buf`
+fn raw_data(buf: &[u8], width: usize, height: usize) -> Result<&[u8], RawEditError> {
+    buf.get(..width * height * 2)
+        .ok_or_else(|| RawEditError::Truncated("the raw data".to_owned()))
+}
+
+pub fn decode_sr2(buf: &[u8], width: usize, height: usize) -> Result<Vec<u16>, RawEditError> {
+    let mut result = vec![0; width * height];
+    BigEndian::read_u16_into(raw_data(buf, width, height)?, &mut result);
+    Ok(result)
+}
+
+pub fn encode_sr2(img: &[u16]) -> Vec<u8> {
+    let mut result = vec![0; img.len() * 2];
+    BigEndian::write_u16_into(img, &mut result);
+    result
+}
+
+pub fn decode_srf(
+    buf: &[u8],
+    key: u32,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u16>, RawEditError> {
+    let mut data = raw_data(buf, width, height)?.to_vec();
+    // the keystream runs continuously across rows
+    SonyDecrypt::new(key).apply(&mut data);
+    let mut result = vec![0; width * height];
+    BigEndian::read_u16_into(&data, &mut result);
+    Ok(result)
+}
+
+pub fn encode_srf(img: &[u16], key: u32) -> Vec<u8> {
+    let mut result = encode_sr2(img);
+    SonyDecrypt::new(key).apply(&mut result);
+    result
+}
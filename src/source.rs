@@ -0,0 +1,65 @@
+//! Random-access byte sources the codecs can read from without loading whole files: anything
+//! `Read + Seek` (files, network streams with seekable buffering, archive entries) and plain
+//! memory through `MemorySource` (e.g. memory maps).
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub trait ByteSource {
+    /// Total number of bytes in the source
+    fn size(&mut self) -> io::Result<u64>;
+
+    /// Fills `buf` with the bytes starting at `offset`
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn read_vec_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read_exact_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl<T: Read + Seek> ByteSource for T {
+    fn size(&mut self) -> io::Result<u64> {
+        let pos = self.stream_position()?;
+        let size = self.seek(SeekFrom::End(0))?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(size)
+    }
+
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+}
+
+/// A source over bytes that are already in memory
+#[derive(Debug, Clone, Copy)]
+pub struct MemorySource<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> MemorySource<'a> {
+    pub fn new(data: &'a [u8]) -> MemorySource<'a> {
+        MemorySource { data }
+    }
+
+    /// Borrows a range of the data without copying
+    pub fn slice(&self, offset: u64, len: usize) -> io::Result<&'a [u8]> {
+        let start = offset as usize;
+        self.data
+            .get(start..start.saturating_add(len))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "read past the end"))
+    }
+}
+
+impl<'a> ByteSource for MemorySource<'a> {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn read_exact_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let len = buf.len();
+        buf.copy_from_slice(self.slice(offset, len)?);
+        Ok(())
+    }
+}
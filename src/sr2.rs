@@ -0,0 +1,54 @@
+//! Sony's private metadata. DNGPrivateData in IFD0 points at the SR2Private IFD, which locates
+//! the SR2SubIFD and the key it is encrypted with (using the keystream of SRF files). The
+//! SR2SubIFD holds the calibration of the sensor: black and white levels, white balance and,
+//! on some bodies, the tone curve of compressed data.
+
+use crate::rawloader::ToneCurve;
+use crate::sony_legacy::SonyDecrypt;
+use crate::tiff::{self, Ifd, Tiff};
+
+/// The decrypted SR2SubIFD of a file
+#[derive(Debug, Clone)]
+pub struct SubIfd {
+    /// The file up to the end of the SR2SubIFD, with the SR2SubIFD decrypted
+    data: Vec<u8>,
+    ifd: Ifd,
+}
+
+impl SubIfd {
+    pub fn read(tiff: &Tiff) -> Option<SubIfd> {
+        let pointer = tiff.ifd0()?.entry(tiff::DNG_PRIVATE_DATA)?.value_pos;
+        let (private, _) = tiff.read_ifd(tiff.read_u32(pointer)? as usize)?;
+        let offset = tiff.value(&private, tiff::SR2_SUB_IFD_OFFSET)? as usize;
+        let length = tiff.value(&private, tiff::SR2_SUB_IFD_LENGTH)? as usize;
+        // four bytes of UNDEFINED, in the byte order of the file
+        let key = tiff.read_u32(private.entry(tiff::SR2_SUB_IFD_KEY)?.value_pos)?;
+
+        let mut data = tiff.data().get(..offset.checked_add(length)?)?.to_vec();
+        SonyDecrypt::new(key).apply(&mut data[offset..]);
+        let (ifd, _) = Tiff::new(&data)?.read_ifd(offset)?;
+        Some(SubIfd { data, ifd })
+    }
+
+    pub fn values(&self, tag: u16) -> Vec<u32> {
+        match (Tiff::new(&self.data), self.ifd.entry(tag)) {
+            (Some(tiff), Some(entry)) => tiff.values(entry),
+            _ => vec![],
+        }
+    }
+}
+
+/// The tone curve of the raw data described by `ifd`, from its own SonyToneCurve or else from
+/// the SR2SubIFD. `None` if neither has a usable one.
+pub fn tone_curve(tiff: &Tiff, ifd: &Ifd, private: Option<&SubIfd>) -> Option<ToneCurve> {
+    let values = match ifd.entry(tiff::SONY_TONE_CURVE) {
+        Some(entry) => tiff.values(entry),
+        None => private?.values(tiff::SONY_TONE_CURVE),
+    };
+    match values[..] {
+        [a, b, c, d] if values.iter().all(|value| *value <= u16::MAX as u32) => {
+            ToneCurve::new([a as u16, b as u16, c as u16, d as u16])
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,297 @@
+//! Measurements on decoded raw data: the levels of every CFA channel of the visible area, and
+//! the masked (optical black) borders of the sensor, which see no light and so show the true
+//! black level and the readout noise of the file. Comparing the statistics of a file before
+//! and after an edit or a re-encode shows whether its levels shifted.
+
+use std::fmt;
+
+use crate::raw::{CfaColor, RawImage};
+
+/// Bins of the histograms of `analyze`, spread evenly from 0 to the white level
+pub const HISTOGRAM_BINS: usize = 256;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy)]
+pub struct BlackStats {
+    /// Mean of the masked pixels at each position of the 2x2 CFA pattern, row-major
+    pub levels: [f64; 4],
+    pub colors: [CfaColor; 4],
+    /// Number of masked pixels measured
+    pub samples: usize,
+    /// Standard deviation of the per-row means of the masked pixels
+    pub row_noise: f64,
+}
+
+impl BlackStats {
+    /// The overall black level, rounded to the nearest integer
+    pub fn black_level(&self) -> u16 {
+        (self.levels.iter().sum::<f64>() / 4.0).round() as u16
+    }
+}
+
+impl fmt::Display for BlackStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "optical black ({} pixels):", self.samples)?;
+        for (i, (level, color)) in self.levels.iter().zip(&self.colors).enumerate() {
+            writeln!(f, "  {:?} ({}, {}): {:.2}", color, i % 2, i / 2, level)?;
+        }
+        writeln!(f, "  black level: {}", self.black_level())?;
+        write!(f, "  row noise:   {:.3}", self.row_noise)
+    }
+}
+
+impl BlackStats {
+    fn to_json(self) -> String {
+        let levels: Vec<_> = self.levels.iter().map(|level| level.to_string()).collect();
+        let colors: Vec<_> = self
+            .colors
+            .iter()
+            .map(|color| format!("\"{:?}\"", color))
+            .collect();
+        format!(
+            "{{\"levels\":[{}],\"colors\":[{}],\"samples\":{},\"row_noise\":{}}}",
+            levels.join(","),
+            colors.join(","),
+            self.samples,
+            self.row_noise
+        )
+    }
+}
+
+/// The values of one position of the 2x2 CFA pattern in the visible area
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+    pub color: CfaColor,
+    /// Position in the CFA pattern
+    pub x: usize,
+    pub y: usize,
+    pub count: usize,
+    pub min: u16,
+    pub max: u16,
+    pub mean: f64,
+    pub median: u16,
+    /// Pixels at or above the white level
+    pub clipped: usize,
+    /// Pixels at or below the black level
+    pub at_black: usize,
+    /// Pixels in each of `HISTOGRAM_BINS` equal bins from 0 to the white level; values above
+    /// the white level count in the last
+    pub histogram: Vec<usize>,
+}
+
+impl ChannelStats {
+    /// Percentage of the pixels at or above the white level
+    pub fn clipped_percent(&self) -> f64 {
+        self.clipped as f64 * 100.0 / self.count.max(1) as f64
+    }
+
+    /// Percentage of the pixels at or below the black level
+    pub fn at_black_percent(&self) -> f64 {
+        self.at_black as f64 * 100.0 / self.count.max(1) as f64
+    }
+
+    fn to_json(&self) -> String {
+        let histogram: Vec<_> = self.histogram.iter().map(|n| n.to_string()).collect();
+        format!(
+            "{{\"color\":\"{:?}\",\"x\":{},\"y\":{},\"count\":{},\"min\":{},\"max\":{},\"mean\":{},\"median\":{},\"clipped\":{},\"at_black\":{},\"histogram\":[{}]}}",
+            self.color,
+            self.x,
+            self.y,
+            self.count,
+            self.min,
+            self.max,
+            self.mean,
+            self.median,
+            self.clipped,
+            self.at_black,
+            histogram.join(",")
+        )
+    }
+}
+
+/// Statistics of a decoded image, as `analyze` measures them
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone)]
+pub struct RawStats {
+    /// Size of the visible area measured
+    pub width: usize,
+    pub height: usize,
+    pub black_level: u16,
+    pub white_level: u16,
+    /// The four positions of the CFA pattern, row-major
+    pub channels: Vec<ChannelStats>,
+    /// The masked borders, if the image has any
+    pub optical_black: Option<BlackStats>,
+}
+
+impl RawStats {
+    /// The statistics as a JSON object, with the fields named as in the struct
+    pub fn to_json(&self) -> String {
+        let channels: Vec<_> = self.channels.iter().map(|c| c.to_json()).collect();
+        format!(
+            "{{\"width\":{},\"height\":{},\"black_level\":{},\"white_level\":{},\"channels\":[{}],\"optical_black\":{}}}",
+            self.width,
+            self.height,
+            self.black_level,
+            self.white_level,
+            channels.join(","),
+            self.optical_black
+                .map_or_else(|| "null".to_owned(), BlackStats::to_json)
+        )
+    }
+}
+
+impl fmt::Display for RawStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "visible area {}x{}, black level {}, white level {}:",
+            self.width, self.height, self.black_level, self.white_level
+        )?;
+        for channel in &self.channels {
+            writeln!(
+                f,
+                "  {:?} ({}, {}): mean {:.2}, median {}, min {}, max {}, clipped {:.3}%, at black {:.3}%",
+                channel.color,
+                channel.x,
+                channel.y,
+                channel.mean,
+                channel.median,
+                channel.min,
+                channel.max,
+                channel.clipped_percent(),
+                channel.at_black_percent()
+            )?;
+        }
+        match self.optical_black {
+            Some(black) => write!(f, "{}", black),
+            None => write!(f, "no optical black area"),
+        }
+    }
+}
+
+/// Measures every CFA channel of the visible area, and the masked borders
+///
+/// ```
+/// use raw_tiff_edit::raw::RawImage;
+/// use raw_tiff_edit::stats;
+///
+/// // clipped reds and greens at the black level, on blues rising from black to white
+/// let pixels = (0..64 * 64).map(|i| match (i / 64 % 2, i % 2) {
+///     (0, 0) => 16383,
+///     (1, 1) => (i * 4) as u16,
+///     _ => 512,
+/// });
+/// let mut image = RawImage::new(pixels.collect(), 64, 64, 16383);
+/// image.black_level = 512;
+/// let stats = stats::analyze(&image);
+/// assert_eq!(stats.channels[0].clipped_percent(), 100.0);
+/// assert_eq!(stats.channels[1].at_black, 64 * 64 / 4);
+/// assert_eq!(stats.channels[3].max, 16380);
+/// assert_eq!(stats.channels[3].histogram.iter().sum::<usize>(), 64 * 64 / 4);
+/// assert!(stats.optical_black.is_none());
+/// ```
+pub fn analyze(image: &RawImage) -> RawStats {
+    let crop = image.crop;
+    let right = (crop.x + crop.width).min(image.width);
+    let bottom = (crop.y + crop.height).min(image.height);
+    let mut values = vec![vec![0usize; u16::MAX as usize + 1]; 4];
+    for y in crop.y..bottom {
+        for x in crop.x..right {
+            let value = image.pixels[y * image.width + x];
+            values[(y % 2) * 2 + x % 2][value as usize] += 1;
+        }
+    }
+    let white = image.white_level.max(1) as usize;
+    let channels = values
+        .iter()
+        .enumerate()
+        .map(|(index, counts)| {
+            let count: usize = counts.iter().sum();
+            let mut histogram = vec![0; HISTOGRAM_BINS];
+            let mut sum = 0u64;
+            let mut median = None;
+            let mut seen = 0;
+            for (value, n) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+                histogram[(value * HISTOGRAM_BINS / (white + 1)).min(HISTOGRAM_BINS - 1)] += n;
+                sum += value as u64 * *n as u64;
+                seen += n;
+                if median.is_none() && seen * 2 >= count {
+                    median = Some(value as u16);
+                }
+            }
+            let present = || counts.iter().enumerate().filter(|(_, n)| **n > 0);
+            ChannelStats {
+                color: image.cfa.colors[index],
+                x: index % 2,
+                y: index / 2,
+                count,
+                min: present().next().map_or(0, |(value, _)| value as u16),
+                max: present().next_back().map_or(0, |(value, _)| value as u16),
+                mean: sum as f64 / count.max(1) as f64,
+                median: median.unwrap_or(0),
+                clipped: counts[image.white_level as usize..].iter().sum(),
+                at_black: counts[..=image.black_level as usize].iter().sum(),
+                histogram,
+            }
+        })
+        .collect();
+    RawStats {
+        width: right.saturating_sub(crop.x),
+        height: bottom.saturating_sub(crop.y),
+        black_level: image.black_level,
+        white_level: image.white_level,
+        channels,
+        optical_black: optical_black(image),
+    }
+}
+
+/// Measures the pixels outside of the image's crop. Returns `None` if there are none or the
+/// masked area doesn't cover every CFA position.
+pub fn optical_black(image: &RawImage) -> Option<BlackStats> {
+    let crop = image.crop;
+    let mut sums = [0u64; 4];
+    let mut counts = [0usize; 4];
+    let mut row_means = vec![];
+
+    for (y, row) in image.pixels.chunks(image.width).enumerate() {
+        let inside_rows = y >= crop.y && y < crop.y + crop.height;
+        let mut row_sum = 0u64;
+        let mut row_count = 0usize;
+        for (x, value) in row.iter().enumerate() {
+            if inside_rows && x >= crop.x && x < crop.x + crop.width {
+                continue;
+            }
+            let index = (y % 2) * 2 + x % 2;
+            sums[index] += *value as u64;
+            counts[index] += 1;
+            row_sum += *value as u64;
+            row_count += 1;
+        }
+        if row_count > 0 {
+            row_means.push(row_sum as f64 / row_count as f64);
+        }
+    }
+
+    if counts.contains(&0) {
+        return None;
+    }
+    let mut levels = [0.0; 4];
+    for (level, (sum, count)) in levels.iter_mut().zip(sums.iter().zip(&counts)) {
+        *level = *sum as f64 / *count as f64;
+    }
+    let mean = row_means.iter().sum::<f64>() / row_means.len() as f64;
+    let variance = row_means
+        .iter()
+        .map(|row_mean| (row_mean - mean) * (row_mean - mean))
+        .sum::<f64>()
+        / row_means.len() as f64;
+
+    Some(BlackStats {
+        levels,
+        colors: image.cfa.colors,
+        samples: counts.iter().sum(),
+        row_noise: variance.sqrt(),
+    })
+}
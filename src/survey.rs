@@ -0,0 +1,186 @@
+//! A survey of the files of a shoot before a batch job runs over them: which cameras,
+//! resolutions and kinds of raw data they hold, and which of them the codec can't re-encode.
+//! Mixed files aren't wrong, but a stamp placed for one resolution, or a recipe written for
+//! one body, may not suit the others.
+
+use std::fmt;
+
+use crate::dither;
+use crate::format::Format;
+use crate::template::Metadata;
+use crate::variant::{self, RawVariant};
+
+/// What a single file holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSurvey {
+    pub path: String,
+    /// Make and model, as far as the file says
+    pub camera: Option<String>,
+    /// Size of the raw data
+    pub dimensions: Option<(usize, usize)>,
+    pub variant: Option<RawVariant>,
+    /// Why the file can't be re-encoded, if it can't
+    pub problem: Option<String>,
+}
+
+impl FileSurvey {
+    /// Surveys the contents of the file at `path`
+    pub fn of(path: &str, buf: &[u8]) -> FileSurvey {
+        let metadata = Metadata::from_file(buf);
+        let camera = match (metadata.get("Make"), metadata.get("Model")) {
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.or(model).map(str::to_owned),
+        };
+        let mut survey = FileSurvey {
+            path: path.to_owned(),
+            camera,
+            dimensions: None,
+            variant: None,
+            problem: None,
+        };
+        let info = match variant::detect(buf, None) {
+            Ok(Some(info)) => info,
+            Ok(None) => {
+                survey.problem = Some("no raw data found".to_owned());
+                return survey;
+            }
+            Err(err) => {
+                survey.problem = Some(err.to_string());
+                return survey;
+            }
+        };
+        survey.dimensions = Some((info.width, info.height));
+        survey.variant = Some(info.variant);
+        let checked = Format::detect(&info, dither::camera).and_then(|format| {
+            format.check(&info)?;
+            Ok(format)
+        });
+        survey.problem = match checked {
+            Ok(format) if info.offset + format.data_len(info.width, info.height) > buf.len() => {
+                Some("the raw data runs past the end of the file".to_owned())
+            }
+            Ok(_) => None,
+            Err(err) => Some(err.to_string()),
+        };
+        survey
+    }
+
+    /// Whether the tool can edit the file
+    pub fn is_editable(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// "1 file", "2 files"
+fn files(count: usize) -> String {
+    match count {
+        1 => "1 file".to_owned(),
+        _ => format!("{} files", count),
+    }
+}
+
+/// One property of the files, grouped by value: the values with the files having them, the
+/// most common first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Groups {
+    pub property: &'static str,
+    pub groups: Vec<(String, Vec<String>)>,
+}
+
+impl Groups {
+    fn of<F: Fn(&FileSurvey) -> Option<String>>(
+        property: &'static str,
+        files: &[FileSurvey],
+        value: F,
+    ) -> Groups {
+        let mut groups: Vec<(String, Vec<String>)> = vec![];
+        for file in files {
+            let value = value(file).unwrap_or_else(|| "unknown".to_owned());
+            match groups.iter_mut().find(|(known, _)| *known == value) {
+                Some((_, paths)) => paths.push(file.path.clone()),
+                None => groups.push((value, vec![file.path.clone()])),
+            }
+        }
+        // stable, so ties keep the order the files came in
+        groups.sort_by_key(|(_, paths)| std::cmp::Reverse(paths.len()));
+        Groups { property, groups }
+    }
+
+    pub fn is_mixed(&self) -> bool {
+        self.groups.len() > 1
+    }
+}
+
+impl fmt::Display for Groups {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.property)?;
+        for (i, (value, paths)) in self.groups.iter().enumerate() {
+            write!(f, "\n  {} ({})", value, files(paths.len()))?;
+            // the files of the most common value are the norm; the others are worth naming
+            if i > 0 {
+                for path in paths {
+                    write!(f, "\n    {}", path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The survey of a set of files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Survey {
+    pub files: Vec<FileSurvey>,
+}
+
+impl Survey {
+    pub fn new(files: Vec<FileSurvey>) -> Survey {
+        Survey { files }
+    }
+
+    /// The files grouped by camera, by resolution and by kind of raw data
+    pub fn groups(&self) -> Vec<Groups> {
+        vec![
+            Groups::of("cameras", &self.files, |file| file.camera.clone()),
+            Groups::of("resolutions", &self.files, |file| {
+                file.dimensions
+                    .map(|(width, height)| format!("{}x{}", width, height))
+            }),
+            Groups::of("raw data", &self.files, |file| {
+                file.variant.map(|variant| format!("{:?}", variant))
+            }),
+        ]
+    }
+
+    /// Whether the files are all alike and all editable
+    pub fn is_consistent(&self) -> bool {
+        self.files.iter().all(FileSurvey::is_editable)
+            && !self.groups().iter().any(Groups::is_mixed)
+    }
+}
+
+impl fmt::Display for Survey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", files(self.files.len()))?;
+        for groups in self.groups() {
+            write!(f, "\n{}", groups)?;
+        }
+        let problems: Vec<_> = self
+            .files
+            .iter()
+            .filter_map(|file| Some((&file.path, file.problem.as_ref()?)))
+            .collect();
+        if problems.is_empty() {
+            write!(f, "\nall files can be edited")?;
+        } else {
+            write!(f, "\n{} cannot be edited:", files(problems.len()))?;
+            for (path, problem) in problems {
+                write!(f, "\n  {}: {}", path, problem)?;
+            }
+        }
+        if self.is_consistent() {
+            write!(f, "\nno inconsistencies")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Tags by name, the way ExifTool calls them: `EXIF:Artist`, `ExifIFD:ISO`, `Sony:SonyModelID`.
+//! A name is looked up in a dictionary of the tags photographers ask about, which knows the
+//! directory each lives in (IFD 0, the Exif IFD, the GPS IFD or Sony's maker notes) and
+//! whether it can be written. The group in front of the name is optional, and may be the
+//! general one ExifTool gives (`EXIF`, `MakerNotes`) or the directory (`IFD0`, `ExifIFD`,
+//! `GPS`, `Sony`). A leading `-`, as ExifTool's command line has it, is ignored.
+
+use std::fmt;
+
+use crate::tiff::{self, Entry, Ifd, Tiff};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagError {
+    /// No tag of that name in the dictionary
+    Unknown(String),
+    /// The tag can only be read
+    ReadOnly(&'static TagInfo),
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagError::Unknown(name) => write!(f, "unknown tag: {}", name),
+            TagError::ReadOnly(info) => write!(f, "{} cannot be written", info),
+        }
+    }
+}
+
+impl std::error::Error for TagError {}
+
+/// Where a tag is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Directory {
+    Ifd0,
+    Exif,
+    Gps,
+    /// The IFD inside Sony's MakerNote
+    Sony,
+}
+
+impl Directory {
+    /// The name ExifTool gives the directory (its family 1 group)
+    pub fn name(self) -> &'static str {
+        match self {
+            Directory::Ifd0 => "IFD0",
+            Directory::Exif => "ExifIFD",
+            Directory::Gps => "GPS",
+            Directory::Sony => "Sony",
+        }
+    }
+
+    /// The general group ExifTool puts the directory in (its family 0 group)
+    fn group(self) -> &'static str {
+        match self {
+            Directory::Ifd0 | Directory::Exif | Directory::Gps => "EXIF",
+            Directory::Sony => "MakerNotes",
+        }
+    }
+
+    /// Reads the directory from a file
+    pub fn read(self, tiff: &Tiff) -> Option<Ifd> {
+        let ifd0 = tiff.ifd0()?;
+        let pointed = |ifd: &Ifd, tag| {
+            let offset = tiff.value(ifd, tag)? as usize;
+            tiff.read_ifd(offset).map(|(ifd, _)| ifd)
+        };
+        match self {
+            Directory::Ifd0 => Some(ifd0),
+            Directory::Exif => pointed(&ifd0, tiff::EXIF_IFD),
+            Directory::Gps => pointed(&ifd0, tiff::GPS_IFD),
+            Directory::Sony => {
+                let maker_note = tiff.exif_ifd()?.entry(tiff::MAKER_NOTE)?.value_pos;
+                // "SONY DSC \0\0\0" or "SONY CAM \0\0\0" in front of the IFD in most bodies;
+                // its offsets count from the start of the file either way
+                let header = tiff.data().get(maker_note..maker_note + 4)?;
+                let offset = if header == b"SONY" {
+                    maker_note + 12
+                } else {
+                    maker_note
+                };
+                tiff.read_ifd(offset).map(|(ifd, _)| ifd)
+            }
+        }
+    }
+}
+
+/// A tag of the dictionary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagInfo {
+    pub name: &'static str,
+    pub directory: Directory,
+    pub tag: u16,
+    /// How the tag is written, if it can be
+    pub writable: Option<Writable>,
+}
+
+/// How a writable tag is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Writable {
+    Text,
+    Short,
+}
+
+impl fmt::Display for TagInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.directory.name(), self.name)
+    }
+}
+
+const fn text(directory: Directory, tag: u16, name: &'static str) -> TagInfo {
+    TagInfo {
+        name,
+        directory,
+        tag,
+        writable: Some(Writable::Text),
+    }
+}
+
+const fn short(directory: Directory, tag: u16, name: &'static str) -> TagInfo {
+    TagInfo {
+        name,
+        directory,
+        tag,
+        writable: Some(Writable::Short),
+    }
+}
+
+const fn read_only(directory: Directory, tag: u16, name: &'static str) -> TagInfo {
+    TagInfo {
+        name,
+        directory,
+        tag,
+        writable: None,
+    }
+}
+
+use Directory::{Exif, Gps, Ifd0, Sony};
+
+/// The dictionary. The GPS IFD and the maker notes are only read; what the tool relies on to
+/// decode the raw data (Make, Model, Orientation, ...) is left alone as well.
+pub const TAGS: &[TagInfo] = &[
+    text(Ifd0, tiff::IMAGE_DESCRIPTION, "ImageDescription"),
+    read_only(Ifd0, tiff::MAKE, "Make"),
+    read_only(Ifd0, tiff::MODEL, "Model"),
+    read_only(Ifd0, tiff::ORIENTATION, "Orientation"),
+    read_only(Ifd0, 0x011a, "XResolution"),
+    read_only(Ifd0, 0x011b, "YResolution"),
+    read_only(Ifd0, 0x0128, "ResolutionUnit"),
+    text(Ifd0, 0x0131, "Software"),
+    text(Ifd0, tiff::DATE_TIME, "ModifyDate"),
+    text(Ifd0, tiff::ARTIST, "Artist"),
+    short(Ifd0, 0x4746, "Rating"),
+    short(Ifd0, 0x4749, "RatingPercent"),
+    text(Ifd0, tiff::COPYRIGHT, "Copyright"),
+    read_only(Exif, 0x829a, "ExposureTime"),
+    read_only(Exif, 0x829d, "FNumber"),
+    read_only(Exif, 0x8822, "ExposureProgram"),
+    read_only(Exif, tiff::ISO_SPEED, "ISO"),
+    read_only(Exif, 0x9000, "ExifVersion"),
+    text(Exif, tiff::DATE_TIME_ORIGINAL, "DateTimeOriginal"),
+    text(Exif, tiff::DATE_TIME_DIGITIZED, "CreateDate"),
+    text(Exif, 0x9010, "OffsetTime"),
+    text(Exif, 0x9011, "OffsetTimeOriginal"),
+    read_only(Exif, 0x9204, "ExposureCompensation"),
+    read_only(Exif, 0x9207, "MeteringMode"),
+    read_only(Exif, 0x9208, "LightSource"),
+    read_only(Exif, 0x9209, "Flash"),
+    read_only(Exif, 0x920a, "FocalLength"),
+    read_only(Exif, 0xa001, "ColorSpace"),
+    read_only(Exif, 0xa002, "ExifImageWidth"),
+    read_only(Exif, 0xa003, "ExifImageHeight"),
+    read_only(Exif, 0xa405, "FocalLengthIn35mmFormat"),
+    text(Exif, 0xa430, "OwnerName"),
+    text(Exif, 0xa431, "SerialNumber"),
+    text(Exif, 0xa433, "LensMake"),
+    text(Exif, 0xa434, "LensModel"),
+    text(Exif, 0xa435, "LensSerialNumber"),
+    read_only(Gps, 0x0000, "GPSVersionID"),
+    read_only(Gps, 0x0001, "GPSLatitudeRef"),
+    read_only(Gps, 0x0002, "GPSLatitude"),
+    read_only(Gps, 0x0003, "GPSLongitudeRef"),
+    read_only(Gps, 0x0004, "GPSLongitude"),
+    read_only(Gps, 0x0005, "GPSAltitudeRef"),
+    read_only(Gps, 0x0006, "GPSAltitude"),
+    read_only(Gps, 0x0007, "GPSTimeStamp"),
+    read_only(Gps, 0x001d, "GPSDateStamp"),
+    read_only(Sony, 0x0102, "Quality"),
+    read_only(Sony, 0x2002, "Rating"),
+    read_only(Sony, 0xb000, "FileFormat"),
+    read_only(Sony, 0xb001, "SonyModelID"),
+    read_only(Sony, 0xb020, "CreativeStyle"),
+    read_only(Sony, 0xb021, "ColorTemperature"),
+    read_only(Sony, 0xb023, "SceneMode"),
+    read_only(Sony, 0xb027, "LensType"),
+    read_only(Sony, 0xb02b, "FullImageSize"),
+    read_only(Sony, 0xb02c, "PreviewImageSize"),
+];
+
+/// Looks a name up in the dictionary: `Name` or `Group:Name`, in any case. Without a group,
+/// the first tag of the name is taken, the one in IFD 0 before the one in the maker notes.
+pub fn lookup(name: &str) -> Result<&'static TagInfo, TagError> {
+    let spec = name.strip_prefix('-').unwrap_or(name);
+    let (group, tag_name) = match spec.rfind(':') {
+        Some(colon) => (Some(&spec[..colon]), &spec[colon + 1..]),
+        None => (None, spec),
+    };
+    TAGS.iter()
+        .find(|info| {
+            info.name.eq_ignore_ascii_case(tag_name)
+                && group.is_none_or(|group| {
+                    group.eq_ignore_ascii_case(info.directory.name())
+                        || group.eq_ignore_ascii_case(info.directory.group())
+                })
+        })
+        .ok_or_else(|| TagError::Unknown(name.to_owned()))
+}
+
+/// Looks a name up for writing
+pub fn lookup_writable(name: &str) -> Result<&'static TagInfo, TagError> {
+    let info = lookup(name)?;
+    match info.writable {
+        Some(_) => Ok(info),
+        None => Err(TagError::ReadOnly(info)),
+    }
+}
+
+/// Most values listed one by one; longer ones are summarized
+const MAX_LISTED: usize = 16;
+
+fn format_number(numerator: i64, denominator: i64) -> String {
+    if denominator == 0 {
+        "undef".to_owned()
+    } else if numerator % denominator == 0 {
+        (numerator / denominator).to_string()
+    } else {
+        let value = numerator as f64 / denominator as f64;
+        format!("{}", (value * 10_000.0).round() / 10_000.0)
+    }
+}
+
+/// The value of an entry as text: strings as they are, numbers separated by spaces, rationals
+/// as decimals, and undefined data as text where it is printable
+fn format_entry(tiff: &Tiff, entry: &Entry) -> Option<String> {
+    if entry.field_type == 2 {
+        return tiff.string(entry);
+    }
+    let count = entry.count as usize;
+    let bytes = tiff
+        .data()
+        .get(entry.value_pos..entry.value_pos + entry.byte_len())?;
+    if entry.field_type == 7 {
+        let text = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        if !text.is_empty()
+            && text
+                .iter()
+                .all(|byte| byte.is_ascii_graphic() || *byte == b' ')
+        {
+            return Some(String::from_utf8_lossy(text).into_owned());
+        }
+    }
+    if count > MAX_LISTED {
+        return Some(format!("({} values)", count));
+    }
+    let size = entry.byte_len() / count.max(1);
+    let numbers = (0..count).map(|i| {
+        let pos = entry.value_pos + i * size;
+        let u16_at = |pos| tiff.read_u16(pos).unwrap_or(0);
+        let u32_at = |pos| tiff.read_u32(pos).unwrap_or(0);
+        match entry.field_type {
+            3 => u16_at(pos).to_string(),
+            8 => (u16_at(pos) as i16).to_string(),
+            4 => u32_at(pos).to_string(),
+            9 => (u32_at(pos) as i32).to_string(),
+            5 => format_number(u32_at(pos) as i64, u32_at(pos + 4) as i64),
+            10 => format_number(u32_at(pos) as i32 as i64, u32_at(pos + 4) as i32 as i64),
+            6 => (bytes[i] as i8).to_string(),
+            _ => bytes[i].to_string(),
+        }
+    });
+    Some(numbers.collect::<Vec<_>>().join(" "))
+}
+
+/// Reads a tag from a TIFF-based file, as text
+pub fn read(buf: &[u8], info: &TagInfo) -> Option<String> {
+    let tiff = Tiff::new(buf)?;
+    let ifd = info.directory.read(&tiff)?;
+    format_entry(&tiff, ifd.entry(info.tag)?)
+}
@@ -0,0 +1,510 @@
+//! Text templates filled in from the file's metadata, e.g. `Shot on {Model}, {DateTimeOriginal:%e %B %Y}`.
+//!
+//! Date fields accept strftime-style format strings, with month and day names taken from the
+//! selected locale.
+
+use std::fmt::Write;
+
+use crate::tiff::{self, Tiff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    De,
+    Fr,
+    Es,
+    It,
+    Pl,
+    Nl,
+    Pt,
+}
+
+struct LocaleNames {
+    months: [&'static str; 12],
+    months_abbr: [&'static str; 12],
+    days: [&'static str; 7],
+    days_abbr: [&'static str; 7],
+    date: &'static str,
+}
+
+const EN: LocaleNames = LocaleNames {
+    months: [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ],
+    months_abbr: [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ],
+    days: [
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+    ],
+    days_abbr: ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"],
+    date: "%m/%d/%Y",
+};
+
+const DE: LocaleNames = LocaleNames {
+    months: [
+        "Januar",
+        "Februar",
+        "März",
+        "April",
+        "Mai",
+        "Juni",
+        "Juli",
+        "August",
+        "September",
+        "Oktober",
+        "November",
+        "Dezember",
+    ],
+    months_abbr: [
+        "Jan", "Feb", "Mär", "Apr", "Mai", "Jun", "Jul", "Aug", "Sep", "Okt", "Nov", "Dez",
+    ],
+    days: [
+        "Sonntag",
+        "Montag",
+        "Dienstag",
+        "Mittwoch",
+        "Donnerstag",
+        "Freitag",
+        "Samstag",
+    ],
+    days_abbr: ["So", "Mo", "Di", "Mi", "Do", "Fr", "Sa"],
+    date: "%d.%m.%Y",
+};
+
+const FR: LocaleNames = LocaleNames {
+    months: [
+        "janvier",
+        "février",
+        "mars",
+        "avril",
+        "mai",
+        "juin",
+        "juillet",
+        "août",
+        "septembre",
+        "octobre",
+        "novembre",
+        "décembre",
+    ],
+    months_abbr: [
+        "janv.", "févr.", "mars", "avr.", "mai", "juin", "juil.", "août", "sept.", "oct.", "nov.",
+        "déc.",
+    ],
+    days: [
+        "dimanche", "lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi",
+    ],
+    days_abbr: ["dim.", "lun.", "mar.", "mer.", "jeu.", "ven.", "sam."],
+    date: "%d/%m/%Y",
+};
+
+const ES: LocaleNames = LocaleNames {
+    months: [
+        "enero",
+        "febrero",
+        "marzo",
+        "abril",
+        "mayo",
+        "junio",
+        "julio",
+        "agosto",
+        "septiembre",
+        "octubre",
+        "noviembre",
+        "diciembre",
+    ],
+    months_abbr: [
+        "ene", "feb", "mar", "abr", "may", "jun", "jul", "ago", "sept", "oct", "nov", "dic",
+    ],
+    days: [
+        "domingo",
+        "lunes",
+        "martes",
+        "miércoles",
+        "jueves",
+        "viernes",
+        "sábado",
+    ],
+    days_abbr: ["dom", "lun", "mar", "mié", "jue", "vie", "sáb"],
+    date: "%d/%m/%Y",
+};
+
+const IT: LocaleNames = LocaleNames {
+    months: [
+        "gennaio",
+        "febbraio",
+        "marzo",
+        "aprile",
+        "maggio",
+        "giugno",
+        "luglio",
+        "agosto",
+        "settembre",
+        "ottobre",
+        "novembre",
+        "dicembre",
+    ],
+    months_abbr: [
+        "gen", "feb", "mar", "apr", "mag", "giu", "lug", "ago", "set", "ott", "nov", "dic",
+    ],
+    days: [
+        "domenica",
+        "lunedì",
+        "martedì",
+        "mercoledì",
+        "giovedì",
+        "venerdì",
+        "sabato",
+    ],
+    days_abbr: ["dom", "lun", "mar", "mer", "gio", "ven", "sab"],
+    date: "%d/%m/%Y",
+};
+
+const PL: LocaleNames = LocaleNames {
+    // genitive forms, as used in dates ("5 marca 2020")
+    months: [
+        "stycznia",
+        "lutego",
+        "marca",
+        "kwietnia",
+        "maja",
+        "czerwca",
+        "lipca",
+        "sierpnia",
+        "września",
+        "października",
+        "listopada",
+        "grudnia",
+    ],
+    months_abbr: [
+        "sty", "lut", "mar", "kwi", "maj", "cze", "lip", "sie", "wrz", "paź", "lis", "gru",
+    ],
+    days: [
+        "niedziela",
+        "poniedziałek",
+        "wtorek",
+        "środa",
+        "czwartek",
+        "piątek",
+        "sobota",
+    ],
+    days_abbr: ["nie", "pon", "wto", "śro", "czw", "pią", "sob"],
+    date: "%d.%m.%Y",
+};
+
+const NL: LocaleNames = LocaleNames {
+    months: [
+        "januari",
+        "februari",
+        "maart",
+        "april",
+        "mei",
+        "juni",
+        "juli",
+        "augustus",
+        "september",
+        "oktober",
+        "november",
+        "december",
+    ],
+    months_abbr: [
+        "jan", "feb", "mrt", "apr", "mei", "jun", "jul", "aug", "sep", "okt", "nov", "dec",
+    ],
+    days: [
+        "zondag",
+        "maandag",
+        "dinsdag",
+        "woensdag",
+        "donderdag",
+        "vrijdag",
+        "zaterdag",
+    ],
+    days_abbr: ["zo", "ma", "di", "wo", "do", "vr", "za"],
+    date: "%d-%m-%Y",
+};
+
+const PT: LocaleNames = LocaleNames {
+    months: [
+        "janeiro",
+        "fevereiro",
+        "março",
+        "abril",
+        "maio",
+        "junho",
+        "julho",
+        "agosto",
+        "setembro",
+        "outubro",
+        "novembro",
+        "dezembro",
+    ],
+    months_abbr: [
+        "jan", "fev", "mar", "abr", "mai", "jun", "jul", "ago", "set", "out", "nov", "dez",
+    ],
+    days: [
+        "domingo",
+        "segunda-feira",
+        "terça-feira",
+        "quarta-feira",
+        "quinta-feira",
+        "sexta-feira",
+        "sábado",
+    ],
+    days_abbr: ["dom", "seg", "ter", "qua", "qui", "sex", "sáb"],
+    date: "%d/%m/%Y",
+};
+
+impl Locale {
+    /// Parses names like `de`, `de_DE` or `de-DE.UTF-8`
+    pub fn parse(name: &str) -> Option<Locale> {
+        let language = name.split(['_', '-', '.']).next()?.to_ascii_lowercase();
+        match &language[..] {
+            "en" | "c" | "posix" => Some(Locale::En),
+            "de" => Some(Locale::De),
+            "fr" => Some(Locale::Fr),
+            "es" => Some(Locale::Es),
+            "it" => Some(Locale::It),
+            "pl" => Some(Locale::Pl),
+            "nl" => Some(Locale::Nl),
+            "pt" => Some(Locale::Pt),
+            _ => None,
+        }
+    }
+
+    /// The locale from the environment (`LC_ALL`, `LC_TIME`, `LANG`), English if unknown
+    pub fn from_env() -> Locale {
+        ["LC_ALL", "LC_TIME", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .and_then(|value| Locale::parse(&value))
+            .unwrap_or(Locale::En)
+    }
+
+    fn names(self) -> &'static LocaleNames {
+        match self {
+            Locale::En => &EN,
+            Locale::De => &DE,
+            Locale::Fr => &FR,
+            Locale::Es => &ES,
+            Locale::It => &IT,
+            Locale::Pl => &PL,
+            Locale::Nl => &NL,
+            Locale::Pt => &PT,
+        }
+    }
+}
+
+/// A date as stored in EXIF (`YYYY:MM:DD HH:MM:SS`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn parse_exif(value: &str) -> Option<DateTime> {
+        let mut parts = value
+            .split([':', ' '])
+            .map(|part| part.trim().parse::<u32>());
+        let mut next = || parts.next()?.ok();
+        let date = DateTime {
+            year: next()? as i32,
+            month: next()?,
+            day: next()?,
+            hour: next().unwrap_or(0),
+            minute: next().unwrap_or(0),
+            second: next().unwrap_or(0),
+        };
+        if date.month == 0 || date.month > 12 || date.day == 0 || date.day > 31 {
+            return None;
+        }
+        Some(date)
+    }
+
+    fn is_leap_year(&self) -> bool {
+        (self.year % 4 == 0 && self.year % 100 != 0) || self.year % 400 == 0
+    }
+
+    /// 0 = Sunday
+    fn weekday(&self) -> usize {
+        const OFFSETS: [i32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let year = if self.month < 3 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let day = year + year / 4 - year / 100
+            + year / 400
+            + OFFSETS[self.month as usize - 1]
+            + self.day as i32;
+        day.rem_euclid(7) as usize
+    }
+
+    fn day_of_year(&self) -> u32 {
+        const DAYS_BEFORE: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+        let leap = if self.month > 2 && self.is_leap_year() {
+            1
+        } else {
+            0
+        };
+        DAYS_BEFORE[self.month as usize - 1] + self.day + leap
+    }
+
+    /// Formats the date with a strftime-style format string
+    pub fn format(&self, format: &str, locale: Locale) -> String {
+        let names = locale.names();
+        let mut out = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            let hour12 = match self.hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            let _ = match chars.next() {
+                Some('Y') => write!(out, "{}", self.year),
+                Some('y') => write!(out, "{:02}", self.year.rem_euclid(100)),
+                Some('m') => write!(out, "{:02}", self.month),
+                Some('d') => write!(out, "{:02}", self.day),
+                Some('e') => write!(out, "{}", self.day),
+                Some('H') => write!(out, "{:02}", self.hour),
+                Some('I') => write!(out, "{:02}", hour12),
+                Some('M') => write!(out, "{:02}", self.minute),
+                Some('S') => write!(out, "{:02}", self.second),
+                Some('p') => write!(out, "{}", if self.hour < 12 { "AM" } else { "PM" }),
+                Some('B') => write!(out, "{}", names.months[self.month as usize - 1]),
+                Some('b') | Some('h') => {
+                    write!(out, "{}", names.months_abbr[self.month as usize - 1])
+                }
+                Some('A') => write!(out, "{}", names.days[self.weekday()]),
+                Some('a') => write!(out, "{}", names.days_abbr[self.weekday()]),
+                Some('j') => write!(out, "{:03}", self.day_of_year()),
+                Some('F') => write!(out, "{}", self.format("%Y-%m-%d", locale)),
+                Some('T') => write!(out, "{}", self.format("%H:%M:%S", locale)),
+                Some('R') => write!(out, "{}", self.format("%H:%M", locale)),
+                Some('x') => write!(out, "{}", self.format(names.date, locale)),
+                Some('X') => write!(out, "{}", self.format("%H:%M:%S", locale)),
+                Some('c') => write!(
+                    out,
+                    "{}",
+                    self.format(&format!("{} %X", names.date), locale)
+                ),
+                Some('%') => write!(out, "%"),
+                Some(other) => write!(out, "%{}", other),
+                None => write!(out, "%"),
+            };
+        }
+        out
+    }
+}
+
+/// Metadata fields available to templates
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    fields: Vec<(&'static str, String)>,
+}
+
+const STRING_TAGS: [(&str, u16); 5] = [
+    ("Make", tiff::MAKE),
+    ("Model", tiff::MODEL),
+    ("Artist", tiff::ARTIST),
+    ("Copyright", tiff::COPYRIGHT),
+    ("ImageDescription", tiff::IMAGE_DESCRIPTION),
+];
+
+const EXIF_STRING_TAGS: [(&str, u16); 2] = [
+    ("DateTimeOriginal", tiff::DATE_TIME_ORIGINAL),
+    ("DateTimeDigitized", tiff::DATE_TIME_DIGITIZED),
+];
+
+impl Metadata {
+    pub fn from_file(buf: &[u8]) -> Metadata {
+        let mut metadata = Metadata::default();
+        let tiff = match Tiff::new(buf) {
+            Some(tiff) => tiff,
+            None => return metadata,
+        };
+        if let Some(ifd0) = tiff.ifd0() {
+            for (name, tag) in STRING_TAGS.iter().chain(&[("DateTime", tiff::DATE_TIME)]) {
+                if let Some(value) = ifd0.entry(*tag).and_then(|entry| tiff.string(entry)) {
+                    metadata.fields.push((name, value));
+                }
+            }
+        }
+        if let Some(exif) = tiff.exif_ifd() {
+            for (name, tag) in EXIF_STRING_TAGS.iter() {
+                if let Some(value) = exif.entry(*tag).and_then(|entry| tiff.string(entry)) {
+                    metadata.fields.push((name, value));
+                }
+            }
+            if let Some(iso) = tiff.value(&exif, tiff::ISO_SPEED) {
+                metadata.fields.push(("ISO", iso.to_string()));
+            }
+        }
+        metadata
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// Expands `{Field}` and `{Field:format}` placeholders. Date fields are formatted with the
+/// strftime-style format; unknown fields expand to nothing. `{{` and `}}` produce literal braces.
+pub fn render(template: &str, metadata: &Metadata, locale: Locale) -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let mut parts = placeholder.splitn(2, ':');
+                let name = parts.next().unwrap_or("").trim();
+                let format = parts.next();
+                let value = metadata.get(name).unwrap_or("");
+                match (format, DateTime::parse_exif(value)) {
+                    (Some(format), Some(date)) => out.push_str(&date.format(format, locale)),
+                    _ => out.push_str(value),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,151 @@
+//! Tiny ARW2 test vectors, for checking other implementations of the codec (and bindings to
+//! this one) against it. Only built with the `test-vectors` feature.
+//!
+//! Every vector is a single row of compressed data. `decoded` is what it decodes to through
+//! the default tone curve with the camera's dither (`CameraDither`, seeded from the first
+//! bits of the row). Vectors with `pixels` also pin down the encoder: `encode_arw2` turns the
+//! pixels into exactly `data`.
+//!
+//! ```
+//! use raw_tiff_edit::dither;
+//! use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_from, encode_arw2};
+//! use raw_tiff_edit::source::MemorySource;
+//! use raw_tiff_edit::test_vectors;
+//!
+//! let curve = calculate_curve();
+//! test_vectors::check_decoder(|data, width| {
+//!     let mut src = MemorySource::new(data);
+//!     decode_arw2_from(&mut src, 0, width, 1, &curve, dither::camera).unwrap()
+//! })?;
+//! test_vectors::check_encoder(|pixels, width| encode_arw2(pixels, width, &curve).unwrap())?;
+//! # Ok::<(), String>(())
+//! ```
+
+#[derive(Debug, Clone, Copy)]
+pub struct TestVector {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Pixels in the row
+    pub width: usize,
+    /// What the data was encoded from; `None` for data the encoder would never write
+    pub pixels: Option<&'static [u16]>,
+    /// The compressed row, `width` bytes unless the row is malformed
+    pub data: &'static [u8],
+    pub decoded: &'static [u16],
+}
+
+pub const VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "flat",
+        description: "every pixel the same",
+        width: 32,
+        pixels: Some(&[1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000]),
+        data: &[0xf4, 0xa1, 0xcf, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf4, 0xa1, 0xcf, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        decoded: &[999, 1000, 999, 999, 999, 1000, 999, 999, 999, 999, 999, 999, 1000, 1000, 999, 1000, 1000, 999, 999, 999, 999, 999, 1000, 999, 999, 999, 999, 999, 999, 999, 999, 999],
+    },
+    TestVector {
+        name: "black",
+        description: "the default black level",
+        width: 32,
+        pixels: Some(&[512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512, 512]),
+        data: &[0x00, 0x01, 0xc8, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xc8, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        decoded: &[511, 511, 511, 512, 511, 511, 511, 511, 511, 511, 511, 512, 511, 511, 512, 511, 511, 511, 511, 511, 511, 511, 511, 512, 511, 511, 511, 511, 512, 512, 512, 511],
+    },
+    TestVector {
+        name: "ramp",
+        description: "a gentle ramp, the smallest delta shift",
+        width: 32,
+        pixels: Some(&[512, 552, 592, 632, 672, 712, 752, 792, 832, 872, 912, 952, 992, 1032, 1072, 1112, 1152, 1192, 1232, 1272, 1312, 1352, 1392, 1432, 1472, 1512, 1552, 1592, 1632, 1672, 1712, 1752]),
+        data: &[0x58, 0x03, 0xc8, 0x43, 0x41, 0xf1, 0xa0, 0x64, 0x3c, 0x23, 0x54, 0x4b, 0x76, 0xe3, 0x05, 0x8d, 0x6c, 0xa3, 0xc8, 0x43, 0x41, 0xf1, 0xa0, 0x64, 0x3c, 0x23, 0x54, 0x4b, 0x76, 0xe3, 0x05, 0x8d],
+        decoded: &[511, 551, 591, 631, 671, 712, 752, 791, 832, 871, 911, 951, 991, 1031, 1072, 1111, 1151, 1191, 1231, 1271, 1311, 1351, 1391, 1432, 1472, 1512, 1551, 1591, 1631, 1671, 1711, 1751],
+    },
+    TestVector {
+        name: "edge",
+        description: "a hard edge across the group, the largest delta shift",
+        width: 32,
+        pixels: Some(&[600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 600, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000, 12000]),
+        data: &[0x5c, 0x67, 0xc9, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xf1, 0x78, 0x3c, 0x1e, 0x8f, 0xc7, 0x5c, 0x67, 0xc9, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xf1, 0x78, 0x3c, 0x1e, 0x8f, 0xc7],
+        decoded: &[600, 599, 600, 599, 599, 600, 600, 599, 599, 599, 600, 600, 599, 599, 599, 599, 11987, 11980, 11988, 11982, 11983, 11986, 11980, 11993, 11987, 11993, 11994, 11989, 11981, 11980, 11985, 11988],
+    },
+    TestVector {
+        name: "spike",
+        description: "a single bright pixel on a flat background",
+        width: 32,
+        pixels: Some(&[2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 9000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000, 2000]),
+        data: &[0xe8, 0x43, 0xdf, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x46, 0x9f, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        decoded: &[1999, 1999, 1999, 2000, 1999, 1999, 2000, 2000, 1999, 1999, 2000, 1999, 1999, 9011, 1999, 2000, 1999, 1999, 1999, 1999, 2000, 2000, 2000, 2000, 2000, 1999, 1999, 1999, 1999, 1999, 2000, 2000],
+    },
+    TestVector {
+        name: "checker",
+        description: "interleaved blocks of different levels",
+        width: 32,
+        pixels: Some(&[800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000, 800, 4000]),
+        data: &[0x90, 0x81, 0xcc, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x78, 0xc5, 0xeb, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        decoded: &[799, 4001, 799, 4001, 799, 4000, 799, 4000, 799, 4000, 800, 3999, 799, 4000, 799, 3999, 799, 3999, 800, 3999, 799, 3999, 799, 4001, 800, 4001, 799, 3998, 799, 4001, 799, 3999],
+    },
+    TestVector {
+        name: "highlights",
+        description: "the top of the curve, where codes are 16 apart",
+        width: 32,
+        pixels: Some(&[15000, 15030, 15060, 15090, 15120, 15150, 15180, 15210, 15240, 15270, 15300, 15330, 15360, 15390, 15420, 15450, 15480, 15510, 15540, 15570, 15600, 15630, 15660, 15690, 15720, 15750, 15780, 15810, 15840, 15870, 15900, 15930]),
+        data: &[0xd6, 0xd7, 0xfd, 0x83, 0x80, 0x60, 0x40, 0x28, 0x16, 0x8d, 0x47, 0x64, 0x52, 0xb9, 0x64, 0x34, 0xd7, 0xdf, 0xfd, 0x83, 0x80, 0x60, 0x40, 0x24, 0x16, 0x8d, 0x47, 0x64, 0x52, 0xb9, 0x60, 0x34],
+        decoded: &[15003, 15029, 15052, 15087, 15128, 15154, 15189, 15220, 15258, 15286, 15310, 15320, 15354, 15375, 15418, 15438, 15479, 15505, 15537, 15579, 15597, 15641, 15669, 15695, 15727, 15760, 15790, 15798, 15832, 15865, 15897, 15919],
+    },
+    TestVector {
+        name: "two-groups",
+        description: "a row of two groups, the dither carrying over",
+        width: 64,
+        pixels: Some(&[700, 707, 728, 763, 812, 875, 952, 1043, 1148, 1267, 1400, 1547, 1708, 1883, 2072, 2275, 2492, 2723, 2968, 3227, 3500, 3787, 4088, 4403, 4732, 5075, 5432, 803, 1188, 1587, 2000, 2427, 2868, 3323, 3792, 4275, 4772, 5283, 808, 1347, 1900, 2467, 3048, 3643, 4252, 4875, 5512, 1163, 1828, 2507, 3200, 3907, 4628, 5363, 1112, 1875, 2652, 3443, 4248, 5067, 900, 1747, 2608, 3483]),
+        data: &[0x2b, 0xf6, 0x4a, 0x03, 0x60, 0x70, 0x70, 0x54, 0x3e, 0x29, 0xd8, 0xad, 0x27, 0x3c, 0x3e, 0x50, 0xfe, 0x15, 0x0b, 0x43, 0xa0, 0xa0, 0x88, 0x68, 0x48, 0xac, 0xd9, 0xee, 0x47, 0x1c, 0x6c, 0x5e, 0x35, 0xa6, 0xcc, 0x8d, 0x8c, 0x47, 0x14, 0xd5, 0x80, 0x1f, 0xdc, 0x30, 0xf1, 0x02, 0x0a, 0x5c, 0x22, 0x36, 0x92, 0xde, 0x56, 0xad, 0x5f, 0x08, 0xc1, 0x73, 0x22, 0x99, 0xd5, 0xb5, 0x93, 0xbc],
+        decoded: &[700, 707, 699, 739, 795, 867, 923, 1028, 1147, 1251, 1371, 1539, 1691, 1860, 2023, 2232, 2471, 2679, 2919, 3192, 3406, 3695, 4047, 4334, 4686, 5074, 5432, 803, 1180, 1571, 1980, 2425, 2815, 3280, 3710, 4240, 4734, 5264, 807, 1339, 1895, 2440, 3008, 3601, 4222, 4815, 5511, 1163, 1799, 2503, 3200, 3857, 4607, 5359, 1095, 1867, 2624, 3408, 4225, 5009, 871, 1739, 2560, 3470],
+    },
+    TestVector {
+        name: "malformed",
+        description: "a malformed first block (imax == imin) with an extra delta, pushing the row past its 32 bytes",
+        width: 32,
+        pixels: None,
+        data: &[0xdc, 0x45, 0xc6, 0x0c, 0x20, 0x21, 0xd9, 0x90, 0x5a, 0xb6, 0x1f, 0x32, 0xaa, 0x1d, 0xb3, 0xeb, 0x7e, 0xc2, 0x49, 0x0d, 0x1e, 0x30, 0x30, 0x24, 0x18, 0x0f, 0x49, 0x05, 0xb3, 0xf1, 0x84, 0x48, 0x27],
+        decoded: &[399, 1799, 687, 1699, 975, 1705, 4799, 1711, 1263, 1717, 1552, 1724, 1839, 1729, 2256, 1735, 2832, 1741, 3617, 1748, 4767, 1753, 6140, 1759, 8792, 1765, 13393, 1772, 17197, 1778, 17201, 1699],
+    },
+];
+
+/// Decodes every vector with `decode`, given the data and the width of the row, and compares
+/// the result with the expected values
+pub fn check_decoder<F: FnMut(&[u8], usize) -> Vec<u16>>(mut decode: F) -> Result<(), String> {
+    for vector in VECTORS {
+        let decoded = decode(vector.data, vector.width);
+        if decoded != vector.decoded {
+            return Err(mismatch(vector.name, vector.decoded, &decoded));
+        }
+    }
+    Ok(())
+}
+
+/// Encodes the pixels of every vector that has them with `encode`, given the pixels and the
+/// width of the row, and compares the result with the expected data
+pub fn check_encoder<F: FnMut(&[u16], usize) -> Vec<u8>>(mut encode: F) -> Result<(), String> {
+    for vector in VECTORS {
+        if let Some(pixels) = vector.pixels {
+            let encoded = encode(pixels, vector.width);
+            if encoded != vector.data {
+                return Err(mismatch(vector.name, vector.data, &encoded));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn mismatch<T: PartialEq + std::fmt::Debug>(name: &str, expected: &[T], actual: &[T]) -> String {
+    match expected.iter().zip(actual).position(|(a, b)| a != b) {
+        Some(i) => format!(
+            "vector `{}`: expected {:?} at {}, got {:?}",
+            name, expected[i], i, actual[i]
+        ),
+        None => format!(
+            "vector `{}`: expected {} values, got {}",
+            name,
+            expected.len(),
+            actual.len()
+        ),
+    }
+}
@@ -0,0 +1,471 @@
+use std::io::{self, Write};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+
+pub const IMAGE_WIDTH: u16 = 0x0100;
+pub const IMAGE_LENGTH: u16 = 0x0101;
+pub const BITS_PER_SAMPLE: u16 = 0x0102;
+pub const COMPRESSION: u16 = 0x0103;
+pub const PHOTOMETRIC_INTERPRETATION: u16 = 0x0106;
+pub const IMAGE_DESCRIPTION: u16 = 0x010e;
+pub const MAKE: u16 = 0x010f;
+pub const MODEL: u16 = 0x0110;
+pub const STRIP_OFFSETS: u16 = 0x0111;
+pub const ORIENTATION: u16 = 0x0112;
+pub const ROWS_PER_STRIP: u16 = 0x0116;
+pub const STRIP_BYTE_COUNTS: u16 = 0x0117;
+pub const DATE_TIME: u16 = 0x0132;
+pub const ARTIST: u16 = 0x013b;
+pub const SUB_IFDS: u16 = 0x014a;
+pub const JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+pub const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+pub const SONY_RAW_FILE_TYPE: u16 = 0x7000;
+pub const SONY_TONE_CURVE: u16 = 0x7010;
+pub const SONY_VIGNETTING_CORR_PARAMS: u16 = 0x7032;
+pub const SONY_CA_CORR_PARAMS: u16 = 0x7035;
+pub const SONY_DISTORTION_CORR_PARAMS: u16 = 0x7037;
+pub const SR2_SUB_IFD_OFFSET: u16 = 0x7200;
+pub const SR2_SUB_IFD_LENGTH: u16 = 0x7201;
+pub const SR2_SUB_IFD_KEY: u16 = 0x7221;
+pub const SONY_BLACK_LEVEL: u16 = 0x7310;
+pub const SONY_WHITE_LEVEL: u16 = 0x7312;
+pub const SONY_WB_RGGB_LEVELS: u16 = 0x7313;
+pub const SONY_CROP_TOP_LEFT: u16 = 0x74c7;
+pub const SONY_CROP_SIZE: u16 = 0x74c8;
+pub const SR2_WHITE_LEVEL: u16 = 0x787f;
+pub const CFA_REPEAT_PATTERN_DIM: u16 = 0x828d;
+pub const CFA_PATTERN: u16 = 0x828e;
+pub const COPYRIGHT: u16 = 0x8298;
+pub const GPS_IFD: u16 = 0x8825;
+pub const EXIF_IFD: u16 = 0x8769;
+pub const ISO_SPEED: u16 = 0x8827;
+pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+pub const DATE_TIME_DIGITIZED: u16 = 0x9004;
+pub const MAKER_NOTE: u16 = 0x927c;
+pub const DEFAULT_CROP_ORIGIN: u16 = 0xc61f;
+pub const DEFAULT_CROP_SIZE: u16 = 0xc620;
+pub const DNG_PRIVATE_DATA: u16 = 0xc634;
+pub const CANON_CR2_SLICE: u16 = 0xc640;
+pub const ACTIVE_AREA: u16 = 0xc68d;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    /// Position of the value in the file (either inline in the entry or pointed to by it)
+    pub value_pos: usize,
+}
+
+impl Entry {
+    /// Size of the value in bytes
+    pub fn byte_len(&self) -> usize {
+        type_size(self.field_type) * self.count as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ifd {
+    pub offset: usize,
+    pub entries: Vec<Entry>,
+}
+
+impl Ifd {
+    pub fn entry(&self, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+}
+
+fn type_size(field_type: u16) -> usize {
+    match field_type {
+        1 | 2 | 6 | 7 => 1,
+        3 | 8 => 2,
+        4 | 9 | 11 | 13 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    pub fn new(data: &'a [u8]) -> Option<Tiff<'a>> {
+        let little_endian = match data.get(0..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn is_little_endian(&self) -> bool {
+        self.little_endian
+    }
+
+    pub fn read_u16(&self, pos: usize) -> Option<u16> {
+        let bytes = self.data.get(pos..pos + 2)?;
+        Some(if self.little_endian {
+            LittleEndian::read_u16(bytes)
+        } else {
+            BigEndian::read_u16(bytes)
+        })
+    }
+
+    pub fn read_u32(&self, pos: usize) -> Option<u32> {
+        let bytes = self.data.get(pos..pos + 4)?;
+        Some(if self.little_endian {
+            LittleEndian::read_u32(bytes)
+        } else {
+            BigEndian::read_u32(bytes)
+        })
+    }
+
+    pub fn read_ifd(&self, offset: usize) -> Option<(Ifd, usize)> {
+        let count = self.read_u16(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = offset + 2 + 12 * i;
+            let field_type = self.read_u16(pos + 2)?;
+            let count = self.read_u32(pos + 4)?;
+            let size = type_size(field_type) * count as usize;
+            let value_pos = if size <= 4 {
+                pos + 8
+            } else {
+                self.read_u32(pos + 8)? as usize
+            };
+            entries.push(Entry {
+                tag: self.read_u16(pos)?,
+                field_type,
+                count,
+                value_pos,
+            });
+        }
+        let next = self.read_u32(offset + 2 + 12 * count)? as usize;
+        Some((Ifd { offset, entries }, next))
+    }
+
+    /// Reads an integer entry as a list of unsigned values
+    pub fn values(&self, entry: &Entry) -> Vec<u32> {
+        let size = type_size(entry.field_type);
+        (0..entry.count as usize)
+            .filter_map(|i| {
+                let pos = entry.value_pos + i * size;
+                match entry.field_type {
+                    1 | 7 => self.data.get(pos).map(|b| *b as u32),
+                    3 => self.read_u16(pos).map(|v| v as u32),
+                    4 | 13 => self.read_u32(pos),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Reads an ASCII entry, up to the first NUL
+    pub fn string(&self, entry: &Entry) -> Option<String> {
+        if entry.field_type != 2 {
+            return None;
+        }
+        let bytes = self
+            .data
+            .get(entry.value_pos..entry.value_pos + entry.count as usize)?;
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        Some(String::from_utf8_lossy(&bytes[..end]).trim_end().to_owned())
+    }
+
+    pub fn value(&self, ifd: &Ifd, tag: u16) -> Option<u32> {
+        ifd.entry(tag)
+            .and_then(|entry| self.values(entry).first().copied())
+    }
+
+    pub fn ifd0(&self) -> Option<Ifd> {
+        let offset = self.read_u32(4)? as usize;
+        self.read_ifd(offset).map(|(ifd, _)| ifd)
+    }
+
+    /// The Exif IFD referenced from IFD0
+    pub fn exif_ifd(&self) -> Option<Ifd> {
+        let offset = self.value(&self.ifd0()?, EXIF_IFD)? as usize;
+        self.read_ifd(offset).map(|(ifd, _)| ifd)
+    }
+
+    /// Position of the offset that points to the IFD at `offset`: in the header, in the IFD
+    /// before it in the main chain, or among the SubIFDs of an IFD
+    pub fn pointer_to(&self, offset: usize) -> Option<usize> {
+        if self.read_u32(4)? as usize == offset {
+            return Some(4);
+        }
+        self.ifds().iter().find_map(|ifd| {
+            let next_pos = ifd.offset + 2 + 12 * ifd.entries.len();
+            if self.read_u32(next_pos) == Some(offset as u32) {
+                return Some(next_pos);
+            }
+            let sub_ifds = ifd.entry(SUB_IFDS).filter(|entry| entry.field_type == 4)?;
+            (0..sub_ifds.count as usize)
+                .map(|i| sub_ifds.value_pos + 4 * i)
+                .find(|pos| self.read_u32(*pos) == Some(offset as u32))
+        })
+    }
+
+    /// All IFDs of the main chain together with their SubIFDs
+    pub fn ifds(&self) -> Vec<Ifd> {
+        let mut result = vec![];
+        let mut pending = vec![];
+        let mut next = self.read_u32(4).unwrap_or(0) as usize;
+        while next != 0 && !result.iter().any(|ifd: &Ifd| ifd.offset == next) {
+            match self.read_ifd(next) {
+                Some((ifd, following)) => {
+                    pending.push(ifd.clone());
+                    result.push(ifd);
+                    next = following;
+                }
+                None => break,
+            }
+        }
+        while let Some(ifd) = pending.pop() {
+            let sub_ifds = ifd
+                .entry(SUB_IFDS)
+                .map(|entry| self.values(entry))
+                .unwrap_or_default();
+            for offset in sub_ifds {
+                let offset = offset as usize;
+                if result.iter().any(|ifd| ifd.offset == offset) {
+                    continue;
+                }
+                if let Some((sub_ifd, _)) = self.read_ifd(offset) {
+                    pending.push(sub_ifd.clone());
+                    result.push(sub_ifd);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Overwrites a 16-bit value in place, in the byte order of the file
+pub fn write_u16(data: &mut [u8], pos: usize, value: u16, little_endian: bool) {
+    let bytes = &mut data[pos..pos + 2];
+    if little_endian {
+        LittleEndian::write_u16(bytes, value);
+    } else {
+        BigEndian::write_u16(bytes, value);
+    }
+}
+
+/// Overwrites a 32-bit value in place, in the byte order of the file
+pub fn write_u32(data: &mut [u8], pos: usize, value: u32, little_endian: bool) {
+    let bytes = &mut data[pos..pos + 4];
+    if little_endian {
+        LittleEndian::write_u32(bytes, value);
+    } else {
+        BigEndian::write_u32(bytes, value);
+    }
+}
+
+/// Where `write_tiff` puts the image data: right after the header
+pub const DATA_OFFSET: u32 = 8;
+
+/// A value to be written into an IFD entry
+#[derive(Debug, Clone)]
+pub enum Value {
+    Byte(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    SRational(Vec<(i32, i32)>),
+    Undefined(Vec<u8>),
+}
+
+impl Value {
+    pub fn field_type(&self) -> u16 {
+        match self {
+            Value::Byte(_) => 1,
+            Value::Ascii(_) => 2,
+            Value::Short(_) => 3,
+            Value::Long(_) => 4,
+            Value::Rational(_) => 5,
+            Value::Undefined(_) => 7,
+            Value::SRational(_) => 10,
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        let count = match self {
+            Value::Byte(v) | Value::Undefined(v) => v.len(),
+            Value::Ascii(s) => s.len() + 1,
+            Value::Short(v) => v.len(),
+            Value::Long(v) => v.len(),
+            Value::Rational(v) => v.len(),
+            Value::SRational(v) => v.len(),
+        };
+        count as u32
+    }
+
+    /// The value in little endian byte order
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes_in_order(true)
+    }
+
+    /// The value in the given byte order
+    pub fn bytes_in_order(&self, little_endian: bool) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Value::Byte(v) | Value::Undefined(v) => out.extend_from_slice(v),
+            Value::Ascii(s) => {
+                out.extend_from_slice(s.as_bytes());
+                out.push(0);
+            }
+            Value::Short(v) => v
+                .iter()
+                .for_each(|x| out.write_u16::<LittleEndian>(*x).unwrap()),
+            Value::Long(v) => v
+                .iter()
+                .for_each(|x| out.write_u32::<LittleEndian>(*x).unwrap()),
+            Value::Rational(v) => v.iter().for_each(|(n, d)| {
+                out.write_u32::<LittleEndian>(*n).unwrap();
+                out.write_u32::<LittleEndian>(*d).unwrap();
+            }),
+            Value::SRational(v) => v.iter().for_each(|(n, d)| {
+                out.write_i32::<LittleEndian>(*n).unwrap();
+                out.write_i32::<LittleEndian>(*d).unwrap();
+            }),
+        }
+        if !little_endian {
+            // every number, and either half of a rational, on its own
+            let unit = match self {
+                Value::Short(_) => 2,
+                Value::Long(_) | Value::Rational(_) | Value::SRational(_) => 4,
+                _ => 1,
+            };
+            for number in out.chunks_mut(unit) {
+                number.reverse();
+            }
+        }
+        out
+    }
+}
+
+/// Rewrites the IFD that the offset at `pointer_pos` points to, with the tags of `changes`
+/// set to their values or, for `None`, removed, and its other entries as they are. The new
+/// IFD and the values that don't fit into their entries are appended to the file, and the
+/// offset is redirected to it; nothing else moves, so every other offset in the file stays
+/// valid. The old IFD is left behind, unused. Returns the offset of the new IFD, or `None` if
+/// the old one can't be read.
+pub fn rewrite_ifd(
+    file: &mut Vec<u8>,
+    pointer_pos: usize,
+    changes: &[(u16, Option<Value>)],
+) -> Option<usize> {
+    let tiff = Tiff::new(file)?;
+    let little_endian = tiff.is_little_endian();
+    let offset = tiff.read_u32(pointer_pos)? as usize;
+    let count = tiff.read_u16(offset)? as usize;
+    let mut entries: Vec<(u16, [u8; 12])> = (0..count)
+        .map(|i| {
+            let pos = offset + 2 + 12 * i;
+            let mut raw = [0; 12];
+            raw.copy_from_slice(file.get(pos..pos + 12)?);
+            Some((tiff.read_u16(pos)?, raw))
+        })
+        .collect::<Option<_>>()?;
+    let next = tiff.read_u32(offset + 2 + 12 * count)?;
+    entries.retain(|(tag, _)| !changes.iter().any(|(changed, _)| changed == tag));
+
+    file.resize(file.len().next_multiple_of(2), 0);
+    for (tag, value) in changes {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut raw = [0; 12];
+        write_u16(&mut raw, 0, *tag, little_endian);
+        write_u16(&mut raw, 2, value.field_type(), little_endian);
+        write_u32(&mut raw, 4, value.count(), little_endian);
+        let bytes = value.bytes_in_order(little_endian);
+        if bytes.len() <= 4 {
+            raw[8..8 + bytes.len()].copy_from_slice(&bytes);
+        } else {
+            write_u32(&mut raw, 8, file.len() as u32, little_endian);
+            file.extend(bytes);
+            file.resize(file.len().next_multiple_of(2), 0);
+        }
+        entries.push((*tag, raw));
+    }
+    entries.sort_by_key(|(tag, _)| *tag);
+
+    let new_offset = file.len();
+    file.resize(new_offset + 2 + 12 * entries.len() + 4, 0);
+    write_u16(file, new_offset, entries.len() as u16, little_endian);
+    for (i, (_, raw)) in entries.iter().enumerate() {
+        let pos = new_offset + 2 + 12 * i;
+        file[pos..pos + 12].copy_from_slice(raw);
+    }
+    write_u32(
+        file,
+        new_offset + 2 + 12 * entries.len(),
+        next,
+        little_endian,
+    );
+    write_u32(file, pointer_pos, new_offset as u32, little_endian);
+    Some(new_offset)
+}
+
+/// Writes a little endian TIFF consisting of `data_len` bytes of image data produced by
+/// `write_data` (placed at `DATA_OFFSET`), followed by a single IFD with the given entries
+pub fn write_tiff<W, F>(
+    out: &mut W,
+    mut entries: Vec<(u16, Value)>,
+    data_len: u32,
+    write_data: F,
+) -> io::Result<()>
+where
+    W: Write,
+    F: FnOnce(&mut W) -> io::Result<()>,
+{
+    entries.sort_by_key(|(tag, _)| *tag);
+
+    let ifd_offset = DATA_OFFSET + data_len + data_len % 2;
+    let ifd_len = 2 + 12 * entries.len() as u32 + 4;
+    let mut extra_offset = ifd_offset + ifd_len;
+    let mut extra = vec![];
+
+    out.write_all(b"II")?;
+    out.write_u16::<LittleEndian>(42)?;
+    out.write_u32::<LittleEndian>(ifd_offset)?;
+    write_data(out)?;
+    if data_len % 2 == 1 {
+        out.write_u8(0)?;
+    }
+
+    out.write_u16::<LittleEndian>(entries.len() as u16)?;
+    for (tag, value) in &entries {
+        out.write_u16::<LittleEndian>(*tag)?;
+        out.write_u16::<LittleEndian>(value.field_type())?;
+        out.write_u32::<LittleEndian>(value.count())?;
+        let mut bytes = value.bytes();
+        if bytes.len() <= 4 {
+            bytes.resize(4, 0);
+            out.write_all(&bytes)?;
+        } else {
+            out.write_u32::<LittleEndian>(extra_offset)?;
+            if bytes.len() % 2 == 1 {
+                bytes.push(0);
+            }
+            extra_offset += bytes.len() as u32;
+            extra.extend(bytes);
+        }
+    }
+    out.write_u32::<LittleEndian>(0)?;
+    out.write_all(&extra)
+}
@@ -0,0 +1,150 @@
+//! Image storage split into square tiles, each with its own dirty flag. Edits only touch the
+//! tiles they cover, and writers can use the dirty flags to re-encode just the affected parts
+//! of the image.
+
+use std::ops::Range;
+
+/// Width and height of a (full) tile
+pub const TILE_SIZE: usize = 256;
+
+/// A rectangle of pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// The same rectangle with the axes swapped
+    pub fn transposed(self) -> Rect {
+        Rect {
+            x: self.y,
+            y: self.x,
+            width: self.height,
+            height: self.width,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Tile {
+    rect: Rect,
+    data: Vec<u16>,
+    dirty: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TiledBuffer {
+    width: usize,
+    height: usize,
+    tiles_across: usize,
+    tiles: Vec<Tile>,
+}
+
+impl TiledBuffer {
+    /// Splits row-major pixels into tiles; tiles along the right and bottom edges are cut
+    /// short to fit the image
+    pub fn from_pixels(pixels: &[u16], width: usize, height: usize) -> TiledBuffer {
+        let tiles_across = width.div_ceil(TILE_SIZE);
+        let mut tiles = vec![];
+        for y in (0..height).step_by(TILE_SIZE) {
+            for x in (0..width).step_by(TILE_SIZE) {
+                let rect = Rect {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(width - x),
+                    height: TILE_SIZE.min(height - y),
+                };
+                let mut data = Vec::with_capacity(rect.width * rect.height);
+                for row in y..y + rect.height {
+                    let start = row * width + x;
+                    data.extend_from_slice(&pixels[start..start + rect.width]);
+                }
+                tiles.push(Tile {
+                    rect,
+                    data,
+                    dirty: false,
+                });
+            }
+        }
+        TiledBuffer {
+            width,
+            height,
+            tiles_across,
+            tiles,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn tile_rect(&self, index: usize) -> Rect {
+        self.tiles[index].rect
+    }
+
+    /// The pixels of a tile, row by row
+    pub fn tile(&self, index: usize) -> &[u16] {
+        &self.tiles[index].data
+    }
+
+    /// Replaces the pixels of a tile and marks it dirty
+    pub fn set_tile(&mut self, index: usize, data: &[u16]) {
+        let tile = &mut self.tiles[index];
+        tile.data.copy_from_slice(data);
+        tile.dirty = true;
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u16 {
+        let tile = &self.tiles[(y / TILE_SIZE) * self.tiles_across + x / TILE_SIZE];
+        tile.data[(y - tile.rect.y) * tile.rect.width + x - tile.rect.x]
+    }
+
+    /// Rectangles of all tiles changed since the buffer was created
+    pub fn dirty_rects(&self) -> Vec<Rect> {
+        self.tiles
+            .iter()
+            .filter(|tile| tile.dirty)
+            .map(|tile| tile.rect)
+            .collect()
+    }
+
+    /// Copies the tiles back into row-major pixels
+    pub fn to_pixels(&self) -> Vec<u16> {
+        let mut pixels = vec![0; self.width * self.height];
+        for tile in &self.tiles {
+            for (i, row) in tile.data.chunks(tile.rect.width).enumerate() {
+                let start = (tile.rect.y + i) * self.width + tile.rect.x;
+                pixels[start..start + tile.rect.width].copy_from_slice(row);
+            }
+        }
+        pixels
+    }
+}
+
+/// Merges rectangles into sorted, non-overlapping ranges of the rows they span
+pub fn spans(rects: &[Rect]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<_> = rects
+        .iter()
+        .map(|rect| rect.y..rect.y + rect.height)
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
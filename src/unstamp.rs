@@ -0,0 +1,143 @@
+//! Removal of text stamps from raw data whose unstamped original is lost. This is an
+//! experimental, approximate restoration: photosites the stamp only partly covered are
+//! recovered by undoing the blend, and those it covered mostly or entirely are inpainted from
+//! the nearest photosites of the same colour around them. Inpainted areas come out smooth,
+//! without the noise and the detail they had.
+
+use crate::edit::{Neutral, RawBuffer, TextEdit};
+
+/// Largest coverage at which a photosite is recovered by undoing the blend; above it, the
+/// error the codec left in the stored value grows too much in the division
+const MAX_UNBLEND_COVERAGE: f32 = 0.5;
+/// Number of smoothing passes over the inpainted photosites, to even out the rings left by
+/// filling them in from the outside in
+const RELAX_PASSES: usize = 32;
+/// Offsets of the nearest photosites of the same colour, with their weights
+const NEIGHBOURS: [(i64, i64, f64); 8] = [
+    (-2, 0, 1.0),
+    (2, 0, 1.0),
+    (0, -2, 1.0),
+    (0, 2, 1.0),
+    (-2, -2, std::f64::consts::FRAC_1_SQRT_2),
+    (2, -2, std::f64::consts::FRAC_1_SQRT_2),
+    (-2, 2, std::f64::consts::FRAC_1_SQRT_2),
+    (2, 2, std::f64::consts::FRAC_1_SQRT_2),
+];
+
+/// How many photosites `unstamp` recovered in either way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Unstamped {
+    pub unblended: usize,
+    pub inpainted: usize,
+}
+
+/// Removes a stamp drawn with `edit` (in image coordinates) and, if it was drawn to develop
+/// neutral grey, `neutral`. Photosites at or above `white_level` are taken as clipped and
+/// inpainted.
+pub fn unstamp(
+    img: &mut RawBuffer,
+    edit: &TextEdit,
+    white_level: u16,
+    neutral: Option<&Neutral>,
+) -> Unstamped {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let mut unknown = vec![];
+    let mut unblended = 0;
+    edit.prepare()
+        .for_each_covered(img.dimensions(), edit.x, edit.y, |x, y, v| {
+            let stamp = match neutral {
+                Some(neutral) => neutral.value(edit.value as f32, x, y),
+                None => edit.value as f32,
+            };
+            let pixel = img.get_pixel_mut(x, y);
+            if v <= MAX_UNBLEND_COVERAGE && pixel.0[0] < white_level {
+                let original = (pixel.0[0] as f32 - stamp * v) / (1.0 - v);
+                pixel.0[0] = original.round().clamp(0.0, white_level as f32) as u16;
+                unblended += 1;
+            } else {
+                unknown.push((x as i64, y as i64));
+            }
+        });
+    let inpainted = unknown.len();
+    if unknown.is_empty() {
+        return Unstamped {
+            unblended,
+            inpainted,
+        };
+    }
+
+    // the area the inpainting reads from, with a margin for the neighbours
+    let left = unknown
+        .iter()
+        .map(|p| p.0)
+        .min()
+        .unwrap()
+        .saturating_sub(2)
+        .max(0);
+    let top = unknown
+        .iter()
+        .map(|p| p.1)
+        .min()
+        .unwrap()
+        .saturating_sub(2)
+        .max(0);
+    let right = (unknown.iter().map(|p| p.0).max().unwrap() + 3).min(width);
+    let bottom = (unknown.iter().map(|p| p.1).max().unwrap() + 3).min(height);
+    let area_width = (right - left) as usize;
+    let index = |x: i64, y: i64| (y - top) as usize * area_width + (x - left) as usize;
+    let mut values: Vec<f64> = (top..bottom)
+        .flat_map(|y| (left..right).map(move |x| (x, y)))
+        .map(|(x, y)| img.get_pixel(x as u32, y as u32).0[0] as f64)
+        .collect();
+    let mut known = vec![true; values.len()];
+    for (x, y) in &unknown {
+        known[index(*x, *y)] = false;
+    }
+    let inside = |x: i64, y: i64| x >= left && x < right && y >= top && y < bottom;
+    let average = |values: &[f64], known: &[bool], x: i64, y: i64| {
+        let (mut sum, mut weights) = (0.0, 0.0);
+        for (dx, dy, weight) in NEIGHBOURS {
+            let (nx, ny) = (x + dx, y + dy);
+            if inside(nx, ny) && known[index(nx, ny)] {
+                sum += values[index(nx, ny)] * weight;
+                weights += weight;
+            }
+        }
+        (weights > 0.0).then(|| sum / weights)
+    };
+
+    // fill in from the edges, a ring at a time
+    let mut remaining = unknown.clone();
+    while !remaining.is_empty() {
+        let filled: Vec<_> = remaining
+            .iter()
+            .filter_map(|(x, y)| average(&values, &known, *x, *y).map(|value| (*x, *y, value)))
+            .collect();
+        if filled.is_empty() {
+            // no photosite of that colour anywhere around
+            break;
+        }
+        for (x, y, value) in filled {
+            values[index(x, y)] = value;
+            known[index(x, y)] = true;
+        }
+        remaining.retain(|(x, y)| !known[index(*x, *y)]);
+    }
+    for _ in 0..RELAX_PASSES {
+        let relaxed: Vec<_> = unknown
+            .iter()
+            .map(|(x, y)| average(&values, &known, *x, *y).unwrap_or(values[index(*x, *y)]))
+            .collect();
+        for ((x, y), value) in unknown.iter().zip(relaxed) {
+            values[index(*x, *y)] = value;
+        }
+    }
+    for (x, y) in &unknown {
+        let value = values[index(*x, *y)].round().clamp(0.0, white_level as f64);
+        img.get_pixel_mut(*x as u32, *y as u32).0[0] = value as u16;
+    }
+    Unstamped {
+        unblended,
+        inpainted,
+    }
+}
@@ -0,0 +1,329 @@
+use std::{cmp, fmt};
+
+use crate::format::Format;
+use crate::rawloader::cr2::{self, Cr2Layout};
+use crate::rawloader::nef::{self, NefLayout};
+use crate::rawloader::sony_lossless::{self, TileLayout};
+use crate::rawloader::{encode_delta_shift, LookupTable};
+use crate::source::MemorySource;
+use crate::RawEditError;
+
+/// Extra slack (in curve codes) allowed on top of the block quantization when no explicit
+/// tolerance is given
+pub const DEFAULT_TOLERANCE: u16 = 1;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegionStats {
+    pub checked: usize,
+    pub mismatched: usize,
+    pub max_deviation: u16,
+}
+
+impl RegionStats {
+    fn record(&mut self, deviation: u16, allowed: u16) {
+        self.checked += 1;
+        self.max_deviation = cmp::max(self.max_deviation, deviation);
+        if deviation > allowed {
+            self.mismatched += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// Pixels changed by the edit, compared against the intended values. With ARW2 data
+    /// these are all the pixels of the blocks it changed, which are quantized anew.
+    pub edited: RegionStats,
+    /// Pixels left alone by the edit, compared against the original decode
+    pub untouched: RegionStats,
+    /// Coordinates of the first pixel outside of the tolerance
+    pub first_mismatch: Option<(usize, usize)>,
+}
+
+impl ValidationReport {
+    pub fn passed(&self) -> bool {
+        self.edited.mismatched == 0 && self.untouched.mismatched == 0
+    }
+}
+
+#[inline]
+fn code(curve: &LookupTable, value: u16) -> u16 {
+    curve.reverse_lookup(value) >> 1
+}
+
+/// How written data keeps its values, which decides how closely they decode to what was
+/// meant to be written
+#[derive(Debug, Clone)]
+pub enum Storage {
+    /// ARW2 blocks: curve codes, with the deltas within a block quantized to 7 bits
+    Arw2(LookupTable),
+    /// Indices into a curve, as lossy NEF data keeps them
+    Curve(LookupTable),
+    /// The values themselves
+    Exact,
+}
+
+impl Storage {
+    /// How the raw data of `file` in the given format keeps its values
+    pub fn of(format: Format, file: &[u8]) -> Result<Storage, RawEditError> {
+        Ok(match format {
+            Format::Arw2 { curve, .. } => Storage::Arw2(curve.table()),
+            Format::Nef { ifd_offset, .. } => match NefLayout::read(file, ifd_offset)?.curve {
+                Some(curve) => Storage::Curve(curve),
+                None => Storage::Exact,
+            },
+            _ => Storage::Exact,
+        })
+    }
+}
+
+/// Compares the decode of the written file against what was meant to be written.
+///
+/// Pixels the edit left alone have to decode to what they did before, and edited pixels to
+/// the values intended for them, give or take `tolerance`: in curve codes or indices where
+/// the data goes through a curve, and in values where it keeps them as they are. With ARW2
+/// data, the pixels of blocks the edit changed are also allowed the quantization of the 7-bit
+/// deltas of their block (which depends on the block's spread).
+///
+/// ```
+/// use raw_tiff_edit::format::Format;
+/// use raw_tiff_edit::validate::{self, Storage};
+///
+/// let original = vec![1000; 64];
+/// let mut intended = original.clone();
+/// intended[5] = 3000;
+/// let mut written = intended.clone();
+/// let storage = Storage::of(Format::Uncompressed, &[])?;
+/// let report = validate::validate(&storage, &original, &intended, &written, 8, 0);
+/// assert!(report.passed());
+///
+/// written[40] += 1;
+/// let report = validate::validate(&storage, &original, &intended, &written, 8, 0);
+/// assert_eq!(report.untouched.mismatched, 1);
+/// assert_eq!(report.first_mismatch, Some((0, 5)));
+/// # Ok::<(), raw_tiff_edit::RawEditError>(())
+/// ```
+pub fn validate(
+    storage: &Storage,
+    original: &[u16],
+    intended: &[u16],
+    written: &[u16],
+    width: usize,
+    tolerance: u16,
+) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let rows = original
+        .chunks(width)
+        .zip(intended.chunks(width))
+        .zip(written.chunks(width));
+    for (y, ((original, intended), written)) in rows.enumerate() {
+        let mut check = |x: usize, edited: bool, deviation: u16, allowed: u16| {
+            let stats = if edited {
+                &mut report.edited
+            } else {
+                &mut report.untouched
+            };
+            stats.record(deviation, allowed);
+            if deviation > allowed && report.first_mismatch.is_none() {
+                report.first_mismatch = Some((x, y));
+            }
+        };
+        let curve = match storage {
+            Storage::Arw2(curve) => curve,
+            Storage::Curve(curve) => {
+                for (x, (expected, actual)) in intended.iter().zip(written).enumerate() {
+                    let (expected, actual) = (
+                        curve.reverse_lookup(*expected),
+                        curve.reverse_lookup(*actual),
+                    );
+                    check(
+                        x,
+                        original[x] != intended[x],
+                        expected.abs_diff(actual),
+                        tolerance,
+                    );
+                }
+                continue;
+            }
+            Storage::Exact => {
+                for (x, (expected, actual)) in intended.iter().zip(written).enumerate() {
+                    check(
+                        x,
+                        original[x] != intended[x],
+                        expected.abs_diff(*actual),
+                        tolerance,
+                    );
+                }
+                continue;
+            }
+        };
+        for (block, intended_block) in intended.chunks(32).enumerate() {
+            let base = block * 32;
+            for j in 0..2 {
+                let codes: Vec<_> = intended_block
+                    .iter()
+                    .skip(j)
+                    .step_by(2)
+                    .map(|value| code(curve, *value))
+                    .collect();
+                let (min, max) = match (codes.iter().min(), codes.iter().max()) {
+                    (Some(min), Some(max)) => (*min, *max),
+                    _ => continue,
+                };
+                let xs = (0..codes.len()).map(|i| base + j + 2 * i);
+                let edited = xs.clone().any(|x| original[x] != intended[x]);
+                let allowed = if edited {
+                    let shift = encode_delta_shift(max - min);
+                    ((1u16 << shift) << 1).saturating_add(tolerance)
+                } else {
+                    tolerance
+                };
+                for (x, expected) in xs.zip(codes) {
+                    check(
+                        x,
+                        edited,
+                        code(curve, written[x]).abs_diff(expected),
+                        allowed,
+                    );
+                }
+            }
+        }
+    }
+
+    report
+}
+
+/// How raw data survives being encoded again without any edits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoundTrip {
+    /// Blocks of the stored data: 32 pixels of a row, tiles for Sony's lossless data, or the
+    /// whole strip of a CR2 or a NEF
+    pub blocks: usize,
+    /// Blocks that encode to other bytes than the file holds
+    pub changed_blocks: usize,
+    pub pixels: usize,
+    /// Pixels that decode to another value from the re-encoded data
+    pub changed_pixels: usize,
+    pub max_deviation: u16,
+}
+
+impl RoundTrip {
+    /// Whether every pixel decodes the same from the re-encoded data
+    pub fn is_lossless(&self) -> bool {
+        self.changed_pixels == 0
+    }
+
+    fn compare(&mut self, first: &[u16], second: &[u16]) {
+        for (first, second) in first.iter().zip(second) {
+            let deviation = cmp::max(first, second) - cmp::min(first, second);
+            self.pixels += 1;
+            if deviation > 0 {
+                self.changed_pixels += 1;
+                self.max_deviation = cmp::max(self.max_deviation, deviation);
+            }
+        }
+    }
+}
+
+impl fmt::Display for RoundTrip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "round trip: {}/{} blocks re-encode differently, {}/{} pixels decode differently (max deviation {})",
+            self.changed_blocks, self.blocks, self.changed_pixels, self.pixels, self.max_deviation
+        )?;
+        if self.is_lossless() {
+            write!(f, "  the raw data survives re-encoding unchanged")
+        } else {
+            write!(f, "  the raw data does NOT survive re-encoding unchanged")
+        }
+    }
+}
+
+/// Encodes `pixels`, the decode of the `width` x `height` raw data of `file` at `offset`,
+/// again without any edits, and compares the result with the file and its decode with
+/// `pixels`
+pub fn round_trip(
+    file: &[u8],
+    format: Format,
+    offset: usize,
+    pixels: &[u16],
+    width: usize,
+    height: usize,
+) -> Result<RoundTrip, RawEditError> {
+    let mut result = RoundTrip::default();
+    if let Format::SonyLossless { ifd_offset } = format {
+        // every tile on its own, as `write_tiles` leaves unchanged tiles alone
+        let layout = TileLayout::read(file, ifd_offset)?;
+        let (tile_width, tile_length) = (layout.tile_width, layout.tile_length);
+        for tile in 0..layout.tiles_across() * layout.tiles_down() {
+            let data = sony_lossless::tile_data(file, &layout, tile)?;
+            let (first, precision) = sony_lossless::decode_tile(data, tile_width, tile_length)?;
+            let encoded = sony_lossless::encode_tile(&first, tile_width, tile_length, precision)?;
+            let (second, _) = sony_lossless::decode_tile(&encoded, tile_width, tile_length)?;
+            result.blocks += 1;
+            if encoded != data {
+                result.changed_blocks += 1;
+            }
+            let rect = layout.tile_rect(tile);
+            for row in 0..rect.height {
+                let start = row * tile_width;
+                result.compare(
+                    &first[start..start + rect.width],
+                    &second[start..start + rect.width],
+                );
+            }
+        }
+        return Ok(result);
+    }
+    if let Format::Cr2 { ifd_offset } = format {
+        let layout = Cr2Layout::read(file, ifd_offset)?;
+        let first = cr2::decode(file, &layout)?;
+        let encoded = cr2::encode(&first, &layout)?;
+        let second = cr2::decode_data(&encoded, &layout)?;
+        result.blocks += 1;
+        if encoded != layout.data(file)? {
+            result.changed_blocks += 1;
+        }
+        result.compare(&first, &second);
+        return Ok(result);
+    }
+    if let Format::Nef { ifd_offset, dither } = format {
+        let layout = NefLayout::read(file, ifd_offset)?;
+        let first = nef::decode(file, &layout, dither)?;
+        let encoded = nef::encode(&first, &layout)?;
+        let mut rewritten = file.to_vec();
+        layout.strip.replace(&mut rewritten, &encoded)?;
+        let second = nef::decode(
+            &rewritten,
+            &NefLayout::read(&rewritten, ifd_offset)?,
+            dither,
+        )?;
+        result.blocks += 1;
+        if encoded != layout.strip.data(file)? {
+            result.changed_blocks += 1;
+        }
+        result.compare(&first, &second);
+        return Ok(result);
+    }
+
+    let len = format.data_len(width, height);
+    let stored = file
+        .get(offset..offset + len)
+        .ok_or_else(|| RawEditError::Truncated("the raw data".to_owned()))?;
+    let mut encoded = vec![0; len];
+    format.encode_into(pixels, width, &mut encoded)?;
+    let row_len = format.data_len(width, 1);
+    let block_len = format.data_len(32, 1);
+    for (stored, encoded) in stored.chunks(row_len).zip(encoded.chunks(row_len)) {
+        for (stored, encoded) in stored.chunks(block_len).zip(encoded.chunks(block_len)) {
+            result.blocks += 1;
+            if stored != encoded {
+                result.changed_blocks += 1;
+            }
+        }
+    }
+    let second = format.decode_from(&mut MemorySource::new(&encoded), 0, width, height)?;
+    result.compare(pixels, &second.pixels);
+    Ok(result)
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+
+use crate::raw::Readout;
+use crate::rawloader::cr2::{self, Cr2Layout};
+use crate::rawloader::nef;
+use crate::rawloader::ToneCurve;
+use crate::sr2;
+use crate::tiff::{self, Tiff};
+
+/// Compression value used by Sony for cRAW (ARW2) data
+pub(crate) const COMPRESSION_SONY_ARW: u32 = 32767;
+const COMPRESSION_NONE: u32 = 1;
+/// PhotometricInterpretation of colour filter array data
+const PHOTOMETRIC_CFA: u32 = 32803;
+const TILE_WIDTH: u16 = 0x0142;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawVariant {
+    Uncompressed14,
+    Uncompressed12,
+    Compressed,
+    LosslessCompressed,
+    LosslessCompressed2,
+    /// Canon's lossless JPEG
+    Cr2,
+    /// Nikon's Huffman-coded NEF, lossy or lossless
+    Nef,
+    Unknown(u32),
+}
+
+impl RawVariant {
+    fn from_raw_file_type(value: u32) -> RawVariant {
+        match value {
+            0 => RawVariant::Uncompressed14,
+            1 => RawVariant::Uncompressed12,
+            2 => RawVariant::Compressed,
+            3 => RawVariant::LosslessCompressed,
+            4 => RawVariant::LosslessCompressed2,
+            other => RawVariant::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RawInfo {
+    pub variant: RawVariant,
+    /// Offset of the IFD describing the raw data
+    pub ifd_offset: usize,
+    pub width: usize,
+    pub height: usize,
+    pub offset: usize,
+    /// Size of the raw data, in all of its strips
+    pub byte_count: usize,
+    pub tiled: bool,
+    pub readout: Readout,
+    /// The tone curve given in the file, if any
+    pub tone_curve: Option<ToneCurve>,
+}
+
+#[derive(Debug, Clone)]
+pub enum VariantError {
+    Unsupported(String),
+}
+
+impl fmt::Display for VariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VariantError::Unsupported(reason) => write!(f, "unsupported variant: {}", reason),
+        }
+    }
+}
+
+/// An IFD that describes an image, raw or not
+#[derive(Debug, Clone, Copy)]
+pub struct ImageIfd {
+    /// Position in the list of all IFDs, as used by `--ifd`
+    pub index: usize,
+    pub offset: usize,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub compression: Option<u32>,
+    /// Whether the IFD references an embedded JPEG (a preview or thumbnail)
+    pub jpeg: bool,
+    /// What kind of raw data the IFD holds, if any
+    pub raw: Option<RawInfo>,
+}
+
+impl fmt::Display for ImageIfd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IFD {} at offset {}: ", self.index, self.offset)?;
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => write!(f, "{}x{}", width, height)?,
+            _ => write!(f, "no dimensions")?,
+        }
+        if let Some(compression) = self.compression {
+            write!(f, ", compression {}", compression)?;
+        }
+        if self.jpeg {
+            write!(f, ", embedded JPEG")?;
+        }
+        if let Some(raw) = self.raw {
+            write!(
+                f,
+                ", {:?} raw data, {} bytes at offset {}",
+                raw.variant, raw.byte_count, raw.offset
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Lists all IFDs describing an image, in the order of `Tiff::ifds`
+pub fn candidates(buf: &[u8]) -> Vec<ImageIfd> {
+    let tiff = match Tiff::new(buf) {
+        Some(tiff) => tiff,
+        None => return vec![],
+    };
+    let private = sr2::SubIfd::read(&tiff);
+    let is_cr2 = cr2::is_cr2(buf);
+    tiff.ifds()
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, ifd)| {
+            let mut width = tiff.value(&ifd, tiff::IMAGE_WIDTH).map(|v| v as usize);
+            let mut height = tiff.value(&ifd, tiff::IMAGE_LENGTH).map(|v| v as usize);
+            let compression = tiff.value(&ifd, tiff::COMPRESSION);
+            let jpeg = ifd.entry(tiff::JPEG_INTERCHANGE_FORMAT).is_some();
+            // the raw IFD of a CR2, the fourth, gives no dimensions; its JPEG and slices do.
+            // The large preview is old-style JPEG as well, but baseline rather than lossless.
+            let cr2_layout = match compression {
+                Some(cr2::COMPRESSION_OLD_JPEG)
+                    if is_cr2 && (index == 3 || ifd.entry(tiff::CANON_CR2_SLICE).is_some()) =>
+                {
+                    Cr2Layout::read(buf, ifd.offset).ok()
+                }
+                _ => None,
+            };
+            if let Some(layout) = &cr2_layout {
+                width = Some(layout.width);
+                height = Some(layout.height);
+            }
+            if width.is_none() && height.is_none() && !jpeg {
+                return None;
+            }
+            let variant = match tiff.value(&ifd, tiff::SONY_RAW_FILE_TYPE) {
+                _ if cr2_layout.is_some() => Some(RawVariant::Cr2),
+                Some(value) => Some(RawVariant::from_raw_file_type(value)),
+                None if compression == Some(COMPRESSION_SONY_ARW) => Some(RawVariant::Compressed),
+                None if compression == Some(nef::COMPRESSION_NIKON) => Some(RawVariant::Nef),
+                // bodies that predate SonyRawFileType only write uncompressed data
+                None if compression == Some(COMPRESSION_NONE)
+                    && tiff.value(&ifd, tiff::PHOTOMETRIC_INTERPRETATION)
+                        == Some(PHOTOMETRIC_CFA) =>
+                {
+                    match tiff.value(&ifd, tiff::BITS_PER_SAMPLE) {
+                        Some(12) => Some(RawVariant::Uncompressed12),
+                        _ => Some(RawVariant::Uncompressed14),
+                    }
+                }
+                None => None,
+            };
+            let raw = match (variant, width, height) {
+                (Some(variant), Some(width), Some(height)) => Some(RawInfo {
+                    variant,
+                    ifd_offset: ifd.offset,
+                    width,
+                    height,
+                    offset: tiff.value(&ifd, tiff::STRIP_OFFSETS).unwrap_or(0) as usize,
+                    // the raw data may be split into strips, one after the other
+                    byte_count: ifd
+                        .entry(tiff::STRIP_BYTE_COUNTS)
+                        .map_or(0, |entry| tiff.values(entry).iter().sum::<u32>())
+                        as usize,
+                    tiled: ifd.entry(TILE_WIDTH).is_some(),
+                    readout: tiff
+                        .value(&ifd, tiff::ORIENTATION)
+                        .map_or(Readout::Rows, Readout::from_orientation),
+                    tone_curve: sr2::tone_curve(&tiff, &ifd, private.as_ref()),
+                }),
+                _ => None,
+            };
+            Some(ImageIfd {
+                index,
+                offset: ifd.offset,
+                width,
+                height,
+                compression,
+                jpeg,
+                raw,
+            })
+        })
+        .collect()
+}
+
+/// The frames of a file that keeps several raw images of the same scene, such as the shots of
+/// a pixel-shift capture: the IFDs holding raw data of the largest size any of them has, in
+/// the order they come in. An ordinary raw file has one.
+pub fn frames(buf: &[u8]) -> Vec<ImageIfd> {
+    let candidates = candidates(buf);
+    let largest = candidates
+        .iter()
+        .filter_map(|candidate| candidate.raw)
+        .map(|info| (info.width, info.height))
+        .max_by_key(|(width, height)| width * height);
+    candidates
+        .into_iter()
+        .filter(|candidate| {
+            candidate.raw.map(|info| (info.width, info.height)) == largest && largest.is_some()
+        })
+        .collect()
+}
+
+/// Finds the raw image IFD and reads what kind of raw data it holds. With several raw IFDs,
+/// the one with the most pixels is taken; `index` picks a specific IFD instead.
+pub fn detect(buf: &[u8], index: Option<usize>) -> Result<Option<RawInfo>, VariantError> {
+    let candidates = candidates(buf);
+    match index {
+        Some(index) => match candidates.iter().find(|candidate| candidate.index == index) {
+            Some(ImageIfd { raw: Some(raw), .. }) => Ok(Some(*raw)),
+            Some(_) => Err(VariantError::Unsupported(format!(
+                "IFD {} does not hold raw data",
+                index
+            ))),
+            None => Err(VariantError::Unsupported(format!(
+                "there is no image IFD {}",
+                index
+            ))),
+        },
+        None => Ok(candidates
+            .iter()
+            .filter_map(|candidate| candidate.raw)
+            .max_by_key(|info| info.width * info.height)),
+    }
+}
+
+/// Checks that the raw data is laid out the way the ARW2 codec expects: full rows of
+/// `width` bytes, one strip, nothing else. Reduced-resolution (S/M) compressed variants and
+/// tiled layouts are rejected instead of being decoded into garbage.
+pub fn check_arw2(info: &RawInfo) -> Result<(), VariantError> {
+    if info.variant != RawVariant::Compressed {
+        return Err(VariantError::Unsupported(format!(
+            "{:?} raw data",
+            info.variant
+        )));
+    }
+    if info.tiled {
+        return Err(VariantError::Unsupported(
+            "tiled compressed RAW (S/M size)".to_owned(),
+        ));
+    }
+    if info.byte_count != 0 && info.byte_count != info.width * info.height {
+        return Err(VariantError::Unsupported(format!(
+            "compressed RAW payload of {} bytes does not match {}x{} (reduced-resolution S/M size?)",
+            info.byte_count, info.width, info.height
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that uncompressed raw data is a single strip of `data_len` bytes
+pub fn check_uncompressed(info: &RawInfo, data_len: usize) -> Result<(), VariantError> {
+    if info.tiled {
+        return Err(VariantError::Unsupported(
+            "tiled uncompressed RAW".to_owned(),
+        ));
+    }
+    if info.byte_count != 0 && info.byte_count != data_len {
+        return Err(VariantError::Unsupported(format!(
+            "uncompressed RAW payload of {} bytes does not match {:?} data of {}x{}",
+            info.byte_count, info.variant, info.width, info.height
+        )));
+    }
+    Ok(())
+}
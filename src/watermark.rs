@@ -0,0 +1,321 @@
+//! An invisible watermark in ARW2 data: a short payload hidden in the least significant
+//! quantization step of one photosite in every block of 16, where the encoder keeps it.
+//!
+//! In every block, one photosite is picked by a keyed order among the ones strictly between
+//! the block's smallest and largest code; the parity of its stored delta carries one bit.
+//! Moving it by one step changes neither the block's range nor which photosite is picked, so
+//! the bit reads back from the decoded data whatever the dither, and survives any number of
+//! decodes and re-encodes of data that isn't edited. The payload, its length and a CRC are
+//! sent as Hamming codes, and every coded bit is spread over thousands of blocks all across
+//! the image and decided by a vote, so edits over parts of the image (stamps, redactions, a
+//! re-encode of some rows) only take away votes.
+
+use std::fmt;
+
+use crate::rawloader::blocks::{BlockPosition, BLOCK_PIXELS, GROUP_PIXELS};
+use crate::rawloader::{encode_delta_shift, LookupTable};
+
+/// Largest payload, in bytes
+pub const MAX_PAYLOAD: usize = 32;
+/// The length of the payload, the payload padded to the largest and a CRC-16 of both
+const FRAME_BYTES: usize = 1 + MAX_PAYLOAD + 2;
+/// Every 4 bits of the frame are sent as 7
+const CODED_BITS: usize = FRAME_BYTES * 2 * 7;
+/// Fewest blocks every coded bit has to be spread over
+const MIN_BLOCKS_PER_BIT: usize = 16;
+/// Key of the order photosites are picked in and of the bits they are scrambled with
+const KEY: u64 = 0x7261_772d_7469_6666;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkError {
+    /// The payload is longer than `MAX_PAYLOAD`
+    TooLong(usize),
+    /// The image has too few blocks to spread the payload over
+    TooSmall { blocks: usize, needed: usize },
+}
+
+impl fmt::Display for WatermarkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatermarkError::TooLong(len) => write!(
+                f,
+                "a watermark of {} bytes is longer than the {} that fit",
+                len, MAX_PAYLOAD
+            ),
+            WatermarkError::TooSmall { blocks, needed } => write!(
+                f,
+                "the image has {} blocks, but a watermark needs {}",
+                blocks, needed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WatermarkError {}
+
+/// A watermark read from an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extracted {
+    pub payload: Vec<u8>,
+    /// Blocks that carry a bit
+    pub blocks: usize,
+    /// Blocks whose bit agrees with the payload read
+    pub agreeing: usize,
+}
+
+impl fmt::Display for Extracted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "watermark {:?} ({} of {} blocks agree)",
+            String::from_utf8_lossy(&self.payload),
+            self.agreeing,
+            self.blocks
+        )
+    }
+}
+
+/// SplitMix64 of the block index, keyed
+fn hash(index: usize) -> u64 {
+    let mut z = (index as u64 ^ KEY).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The Hamming (7, 4) code of a nibble, parity bits at positions 1, 2 and 4
+fn hamming_encode(nibble: u8) -> [bool; 7] {
+    let data = |bit: u8| nibble >> bit & 1 == 1;
+    let (d1, d2, d3, d4) = (data(3), data(2), data(1), data(0));
+    [d1 ^ d2 ^ d4, d1 ^ d3 ^ d4, d1, d2 ^ d3 ^ d4, d2, d3, d4]
+}
+
+/// The nibble of a Hamming (7, 4) code, with up to one bit corrected
+fn hamming_decode(code: &[bool]) -> u8 {
+    let mut code = [
+        code[0], code[1], code[2], code[3], code[4], code[5], code[6],
+    ];
+    let syndrome = (1..=7)
+        .filter(|position| code[position - 1])
+        .fold(0, |s, p| s ^ p);
+    if syndrome != 0 {
+        code[syndrome - 1] ^= true;
+    }
+    (code[2] as u8) << 3 | (code[4] as u8) << 2 | (code[5] as u8) << 1 | code[6] as u8
+}
+
+/// The coded bits of a payload of at most `MAX_PAYLOAD` bytes
+fn frame_bits(payload: &[u8]) -> Vec<bool> {
+    let mut frame = [0u8; FRAME_BYTES];
+    frame[0] = payload.len() as u8;
+    frame[1..1 + payload.len()].copy_from_slice(payload);
+    let crc = crc16(&frame[..FRAME_BYTES - 2]);
+    frame[FRAME_BYTES - 2..].copy_from_slice(&crc.to_be_bytes());
+    frame
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0xf])
+        .flat_map(hamming_encode)
+        .collect()
+}
+
+/// The payload of coded bits, if they make up a frame whose CRC matches
+fn payload_of(bits: &[bool]) -> Option<Vec<u8>> {
+    let nibbles: Vec<u8> = bits.chunks(7).map(hamming_decode).collect();
+    let frame: Vec<u8> = nibbles
+        .chunks(2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect();
+    let crc = u16::from_be_bytes([frame[FRAME_BYTES - 2], frame[FRAME_BYTES - 1]]);
+    let len = frame[0] as usize;
+    if crc != crc16(&frame[..FRAME_BYTES - 2]) || len == 0 || len > MAX_PAYLOAD {
+        return None;
+    }
+    Some(frame[1..1 + len].to_vec())
+}
+
+/// The photosite of a block that carries its bit
+#[derive(Debug, Clone, Copy)]
+struct Carrier {
+    /// Position in the block
+    position: usize,
+    /// The delta the encoder stores for it
+    delta: u16,
+    min: u16,
+    max: u16,
+    shift: u32,
+}
+
+impl Carrier {
+    /// The first photosite in the block's keyed order whose stored code lies strictly
+    /// between the block's smallest and largest, if any
+    fn find(codes: &[u16; BLOCK_PIXELS], hash: u64) -> Option<Carrier> {
+        let max = *codes.iter().max()?;
+        let min = *codes.iter().min()?;
+        let shift = encode_delta_shift(max - min);
+        let start = hash as usize % BLOCK_PIXELS;
+        (0..BLOCK_PIXELS)
+            .map(|i| (start + i) % BLOCK_PIXELS)
+            .map(|position| Carrier {
+                position,
+                delta: (codes[position] - min) >> shift,
+                min,
+                max,
+                shift,
+            })
+            .find(|carrier| carrier.delta > 0 && carrier.code(carrier.delta) < max)
+    }
+
+    fn code(&self, delta: u16) -> u16 {
+        self.min + (delta << self.shift)
+    }
+}
+
+/// Every full block of the image with its index, and the indices of its photosites in
+/// `pixels`
+fn blocks(width: usize, height: usize) -> impl Iterator<Item = (usize, [usize; BLOCK_PIXELS])> {
+    let groups = width / GROUP_PIXELS;
+    (0..height * groups * 2).map(move |index| {
+        let position = BlockPosition {
+            row: index / 2 / groups,
+            group: index / 2 % groups,
+            half: index % 2,
+        };
+        let mut pixels = [0; BLOCK_PIXELS];
+        for (pixel, column) in pixels.iter_mut().zip(position.columns()) {
+            *pixel = position.row * width + column;
+        }
+        (index, pixels)
+    })
+}
+
+/// The stored codes of a block
+fn codes(
+    pixels: &[u16],
+    curve: &LookupTable,
+    block: &[usize; BLOCK_PIXELS],
+) -> [u16; BLOCK_PIXELS] {
+    let mut codes = [0; BLOCK_PIXELS];
+    for (code, index) in codes.iter_mut().zip(block) {
+        *code = curve.reverse_lookup(pixels[*index]) >> 1;
+    }
+    codes
+}
+
+/// Hides `payload` in the rows of `width` pixels of ARW2 data about to be encoded with
+/// `curve`. Only the rows' full groups of 32 pixels are used, in the layout the data is
+/// stored in.
+///
+/// ```
+/// use raw_tiff_edit::dither;
+/// use raw_tiff_edit::rawloader::{calculate_curve, decode_arw2_from, encode_arw2};
+/// use raw_tiff_edit::source::MemorySource;
+/// use raw_tiff_edit::watermark;
+///
+/// let (width, height) = (512, 256);
+/// let curve = calculate_curve();
+/// let mut pixels: Vec<u16> = (0..width * height)
+///     .map(|i| (600 + (i % width) * 20 + (i * 7919 % 97)) as u16)
+///     .collect();
+/// watermark::embed(&mut pixels, width, &curve, b"(c) 2024 F. Z.")?;
+///
+/// let data = encode_arw2(&pixels, width, &curve)?;
+/// let mut src = MemorySource::new(&data);
+/// let mut decoded = decode_arw2_from(&mut src, 0, width, height, &curve, dither::camera)?;
+/// // a stamp over a tenth of the image takes away some of the votes
+/// for pixel in &mut decoded[..width * height / 10] {
+///     *pixel = 4000;
+/// }
+/// let found = watermark::extract(&decoded, width, &curve).unwrap();
+/// assert_eq!(found.payload, b"(c) 2024 F. Z.");
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn embed(
+    pixels: &mut [u16],
+    width: usize,
+    curve: &LookupTable,
+    payload: &[u8],
+) -> Result<(), WatermarkError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(WatermarkError::TooLong(payload.len()));
+    }
+    let height = pixels.len().checked_div(width).unwrap_or(0);
+    let available = height * (width / GROUP_PIXELS) * 2;
+    let needed = CODED_BITS * MIN_BLOCKS_PER_BIT;
+    if available < needed {
+        return Err(WatermarkError::TooSmall {
+            blocks: available,
+            needed,
+        });
+    }
+    let bits = frame_bits(payload);
+    let centers = curve.centers();
+    for (index, block) in blocks(width, height) {
+        let hash = hash(index);
+        let carrier = match Carrier::find(&codes(pixels, curve, &block), hash) {
+            Some(carrier) => carrier,
+            None => continue,
+        };
+        let bit = bits[index % CODED_BITS] ^ (hash >> 32 & 1 == 1);
+        if (carrier.delta & 1 == 1) == bit {
+            continue;
+        }
+        // one step down, unless that would make it the block's smallest
+        let delta = if carrier.delta > 1 {
+            carrier.delta - 1
+        } else {
+            carrier.delta + 1
+        };
+        let code = carrier.code(delta);
+        if code >= carrier.max {
+            continue;
+        }
+        let value = centers[(code as usize) << 1];
+        if curve.reverse_lookup(value) >> 1 == code {
+            pixels[block[carrier.position]] = value;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the watermark `embed` hid in ARW2 data decoded with `curve`, if there is one
+pub fn extract(pixels: &[u16], width: usize, curve: &LookupTable) -> Option<Extracted> {
+    let height = pixels.len().checked_div(width).unwrap_or(0);
+    let mut votes = vec![0i64; CODED_BITS];
+    let mut read = vec![];
+    for (index, block) in blocks(width, height) {
+        let hash = hash(index);
+        if let Some(carrier) = Carrier::find(&codes(pixels, curve, &block), hash) {
+            let bit = (carrier.delta & 1 == 1) ^ (hash >> 32 & 1 == 1);
+            votes[index % CODED_BITS] += if bit { 1 } else { -1 };
+            read.push((index, bit));
+        }
+    }
+    let bits: Vec<bool> = votes.iter().map(|vote| *vote > 0).collect();
+    let payload = payload_of(&bits)?;
+    let sent = frame_bits(&payload);
+    let agreeing = read
+        .iter()
+        .filter(|(index, bit)| sent[index % CODED_BITS] == *bit)
+        .count();
+    Some(Extracted {
+        payload,
+        blocks: read.len(),
+        agreeing,
+    })
+}
@@ -0,0 +1,107 @@
+//! Pins down the output of `decode_arw2_group`, the inner loop of ARW2 decoding, on fixed
+//! data, and checks that it decodes bit for bit what the pixel-at-a-time reference
+//! `decode_arw2_group_scalar` does on random groups.
+
+use raw_tiff_edit::dither::{CameraDither, DitherSource};
+use raw_tiff_edit::rawloader::{
+    calculate_curve, decode_arw2_group, decode_arw2_group_scalar, BitPump, BitPumpLSB, BitWriter,
+    BitWriterLSB, LookupTable,
+};
+
+const CASES: usize = if cfg!(miri) { 8 } else { 2000 };
+
+/// xorshift64*, as in the bit pump tests
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+/// Decodes a row of `width` pixels group by group, the way the decoders do
+fn decode_row(data: &[u8], width: usize, curve: &LookupTable) -> Vec<u16> {
+    let mut pump = BitPumpLSB::new(data);
+    let mut dither = CameraDither::default();
+    dither.reseed(pump.peek_bits(16));
+    let mut out = vec![0; width];
+    for group in out.chunks_mut(32) {
+        decode_arw2_group(&mut pump, curve, &mut dither, group);
+    }
+    out
+}
+
+/// Two groups of blocks spanning wide ranges of values, the second cut short to 16 pixels;
+/// the dither carries over from the first group to the second
+#[rustfmt::skip]
+const DATA: [u8; 64] = [
+    0x58, 0xb7, 0xcb, 0x05, 0xb1, 0x51, 0xb2, 0x09, 0x9d, 0x0a, 0x1e, 0x34, 0xfb, 0xa3, 0x42, 0xa5,
+    0x5a, 0xff, 0x89, 0x42, 0x62, 0x33, 0x05, 0x32, 0xab, 0xdb, 0xd7, 0xb2, 0x05, 0xed, 0x26, 0xb9,
+    0x06, 0xdf, 0x94, 0x21, 0x27, 0xa8, 0x32, 0xee, 0x7e, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xf8, 0xde, 0x14, 0x1d, 0xad, 0xd6, 0x13, 0xf1, 0x54, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[rustfmt::skip]
+const DECODED: [u16; 48] = [
+    4497, 637, 747, 925, 1163, 1501, 1931, 6251, 2951, 3544, 4239, 5081, 5852, 6764, 11865, 8505,
+    1067, 2283, 3472, 4953, 6362, 11922, 9234, 2155, 3856, 5590, 7389, 9524, 6366, 4695, 6873, 9017,
+    2459, 4791, 7211, 4920, 3511, 6187, 8877, 2845, 5686, 8790, 6703, 5931, 9233, 3514, 6702, 1333,
+];
+
+#[test]
+fn groups_decode_to_pinned_pixels() {
+    let curve = calculate_curve();
+    assert_eq!(decode_row(&DATA, DECODED.len(), &curve), DECODED);
+}
+
+#[test]
+fn groups_decode_as_the_scalar_reference_does() {
+    let curve = calculate_curve();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..CASES {
+        // random blocks after random bits, some with the maximum and the minimum at the same
+        // pixel (the block then has 15 deltas instead of 14, and runs 7 bits longer)
+        let mut writer = BitWriterLSB::new();
+        let start = rng.next() % 8;
+        writer.push_bits(rng.next() as u32, start as u32);
+        for _ in 0..2 {
+            let imax = rng.next() as u32 % 16;
+            let imin = if rng.next().is_multiple_of(4) {
+                imax
+            } else {
+                rng.next() as u32 % 16
+            };
+            writer.push_bits(rng.next() as u32, 22);
+            writer.push_bits(imax, 4);
+            writer.push_bits(imin, 4);
+            for _ in 0..15 {
+                writer.push_bits(rng.next() as u32, 7);
+            }
+        }
+        let data = writer.into_data();
+        let len = 1 + (rng.next() % 32) as usize;
+        let seed = rng.next() as u32;
+
+        let mut decoded = vec![];
+        for decode in [decode_arw2_group::<CameraDither>, decode_arw2_group_scalar] {
+            let mut pump = BitPumpLSB::at_bit(&data, start);
+            let mut dither = CameraDither(seed);
+            let mut out = vec![0; len];
+            decode(&mut pump, &curve, &mut dither, &mut out);
+            decoded.push((out, pump.bit_position(), dither.0));
+        }
+        assert_eq!(decoded[0], decoded[1], "{:02x?} from bit {}", data, start);
+    }
+}
+
+#[cfg(feature = "test-vectors")]
+#[test]
+fn groups_decode_test_vectors() {
+    use raw_tiff_edit::test_vectors;
+
+    let curve = calculate_curve();
+    test_vectors::check_decoder(|data, width| decode_row(data, width, &curve)).unwrap();
+}
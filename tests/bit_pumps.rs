@@ -0,0 +1,182 @@
+//! Property tests of the bit pumps and writers: whatever is written at whatever widths comes
+//! back the same, wherever the values fall relative to bytes and refill words, and arbitrary
+//! reads of arbitrary data never panic, nor does decoding arbitrary ARW2 data. The cases come
+//! from a fixed generator, so failures reproduce.
+//!
+//! They run under miri, with fewer cases, as
+//!
+//! ```text
+//! MIRIFLAGS=-Zmiri-ignore-leaks cargo +nightly miri test --test bit_pumps
+//! ```
+//!
+//! where the flag lets the threads of rayon's global pool, which decoding starts and never
+//! joins, outlive the test.
+
+use raw_tiff_edit::dither;
+use raw_tiff_edit::rawloader::{
+    calculate_curve, decode_arw2_from, BEu32_padded, BitPump, BitPumpLSB, BitPumpMSB, BitWriter,
+    BitWriterLSB, BitWriterMSB, LEu32_padded,
+};
+use raw_tiff_edit::source::MemorySource;
+use raw_tiff_edit::RawEditError;
+
+const CASES: usize = if cfg!(miri) { 8 } else { 500 };
+
+/// xorshift64*, enough to spread the cases around
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Values paired with widths from 0 to 32, each value fitting its width
+fn fields(rng: &mut Rng) -> Vec<(u32, u32)> {
+    let len = rng.below(200) as usize;
+    (0..len)
+        .map(|_| {
+            let width = rng.below(33) as u32;
+            let value = (rng.next() as u32).checked_shr(32 - width).unwrap_or(0);
+            (value, width)
+        })
+        .collect()
+}
+
+fn round_trip<W: BitWriter>(writer: fn() -> W, pump: fn(&[u8]) -> Box<dyn BitPump + '_>) {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..CASES {
+        let fields = fields(&mut rng);
+        let mut w = writer();
+        for &(value, width) in &fields {
+            w.push_bits(value, width);
+        }
+        let data = w.into_data();
+        let bits: u64 = fields.iter().map(|&(_, width)| width as u64).sum();
+        assert_eq!(data.len() as u64, bits.div_ceil(8));
+
+        let mut pump = pump(&data);
+        let mut position = 0;
+        for &(value, width) in &fields {
+            assert_eq!(pump.peek_bits(width), value, "{:?}", fields);
+            assert_eq!(pump.get_bits(width), value, "{:?}", fields);
+            position += width as u64;
+            assert_eq!(pump.bit_position(), position);
+        }
+        // past the end of the data come zeros
+        assert_eq!(pump.get_bits(32), 0);
+        assert_eq!(pump.get_bits(32), 0);
+    }
+}
+
+#[test]
+fn lsb_round_trip() {
+    round_trip(BitWriterLSB::new, |data| Box::new(BitPumpLSB::new(data)));
+}
+
+#[test]
+fn msb_round_trip() {
+    round_trip(BitWriterMSB::new, |data| Box::new(BitPumpMSB::new(data)));
+}
+
+#[test]
+fn writers_keep_only_the_low_bits() {
+    let mut rng = Rng(0x0123_4567_89ab_cdef);
+    for _ in 0..CASES {
+        let value = rng.next() as u32;
+        let width = rng.below(40) as u32;
+        // widths past 32 are clamped
+        let kept = value & u32::MAX.checked_shr(32 - width.min(32)).unwrap_or(0);
+
+        let mut lsb = BitWriterLSB::new();
+        let mut msb = BitWriterMSB::new();
+        lsb.push_bits(value, width);
+        msb.push_bits(value, width);
+        let (lsb, msb) = (lsb.into_data(), msb.into_data());
+        assert_eq!(BitPumpLSB::new(&lsb).get_bits(width), kept);
+        assert_eq!(BitPumpMSB::new(&msb).get_bits(width), kept);
+    }
+}
+
+#[test]
+fn lsb_pump_starts_at_any_bit() {
+    let mut rng = Rng(0xdead_beef_cafe_f00d);
+    for _ in 0..CASES {
+        let data: Vec<u8> = (0..rng.below(64)).map(|_| rng.next() as u8).collect();
+        let start = rng.below(data.len() as u64 * 8 + 80);
+        let width = rng.below(33) as u32;
+
+        let mut from_start = BitPumpLSB::new(&data);
+        let mut skip = start;
+        while skip > 0 {
+            let step = skip.min(32) as u32;
+            from_start.get_bits(step);
+            skip -= step as u64;
+        }
+        let mut at_bit = BitPumpLSB::at_bit(&data, start);
+        assert_eq!(at_bit.get_bits(width), from_start.get_bits(width));
+    }
+}
+
+#[test]
+fn arbitrary_reads_do_not_panic() {
+    let mut rng = Rng(0x5555_aaaa_3333_cccc);
+    for _ in 0..CASES {
+        let data: Vec<u8> = (0..rng.below(24)).map(|_| rng.next() as u8).collect();
+        let mut lsb = BitPumpLSB::at_bit(&data, rng.next());
+        let mut msb = BitPumpMSB::new(&data);
+        for _ in 0..32 {
+            // widths past 32 are clamped, not shifted out of range
+            let width = rng.below(70) as u32;
+            match rng.below(3) {
+                0 => {
+                    lsb.get_bits(width);
+                    msb.get_bits(width);
+                }
+                1 => {
+                    lsb.peek_bits(width);
+                    msb.peek_bits(width);
+                }
+                _ => {
+                    lsb.consume_bits(width);
+                    msb.consume_bits(width);
+                }
+            }
+            lsb.bit_position();
+            msb.bit_position();
+        }
+        let pos = rng.below(data.len() as u64 + 8) as usize;
+        LEu32_padded(&data, pos);
+        BEu32_padded(&data, pos);
+        LEu32_padded(&data, usize::MAX);
+        BEu32_padded(&data, usize::MAX);
+    }
+}
+
+#[test]
+fn arbitrary_arw2_data_decodes_without_panicking() {
+    let mut rng = Rng(0x0f0f_1e1e_2d2d_3c3c);
+    let curve = calculate_curve();
+    for _ in 0..CASES / 4 {
+        let width = 1 + rng.below(80) as usize;
+        let height = 1 + rng.below(3) as usize;
+        let data: Vec<u8> = (0..width * height).map(|_| rng.next() as u8).collect();
+        let mut src = MemorySource::new(&data);
+        let decoded = decode_arw2_from(&mut src, 0, width, height, &curve, dither::camera)
+            .expect("the data covers every row");
+        assert_eq!(decoded.len(), width * height);
+        // and cut short, it is reported as truncated
+        let mut src = MemorySource::new(&data[..data.len() - 1]);
+        assert!(matches!(
+            decode_arw2_from(&mut src, 0, width, height, &curve, dither::camera),
+            Err(RawEditError::Truncated(_))
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+//! The command line as a user meets it: the help, how values are taken, and what options
+//! cannot be combined with. Usage errors are found before any input is read, so none of the
+//! files named here exist.
+
+use std::process::{Command, Output};
+
+/// Exit status of usage errors
+const USAGE: i32 = 2;
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_raw-tiff-edit"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// The message of a usage error
+fn refused(args: &[&str]) -> String {
+    let output = run(args);
+    assert_eq!(output.status.code(), Some(USAGE), "{:?}", args);
+    String::from_utf8(output.stderr)
+        .unwrap()
+        .trim_end()
+        .to_owned()
+}
+
+#[test]
+fn help_lists_every_option_within_the_line_width() {
+    let output = run(&["--help"]);
+    assert!(output.status.success());
+    let help = String::from_utf8(output.stdout).unwrap();
+    for option in [
+        "--input FILE",
+        "--validate[=TOLERANCE]",
+        "--yes-i-know",
+        "--y Y",
+    ] {
+        assert!(help.contains(&format!("\n  {} ", option)), "{}", option);
+    }
+    assert!(help.lines().all(|line| line.len() <= 100), "{}", help);
+    // and without inputs, it is what the usage error says
+    assert_eq!(refused(&[]), help.trim_end());
+}
+
+#[test]
+fn values_are_taken_either_way() {
+    assert_eq!(refused(&["--jobs", "0", "x.arw"]), "invalid --jobs: 0");
+    assert_eq!(refused(&["--jobs=0", "x.arw"]), "invalid --jobs: 0");
+    assert_eq!(refused(&["x.arw", "--output"]), "--output needs a value");
+    // optional values only come after an equals sign, so this is an input
+    assert_eq!(
+        refused(&["--stats", "yaml", "--opacity=1"]),
+        "--opacity needs --overlay"
+    );
+    assert_eq!(
+        refused(&["--stats=yaml", "x.arw"]),
+        "invalid --stats format: yaml, expected text or json"
+    );
+    assert_eq!(
+        refused(&["--tile=3", "x.arw"]),
+        "unknown argument: --tile=3"
+    );
+    assert_eq!(
+        refused(&["--colour", "x.arw"]),
+        "unknown argument: --colour"
+    );
+}
+
+#[test]
+fn conflicting_options_are_refused() {
+    assert_eq!(
+        refused(&["--in-place", "--yes-i-know", "--output=o.arw", "x.arw"]),
+        "--in-place and --output cannot be combined"
+    );
+    assert_eq!(
+        refused(&["--anchor=center", "--tile", "--overlay=o.png", "x.arw"]),
+        "--anchor and --tile cannot be combined"
+    );
+    assert_eq!(
+        refused(&["--frames=2", "--ifd=1", "x.arw"]),
+        "--frames and --ifd cannot be combined"
+    );
+    assert_eq!(
+        refused(&["--in-place", "x.arw"]),
+        "--in-place overwrites the original file; add --yes-i-know to confirm"
+    );
+}
+
+#[test]
+fn options_that_need_another_are_refused_alone() {
+    assert_eq!(
+        refused(&["--upright-previews", "x.arw"]),
+        "--upright-previews needs --all-renditions, --previews-only or --regenerate-previews"
+    );
+    assert_eq!(refused(&["--radius=3", "x.arw"]), "--radius needs --probe");
+    assert_eq!(
+        refused(&["--export-only", "x.arw"]),
+        "--export-only needs --export, --export-preview or --export-frame"
+    );
+}
+
+#[test]
+fn groups_of_options_are_refused_together() {
+    assert_eq!(
+        refused(&["--previews-only", "--rotate180", "x.arw"]),
+        "--rotate180 needs the raw data and cannot be used with --previews-only"
+    );
+    assert_eq!(
+        refused(&["--frames=all", "--container=minimal", "x.arw"]),
+        "--container minimal is about a single raw image and cannot be used with --frames all"
+    );
+    // the original container is about every frame
+    let output = run(&["--frames=all", "--container=original", "missing.arw"]);
+    assert_ne!(output.status.code(), Some(USAGE));
+    assert_eq!(
+        refused(&["--dng=a.dng", "x.arw", "y.arw"]),
+        "--dng names a single file and cannot be used with several inputs"
+    );
+    assert_eq!(
+        refused(&["x.arw", "y.arw"]),
+        "several inputs need an --output-dir (or --in-place --yes-i-know)"
+    );
+    let output = run(&["--info", "x.arw", "y.arw"]);
+    assert_ne!(output.status.code(), Some(USAGE));
+}
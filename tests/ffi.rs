@@ -0,0 +1,232 @@
+//! The C interface, called the way a C host would, following the ownership rules in the
+//! documentation of `ffi`: what each pointer handed out points to, and for how long.
+//!
+//! Under miri, which checks that the pointers are only used while they are valid, they run
+//! as
+//!
+//! ```text
+//! MIRIFLAGS="-Zmiri-ignore-leaks -Zmiri-disable-isolation" \
+//!     cargo +nightly miri test --features ffi --test ffi
+//! ```
+//!
+//! with access to the files the tests write, and leaving rayon's threads running at the end.
+
+#![cfg(feature = "ffi")]
+
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
+use std::{fs, process, ptr, slice};
+
+use raw_tiff_edit::container::write_minimal_arw2;
+use raw_tiff_edit::ffi::*;
+use raw_tiff_edit::rawloader::{calculate_curve, encode_arw2};
+use raw_tiff_edit::RawImage;
+
+const WIDTH: usize = 96;
+const HEIGHT: usize = 16;
+
+/// A directory of its own for every test, holding a small ARW2 file
+struct Files {
+    dir: PathBuf,
+    original: CString,
+}
+
+impl Files {
+    fn new(test: &str) -> Files {
+        let dir = std::env::temp_dir().join(format!("ffi-{}-{}", test, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pixels: Vec<u16> = (0..WIDTH * HEIGHT)
+            .map(|i| 600 + (i * 37 % 4000) as u16)
+            .collect();
+        let image = RawImage::new(pixels, WIDTH, HEIGHT, 16383);
+        let data = encode_arw2(&image.pixels, WIDTH, &calculate_curve()).unwrap();
+        let mut file = vec![];
+        write_minimal_arw2(&mut file, &[], &image, &data, HEIGHT).unwrap();
+        let original = dir.join("original.arw");
+        fs::write(&original, file).unwrap();
+        Files {
+            original: CString::new(original.to_str().unwrap()).unwrap(),
+            dir,
+        }
+    }
+
+    fn path(&self, name: &str) -> CString {
+        CString::new(self.dir.join(name).to_str().unwrap()).unwrap()
+    }
+}
+
+impl Drop for Files {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn empty_plane() -> RawEditPlane {
+    RawEditPlane {
+        pixels: ptr::null_mut(),
+        width: 0,
+        height: 0,
+        black_level: 0,
+        white_level: 0,
+        crop_x: 0,
+        crop_y: 0,
+        crop_width: 0,
+        crop_height: 0,
+    }
+}
+
+/// Sets the first row of the plane, through the pointer the handle handed out, as a C host
+/// would
+unsafe fn fill_first_row(plane: &RawEditPlane, value: u16) {
+    slice::from_raw_parts_mut(plane.pixels, plane.width).fill(value);
+}
+
+unsafe fn last_error(handle: *const RawEditHandle) -> String {
+    CStr::from_ptr(raw_edit_last_error(handle))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[test]
+fn the_plane_belongs_to_the_handle_until_the_next_decode() {
+    let files = Files::new("plane");
+    unsafe {
+        let handle = raw_edit_open(files.original.as_ptr());
+        assert!(!handle.is_null());
+        let mut plane = empty_plane();
+        assert_eq!(raw_edit_decode(handle, &mut plane), RawEditStatus::Ok);
+        assert_eq!((plane.width, plane.height), (WIDTH, HEIGHT));
+        let pixels = plane.pixels;
+
+        // the host writes into the plane; drawing and encoding keep it where it is
+        fill_first_row(&plane, plane.white_level);
+        // the `image` crate draws through a pointer made from a shared reference, which miri
+        // rejects
+        if !cfg!(miri) {
+            let text = CString::new("HI").unwrap();
+            let status = raw_edit_draw_text(handle, text.as_ptr(), 4, 4, 8.0, 9000);
+            assert_eq!(status, RawEditStatus::Ok);
+            assert_eq!(plane.pixels, pixels);
+        }
+        let (mut data, mut len) = (ptr::null(), 0);
+        assert_eq!(
+            raw_edit_encode(handle, &mut data, &mut len),
+            RawEditStatus::Ok
+        );
+        assert_eq!(plane.pixels, pixels);
+        let edited = slice::from_raw_parts(pixels, WIDTH * HEIGHT).to_vec();
+        assert!(edited[..WIDTH]
+            .iter()
+            .all(|pixel| *pixel == plane.white_level));
+
+        // a new decode hands out a new plane, from the file again
+        let mut again = empty_plane();
+        assert_eq!(raw_edit_decode(handle, &mut again), RawEditStatus::Ok);
+        let decoded = slice::from_raw_parts(again.pixels, WIDTH * HEIGHT);
+        assert_ne!(decoded, &edited[..]);
+
+        raw_edit_close(handle);
+    }
+}
+
+#[test]
+fn the_encoded_file_belongs_to_the_handle_until_replaced() {
+    let files = Files::new("encoded");
+    unsafe {
+        let handle = raw_edit_open(files.original.as_ptr());
+        let mut plane = empty_plane();
+        assert_eq!(raw_edit_decode(handle, &mut plane), RawEditStatus::Ok);
+        fill_first_row(&plane, 1000);
+
+        // the encoded file is readable through the pointer until the next encode
+        let (mut data, mut len) = (ptr::null(), 0);
+        assert_eq!(
+            raw_edit_encode(handle, &mut data, &mut len),
+            RawEditStatus::Ok
+        );
+        let first = slice::from_raw_parts(data, len).to_vec();
+        let decoded = RawImage::decode(&first).unwrap();
+        assert!(decoded.pixels[..WIDTH]
+            .iter()
+            .all(|pixel| pixel.abs_diff(1000) < 16));
+
+        // which replaces it with the file of the plane as it is then
+        fill_first_row(&plane, 3000);
+        assert_eq!(
+            raw_edit_encode(handle, &mut data, &mut len),
+            RawEditStatus::Ok
+        );
+        let second = slice::from_raw_parts(data, len).to_vec();
+        assert_ne!(first, second);
+
+        // saving writes the file of the last encode, not the plane as it is now
+        fill_first_row(&plane, 5000);
+        let edited = files.path("edited.arw");
+        assert_eq!(raw_edit_save(handle, edited.as_ptr()), RawEditStatus::Ok);
+        assert_eq!(fs::read(edited.to_str().unwrap()).unwrap(), second);
+
+        // a decode drops the encoded file along with the old plane
+        assert_eq!(raw_edit_decode(handle, &mut plane), RawEditStatus::Ok);
+        let status = raw_edit_save(handle, edited.as_ptr());
+        assert_eq!(status, RawEditStatus::NotReady);
+        assert_eq!(last_error(handle), "the image has not been encoded");
+
+        raw_edit_close(handle);
+    }
+}
+
+#[test]
+fn the_original_is_never_saved_over() {
+    let files = Files::new("original");
+    let before = fs::read(files.original.to_str().unwrap()).unwrap();
+    unsafe {
+        let handle = raw_edit_open(files.original.as_ptr());
+        let mut plane = empty_plane();
+        assert_eq!(raw_edit_decode(handle, &mut plane), RawEditStatus::Ok);
+        fill_first_row(&plane, 2000);
+        assert_eq!(
+            raw_edit_encode(handle, ptr::null_mut(), ptr::null_mut()),
+            RawEditStatus::Ok
+        );
+        let status = raw_edit_save(handle, files.original.as_ptr());
+        assert_eq!(status, RawEditStatus::WouldOverwriteOriginal);
+        assert!(last_error(handle).contains("refusing to overwrite the original"));
+        raw_edit_close(handle);
+    }
+    assert_eq!(fs::read(files.original.to_str().unwrap()).unwrap(), before);
+}
+
+#[test]
+fn misuse_is_reported_without_touching_anything() {
+    let files = Files::new("misuse");
+    unsafe {
+        assert!(raw_edit_open(ptr::null()).is_null());
+        assert!(raw_edit_open(files.path("missing.arw").as_ptr()).is_null());
+        let mut plane = empty_plane();
+        let status = raw_edit_decode(ptr::null_mut(), &mut plane);
+        assert_eq!(status, RawEditStatus::InvalidArgument);
+        assert!(raw_edit_last_error(ptr::null()).is_null());
+        raw_edit_close(ptr::null_mut());
+
+        let handle = raw_edit_open(files.original.as_ptr());
+        assert_eq!(last_error(handle), "");
+        let status = raw_edit_encode(handle, ptr::null_mut(), ptr::null_mut());
+        assert_eq!(status, RawEditStatus::NotReady);
+        assert_eq!(last_error(handle), "the image has not been decoded");
+        let status = raw_edit_draw_text(handle, ptr::null(), 0, 0, 8.0, 0);
+        assert_eq!(status, RawEditStatus::InvalidArgument);
+        assert_eq!(
+            raw_edit_decode(handle, ptr::null_mut()),
+            RawEditStatus::InvalidArgument
+        );
+        raw_edit_close(handle);
+
+        let not_raw = files.path("not-raw.txt");
+        fs::write(not_raw.to_str().unwrap(), "hello").unwrap();
+        let handle = raw_edit_open(not_raw.as_ptr());
+        let status = raw_edit_decode(handle, &mut plane);
+        assert_eq!(status, RawEditStatus::Unsupported);
+        assert!(plane.pixels.is_null());
+        raw_edit_close(handle);
+    }
+}
@@ -0,0 +1,113 @@
+//! Stress test of the thread safety the codec promises: many threads decoding and encoding
+//! at once, sharing the curve and the data, get exactly what a single thread gets.
+
+use std::thread;
+
+use raw_tiff_edit::dither;
+use raw_tiff_edit::pipeline::{encode_arw2_pipelined, PipelineConfig};
+use raw_tiff_edit::rawloader::{
+    calculate_curve, decode_arw2_from, decode_arw2_roi, decode_arw_packed12, encode_arw2,
+    encode_arw_packed12, LookupTable,
+};
+use raw_tiff_edit::source::MemorySource;
+use raw_tiff_edit::tiled::Rect;
+
+const THREADS: usize = 8;
+const ROUNDS: usize = 4;
+
+/// Everything the codec makes of one image
+#[derive(Debug, PartialEq, Eq)]
+struct Results {
+    encoded: Vec<u8>,
+    pipelined: Vec<u8>,
+    decoded: Vec<u16>,
+    roi: Vec<u16>,
+    reencoded: Vec<u8>,
+    packed12: Vec<u16>,
+}
+
+/// An image with a bit of everything: flat areas, ramps, and noise
+fn image(width: usize, height: usize, seed: u32) -> Vec<u16> {
+    let mut state = seed | 1;
+    (0..width * height)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            match (i / width) % 3 {
+                0 => 2000,
+                1 => (i % width * 97) as u16,
+                _ => 512 + (state % 15000) as u16,
+            }
+        })
+        .collect()
+}
+
+fn run(img: &[u16], width: usize, curve: &LookupTable) -> Results {
+    let height = img.len() / width;
+    let encoded = encode_arw2(img, width, curve).unwrap();
+    let mut pipelined = Vec::new();
+    let config = PipelineConfig {
+        rows_per_chunk: 5,
+        ..PipelineConfig::default()
+    };
+    encode_arw2_pipelined(img, width, curve, &config, &mut pipelined).unwrap();
+    let mut src = MemorySource::new(&encoded);
+    let decoded = decode_arw2_from(&mut src, 0, width, height, curve, dither::camera).unwrap();
+    let roi = Rect {
+        x: 33,
+        y: 7,
+        width: 50,
+        height: 20,
+    };
+    let roi = decode_arw2_roi(&encoded, width, height, roi, curve, dither::camera).unwrap();
+    let reencoded = encode_arw2(&decoded, width, curve).unwrap();
+    let packed = encode_arw_packed12(img, width).unwrap();
+    let packed12 = decode_arw_packed12(&packed, width, height).unwrap();
+    Results {
+        encoded,
+        pipelined,
+        decoded,
+        roi,
+        reencoded,
+        packed12,
+    }
+}
+
+#[test]
+fn threads_get_what_a_single_thread_gets() {
+    let curve = calculate_curve();
+    let images: Vec<(usize, Vec<u16>)> = [(100, 40), (160, 70), (96, 33)]
+        .iter()
+        .zip(1..)
+        .map(|(&(width, height), seed)| (width, image(width, height, seed)))
+        .collect();
+
+    let single = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let expected: Vec<Results> = single.install(|| {
+        images
+            .iter()
+            .map(|(width, img)| run(img, *width, &curve))
+            .collect()
+    });
+
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            let (curve, images, expected) = (&curve, &images, &expected);
+            scope.spawn(move || {
+                for round in 0..ROUNDS {
+                    // every thread starts on a different image, so all of them are in
+                    // flight at once
+                    for i in 0..images.len() {
+                        let i = (i + t + round) % images.len();
+                        let (width, img) = &images[i];
+                        assert!(run(img, *width, curve) == expected[i], "image {}", i);
+                    }
+                }
+            });
+        }
+    });
+}